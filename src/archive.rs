@@ -0,0 +1,255 @@
+//! Single file archives of a store
+//!
+//! An archive packs every file in a store's directory, other than its
+//! lock, into one file that can be copied around as a backup:
+//!
+//! * magic (u32) and version (u32)
+//! * entry count (u32), then per file its name length (u16), name, length
+//!   (u64) and sha3 hash (32 bytes)
+//! * sha3 hash of everything before it (32 bytes)
+//! * the files' contents, in entry order
+//!
+//! Integers are little endian.  Importing unpacks to a directory beside
+//! the destination, checking every file against its hash, and renames it
+//! into place only once everything has checked out.
+use super::Result;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use errors::Error;
+use hashutils::Sha3Hash;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use store::{create_dirs, set_mode, LOCK_FILE_NAME};
+
+const ARCHIVE_MAGIC: u32 = 0x6172_6b6c;
+const ARCHIVE_VERSION: u32 = 1;
+
+/// A file packed in an archive
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub length: u64,
+    pub hash: [u8; 32],
+}
+
+// Copy `length` bytes from `rdr` to `wtr`, returning their hash
+fn copy_hashed<R: Read, W: Write>(rdr: &mut R, wtr: &mut W, length: u64) -> Result<[u8; 32]> {
+    let mut hash = Sha3Hash::new();
+    let mut chunk = vec![0; 64 * 1024];
+    let mut left = length;
+    while left > 0 {
+        let n = left.min(chunk.len() as u64) as usize;
+        rdr.read_exact(&mut chunk[..n])?;
+        hash.update(&chunk[..n]);
+        wtr.write_all(&chunk[..n])?;
+        left -= n as u64;
+    }
+    Ok(hash.finalize())
+}
+
+// The files to pack from `dir`, in name order
+fn entries(dir: &Path) -> Result<Vec<ArchiveEntry>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if name == LOCK_FILE_NAME || name.ends_with(".tmp") || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let mut f = File::open(entry.path())?;
+        let length = f.metadata()?.len();
+        let hash = copy_hashed(&mut f, &mut io::sink(), length)?;
+        entries.push(ArchiveEntry { name, length, hash });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Pack the files in `dir` into an archive at `path`, returning what it
+/// holds.  The store must not be written to while it's packed.
+pub fn write_archive(dir: &Path, path: &Path, mode: u32) -> Result<Vec<ArchiveEntry>> {
+    let entries = entries(dir)?;
+    let mut header = vec![];
+    header.write_u32::<LittleEndian>(ARCHIVE_MAGIC)?;
+    header.write_u32::<LittleEndian>(ARCHIVE_VERSION)?;
+    header.write_u32::<LittleEndian>(entries.len() as u32)?;
+    for entry in &entries {
+        header.write_u16::<LittleEndian>(entry.name.len() as u16)?;
+        header.extend_from_slice(entry.name.as_bytes());
+        header.write_u64::<LittleEndian>(entry.length)?;
+        header.extend_from_slice(&entry.hash);
+    }
+    let mut hash = Sha3Hash::new();
+    hash.update(&header);
+    header.extend_from_slice(&hash.finalize());
+
+    let mut options = OpenOptions::new();
+    options.create(true).truncate(true).write(true);
+    set_mode(&mut options, mode);
+    let mut wtr = BufWriter::new(options.open(path)?);
+    wtr.write_all(&header)?;
+    for entry in &entries {
+        let mut f = File::open(dir.join(&entry.name))?;
+        // Changed since it was hashed
+        if copy_hashed(&mut f, &mut wtr, entry.length)? != entry.hash {
+            return Err(Error::ArchiveChecksum {
+                name: entry.name.clone(),
+            });
+        }
+    }
+    wtr.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(entries)
+}
+
+fn read_u32<R: Read>(rdr: &mut R, hash: &mut Sha3Hash) -> Result<u32> {
+    let mut bits = [0; 4];
+    rdr.read_exact(&mut bits)?;
+    hash.update(&bits);
+    Ok(LittleEndian::read_u32(&bits))
+}
+
+// Read and check the entries at the start of an archive
+fn read_entries<R: Read>(rdr: &mut R) -> Result<Vec<ArchiveEntry>> {
+    let mut hash = Sha3Hash::new();
+    if read_u32(rdr, &mut hash)? != ARCHIVE_MAGIC {
+        return Err(Error::Decode("Not an archive"));
+    }
+    if read_u32(rdr, &mut hash)? != ARCHIVE_VERSION {
+        return Err(Error::Decode("Unknown archive version"));
+    }
+    let count = read_u32(rdr, &mut hash)?;
+
+    let mut entries = vec![];
+    for _ in 0..count {
+        let mut bits = [0; 2];
+        rdr.read_exact(&mut bits)?;
+        hash.update(&bits);
+        let mut name = vec![0; LittleEndian::read_u16(&bits) as usize];
+        rdr.read_exact(&mut name)?;
+        hash.update(&name);
+        let mut rest = [0; 40];
+        rdr.read_exact(&mut rest)?;
+        hash.update(&rest);
+
+        // Only plain names, nothing that could land outside the directory
+        let name = String::from_utf8(name).map_err(|_| Error::Decode("Bad archive entry name"))?;
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(Error::Decode("Bad archive entry name"));
+        }
+        let mut entry_hash = [0; 32];
+        entry_hash.copy_from_slice(&rest[8..]);
+        entries.push(ArchiveEntry {
+            name,
+            length: LittleEndian::read_u64(&rest),
+            hash: entry_hash,
+        });
+    }
+
+    let mut expected = [0; 32];
+    rdr.read_exact(&mut expected)?;
+    if hash.finalize() != expected {
+        return Err(Error::Decode("Archive header checksum mismatch"));
+    }
+    Ok(entries)
+}
+
+/// Unpack the archive at `path` into `dir`, which must not already hold
+/// anything.  Nothing appears at `dir` unless every file checks out.
+pub fn read_archive(path: &Path, dir: &Path, mode: u32) -> Result<Vec<ArchiveEntry>> {
+    if dir.exists() {
+        if fs::read_dir(dir)?.next().is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        fs::remove_dir(dir)?;
+    }
+    let mut staging = dir.as_os_str().to_owned();
+    staging.push(".import");
+    let staging = PathBuf::from(staging);
+    if staging.exists() {
+        // Left by an import that was interrupted
+        fs::remove_dir_all(&staging)?;
+    }
+    create_dirs(&staging, mode)?;
+
+    let result = unpack(path, &staging, mode);
+    match result {
+        Ok(entries) => {
+            fs::rename(&staging, dir)?;
+            Ok(entries)
+        }
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging);
+            Err(e)
+        }
+    }
+}
+
+fn unpack(path: &Path, dir: &Path, mode: u32) -> Result<Vec<ArchiveEntry>> {
+    let mut rdr = BufReader::new(File::open(path)?);
+    let entries = read_entries(&mut rdr)?;
+    for entry in &entries {
+        let mut options = OpenOptions::new();
+        options.create_new(true).write(true);
+        set_mode(&mut options, mode);
+        let mut f = options.open(dir.join(&entry.name))?;
+        if copy_hashed(&mut rdr, &mut f, entry.length)? != entry.hash {
+            return Err(Error::ArchiveChecksum {
+                name: entry.name.clone(),
+            });
+        }
+        f.sync_all()?;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashutils::sha3;
+    use store::StoreOptions;
+    use test_dir;
+    use tree::UrkelTree;
+
+    #[test]
+    fn archive_round_trip() {
+        let dir = test_dir("archive_round_trip");
+        let archive = PathBuf::from(test_dir("archive_round_trip_file"));
+        let restored = test_dir("archive_round_trip_restored");
+
+        let mut t = UrkelTree::open(&dir);
+        for i in 0..50u8 {
+            t.insert_owned(sha3(&[i]), vec![i; 20]).unwrap();
+        }
+        t.commit().unwrap();
+        let entries = t.export_archive(&archive).unwrap();
+        assert!(entries.iter().any(|e| e.name == "meta"));
+        assert!(entries.iter().all(|e| e.name != LOCK_FILE_NAME));
+
+        let mut r =
+            UrkelTree::import_archive(&archive, &restored, StoreOptions::default()).unwrap();
+        assert_eq!(r.get_root(), t.get_root());
+        assert_eq!(r.get(sha3(&[7])).unwrap(), Some(vec![7; 20]));
+        drop(r);
+
+        // A damaged archive leaves nothing behind
+        let other = test_dir("archive_round_trip_damaged");
+        let mut bits = fs::read(&archive).unwrap();
+        let last = bits.len() - 1;
+        bits[last] ^= 1;
+        fs::write(&archive, &bits).unwrap();
+        match UrkelTree::import_archive(&archive, &other, StoreOptions::default()) {
+            Err(Error::ArchiveChecksum { .. }) => {}
+            other => panic!("Unexpected {:?}", other.err()),
+        }
+        assert!(!Path::new(&other).exists());
+
+        // Nor does one restored over an existing store
+        match UrkelTree::import_archive(&archive, &restored, StoreOptions::default()) {
+            Err(Error::AlreadyExists) => {}
+            other => panic!("Unexpected {:?}", other.err()),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Where the store reads records from
+//!
+//! Nodes and values are read through a `RangeReader`.  By default that's
+//! the data files in the store's directory, but a client can inject one
+//! that fetches byte ranges from elsewhere and resolve nodes lazily.
+//!
+//! Reads are synchronous, and the meta records are still read from the
+//! store's directory, so the client needs a local copy of the files that
+//! hold them.  There's no async reader, and nothing specific to wasm32.
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+/// Reads byte ranges of the numbered data files
+pub trait RangeReader {
+    /// Fill `buf` from data file `index`, starting at byte `pos`
+    fn read_range(&self, index: u16, pos: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// Reads from the data files in a directory
+pub struct FileReader {
+    dir: PathBuf,
+}
+
+impl FileReader {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FileReader { dir: dir.into() }
+    }
+}
+
+impl RangeReader for FileReader {
+    fn read_range(&self, index: u16, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        let path = self.dir.join(format!("{:010}", index));
+        let mut f = OpenOptions::new().read(true).open(path)?;
+        f.seek(SeekFrom::Start(pos))?;
+        f.read_exact(buf)
+    }
+}
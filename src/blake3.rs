@@ -0,0 +1,122 @@
+//! BLAKE3 hashing
+//!
+//! `Blake3` is both a `Sha3Provider`, so installing it with
+//! `set_sha3_provider` makes everything the crate hashes (nodes, leaves
+//! and meta checksums) use BLAKE3, and a `Hasher`, so verifiers can check
+//! proofs from such trees:
+//!
+//! ```ignore
+//! set_sha3_provider(&Blake3);
+//! // ...
+//! verify_bytes_with(&Blake3, &root, &key, &proof, 256)?;
+//! ```
+//!
+//! The provider can only be installed once, before anything is hashed.
+//! New stores record the hash they're written with in their version file,
+//! and opening one with a different provider installed fails.
+//!
+//! The hashing is the `blake3` crate's unkeyed 32 byte hash.
+use blake3_hash;
+use hashutils::{Digest, Hasher, Sha3Provider, Sha3State, INTERNAL_PREFIX, LEAF_PREFIX};
+
+/// Tag of the BLAKE3 hasher
+pub const BLAKE3_ID: u8 = 1;
+
+impl Sha3State for blake3_hash::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3_hash::Hasher::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> [u8; 32] {
+        *blake3_hash::Hasher::finalize(&self).as_bytes()
+    }
+}
+
+/// BLAKE3 hash of `data`
+pub fn blake3(data: &[u8]) -> Digest {
+    Digest(*blake3_hash::hash(data).as_bytes())
+}
+
+/// BLAKE3 in place of sha3.  The crate hashes with it inline rather than
+/// through `start`, so installing it costs no allocation per hash.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blake3;
+
+impl Sha3Provider for Blake3 {
+    fn start(&self) -> Box<dyn Sha3State> {
+        Box::new(blake3_hash::Hasher::new())
+    }
+
+    fn hash_id(&self) -> u8 {
+        BLAKE3_ID
+    }
+}
+
+impl Hasher for Blake3 {
+    fn id(&self) -> u8 {
+        BLAKE3_ID
+    }
+
+    fn hash(&self, data: &[u8]) -> Digest {
+        blake3(data)
+    }
+
+    fn hash_leaf(&self, salt: Option<&[u8; 32]>, key: Digest, value: &[u8]) -> Digest {
+        let mut state = blake3_hash::Hasher::new();
+        state.update(&[LEAF_PREFIX]);
+        if let Some(s) = salt {
+            state.update(s);
+        }
+        state.update(&key.0);
+        state.update(value);
+        Digest(*state.finalize().as_bytes())
+    }
+
+    fn hash_internal(&self, left: Digest, right: Digest) -> Digest {
+        let mut state = blake3_hash::Hasher::new();
+        state.update(&[INTERNAL_PREFIX]);
+        state.update(&left.0);
+        state.update(&right.0);
+        Digest(*state.finalize().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashutils::hasher_for;
+
+    #[test]
+    fn matches_reference_hashes() {
+        // Inputs of the official test vectors: bytes 0..251 repeated
+        let input: Vec<u8> = (0..3073).map(|i| (i % 251) as u8).collect();
+        for (len, expected) in &[
+            (
+                0,
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                1,
+                "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+            ),
+            (
+                1024,
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                3072,
+                "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2",
+            ),
+        ] {
+            assert_eq!(format!("{:x}", blake3(&input[..*len]))[2..], **expected);
+        }
+
+        let hasher = hasher_for(BLAKE3_ID).unwrap();
+        assert_eq!(hasher.id(), BLAKE3_ID);
+        let (left, right) = (blake3(b"left"), blake3(b"right"));
+        let mut joined = vec![INTERNAL_PREFIX];
+        joined.extend_from_slice(&left.0);
+        joined.extend_from_slice(&right.0);
+        assert_eq!(hasher.hash_internal(left, right), blake3(&joined));
+    }
+}
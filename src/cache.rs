@@ -0,0 +1,134 @@
+//! Block cache that can be shared by every tree opened on a directory
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Least recently used cache of raw node and value records, keyed by
+/// their location in the store.  Records are never rewritten in place, so
+/// trees opened on the *same* directory can share one cache through an
+/// `Arc` (see `StoreOptions::cache`).
+pub struct BlockCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // (index, pos) -> (last use, record)
+    blocks: HashMap<(u16, u32), (u64, Vec<u8>)>,
+    // last use -> (index, pos), oldest first
+    order: BTreeMap<u64, (u16, u32)>,
+    tick: u64,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    /// A cache holding up to `capacity` bytes of records
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// The record at `index`/`pos`, if it is cached and `size` bytes long
+    pub fn get(&self, index: u16, pos: u32, size: usize) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let found = match inner.blocks.get_mut(&(index, pos)) {
+            Some(&mut (ref mut used, ref bytes)) if bytes.len() == size => {
+                let last = *used;
+                *used = tick;
+                Some((last, bytes.clone()))
+            }
+            _ => None,
+        };
+
+        match found {
+            Some((last, bytes)) => {
+                inner.order.remove(&last);
+                inner.order.insert(tick, (index, pos));
+                inner.hits += 1;
+                Some(bytes)
+            }
+            None => {
+                inner.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache the record read from `index`/`pos`, evicting the least
+    /// recently used records to stay within capacity
+    pub fn insert(&self, index: u16, pos: u32, bytes: &[u8]) {
+        if bytes.len() > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some((used, old)) = inner.blocks.remove(&(index, pos)) {
+            inner.order.remove(&used);
+            inner.bytes -= old.len();
+        }
+        while inner.bytes + bytes.len() > self.capacity {
+            let oldest = match inner.order.keys().next() {
+                Some(&t) => t,
+                None => break,
+            };
+            let loc = inner.order.remove(&oldest).unwrap();
+            if let Some((_, old)) = inner.blocks.remove(&loc) {
+                inner.bytes -= old.len();
+            }
+        }
+
+        inner.bytes += bytes.len();
+        inner.order.insert(tick, (index, pos));
+        inner.blocks.insert((index, pos), (tick, bytes.to_vec()));
+    }
+
+    /// Bytes of records currently cached
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().bytes
+    }
+
+    /// Lookups answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.inner.lock().unwrap().hits
+    }
+
+    /// Lookups that had to go to disk
+    pub fn misses(&self) -> u64 {
+        self.inner.lock().unwrap().misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = BlockCache::new(8);
+        cache.insert(1, 0, &[1; 4]);
+        cache.insert(1, 4, &[2; 4]);
+        assert_eq!(cache.get(1, 0, 4), Some(vec![1; 4]));
+
+        // (1, 4) is now the oldest
+        cache.insert(1, 8, &[3; 4]);
+        assert_eq!(cache.size(), 8);
+        assert_eq!(cache.get(1, 4, 4), None);
+        assert_eq!(cache.get(1, 0, 4), Some(vec![1; 4]));
+        assert_eq!(cache.get(1, 8, 4), Some(vec![3; 4]));
+
+        // Size mismatches and oversized records never hit
+        assert_eq!(cache.get(1, 0, 2), None);
+        cache.insert(2, 0, &[0; 9]);
+        assert_eq!(cache.size(), 8);
+        assert_eq!((cache.hits(), cache.misses()), (3, 2));
+    }
+}
@@ -0,0 +1,192 @@
+//! Resumable cursors over a tree's keys
+//!
+//! A `Cursor` only remembers the last key it returned, and every step
+//! descends from the root to that key's neighbour.  That makes its
+//! position cheap to hand out as a `ResumeToken` and still valid if the
+//! key has since been removed, so a listing can pick up where a previous
+//! request left off.
+use super::Result;
+use errors::Error;
+use hashutils::Digest;
+use tree::UrkelTree;
+
+/// Where a cursor is in the key order
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Position {
+    /// Before the first key
+    Start,
+    /// At a key, which may no longer be in the tree
+    At(Digest),
+    /// After the last key
+    End,
+}
+
+/// A cursor position that can be stored or sent to a client
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResumeToken {
+    position: Position,
+}
+
+impl ResumeToken {
+    /// Serialize the token
+    pub fn encode(&self) -> Vec<u8> {
+        match self.position {
+            Position::Start => vec![0],
+            Position::At(key) => {
+                let mut bits = Vec::with_capacity(33);
+                bits.push(1);
+                bits.extend_from_slice(&key.0);
+                bits
+            }
+            Position::End => vec![2],
+        }
+    }
+
+    /// Deserialize a token made by `encode`
+    pub fn decode(bits: &[u8]) -> Result<ResumeToken> {
+        let position = match (bits.first(), bits.len()) {
+            (Some(0), 1) => Position::Start,
+            (Some(1), 33) => Position::At(Digest::from(&bits[1..])),
+            (Some(2), 1) => Position::End,
+            _ => return Err(Error::Decode("Malformed resume token")),
+        };
+        Ok(ResumeToken { position })
+    }
+}
+
+/// Steps through a tree's entries in key order, in either direction
+pub struct Cursor<'t> {
+    tree: &'t mut UrkelTree,
+    position: Position,
+}
+
+impl<'t> Cursor<'t> {
+    /// A cursor before the first key of `tree`
+    pub fn new(tree: &'t mut UrkelTree) -> Self {
+        Cursor {
+            tree,
+            position: Position::Start,
+        }
+    }
+
+    /// A cursor over `tree` at the position saved in `token`
+    pub fn resume(tree: &'t mut UrkelTree, token: &ResumeToken) -> Self {
+        Cursor {
+            tree,
+            position: token.position,
+        }
+    }
+
+    /// Move to the first entry with a key of at least `key`
+    pub fn seek(&mut self, key: Digest) -> Result<Option<(Digest, Vec<u8>)>> {
+        let found = match self.tree.get(key)? {
+            Some(value) => Some((key, value)),
+            None => self.tree.entry_after(key)?,
+        };
+        Ok(self.settle(found))
+    }
+
+    /// Move back to the previous entry
+    pub fn prev(&mut self) -> Option<Result<(Digest, Vec<u8>)>> {
+        let found = match self.position {
+            Position::Start => return None,
+            Position::At(key) => self.tree.entry_before(key),
+            Position::End => self.tree.last_entry(),
+        };
+        match found {
+            Ok(Some(entry)) => {
+                self.position = Position::At(entry.0);
+                Some(Ok(entry))
+            }
+            Ok(None) => {
+                self.position = Position::Start;
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Save the cursor's position
+    pub fn token(&self) -> ResumeToken {
+        ResumeToken {
+            position: self.position,
+        }
+    }
+
+    // Stop at `found`, or run off the end
+    fn settle(&mut self, found: Option<(Digest, Vec<u8>)>) -> Option<(Digest, Vec<u8>)> {
+        self.position = match found {
+            Some((key, _)) => Position::At(key),
+            None => Position::End,
+        };
+        found
+    }
+}
+
+impl<'t> Iterator for Cursor<'t> {
+    type Item = Result<(Digest, Vec<u8>)>;
+
+    /// Move on to the next entry
+    fn next(&mut self) -> Option<Self::Item> {
+        let found = match self.position {
+            Position::Start => self.tree.first_entry(),
+            Position::At(key) => self.tree.entry_after(key),
+            Position::End => return None,
+        };
+        found.map(|f| self.settle(f)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashutils::sha3;
+    use test_dir;
+
+    #[test]
+    fn walks_both_ways_and_resumes() {
+        let mut t = UrkelTree::open(test_dir("walks_both_ways_and_resumes"));
+        let mut keys: Vec<Digest> = (0..30)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        for k in &keys[..15] {
+            t.insert(*k, b"value").unwrap();
+        }
+        t.commit().unwrap();
+        for k in &keys[15..] {
+            t.insert(*k, b"value").unwrap();
+        }
+        keys.sort_by_key(|k| k.0);
+
+        let forward: Vec<Digest> = Cursor::new(&mut t).map(|e| e.unwrap().0).collect();
+        assert_eq!(forward, keys);
+
+        // Back from the end
+        let mut cursor = Cursor::new(&mut t);
+        while cursor.next().is_some() {}
+        let mut backward = vec![];
+        while let Some(entry) = cursor.prev() {
+            backward.push(entry.unwrap().0);
+        }
+        backward.reverse();
+        assert_eq!(backward, keys);
+
+        // Seek lands on or after the key
+        let mut cursor = Cursor::new(&mut t);
+        assert_eq!(cursor.seek(keys[3]).unwrap().unwrap().0, keys[3]);
+        let token = ResumeToken::decode(&cursor.token().encode()).unwrap();
+        assert_eq!(
+            Cursor::resume(&mut t, &token).next().unwrap().unwrap().0,
+            keys[4]
+        );
+
+        // The token outlives its key
+        t.remove(keys[3]).unwrap();
+        let mut cursor = Cursor::resume(&mut t, &token);
+        assert_eq!(cursor.next().unwrap().unwrap().0, keys[4]);
+        assert_eq!(cursor.prev().unwrap().unwrap().0, keys[2]);
+        assert_eq!(cursor.seek(keys[3]).unwrap().unwrap().0, keys[4]);
+
+        assert!(ResumeToken::decode(&[1, 2, 3]).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//! Self-describing proof envelope
+//!
+//! The raw proof encoding says nothing about the tree it came from.  An
+//! envelope wraps it with what a verifier needs to check it's reading a
+//! format it understands:
+//!
+//! * magic: `URKP`
+//! * version: u8, currently 1
+//! * hash id: u8 (see `Hasher::id`)
+//! * key bits: u16
+//! * payload length: u32, then the encoded proof
+//!
+//! Integers are little endian.  Decoding rejects anything it doesn't
+//! understand, so a verifier meeting a newer format fails instead of
+//! misreading it.
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use errors::Error;
+use hashutils::{hasher_for, Digest, Hasher};
+use proof::{Proof, MAX_PROOF_DEPTH};
+
+pub const ENVELOPE_MAGIC: &[u8; 4] = b"URKP";
+pub const ENVELOPE_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 12; // 4 + 1 + 1 + 2 + 4
+
+/// An encoded proof with the parameters of the tree it's from
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Envelope {
+    pub hash_id: u8,
+    pub key_bits: u16,
+    /// The encoded proof
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    /// Wrap `proof` from a tree using `hasher` and `key_bits` bit keys
+    pub fn wrap(hasher: &dyn Hasher, key_bits: u16, proof: &Proof) -> Result<Envelope, Error> {
+        Ok(Envelope {
+            hash_id: hasher.id(),
+            key_bits,
+            payload: proof.encode()?,
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut wtr = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        wtr.extend_from_slice(ENVELOPE_MAGIC);
+        wtr.push(ENVELOPE_VERSION);
+        wtr.push(self.hash_id);
+        wtr.write_u16::<LittleEndian>(self.key_bits)?;
+        wtr.write_u32::<LittleEndian>(self.payload.len() as u32)?;
+        wtr.extend_from_slice(&self.payload);
+        Ok(wtr)
+    }
+
+    /// Decode an envelope from an untrusted peer, checking the header
+    /// describes a proof this build can verify.  The payload itself is
+    /// only decoded by `proof` or `verify`.
+    pub fn decode(bits: &[u8]) -> Result<Envelope, Error> {
+        if bits.len() < HEADER_SIZE {
+            return Err(Error::Decode("Truncated envelope"));
+        }
+        if &bits[0..4] != ENVELOPE_MAGIC {
+            return Err(Error::Decode("Not a proof envelope"));
+        }
+        if bits[4] != ENVELOPE_VERSION {
+            return Err(Error::Decode("Unsupported envelope version"));
+        }
+        let hash_id = bits[5];
+        if hasher_for(hash_id).is_none() {
+            return Err(Error::Decode("Unknown hasher"));
+        }
+        let key_bits = LittleEndian::read_u16(&bits[6..]);
+        if key_bits == 0 || key_bits % 8 != 0 || key_bits as usize > MAX_PROOF_DEPTH {
+            return Err(Error::Decode("Unsupported key size"));
+        }
+        let len = LittleEndian::read_u32(&bits[8..]) as usize;
+        if bits.len() - HEADER_SIZE != len {
+            return Err(Error::Decode("Envelope length doesn't match its payload"));
+        }
+        Ok(Envelope {
+            hash_id,
+            key_bits,
+            payload: bits[HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    pub fn proof(&self) -> Result<Proof, Error> {
+        Proof::decode(&self.payload)
+    }
+
+    /// Verify the proof with the hasher and key size the envelope names.
+    /// Returns the key's value, or None if it's absent.
+    pub fn verify(&self, root: Digest, key: Digest) -> Result<Option<Vec<u8>>, Error> {
+        let hasher = hasher_for(self.hash_id).ok_or(Error::Decode("Unknown hasher"))?;
+        let proof = self.proof()?;
+        proof
+            .check_with(hasher, root, key, self.key_bits as usize, None)
+            .map_err(Error::InvalidProof)?;
+        Ok(proof.value)
+    }
+}
+
+#[cfg(all(test, feature = "keccak"))]
+mod tests {
+    use super::*;
+    use hashutils::{sha3, sha3_value_salted, Sha3};
+    use proof::ProofType;
+
+    #[test]
+    fn envelope_round_trip() {
+        let key = sha3(b"key");
+        let mut proof = Proof::default();
+        proof.proof_type = ProofType::Exists;
+        proof.value = Some(b"value".to_vec());
+        let root = sha3_value_salted(None, key, b"value");
+        let envelope = Envelope::wrap(&Sha3, 256, &proof).unwrap();
+        let bits = envelope.encode().unwrap();
+
+        let decoded = Envelope::decode(&bits).unwrap();
+        assert_eq!(decoded, envelope);
+        assert_eq!(decoded.verify(root, key).unwrap(), Some(b"value".to_vec()));
+
+        // Every truncation, trailing bytes and a bad header are rejected
+        for len in 0..bits.len() {
+            assert!(Envelope::decode(&bits[..len]).is_err());
+        }
+        let mut long = bits.clone();
+        long.push(0);
+        assert!(Envelope::decode(&long).is_err());
+        for (pos, byte) in &[(0, b'X'), (4, 2), (5, 0xfe), (6, 7), (7, 0xff)] {
+            let mut bad = bits.clone();
+            bad[*pos] = *byte;
+            assert!(Envelope::decode(&bad).is_err());
+        }
+    }
+}
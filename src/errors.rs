@@ -0,0 +1,303 @@
+use hashutils::Digest;
+use std::error;
+use std::fmt;
+use std::io;
+
+/// The kind of record being read when a storage error occurred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    Leaf,
+    Internal,
+    Value,
+    Meta,
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordType::Leaf => write!(f, "leaf node"),
+            RecordType::Internal => write!(f, "internal node"),
+            RecordType::Value => write!(f, "leaf value"),
+            RecordType::Meta => write!(f, "meta record"),
+        }
+    }
+}
+
+/// Errors returned by the tree and store
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O failure not tied to a particular record
+    Io(io::Error),
+    /// Bytes that don't decode to the expected record
+    Decode(&'static str),
+    /// A meta record whose checksum doesn't match
+    Checksum,
+    /// A failure reading a record from a data file
+    Storage {
+        /// Data file index
+        index: u16,
+        /// Byte offset of the record in the file
+        pos: u32,
+        /// What was expected at that location
+        record: RecordType,
+        cause: Box<Error>,
+    },
+    /// A corrupt record was found while traversing to `key`
+    Corrupt {
+        key: Digest,
+        index: u16,
+        pos: u32,
+        record: RecordType,
+    },
+    /// A region previously quarantined as corrupt
+    Quarantined,
+    /// There is no commit before the latest one
+    NoPreviousCommit,
+    /// A pre-commit hook refused the commit
+    CommitVetoed(String),
+    /// A missing or invalid commit signature
+    Signature(&'static str),
+    /// A value larger than the configured maximum
+    ValueTooLarge { size: usize, max: usize },
+    /// A sealed data file was changed after it was sealed
+    SealBroken { index: u16 },
+    /// Another store holds the directory's lock
+    Locked,
+    /// The tree holds as many uncommitted nodes as it's allowed
+    OutOfBudget { nodes: usize, budget: usize },
+    /// A node read in audit mode doesn't hash to what its parent recorded
+    HashMismatch {
+        index: u16,
+        pos: u32,
+        expected: Digest,
+        actual: Digest,
+    },
+    /// A proof that doesn't lead to the expected root
+    InvalidProof(&'static str),
+    /// A version that was never saved or has been deleted
+    UnknownVersion { version: u64 },
+    /// The version the working tree is based on can't be deleted
+    ActiveVersion { version: u64 },
+    /// A compare-and-swap found a different value than expected
+    CompareFailed {
+        key: Digest,
+        /// The value actually held, if any
+        current: Option<Vec<u8>>,
+    },
+    /// A merge was requested without a merge operator registered
+    NoMergeOperator,
+    /// A root that isn't in the store's commit history
+    UnknownRoot { root: Digest },
+    /// There's no store in the directory to open
+    NotFound,
+    /// There's already a store in the directory
+    AlreadyExists,
+    /// The store was written in a format version this build can't read
+    WrongVersion { found: u32, supported: u32 },
+    /// `StoreOptions::meta_key` isn't the store's meta key
+    MetaKeyMismatch,
+    /// The store's meta key isn't kept in its directory, so it has to be
+    /// given in `StoreOptions::meta_key`
+    MissingMetaKey,
+    /// A new store needs a meta key, but the `rng` feature is off and
+    /// there's no `StoreOptions::key_source`
+    NoKeySource,
+    /// A file in an archive doesn't match its checksum
+    ArchiveChecksum { name: String },
+    /// `StoreOptions::key_size` isn't the width of the store's keys
+    KeySizeMismatch { found: u8, expected: u8 },
+    /// The store was written with a different hash than the installed
+    /// provider's (see `Sha3Provider::hash_id`)
+    HashFunctionMismatch { found: u8, expected: u8 },
+    /// Keys can be 1 to 32 bytes wide
+    UnsupportedKeySize(u8),
+    /// A key with bits set past the tree's key size
+    KeyTooWide { key_size: u8 },
+}
+
+impl Error {
+    /// Attach the location of the record being read to an error
+    pub fn storage<E: Into<Error>>(index: u16, pos: u32, record: RecordType, cause: E) -> Self {
+        Error::Storage {
+            index,
+            pos,
+            record,
+            cause: Box::new(cause.into()),
+        }
+    }
+
+    /// Is this a deterministic failure of the stored data, rather than
+    /// a failure to access it?
+    pub fn is_corruption(&self) -> bool {
+        match self {
+            Error::Decode(_)
+            | Error::Checksum
+            | Error::Corrupt { .. }
+            | Error::Quarantined
+            | Error::HashMismatch { .. } => true,
+            Error::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+            Error::Storage { cause, .. } => cause.is_corruption(),
+            _ => false,
+        }
+    }
+
+    /// Is this an I/O failure that may succeed if retried?
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(e) => is_transient_io(e),
+            Error::Storage { cause, .. } => cause.is_transient(),
+            _ => false,
+        }
+    }
+
+    /// Report a corrupt record as a `Corrupt` error for the key being
+    /// looked up. Other errors are returned unchanged.
+    pub fn for_key(self, key: Digest) -> Self {
+        match self {
+            Error::Storage {
+                index,
+                pos,
+                record,
+                ref cause,
+            } if cause.is_corruption() => Error::Corrupt {
+                key,
+                index,
+                pos,
+                record,
+            },
+            e => e,
+        }
+    }
+}
+
+// EIO, and ESTALE from network filesystems
+#[cfg(target_os = "linux")]
+const TRANSIENT_OS_ERRORS: &[i32] = &[5, 116];
+#[cfg(not(target_os = "linux"))]
+const TRANSIENT_OS_ERRORS: &[i32] = &[5];
+
+/// I/O errors worth retrying, as opposed to missing files or short reads
+/// which will fail the same way every time
+pub fn is_transient_io(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe => true,
+        _ => e
+            .raw_os_error()
+            .is_some_and(|code| TRANSIENT_OS_ERRORS.contains(&code)),
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Decode(msg) => write!(f, "Decode error: {}", msg),
+            Error::Checksum => write!(f, "Invalid meta checksum"),
+            Error::Storage {
+                index,
+                pos,
+                record,
+                cause,
+            } => write!(
+                f,
+                "Failed to read {} in file {} at position {}: {}",
+                record, index, pos, cause
+            ),
+            Error::Corrupt {
+                key,
+                index,
+                pos,
+                record,
+            } => write!(
+                f,
+                "Corrupt {} in file {} at position {} while looking up {:x}",
+                record, index, pos, key
+            ),
+            Error::Quarantined => write!(f, "Region is quarantined"),
+            Error::NoPreviousCommit => write!(f, "No previous commit"),
+            Error::CommitVetoed(reason) => write!(f, "Commit vetoed: {}", reason),
+            Error::Signature(msg) => write!(f, "Signature error: {}", msg),
+            Error::InvalidProof(msg) => write!(f, "Invalid proof: {}", msg),
+            Error::CompareFailed { key, .. } => {
+                write!(f, "Value of {:x} isn't the one expected", key)
+            }
+            Error::UnknownRoot { root } => write!(f, "Root {:x} was never committed", root),
+            Error::NoMergeOperator => write!(f, "No merge operator registered"),
+            Error::NotFound => write!(f, "No store found"),
+            Error::AlreadyExists => write!(f, "A store already exists"),
+            Error::MetaKeyMismatch => write!(f, "Meta key doesn't match the store's"),
+            Error::MissingMetaKey => write!(f, "Store needs its meta key supplied"),
+            Error::NoKeySource => write!(f, "No key source to make a meta key with"),
+            Error::KeySizeMismatch { found, expected } => write!(
+                f,
+                "Store has {} byte keys, not {} byte keys",
+                found, expected
+            ),
+            Error::HashFunctionMismatch { found, expected } => write!(
+                f,
+                "Store is hashed with hash {}, not hash {}",
+                found, expected
+            ),
+            Error::UnsupportedKeySize(size) => write!(f, "Unsupported key size {}", size),
+            Error::KeyTooWide { key_size } => {
+                write!(f, "Key has bits set past its first {} bytes", key_size)
+            }
+            Error::ArchiveChecksum { name } => {
+                write!(f, "Archived file {} doesn't match its checksum", name)
+            }
+            Error::WrongVersion { found, supported } => write!(
+                f,
+                "Store format version {} isn't supported (expected {})",
+                found, supported
+            ),
+            Error::UnknownVersion { version } => write!(f, "No saved version {}", version),
+            Error::ActiveVersion { version } => {
+                write!(f, "Version {} is the working tree's base", version)
+            }
+            Error::ValueTooLarge { size, max } => {
+                write!(f, "Value of {} bytes exceeds the maximum of {}", size, max)
+            }
+            Error::Locked => write!(f, "Store is locked by another process"),
+            Error::OutOfBudget { nodes, budget } => write!(
+                f,
+                "{} uncommitted nodes reached the budget of {}",
+                nodes, budget
+            ),
+            Error::SealBroken { index } => {
+                write!(f, "Data file {} no longer matches its seal", index)
+            }
+            Error::HashMismatch {
+                index,
+                pos,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Node in file {} at position {} hashes to {:x}, expected {:x}",
+                index, pos, actual, expected
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Storage { cause, .. } => Some(cause.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
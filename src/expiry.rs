@@ -0,0 +1,194 @@
+//! Values that expire
+//!
+//! An expiring value carries its expiry time in its first 8 bytes, so the
+//! expiry is part of what the leaf commits to and shows up in proofs.
+//! `UrkelTree::insert_expiring` also notes the key in an `expiry` file,
+//! which `purge_expired` sweeps instead of walking the whole tree.  Notes
+//! are only dropped by a sweep, and a note is only acted on if the key
+//! still holds a value with that expiry, so stale notes are harmless.
+use super::Result;
+use byteorder::{ByteOrder, LittleEndian};
+use errors::Error;
+use hashutils::Digest;
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use store::{open_append, set_mode};
+
+pub const EXPIRY_FILE_NAME: &str = "expiry";
+const ENTRY_SIZE: usize = 40; // 8 + 32
+const EXPIRY_SIZE: usize = 8;
+
+/// Encode `value` to expire at `expires_at`
+pub fn expiring_value(expires_at: u64, value: &[u8]) -> Vec<u8> {
+    let mut bits = vec![0; EXPIRY_SIZE];
+    LittleEndian::write_u64(&mut bits, expires_at);
+    bits.extend_from_slice(value);
+    bits
+}
+
+/// Split an expiring value into its expiry time and the value
+pub fn split_expiring(bits: &[u8]) -> Result<(u64, &[u8])> {
+    if bits.len() < EXPIRY_SIZE {
+        return Err(Error::Decode("Value is too short to carry an expiry"));
+    }
+    Ok((LittleEndian::read_u64(bits), &bits[EXPIRY_SIZE..]))
+}
+
+/// Keys noted as holding expiring values
+pub struct ExpiryNotes {
+    path: PathBuf,
+    mode: u32,
+    /// (expiry, key), soonest first
+    notes: BTreeSet<(u64, [u8; 32])>,
+}
+
+impl ExpiryNotes {
+    /// No notes, to be written to `dir`
+    pub fn empty(dir: &Path, mode: u32) -> Self {
+        ExpiryNotes {
+            path: dir.join(EXPIRY_FILE_NAME),
+            mode,
+            notes: BTreeSet::new(),
+        }
+    }
+
+    /// Load the notes in `dir`
+    pub fn open(dir: &Path, mode: u32) -> Result<Self> {
+        let mut notes = ExpiryNotes::empty(dir, mode);
+        if !notes.path.exists() {
+            return Ok(notes);
+        }
+
+        let mut bits = vec![];
+        OpenOptions::new()
+            .read(true)
+            .open(&notes.path)?
+            .read_to_end(&mut bits)?;
+
+        // Ignore a torn trailing entry from an interrupted append
+        for entry in bits.chunks_exact(ENTRY_SIZE) {
+            let mut key = [0; 32];
+            key.copy_from_slice(&entry[EXPIRY_SIZE..]);
+            notes.notes.insert((LittleEndian::read_u64(entry), key));
+        }
+        Ok(notes)
+    }
+
+    /// Note that `key` holds a value expiring at `expires_at`
+    pub fn note(&mut self, key: Digest, expires_at: u64) -> Result<()> {
+        if self.notes.insert((expires_at, key.0)) {
+            open_append(&self.path, self.mode)
+                .and_then(|mut f| f.write_all(&encode(expires_at, &key.0)))?;
+        }
+        Ok(())
+    }
+
+    /// Notes expiring at or before `now`
+    pub fn due(&self, now: u64) -> Vec<(u64, Digest)> {
+        self.notes
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .map(|(expires_at, key)| (*expires_at, Digest(*key)))
+            .collect()
+    }
+
+    /// Drop every note, as the tree is cleared
+    pub fn clear(&mut self) -> Result<()> {
+        self.forget_due(u64::MAX)
+    }
+
+    /// Drop the notes expiring at or before `now`, rewriting the file
+    pub fn forget_due(&mut self, now: u64) -> Result<()> {
+        self.notes.retain(|(expires_at, _)| *expires_at > now);
+
+        let mut bits = Vec::with_capacity(self.notes.len() * ENTRY_SIZE);
+        for (expires_at, key) in &self.notes {
+            bits.extend_from_slice(&encode(*expires_at, key));
+        }
+        let mut options = OpenOptions::new();
+        options.create(true).truncate(true).write(true);
+        set_mode(&mut options, self.mode);
+        options.open(&self.path)?.write_all(&bits)?;
+        Ok(())
+    }
+}
+
+fn encode(expires_at: u64, key: &[u8; 32]) -> Vec<u8> {
+    let mut entry = vec![0; EXPIRY_SIZE];
+    LittleEndian::write_u64(&mut entry, expires_at);
+    entry.extend_from_slice(key);
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashutils::sha3;
+    use test_dir;
+    use tree::UrkelTree;
+
+    #[test]
+    fn clearing_forgets_expiries() {
+        let dir = test_dir("clearing_forgets_expiries");
+        let key = sha3(b"a");
+        let soon = expiring_value(100, b"soon");
+        {
+            let mut t = UrkelTree::open(&dir);
+            t.insert_expiring(key, &soon).unwrap();
+            t.commit().unwrap();
+            t.clear().unwrap();
+            // Added back without an expiry, though the value carries one
+            t.insert(key, &soon).unwrap();
+            t.commit().unwrap();
+            assert_eq!(t.purge_expired(150).unwrap(), 0);
+        }
+
+        let mut t = UrkelTree::open(&dir);
+        assert_eq!(t.purge_expired(150).unwrap(), 0);
+        assert_eq!(t.get(key).unwrap(), Some(soon));
+    }
+
+    #[test]
+    fn purges_due_values_in_one_commit() {
+        let dir = test_dir("purges_due_values_in_one_commit");
+        let (a, b, c, d) = (sha3(b"a"), sha3(b"b"), sha3(b"c"), sha3(b"d"));
+        let soon = expiring_value(100, b"soon");
+        let later = expiring_value(200, b"later");
+        let renewed = expiring_value(300, b"renewed");
+        {
+            let mut t = UrkelTree::open(&dir);
+            t.insert_expiring(a, &soon).unwrap();
+            t.insert_expiring(b, &later).unwrap();
+            t.insert_expiring(c, &soon).unwrap();
+            t.insert(d, b"forever").unwrap();
+            t.commit().unwrap();
+            // Renewing c leaves its old note stale
+            t.insert_expiring(c, &renewed).unwrap();
+            t.commit().unwrap();
+        }
+
+        let mut t = UrkelTree::open(&dir);
+        let proof = t.prove(a).unwrap();
+        assert_eq!(
+            split_expiring(proof.value.as_ref().unwrap()).unwrap().0,
+            100
+        );
+
+        let before = t.previous_root();
+        assert_eq!(t.purge_expired(150).unwrap(), 1);
+        assert_ne!(t.previous_root(), before);
+        assert_eq!(t.get(a).unwrap(), None);
+        assert_eq!(t.get(c).unwrap(), Some(renewed.clone()));
+
+        // Nothing due: nothing committed
+        let previous = t.previous_root();
+        assert_eq!(t.purge_expired(150).unwrap(), 0);
+        assert_eq!(t.previous_root(), previous);
+
+        assert_eq!(t.purge_expired(1000).unwrap(), 2);
+        assert_eq!(t.get(d).unwrap(), Some(Vec::from("forever")));
+        assert!(split_expiring(b"short").is_err());
+    }
+}
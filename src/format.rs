@@ -0,0 +1,275 @@
+//! Record formats
+//!
+//! The `version` file in a store's directory holds its format version as a
+//! little endian u32 followed, from version 2, by a byte giving the order
+//! of the integers in its node and meta records (0 little, 1 big endian)
+//! and, from version 3, by the store's key size in bytes.  In version 4
+//! a last byte tags the hash the store was written with (see
+//! `Sha3Provider::hash_id`), left out for SHA3.  Stores without a version
+//! file predate it and are version 1.
+//!
+//! Records, with integers in the store's byte order:
+//!
+//! * leaf (9 bytes and the key): value file index * 2 + 1 (u16), value
+//!   position (u32), value size (u16), key size (u8), key
+//! * internal (76 bytes), for the left then the right child: file index * 2
+//!   (u16), position * 2 + 1 if it's a leaf (u32), hash (32 bytes).  An
+//!   empty child has index 0.
+//! * meta (44 bytes, written after zero padding to a multiple of 44): magic
+//!   (u32), previous meta file index (u16) and position (u32), root file
+//!   index (u16), root position * 2 + 1 if it's a leaf (u32), the tree's
+//!   leaf count (u64), then the first 20 bytes of the checksum
+//! * checkpoint (44 bytes): laid out as a meta record with its own magic
+//!   number.  The root is a subtree written so far by the commit in
+//!   progress and the previous record is the checkpoint's one before, or
+//!   the latest commit's meta.
+//! * footer, ending a data file that filled up: the position of each of
+//!   its meta records (u32), their count (u32), 20 bytes of checksum and a
+//!   magic number (u32).  Opening reads it rather than scanning the file.
+//!
+//! Version 1 is always little endian and stores the right child's file
+//! index of an internal node as is, rather than doubled.  Versions 1 and 2
+//! only have 32 byte keys, and leaves (40 bytes) without the key size.
+//! Versions 1 to 3 have meta and checkpoint records (36 bytes) without the
+//! leaf count.  The other files in the directory are little endian in
+//! every version.
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use errors::Error;
+use hashutils::SHA3_ID;
+use Result;
+
+/// Format version written to new stores
+pub const FORMAT_VERSION: u32 = 4;
+
+/// Widest key a store can have, in bytes
+pub const MAX_KEY_SIZE: u8 = 32;
+
+/// Byte order of the integers in node and meta records
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Endianness {
+    #[default]
+    Little,
+    /// Network byte order
+    Big,
+}
+
+/// How a store's records are encoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Format {
+    pub version: u32,
+    pub endianness: Endianness,
+    /// Bytes of each key that are stored, the rest being zero
+    pub key_size: u8,
+    /// Tag of the hash the store's nodes and checksums are made with
+    pub hash_id: u8,
+}
+
+/// Stores written before formats were versioned
+pub const LEGACY_FORMAT: Format = Format {
+    version: 1,
+    endianness: Endianness::Little,
+    key_size: MAX_KEY_SIZE,
+    hash_id: SHA3_ID,
+};
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::new(Endianness::Little)
+    }
+}
+
+impl Format {
+    /// The current version in the given byte order
+    pub fn new(endianness: Endianness) -> Self {
+        Format::with_key_size(endianness, MAX_KEY_SIZE)
+    }
+
+    /// The current version in the given byte order, for keys of
+    /// `key_size` bytes
+    pub fn with_key_size(endianness: Endianness, key_size: u8) -> Self {
+        Format {
+            version: FORMAT_VERSION,
+            endianness,
+            key_size,
+            hash_id: SHA3_ID,
+        }
+    }
+
+    /// Size of an encoded leaf
+    pub fn leaf_size(&self) -> usize {
+        if self.version >= 3 {
+            9 + self.key_size as usize
+        } else {
+            40
+        }
+    }
+
+    /// Size of an encoded meta or checkpoint record
+    pub fn meta_size(&self) -> usize {
+        if self.records_leaf_count() {
+            44
+        } else {
+            36
+        }
+    }
+
+    /// Do meta records carry the tree's leaf count?
+    pub fn records_leaf_count(&self) -> bool {
+        self.version >= 4
+    }
+
+    /// Does an internal node double its right child's index, as it does
+    /// the left's?
+    pub fn doubles_right_index(&self) -> bool {
+        self.version >= 2
+    }
+
+    pub fn put_u16(&self, wtr: &mut Vec<u8>, n: u16) {
+        let mut bits = [0; 2];
+        match self.endianness {
+            Endianness::Little => LittleEndian::write_u16(&mut bits, n),
+            Endianness::Big => BigEndian::write_u16(&mut bits, n),
+        }
+        wtr.extend_from_slice(&bits);
+    }
+
+    pub fn put_u32(&self, wtr: &mut Vec<u8>, n: u32) {
+        let mut bits = [0; 4];
+        match self.endianness {
+            Endianness::Little => LittleEndian::write_u32(&mut bits, n),
+            Endianness::Big => BigEndian::write_u32(&mut bits, n),
+        }
+        wtr.extend_from_slice(&bits);
+    }
+
+    pub fn put_u64(&self, wtr: &mut Vec<u8>, n: u64) {
+        let mut bits = [0; 8];
+        match self.endianness {
+            Endianness::Little => LittleEndian::write_u64(&mut bits, n),
+            Endianness::Big => BigEndian::write_u64(&mut bits, n),
+        }
+        wtr.extend_from_slice(&bits);
+    }
+
+    /// Read a u16 from the start of `bits`
+    pub fn u16(&self, bits: &[u8]) -> u16 {
+        match self.endianness {
+            Endianness::Little => LittleEndian::read_u16(bits),
+            Endianness::Big => BigEndian::read_u16(bits),
+        }
+    }
+
+    /// Read a u32 from the start of `bits`
+    pub fn u32(&self, bits: &[u8]) -> u32 {
+        match self.endianness {
+            Endianness::Little => LittleEndian::read_u32(bits),
+            Endianness::Big => BigEndian::read_u32(bits),
+        }
+    }
+
+    /// Read a u64 from the start of `bits`
+    pub fn u64(&self, bits: &[u8]) -> u64 {
+        match self.endianness {
+            Endianness::Little => LittleEndian::read_u64(bits),
+            Endianness::Big => BigEndian::read_u64(bits),
+        }
+    }
+
+    /// Contents of the version file
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bits = self.version.to_le_bytes().to_vec();
+        if self.version >= 2 {
+            bits.push(match self.endianness {
+                Endianness::Little => 0,
+                Endianness::Big => 1,
+            });
+        }
+        if self.version >= 3 {
+            bits.push(self.key_size);
+        }
+        if self.version >= 4 && self.hash_id != SHA3_ID {
+            bits.push(self.hash_id);
+        }
+        bits
+    }
+
+    /// Parse a version file
+    pub fn decode(bits: &[u8]) -> Result<Format> {
+        if bits.len() < 4 {
+            return Err(Error::Decode("Version file is too short"));
+        }
+        let version = LittleEndian::read_u32(bits);
+        match (version, &bits[4..]) {
+            (1, []) => Ok(LEGACY_FORMAT),
+            (2, [e @ 0..=1]) => Ok(Format {
+                version: 2,
+                ..Format::new(endianness(*e))
+            }),
+            (3..=4, [e @ 0..=1, k @ 1..=MAX_KEY_SIZE]) => Ok(Format {
+                version,
+                ..Format::with_key_size(endianness(*e), *k)
+            }),
+            (4, [e @ 0..=1, k @ 1..=MAX_KEY_SIZE, h]) if *h != SHA3_ID => Ok(Format {
+                hash_id: *h,
+                ..Format::with_key_size(endianness(*e), *k)
+            }),
+            (1..=4, _) => Err(Error::Decode("Malformed version file")),
+            (found, _) => Err(Error::WrongVersion {
+                found,
+                supported: FORMAT_VERSION,
+            }),
+        }
+    }
+}
+
+fn endianness(bits: u8) -> Endianness {
+    if bits == 0 {
+        Endianness::Little
+    } else {
+        Endianness::Big
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_file_round_trip() {
+        for format in &[
+            LEGACY_FORMAT,
+            Format::new(Endianness::Little),
+            Format::new(Endianness::Big),
+            Format::with_key_size(Endianness::Little, 20),
+            Format {
+                version: 2,
+                ..Format::new(Endianness::Big)
+            },
+            Format {
+                version: 3,
+                ..Format::with_key_size(Endianness::Little, 20)
+            },
+            Format {
+                hash_id: 1,
+                ..Format::new(Endianness::Big)
+            },
+        ] {
+            assert_eq!(Format::decode(&format.encode()).unwrap(), *format);
+        }
+        assert!(Format::decode(&[2, 0, 0, 0]).is_err());
+        assert!(Format::decode(&[2, 0, 0, 0, 7]).is_err());
+        assert!(Format::decode(&[3, 0, 0, 0, 0, 33]).is_err());
+        assert!(Format::decode(&[3, 0, 0, 0, 0, 32, 1]).is_err());
+        // SHA3 is never written out
+        assert!(Format::decode(&[4, 0, 0, 0, 0, 32, 0]).is_err());
+        assert_eq!(Format::new(Endianness::Little).encode().len(), 6);
+        match Format::decode(&[5, 0, 0, 0, 0]) {
+            Err(Error::WrongVersion { found: 5, .. }) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        let mut bits = vec![];
+        Format::new(Endianness::Big).put_u32(&mut bits, 1);
+        assert_eq!(bits, [0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,72 @@
+//! Persistent cache of root hashes
+//!
+//! A root stored as an internal node only records its children's hashes,
+//! so answering `get_root` means reading the node back and hashing it, and
+//! a leaf root needs its value read too.  With `StoreOptions::hash_cache`
+//! set, the store appends the hash of every committed root to a `hashes`
+//! file, keyed by the root's location, and answers from it instead.
+use super::Result;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use hashutils::{checksum, Digest};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::Path;
+use store::open_append;
+
+pub const HASH_CACHE_FILE_NAME: &str = "hashes";
+const ENTRY_SIZE: usize = 58; // 2 + 4 + 32 + 20
+const CHECKSUM_SIZE: usize = 20;
+
+// Encode an entry, checksummed with the store's meta key
+fn encode(index: u16, pos: u32, hash: Digest, key: [u8; 32]) -> Vec<u8> {
+    let mut wtr = Vec::with_capacity(ENTRY_SIZE);
+    wtr.write_u16::<LittleEndian>(index).unwrap();
+    wtr.write_u32::<LittleEndian>(pos).unwrap();
+    wtr.extend_from_slice(&hash.0);
+    let sum = checksum(&wtr, key);
+    wtr.extend_from_slice(&sum[..CHECKSUM_SIZE]);
+    wtr
+}
+
+/// Append the hash of the root at `index`/`pos` to the cache in `dir`
+pub fn append_root_hash(
+    dir: &Path,
+    index: u16,
+    pos: u32,
+    hash: Digest,
+    key: [u8; 32],
+    mode: u32,
+) -> Result<()> {
+    open_append(&dir.join(HASH_CACHE_FILE_NAME), mode)
+        .and_then(|mut f| f.write_all(&encode(index, pos, hash, key)))?;
+    Ok(())
+}
+
+/// Load the cached root hashes in `dir`.  Entries failing their checksum
+/// are skipped, so a damaged cache only costs a trip to the data files.
+pub fn load_root_hashes(dir: &Path, key: [u8; 32]) -> Result<HashMap<(u16, u32), Digest>> {
+    let path = dir.join(HASH_CACHE_FILE_NAME);
+    let mut hashes = HashMap::new();
+    if !path.exists() {
+        return Ok(hashes);
+    }
+
+    let mut bits = vec![];
+    OpenOptions::new()
+        .read(true)
+        .open(path)?
+        .read_to_end(&mut bits)?;
+
+    // Ignore a torn trailing entry from an interrupted append
+    for entry in bits.chunks_exact(ENTRY_SIZE) {
+        let body = &entry[..ENTRY_SIZE - CHECKSUM_SIZE];
+        if checksum(body, key)[..CHECKSUM_SIZE] != entry[ENTRY_SIZE - CHECKSUM_SIZE..] {
+            continue;
+        }
+        let index = LittleEndian::read_u16(&body[0..]);
+        let pos = LittleEndian::read_u32(&body[2..]);
+        hashes.insert((index, pos), Digest::from(&body[6..]));
+    }
+    Ok(hashes)
+}
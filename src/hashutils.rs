@@ -1,11 +1,19 @@
+#[cfg(feature = "blake3")]
+use blake3::{Blake3, BLAKE3_ID};
+#[cfg(feature = "blake3")]
+use blake3_hash;
+use byteorder::{ByteOrder, LittleEndian};
 use std::fmt;
+use std::sync::OnceLock;
+#[cfg(feature = "keccak")]
 use tiny_keccak::Keccak;
 
-const LEAF_PREFIX: u8 = 0x00u8;
-const INTERNAL_PREFIX: u8 = 0x01u8;
+pub(crate) const LEAF_PREFIX: u8 = 0x00u8;
+pub(crate) const INTERNAL_PREFIX: u8 = 0x01u8;
 
-/// Container for a Hash
-#[derive(Eq, PartialEq, PartialOrd, Debug, Clone, Copy)]
+/// Container for a Hash.  Ordered bytewise, the same order keys take
+/// in the tree.
+#[derive(Eq, PartialEq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
 pub struct Digest(pub [u8; 32]);
 
 /// Default returns a zero hash - used as a sentinal marker
@@ -35,61 +43,306 @@ impl fmt::LowerHex for Digest {
     }
 }
 
+/// A SHA3-256 hash in progress, from a `Sha3Provider`
+pub trait Sha3State {
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self: Box<Self>) -> [u8; 32];
+}
+
+/// Supplies the SHA3-256 implementation everything in the crate hashes
+/// with, in place of tiny-keccak or in builds without the `keccak` feature
+pub trait Sha3Provider: Send + Sync {
+    fn start(&self) -> Box<dyn Sha3State>;
+
+    /// Tag of the hash this computes, recorded in new stores' version
+    /// files.  Hashes the crate implements itself (`BLAKE3_ID`) are then
+    /// computed inline, without calling `start`.
+    fn hash_id(&self) -> u8 {
+        SHA3_ID
+    }
+}
+
+static SHA3_PROVIDER: OnceLock<&'static dyn Sha3Provider> = OnceLock::new();
+
+/// Hash with `provider` from now on.  It can only be set once, before
+/// anything is hashed, so returns false if one was already set.
+pub fn set_sha3_provider(provider: &'static dyn Sha3Provider) -> bool {
+    SHA3_PROVIDER.set(provider).is_ok()
+}
+
+/// Tag of the hash everything in the crate hashes with: the installed
+/// provider's, or `SHA3_ID`
+pub fn hash_id() -> u8 {
+    SHA3_PROVIDER
+        .get()
+        .map_or(SHA3_ID, |provider| provider.hash_id())
+}
+
+/// Incremental SHA3-256 with the installed provider, or tiny-keccak
+// Keccak's and BLAKE3's states stay inline, as one is made for every
+// node hashed
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Sha3Hash {
+    #[cfg(feature = "keccak")]
+    Keccak(Keccak),
+    #[cfg(feature = "blake3")]
+    Blake3(blake3_hash::Hasher),
+    Provided(Box<dyn Sha3State>),
+}
+
+impl Sha3Hash {
+    pub fn new() -> Self {
+        match SHA3_PROVIDER.get() {
+            #[cfg(feature = "blake3")]
+            Some(provider) if provider.hash_id() == BLAKE3_ID => {
+                Sha3Hash::Blake3(blake3_hash::Hasher::new())
+            }
+            Some(provider) => Sha3Hash::Provided(provider.start()),
+            #[cfg(feature = "keccak")]
+            None => Sha3Hash::Keccak(Keccak::new_sha3_256()),
+            #[cfg(not(feature = "keccak"))]
+            None => panic!(
+                "No SHA3 implementation: enable the keccak feature or call set_sha3_provider"
+            ),
+        }
+    }
+
+    // SHA3-256 itself, whatever provider is installed
+    fn sha3_256() -> Self {
+        #[cfg(feature = "keccak")]
+        return Sha3Hash::Keccak(Keccak::new_sha3_256());
+        #[cfg(not(feature = "keccak"))]
+        match SHA3_PROVIDER.get() {
+            Some(provider) if provider.hash_id() == SHA3_ID => Sha3Hash::new(),
+            _ => panic!("No SHA3 implementation: enable the keccak feature"),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            #[cfg(feature = "keccak")]
+            Sha3Hash::Keccak(k) => k.update(data),
+            #[cfg(feature = "blake3")]
+            Sha3Hash::Blake3(b) => {
+                b.update(data);
+            }
+            Sha3Hash::Provided(state) => state.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        match self {
+            #[cfg(feature = "keccak")]
+            Sha3Hash::Keccak(k) => {
+                let mut res = [0; 32];
+                k.finalize(&mut res);
+                res
+            }
+            #[cfg(feature = "blake3")]
+            Sha3Hash::Blake3(b) => *b.finalize().as_bytes(),
+            Sha3Hash::Provided(state) => state.finalize(),
+        }
+    }
+}
+
 /// Hash of the content
 pub fn sha3(data: &[u8]) -> Digest {
-    let mut hash = Keccak::new_sha3_256();
-    let mut res: [u8; 32] = [0; 32];
-    hash.update(&data);
-    hash.finalize(&mut res);
-    Digest(res)
+    hash_with(Sha3Hash::new(), data)
 }
 
-/// Hash a leaf's key/values
-pub fn sha3_leaf(key: Digest, value: &[u8]) -> Digest {
-    let mut hash = Keccak::new_sha3_256();
-    let mut res: [u8; 32] = [0; 32];
+fn hash_with(mut hash: Sha3Hash, data: &[u8]) -> Digest {
+    hash.update(data);
+    Digest(hash.finalize())
+}
 
+/// Hash a leaf's key/values, mixing in the tree's salt if it has one
+/// so its leaves can't collide with another tree's
+pub fn sha3_leaf_salted(salt: Option<&[u8; 32]>, key: Digest, value: &[u8]) -> Digest {
+    leaf_with(Sha3Hash::new(), salt, key, value)
+}
+
+fn leaf_with(mut hash: Sha3Hash, salt: Option<&[u8; 32]>, key: Digest, value: &[u8]) -> Digest {
     hash.update(&[LEAF_PREFIX]);
+    if let Some(s) = salt {
+        hash.update(s);
+    }
     hash.update(&key.0);
     hash.update(value);
-    hash.finalize(&mut res);
-    Digest(res)
+    Digest(hash.finalize())
 }
 
-/// Hash a leaf's k/v into the node's representation
-pub fn sha3_value(key: Digest, value: &[u8]) -> Digest {
-    let mut hash = Keccak::new_sha3_256();
-    let mut res: [u8; 32] = [0; 32];
+/// Hash a leaf's k/v into the node's representation, salted
+pub fn sha3_value_salted(salt: Option<&[u8; 32]>, key: Digest, value: &[u8]) -> Digest {
+    sha3_leaf_salted(salt, key, &sha3(value).0)
+}
 
-    let val = sha3(value);
-    hash.update(&[LEAF_PREFIX]);
-    hash.update(&key.0);
-    hash.update(&val.0);
-    hash.finalize(&mut res);
-    Digest(res)
+/// Commit to a root and the number of leaves under it
+pub fn sha3_sized_root(root: Digest, leaf_count: u64) -> Digest {
+    let mut hash = Sha3Hash::new();
+    let mut count = [0; 8];
+    LittleEndian::write_u64(&mut count, leaf_count);
+
+    hash.update(&root.0);
+    hash.update(&count);
+    Digest(hash.finalize())
+}
+
+/// Compare digests in time independent of where they first differ
+pub fn ct_eq(a: &Digest, b: &Digest) -> bool {
+    let diff =
+        a.0.iter()
+            .zip(b.0.iter())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    ::std::hint::black_box(diff) == 0
 }
 
 /// Hash an internal node
 pub fn sha3_internal(left: Digest, right: Digest) -> Digest {
-    let mut hash = Keccak::new_sha3_256();
-    let mut res: [u8; 32] = [0; 32];
+    internal_with(Sha3Hash::new(), left, right)
+}
 
+fn internal_with(mut hash: Sha3Hash, left: Digest, right: Digest) -> Digest {
     hash.update(&[INTERNAL_PREFIX]);
     hash.update(&left.0);
     hash.update(&right.0);
-    hash.finalize(&mut res);
-    Digest(res)
+    Digest(hash.finalize())
+}
+
+/// The hash functions a tree is built with, so verifiers can check proofs
+/// from trees using different ones
+pub trait Hasher: Sync {
+    /// Tags proofs from trees using this hasher (see `verify_tagged`)
+    fn id(&self) -> u8;
+    /// Hash of the content
+    fn hash(&self, data: &[u8]) -> Digest;
+    /// Hash a leaf's key and value hash, salted
+    fn hash_leaf(&self, salt: Option<&[u8; 32]>, key: Digest, value: &[u8]) -> Digest;
+    /// Hash an internal node
+    fn hash_internal(&self, left: Digest, right: Digest) -> Digest;
+
+    /// Hash a leaf's key and value, salted
+    fn hash_value(&self, salt: Option<&[u8; 32]>, key: Digest, value: &[u8]) -> Digest {
+        self.hash_leaf(salt, key, &self.hash(value).0)
+    }
+}
+
+/// Tag of the sha3 hasher
+pub const SHA3_ID: u8 = 0;
+
+/// SHA3-256, as the tree hashes by default.  With the `keccak` feature
+/// this is tiny-keccak whatever provider is installed, so proofs tagged
+/// with it mean what the tag says; without it, it's the provider, which
+/// has to be tagged `SHA3_ID`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha3;
+
+impl Hasher for Sha3 {
+    fn id(&self) -> u8 {
+        SHA3_ID
+    }
+
+    fn hash(&self, data: &[u8]) -> Digest {
+        hash_with(Sha3Hash::sha3_256(), data)
+    }
+
+    fn hash_leaf(&self, salt: Option<&[u8; 32]>, key: Digest, value: &[u8]) -> Digest {
+        leaf_with(Sha3Hash::sha3_256(), salt, key, value)
+    }
+
+    fn hash_internal(&self, left: Digest, right: Digest) -> Digest {
+        internal_with(Sha3Hash::sha3_256(), left, right)
+    }
+}
+
+/// Hashes as the tree does, with the installed provider (or tiny-keccak),
+/// for the verifiers that don't take a `Hasher`
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Installed;
+
+impl Hasher for Installed {
+    fn id(&self) -> u8 {
+        hash_id()
+    }
+
+    fn hash(&self, data: &[u8]) -> Digest {
+        sha3(data)
+    }
+
+    fn hash_leaf(&self, salt: Option<&[u8; 32]>, key: Digest, value: &[u8]) -> Digest {
+        sha3_leaf_salted(salt, key, value)
+    }
+
+    fn hash_internal(&self, left: Digest, right: Digest) -> Digest {
+        sha3_internal(left, right)
+    }
+}
+
+/// The hasher tagged `id`, if it's one this build knows.  Without the
+/// `keccak` feature `Sha3` is only known while a SHA3 provider is
+/// installed.
+pub fn hasher_for(id: u8) -> Option<&'static dyn Hasher> {
+    match id {
+        SHA3_ID if has_sha3() => Some(&Sha3),
+        #[cfg(feature = "blake3")]
+        BLAKE3_ID => Some(&Blake3),
+        _ => None,
+    }
+}
+
+// Is there a SHA3-256 for `Sha3` to hash with?
+fn has_sha3() -> bool {
+    cfg!(feature = "keccak") || SHA3_PROVIDER.get().is_some_and(|p| p.hash_id() == SHA3_ID)
 }
 
 /// Calculate the checksum for the metaroot.
 /// NOTE: this returns a full 32 byte hash, but the metaroot uses 20bytes
 /// We chop it in the MetaEntry to simplify borrowing issues.
 pub fn checksum(data: &[u8], meta_key: [u8; 32]) -> [u8; 32] {
-    let mut hash = Keccak::new_sha3_256();
-    let mut res: [u8; 32] = [0; 32];
+    let mut hash = Sha3Hash::new();
 
     hash.update(data);
     hash.update(&meta_key);
-    hash.finalize(&mut res);
-    res
+    hash.finalize()
+}
+
+#[cfg(all(test, feature = "keccak"))]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+    // tiny-keccak again, counting the hashes it's asked for
+    struct Counting;
+
+    impl Sha3State for Keccak {
+        fn update(&mut self, data: &[u8]) {
+            Keccak::update(self, data)
+        }
+
+        fn finalize(self: Box<Self>) -> [u8; 32] {
+            let mut res = [0; 32];
+            Keccak::finalize(*self, &mut res);
+            res
+        }
+    }
+
+    impl Sha3Provider for Counting {
+        fn start(&self) -> Box<dyn Sha3State> {
+            STARTED.fetch_add(1, Ordering::SeqCst);
+            Box::new(Keccak::new_sha3_256())
+        }
+    }
+
+    #[test]
+    fn installed_provider_is_used() {
+        assert!(set_sha3_provider(&Counting));
+        assert!(!set_sha3_provider(&Counting));
+        let before = STARTED.load(Ordering::SeqCst);
+        assert_eq!(
+            format!("{:x}", sha3(b"")),
+            "0xa7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a"
+        );
+        assert!(STARTED.load(Ordering::SeqCst) > before);
+    }
 }
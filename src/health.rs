@@ -0,0 +1,168 @@
+//! Integrity scans
+//!
+//! A scan follows the meta chain back from the latest commit, resolving
+//! each commit's root, then descends from the latest root along sampled
+//! paths checking every node hashes to what its parent recorded.  Both
+//! parts are bounded, so a scan of a large store takes a predictable
+//! number of reads rather than walking the whole tree.
+//!
+//! Set `StoreOptions::startup_scan` to scan as the tree is opened and
+//! read the result from `UrkelTree::health_report`, or call
+//! `UrkelTree::scan` at any time.
+use errors::Error;
+use hashutils::sha3;
+use nodes::Node;
+use proof::has_bit;
+use store::Store;
+
+/// How much of the store a scan looks at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScanLimits {
+    /// Most commits to follow back from the latest one
+    pub max_commits: usize,
+    /// Most nodes to read while sampling paths from the latest root
+    pub max_nodes: usize,
+}
+
+impl Default for ScanLimits {
+    fn default() -> Self {
+        ScanLimits {
+            max_commits: 16,
+            max_nodes: 1024,
+        }
+    }
+}
+
+/// What a scan found
+#[derive(Debug, Default)]
+pub struct HealthReport {
+    /// Commits whose meta record and root were read
+    pub commits_checked: usize,
+    /// Nodes read and checked against their parent's hash
+    pub nodes_checked: usize,
+    /// Everything that failed.  The scan of the meta chain stops at its
+    /// first failure, as does sampling.
+    pub problems: Vec<Error>,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Scan `store` within `limits`
+pub fn scan(store: &mut Store, limits: &ScanLimits) -> HealthReport {
+    let mut report = HealthReport::default();
+
+    let mut latest: Option<Node> = None;
+    let mut meta = store.state().clone();
+    while meta.meta_index != 0 && report.commits_checked < limits.max_commits {
+        match store.get_root_node(&meta) {
+            Ok(root) => {
+                latest.get_or_insert(root);
+            }
+            Err(e) => {
+                report.problems.push(e);
+                break;
+            }
+        }
+        report.commits_checked += 1;
+        meta = match store.previous_meta(&meta) {
+            Ok(previous) => previous,
+            Err(e) => {
+                report.problems.push(e);
+                break;
+            }
+        };
+    }
+
+    if let Some(root) = latest {
+        sample(store, root, limits, &mut report);
+    }
+    report
+}
+
+// Descend along pseudo-random keys until `max_nodes` have been read
+fn sample(store: &mut Store, root: Node, limits: &ScanLimits, report: &mut HealthReport) {
+    for n in 0..limits.max_nodes as u64 {
+        if report.nodes_checked >= limits.max_nodes {
+            return;
+        }
+        let key = sha3(&n.to_le_bytes());
+        let mut node = root.clone();
+        let mut depth = 0;
+        while let Node::Hash { index, pos, hash } = node {
+            match store.resolve_checked(index, pos, hash) {
+                Ok(Node::Internal { left, right, .. }) => {
+                    node = if has_bit(&key, depth) { *right } else { *left };
+                    depth += 1;
+                }
+                Ok(_) => node = Node::empty(),
+                Err(e) => {
+                    report.problems.push(e);
+                    return;
+                }
+            }
+            report.nodes_checked += 1;
+        }
+        // The root is a lone leaf, so every descent would end there
+        if depth == 0 {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+    use store::StoreOptions;
+    use test_dir;
+    use tree::UrkelTree;
+
+    #[test]
+    fn scan_finds_corruption() {
+        let dir = test_dir("scan_finds_corruption");
+        let options = || StoreOptions {
+            startup_scan: Some(ScanLimits::default()),
+            ..Default::default()
+        };
+        {
+            let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+            assert_eq!(t.health_report().unwrap().commits_checked, 0);
+            for i in 0..3u8 {
+                for j in 0..20u8 {
+                    t.insert_owned(sha3(&[i, j]), vec![i, j]).unwrap();
+                }
+                t.commit().unwrap();
+            }
+        }
+
+        let t = UrkelTree::open_with_options(&dir, options()).unwrap();
+        let report = t.health_report().unwrap();
+        assert!(report.is_healthy(), "{:?}", report.problems);
+        assert_eq!(report.commits_checked, 3);
+        assert!(report.nodes_checked > 0);
+        drop(t);
+
+        // The first value written is still reachable from the latest root
+        OpenOptions::new()
+            .write(true)
+            .open(PathBuf::from(&dir).join("0000000001"))
+            .and_then(|mut f| {
+                f.seek(SeekFrom::Start(0))?;
+                f.write_all(&[0xff])
+            })
+            .unwrap();
+        let mut t = UrkelTree::open(&dir);
+        assert!(t.health_report().is_none());
+        let report = t.scan(&ScanLimits::default());
+        match report.problems.as_slice() {
+            [Error::HashMismatch { index: 1, .. }] => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
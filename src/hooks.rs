@@ -0,0 +1,105 @@
+//! Callbacks embedders can register to observe the tree
+use errors::RecordType;
+use hashutils::Digest;
+use std::time::Duration;
+
+/// An operation that can be reported as slow
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    /// Reading a record from a data file
+    Read {
+        index: u16,
+        pos: u32,
+        record: RecordType,
+    },
+    /// Hashing the uncommitted part of the tree
+    Hash,
+    /// Writing and committing the tree
+    Commit { root: Digest },
+}
+
+/// An operation that took longer than the configured threshold
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlowOperation {
+    pub operation: Operation,
+    pub elapsed: Duration,
+}
+
+/// Callback fired for operations slower than `threshold`
+pub struct SlowHook {
+    pub threshold: Duration,
+    pub callback: Box<dyn Fn(&SlowOperation) + Send + Sync>,
+}
+
+impl SlowHook {
+    /// Fire the callback if the operation took too long
+    pub fn check(&self, operation: Operation, elapsed: Duration) {
+        if elapsed > self.threshold {
+            (self.callback)(&SlowOperation { operation, elapsed });
+        }
+    }
+}
+
+/// Details of a completed commit
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommitInfo {
+    /// The newly committed root
+    pub root: Digest,
+    /// Data file holding the commit's meta record
+    pub meta_index: u16,
+    /// Position of the meta record in that file
+    pub meta_pos: u32,
+}
+
+/// Callbacks run around every commit
+pub trait CommitHook {
+    /// Inspect the root about to be committed.  Returning an error vetoes
+    /// the commit and nothing is written.
+    fn pre_commit(&mut self, _pending_root: Digest) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called once the commit is durable
+    fn post_commit(&mut self, _info: &CommitInfo) {}
+}
+
+/// A root committed by a tree, sent to `UrkelTree::subscribe_roots`
+/// receivers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RootUpdate {
+    /// Commits made by this process since the tree was opened, including
+    /// this one.  Not persisted: it starts again from 1 when the tree is
+    /// reopened, so use the meta record's location to tell commits apart
+    /// across restarts.
+    pub sequence: u64,
+    pub root: Digest,
+    /// Data file holding the commit's meta record
+    pub meta_index: u16,
+    /// Position of the meta record in that file
+    pub meta_pos: u32,
+}
+
+/// A committed change to a watched key
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyChange {
+    pub key: Digest,
+    /// Value before the commit, if any
+    pub old: Option<Vec<u8>>,
+    /// Value after the commit, if any
+    pub new: Option<Vec<u8>>,
+}
+
+/// Folds merge operands into values (see `UrkelTree::merge_value`)
+pub trait MergeOperator {
+    /// The value of `key` after applying `operand` to its `existing` value
+    fn merge(&self, key: Digest, existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8>;
+}
+
+impl<F> MergeOperator for F
+where
+    F: Fn(Digest, Option<&[u8]>, &[u8]) -> Vec<u8>,
+{
+    fn merge(&self, key: Digest, existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        self(key, existing, operand)
+    }
+}
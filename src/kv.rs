@@ -0,0 +1,293 @@
+//! A plain key/value interface to a tree
+//!
+//! `UrkelKv` takes raw byte keys and hashes them internally, so it can be
+//! used like a map without knowing about digests, nodes or proofs.  The
+//! tree only holds key hashes, so the raw keys are remembered in a `keys`
+//! file next to the data files and looked up again when iterating.
+//! With `StoreOptions::key_index` set they're also kept sorted, for prefix
+//! scans over human readable keys.
+use super::Result;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use errors::Error;
+use hashutils::{sha3, Digest};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use store::{open_append, StoreOptions, DEFAULT_FILE_MODE};
+use tree::{Iter, UrkelTree};
+
+pub const KEYS_FILE_NAME: &str = "keys";
+/// Longest key that can be stored, as keys are stored with a u16 size
+pub const MAX_KEY_SIZE: usize = 0xffff;
+
+// Load the raw keys in `dir`, by their hash
+fn load_keys(dir: &Path) -> Result<HashMap<[u8; 32], Vec<u8>>> {
+    let path = dir.join(KEYS_FILE_NAME);
+    let mut keys = HashMap::new();
+    if !path.exists() {
+        return Ok(keys);
+    }
+
+    let mut bits = vec![];
+    OpenOptions::new()
+        .read(true)
+        .open(path)?
+        .read_to_end(&mut bits)?;
+
+    let mut offset = 0;
+    while offset + 2 <= bits.len() {
+        let size = LittleEndian::read_u16(&bits[offset..]) as usize;
+        offset += 2;
+        // Ignore a torn trailing entry from an interrupted append
+        if offset + size > bits.len() {
+            break;
+        }
+        let key = bits[offset..offset + size].to_vec();
+        offset += size;
+        keys.insert(sha3(&key).0, key);
+    }
+    Ok(keys)
+}
+
+/// A verifiable key/value store over raw byte keys
+pub struct UrkelKv {
+    tree: UrkelTree,
+    /// Raw keys by their hash
+    keys: HashMap<[u8; 32], Vec<u8>>,
+    /// Hashes by raw key, when `key_index` is set
+    index: Option<BTreeMap<Vec<u8>, Digest>>,
+    dir: PathBuf,
+    mode: u32,
+}
+
+impl UrkelKv {
+    /// Open the store in `dir`
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        UrkelKv::open_with_options(dir, StoreOptions::default())
+    }
+
+    /// Open the store in `dir` with the given store options
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, options: StoreOptions) -> Result<Self> {
+        let mode = options.file_mode.unwrap_or(DEFAULT_FILE_MODE);
+        let key_index = options.key_index;
+        let tree = UrkelTree::open_with_options(&dir, options)?;
+        let dir = dir.as_ref().to_path_buf();
+        let keys = load_keys(&dir)?;
+        let index = if key_index {
+            Some(
+                keys.iter()
+                    .map(|(hash, key)| (key.clone(), Digest(*hash)))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        Ok(UrkelKv {
+            tree,
+            keys,
+            index,
+            dir,
+            mode,
+        })
+    }
+
+    /// The value for `key`, if any
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.tree.get(sha3(key))
+    }
+
+    /// Set the value for `key`
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(Error::ValueTooLarge {
+                size: key.len(),
+                max: MAX_KEY_SIZE,
+            });
+        }
+        let hash = sha3(key);
+        self.tree.insert(hash, value)?;
+
+        if !self.keys.contains_key(&hash.0) {
+            let mut entry = Vec::with_capacity(2 + key.len());
+            entry.write_u16::<LittleEndian>(key.len() as u16)?;
+            entry.extend_from_slice(key);
+            open_append(&self.dir.join(KEYS_FILE_NAME), self.mode)
+                .and_then(|mut f| f.write_all(&entry))?;
+            self.keys.insert(hash.0, key.to_vec());
+            if let Some(ref mut index) = self.index {
+                index.insert(key.to_vec(), hash);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove `key`, returning whether it was there
+    pub fn remove(&mut self, key: &[u8]) -> Result<bool> {
+        self.tree.remove(sha3(key))
+    }
+
+    /// The key/value pairs whose key starts with `prefix`, in key order
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut candidates: Vec<(Vec<u8>, Digest)> = match self.index {
+            Some(ref index) => index
+                .range(prefix.to_vec()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .map(|(key, hash)| (key.clone(), *hash))
+                .collect(),
+            None => {
+                let mut found: Vec<(Vec<u8>, Digest)> = self
+                    .keys
+                    .iter()
+                    .filter(|(_, key)| key.starts_with(prefix))
+                    .map(|(hash, key)| (key.clone(), Digest(*hash)))
+                    .collect();
+                found.sort_by(|a, b| a.0.cmp(&b.0));
+                found
+            }
+        };
+
+        // The keys file remembers removed keys too
+        let mut pairs = Vec::with_capacity(candidates.len());
+        for (key, hash) in candidates.drain(..) {
+            if let Some(value) = self.tree.get(hash)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// The raw key hashed to `hash`, if it was ever stored
+    pub fn original_key(&self, hash: Digest) -> Option<&[u8]> {
+        self.keys.get(&hash.0).map(|key| key.as_slice())
+    }
+
+    /// Iterate over the key/value pairs, in the order of the keys' hashes
+    pub fn iter<'t>(&'t mut self) -> KvIter<'t> {
+        KvIter {
+            inner: self.tree.iter(),
+            keys: &self.keys,
+        }
+    }
+
+    /// Number of keys stored.  This walks the whole tree.
+    pub fn len(&mut self) -> Result<usize> {
+        let mut len = 0;
+        for pair in self.tree.iter() {
+            pair?;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    /// Is the store empty?
+    pub fn is_empty(&self) -> bool {
+        self.root() == [0; 32]
+    }
+
+    /// Make the changes so far durable
+    pub fn commit(&mut self) -> Result<()> {
+        self.tree.commit()
+    }
+
+    /// Root hash committing to the contents
+    pub fn root(&self) -> [u8; 32] {
+        self.tree.get_root().0
+    }
+
+    /// The underlying tree, for proofs and the rest of its API
+    pub fn tree(&mut self) -> &mut UrkelTree {
+        &mut self.tree
+    }
+}
+
+/// Raw key/value pairs of an `UrkelKv`
+pub struct KvIter<'t> {
+    inner: Iter<'t>,
+    keys: &'t HashMap<[u8; 32], Vec<u8>>,
+}
+
+impl<'t> Iterator for KvIter<'t> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let keys = self.keys;
+        self.inner.next().map(|pair| {
+            let (hash, value) = pair?;
+            // Every key is recorded before it reaches the tree
+            let key = keys
+                .get(&hash.0)
+                .cloned()
+                .ok_or(Error::Decode("Key missing from the keys file"))?;
+            Ok((key, value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_dir;
+
+    #[test]
+    fn prefix_scans() {
+        for &key_index in &[false, true] {
+            let dir = test_dir(&format!("prefix_scans_{}", key_index));
+            let options = || StoreOptions {
+                key_index,
+                ..Default::default()
+            };
+            {
+                let mut kv = UrkelKv::open_with_options(&dir, options()).unwrap();
+                kv.insert(b"user/bob", b"2").unwrap();
+                kv.insert(b"user/alice", b"1").unwrap();
+                kv.insert(b"users", b"-").unwrap();
+                kv.insert(b"user/carol", b"3").unwrap();
+                kv.remove(b"user/carol").unwrap();
+                kv.commit().unwrap();
+            }
+
+            let mut kv = UrkelKv::open_with_options(&dir, options()).unwrap();
+            assert_eq!(
+                kv.scan_prefix(b"user/").unwrap(),
+                vec![
+                    (Vec::from("user/alice"), Vec::from("1")),
+                    (Vec::from("user/bob"), Vec::from("2")),
+                ]
+            );
+            assert_eq!(kv.scan_prefix(b"nobody").unwrap(), vec![]);
+            assert_eq!(kv.original_key(sha3(b"users")), Some(&b"users"[..]));
+        }
+    }
+
+    #[test]
+    fn behaves_like_a_map() {
+        let dir = test_dir("behaves_like_a_map");
+        {
+            let mut kv = UrkelKv::open(&dir).unwrap();
+            assert!(kv.is_empty());
+            kv.insert(b"apple", b"red").unwrap();
+            kv.insert(b"banana", b"yellow").unwrap();
+            kv.insert(b"cherry", b"dark").unwrap();
+            kv.insert(b"cherry", b"red").unwrap();
+            assert!(kv.remove(b"banana").unwrap());
+            assert!(!kv.remove(b"banana").unwrap());
+            kv.commit().unwrap();
+        }
+
+        let mut kv = UrkelKv::open(&dir).unwrap();
+        assert_eq!(kv.len().unwrap(), 2);
+        assert_eq!(kv.get(b"cherry").unwrap(), Some(Vec::from("red")));
+        assert_eq!(kv.get(b"banana").unwrap(), None);
+
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = kv.iter().map(|p| p.unwrap()).collect();
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            vec![
+                (Vec::from("apple"), Vec::from("red")),
+                (Vec::from("cherry"), Vec::from("red")),
+            ]
+        );
+    }
+}
@@ -4,18 +4,101 @@
 //!
 //!
 extern crate byteorder;
+// Renamed, as the blake3 module wraps it
+#[cfg(feature = "blake3")]
+extern crate blake3 as blake3_hash;
+#[cfg(feature = "signing")]
+extern crate ed25519_dalek;
+#[cfg(feature = "rng")]
 extern crate rand;
+#[cfg(feature = "keccak")]
 extern crate tiny_keccak;
 
+#[cfg(feature = "store")]
+pub mod archive;
+#[cfg(feature = "store")]
+pub mod backend;
+#[cfg(feature = "blake3")]
+pub mod blake3;
+#[cfg(feature = "store")]
+pub mod cache;
+#[cfg(feature = "store")]
+pub mod cursor;
+pub mod envelope;
+pub mod errors;
+#[cfg(feature = "store")]
+pub mod expiry;
+#[cfg(feature = "store")]
+pub mod format;
+#[cfg(feature = "store")]
+mod hashcache;
+#[cfg_attr(not(feature = "store"), allow(dead_code))]
 mod hashutils;
+#[cfg(feature = "store")]
+pub mod health;
+pub mod hooks;
+#[cfg(feature = "store")]
+pub mod kv;
+#[cfg(feature = "store")]
+pub mod manifest;
+pub mod map;
+#[cfg(feature = "store")]
 mod metadata;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "store")]
 mod nodes;
 pub mod proof;
+#[cfg(feature = "store")]
+pub mod roots;
+#[cfg(feature = "store")]
+pub mod shared;
+#[cfg(feature = "signing")]
+pub mod signing;
+pub mod stats;
+#[cfg(feature = "store")]
 mod store;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "text")]
+pub mod text;
+#[cfg(feature = "store")]
+pub mod transaction;
+#[cfg(feature = "store")]
 pub mod tree;
+#[cfg(feature = "store")]
+pub mod typed;
+#[cfg(feature = "store")]
+mod valueindex;
+#[cfg(feature = "store")]
+pub mod vectors;
+pub mod verify;
+#[cfg(feature = "store")]
+pub mod versioned;
+
+#[cfg(feature = "store")]
+pub use cache::BlockCache;
+pub use errors::{Error, RecordType};
+#[cfg(feature = "store")]
+pub use format::Endianness;
+pub use hashutils::{hash_id, set_sha3_provider, Digest, Sha3Provider, Sha3State};
+#[cfg(feature = "store")]
+pub use kv::UrkelKv;
+pub use map::AuthenticatedMap;
+#[cfg(feature = "store")]
+pub use store::{KeySource, QuarantinedRegion, RootRetention, StoreOptions, WriteLayout};
+#[cfg(feature = "store")]
+pub use typed::UrkelMap;
 
-use std::io::Error;
 use std::result;
 
 /// Result type used across project
 pub type Result<T> = result::Result<T, Error>;
+
+// Fresh, per-process data directory for a test
+#[cfg(all(test, feature = "store"))]
+fn test_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("urkel-rs-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir.to_string_lossy().into_owned()
+}
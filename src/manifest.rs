@@ -0,0 +1,132 @@
+//! Sealed data files
+//!
+//! The meta checksum only covers each commit's meta record, so an archived
+//! data file could be altered offline without anything noticing until a
+//! bad node is read.  With `StoreOptions::seal_files` set, every data file
+//! that fills up is sealed by appending its length and a MAC keyed with the
+//! store's meta key to a `manifest` file, and sealed files are checked
+//! against it when the store is opened.
+use super::Result;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use errors::Error;
+use hashutils::Sha3Hash;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use store::open_append;
+
+pub const MANIFEST_FILE_NAME: &str = "manifest";
+const ENTRY_SIZE: usize = 30; // 2 + 8 + 20
+const MAC_SIZE: usize = 20;
+
+/// A sealed data file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Seal {
+    pub index: u16,
+    pub length: u64,
+    pub mac: [u8; MAC_SIZE],
+}
+
+// MAC over the index, length and contents of the data file at `path`
+fn file_mac(path: &Path, index: u16, key: [u8; 32]) -> Result<(u64, [u8; MAC_SIZE])> {
+    let mut f = File::open(path)?;
+    let length = f.metadata()?.len();
+
+    let mut hash = Sha3Hash::new();
+    let mut header = vec![];
+    header.write_u16::<LittleEndian>(index)?;
+    header.write_u64::<LittleEndian>(length)?;
+    hash.update(&header);
+
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        let n = f.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        hash.update(&chunk[..n]);
+    }
+    hash.update(&key);
+
+    let res = hash.finalize();
+    let mut mac = [0; MAC_SIZE];
+    mac.copy_from_slice(&res[..MAC_SIZE]);
+    Ok((length, mac))
+}
+
+/// Seal the full data file `index` in `dir`
+pub fn seal_file(dir: &Path, path: &Path, index: u16, key: [u8; 32], mode: u32) -> Result<Seal> {
+    let (length, mac) = file_mac(path, index, key)?;
+    let mut wtr = Vec::with_capacity(ENTRY_SIZE);
+    wtr.write_u16::<LittleEndian>(index)?;
+    wtr.write_u64::<LittleEndian>(length)?;
+    wtr.extend_from_slice(&mac);
+
+    open_append(&dir.join(MANIFEST_FILE_NAME), mode).and_then(|mut f| f.write_all(&wtr))?;
+    Ok(Seal { index, length, mac })
+}
+
+/// Load the seals in `dir`, oldest first
+pub fn load_seals(dir: &Path) -> Result<Vec<Seal>> {
+    let path = dir.join(MANIFEST_FILE_NAME);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut bits = vec![];
+    OpenOptions::new()
+        .read(true)
+        .open(path)?
+        .read_to_end(&mut bits)?;
+
+    // Ignore a torn trailing entry from an interrupted append
+    Ok(bits
+        .chunks_exact(ENTRY_SIZE)
+        .map(|entry| {
+            let mut mac = [0; MAC_SIZE];
+            mac.copy_from_slice(&entry[10..]);
+            Seal {
+                index: LittleEndian::read_u16(&entry[0..]),
+                length: LittleEndian::read_u64(&entry[2..]),
+                mac,
+            }
+        })
+        .collect())
+}
+
+/// Drop the seals of data files that have been removed.  The manifest is rewritten to the side and
+/// renamed over the old one.
+pub fn drop_seals(dir: &Path, removed: &[u16], mode: u32) -> Result<()> {
+    let path = dir.join(MANIFEST_FILE_NAME);
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut wtr = vec![];
+    for seal in load_seals(dir)? {
+        if !removed.contains(&seal.index) {
+            wtr.write_u16::<LittleEndian>(seal.index)?;
+            wtr.write_u64::<LittleEndian>(seal.length)?;
+            wtr.extend_from_slice(&seal.mac);
+        }
+    }
+
+    let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+    let _ = fs::remove_file(&tmp);
+    open_append(&tmp, mode).and_then(|mut f| {
+        f.write_all(&wtr)?;
+        f.sync_all()
+    })?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Check the data file at `path` still matches its seal
+pub fn verify_seal(seal: &Seal, path: &Path, key: [u8; 32]) -> Result<()> {
+    let broken = Error::SealBroken { index: seal.index };
+    match file_mac(path, seal.index, key) {
+        Ok((length, mac)) if length == seal.length && mac == seal.mac => Ok(()),
+        Ok(_) => Err(broken),
+        Err(Error::Io(_)) => Err(broken),
+        Err(e) => Err(e),
+    }
+}
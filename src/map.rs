@@ -0,0 +1,32 @@
+//! A common interface to authenticated key/value structures
+//!
+//! Frameworks that write against `AuthenticatedMap` rather than
+//! `UrkelTree` can swap in another authenticated structure, or a mock, in
+//! their tests and benchmarks.
+use hashutils::Digest;
+use Result;
+
+/// A key/value map whose contents are committed to by a root hash
+pub trait AuthenticatedMap {
+    /// Proof that a key does or doesn't map to a value under a root
+    type Proof;
+
+    /// The root hash committing to the current contents
+    fn root(&self) -> Digest;
+
+    /// The value for `key`, if any
+    fn get(&mut self, key: Digest) -> Result<Option<Vec<u8>>>;
+
+    /// Set the value for `key`
+    fn insert(&mut self, key: Digest, value: &[u8]) -> Result<()>;
+
+    /// Remove `key`, returning whether it was there
+    fn remove(&mut self, key: Digest) -> Result<bool>;
+
+    /// Prove the value, or absence, of `key`
+    fn prove(&mut self, key: Digest) -> Result<Self::Proof>;
+
+    /// Check `proof` for `key` against `root`, returning the proven value
+    /// or `None` if the proof shows the key is absent
+    fn verify(&self, root: Digest, key: Digest, proof: &Self::Proof) -> Result<Option<Vec<u8>>>;
+}
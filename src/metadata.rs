@@ -1,57 +1,78 @@
 use super::Result;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use errors::Error;
+use format::Format;
 use hashutils::checksum;
 use std::fs::File;
-use std::io::Cursor;
+use std::io;
 use std::io::Read;
-use std::io::{Error, ErrorKind};
 use std::io::{Seek, SeekFrom};
-use std::path::PathBuf;
+use std::path::Path;
 
 const META_MAGIC: u32 = 0x6d72_6b6c;
-const META_SIZE: usize = 36; // 4 + 2 + 4 + 2 + 4 + 20;
+// Marks a checkpoint: a meta record written part way through a commit,
+// whose root is a subtree already written rather than the tree's root
+const CHECKPOINT_MAGIC: u32 = 0x6370_6b6c;
 const SLAB_SIZE: u64 = 1_048_572; // 1mb
+const FOOTER_MAGIC: u32 = 0x6674_6b6c;
+const FOOTER_TRAILER_SIZE: usize = 28; // 4 + 20 + 4
 
-#[derive(Clone, Debug)]
+/// In memory, `meta_index`/`meta_pos` point at the record this entry was read
+/// from (or written to).  On disk they point at the *previous* meta record,
+/// which chains the commits together.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct MetaEntry {
     pub meta_index: u16,
     pub meta_pos: u32,
     pub root_index: u16,
     pub root_pos: u32,
     pub root_leaf: bool,
-    //pub root_node: Option<Node<'a>>,
-}
-
-impl Default for MetaEntry {
-    fn default() -> Self {
-        MetaEntry {
-            meta_index: 0,
-            meta_pos: 0,
-            root_index: 0,
-            root_pos: 0,
-            root_leaf: false,
-        }
-    }
+    /// Leaves in the tree at `root`, when the format records them
+    pub leaf_count: u64,
+    //pub root_node: Option<Node>,
 }
 
 impl MetaEntry {
     /// Encode the metadata for inclusion in the FF
-    pub fn encode(&self, buffer_pos: u32, meta_key: [u8; 32]) -> Result<Vec<u8>> {
-        let padding = META_SIZE - (buffer_pos as usize % META_SIZE);
-        let mut wtr = vec![0; padding as usize];
+    pub fn encode(&self, buffer_pos: u32, meta_key: [u8; 32], format: Format) -> Result<Vec<u8>> {
+        self.encode_record(META_MAGIC, buffer_pos, meta_key, format)
+    }
+
+    /// Encode as a checkpoint, which recovery doesn't take for a commit
+    pub fn encode_checkpoint(
+        &self,
+        buffer_pos: u32,
+        meta_key: [u8; 32],
+        format: Format,
+    ) -> Result<Vec<u8>> {
+        self.encode_record(CHECKPOINT_MAGIC, buffer_pos, meta_key, format)
+    }
+
+    fn encode_record(
+        &self,
+        magic: u32,
+        buffer_pos: u32,
+        meta_key: [u8; 32],
+        format: Format,
+    ) -> Result<Vec<u8>> {
+        let meta_size = format.meta_size();
+        let padding = meta_size - (buffer_pos as usize % meta_size);
+        let mut wtr = vec![0; padding];
 
         let leaf_flag = if self.root_leaf { 1 } else { 0 };
         let root_pos = (self.root_pos * 2) + leaf_flag;
 
-        wtr.write_u32::<LittleEndian>(META_MAGIC)?;
-        wtr.write_u16::<LittleEndian>(self.meta_index)?;
-        wtr.write_u32::<LittleEndian>(self.meta_pos)?;
-        wtr.write_u16::<LittleEndian>(self.root_index)?;
-        wtr.write_u32::<LittleEndian>(root_pos)?;
+        format.put_u32(&mut wtr, magic);
+        format.put_u16(&mut wtr, self.meta_index);
+        format.put_u32(&mut wtr, self.meta_pos);
+        format.put_u16(&mut wtr, self.root_index);
+        format.put_u32(&mut wtr, root_pos);
+        if format.records_leaf_count() {
+            format.put_u64(&mut wtr, self.leaf_count);
+        }
 
         // Create the checksum
         // Slice off the contents above
-        let preimage = &wtr.clone()[padding as usize..];
+        let preimage = &wtr.clone()[padding..];
         // Checksum it
         let chktotal = checksum(preimage, meta_key);
         // Copy to the writer
@@ -60,34 +81,49 @@ impl MetaEntry {
         Ok(wtr)
     }
 
-    pub fn decode(bits: &[u8], meta_key: [u8; 32]) -> Result<MetaEntry> {
-        let preimage = &bits.to_owned()[0..16];
-        let expected_checksum = &bits.to_owned()[16..36];
-        let mut rdr = Cursor::new(bits);
+    pub fn decode(bits: &[u8], meta_key: [u8; 32], format: Format) -> Result<MetaEntry> {
+        MetaEntry::decode_record(META_MAGIC, bits, meta_key, format)
+    }
+
+    pub fn decode_checkpoint(bits: &[u8], meta_key: [u8; 32], format: Format) -> Result<MetaEntry> {
+        MetaEntry::decode_record(CHECKPOINT_MAGIC, bits, meta_key, format)
+    }
 
-        let magic = rdr.read_u32::<LittleEndian>()?;
-        if magic != META_MAGIC {
-            panic!("Invalid meta magic number");
+    fn decode_record(
+        magic: u32,
+        bits: &[u8],
+        meta_key: [u8; 32],
+        format: Format,
+    ) -> Result<MetaEntry> {
+        let body = format.meta_size() - 20;
+        if bits.len() != format.meta_size() {
+            return Err(Error::Decode("meta record has wrong size"));
+        }
+        let preimage = &bits[0..body];
+        let expected_checksum = &bits[body..];
+        if format.u32(bits) != magic {
+            return Err(Error::Decode("Invalid meta magic number"));
         }
-        assert!(
-            expected_checksum.len() == 20,
-            "meta checksum has wrong size"
-        );
         let chk = checksum(preimage, meta_key);
 
         // Carve off first 20 bytes
         let preimage_chk = &chk[0..20];
 
         if preimage_chk != expected_checksum {
-            panic!("Invalid metaroot checksum!");
+            return Err(Error::Checksum);
         }
 
-        let meta_index = rdr.read_u16::<LittleEndian>()?;
-        let meta_pos = rdr.read_u32::<LittleEndian>()?;
-        let root_index = rdr.read_u16::<LittleEndian>()?;
-        let root_pos = rdr.read_u32::<LittleEndian>()?;
+        let meta_index = format.u16(&bits[4..]);
+        let meta_pos = format.u32(&bits[6..]);
+        let root_index = format.u16(&bits[10..]);
+        let root_pos = format.u32(&bits[12..]);
         let is_leaf = root_pos & 1 == 1;
         let adj_root_pos = root_pos >> 1;
+        let leaf_count = if format.records_leaf_count() {
+            format.u64(&bits[16..])
+        } else {
+            0
+        };
 
         Ok(MetaEntry {
             meta_index,
@@ -95,35 +131,133 @@ impl MetaEntry {
             root_index,
             root_pos: adj_root_pos,
             root_leaf: is_leaf,
+            leaf_count,
         })
     }
+
+    /// Does this entry point at a previous meta record?
+    pub fn has_previous(&self) -> bool {
+        self.meta_index != 0
+    }
+}
+
+/// Footer appended to a data file once it's full: the position of each
+/// meta record in the file, oldest first, their count, a checksum and a
+/// magic number.  An empty list says the file holds no meta at all.
+pub fn encode_footer(positions: &[u32], meta_key: [u8; 32], format: Format) -> Vec<u8> {
+    let mut wtr = Vec::with_capacity(positions.len() * 4 + FOOTER_TRAILER_SIZE);
+    for pos in positions {
+        format.put_u32(&mut wtr, *pos);
+    }
+    format.put_u32(&mut wtr, positions.len() as u32);
+    let chk = checksum(&wtr, meta_key);
+    wtr.extend_from_slice(&chk[0..20]);
+    format.put_u32(&mut wtr, FOOTER_MAGIC);
+    wtr
+}
+
+// The meta positions listed in the footer of the file at `path`, or None
+// if it doesn't end with a valid footer
+pub fn read_footer(path: &Path, meta_key: [u8; 32], format: Format) -> Result<Option<Vec<u32>>> {
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
+    if size < FOOTER_TRAILER_SIZE as u64 {
+        return Ok(None);
+    }
+
+    let mut trailer = [0; FOOTER_TRAILER_SIZE];
+    f.seek(SeekFrom::Start(size - FOOTER_TRAILER_SIZE as u64))?;
+    f.read_exact(&mut trailer)?;
+    if format.u32(&trailer[24..]) != FOOTER_MAGIC {
+        return Ok(None);
+    }
+    let count = format.u32(&trailer) as u64;
+    let len = count * 4 + FOOTER_TRAILER_SIZE as u64;
+    if len > size {
+        return Ok(None);
+    }
+
+    let mut bits = vec![0; len as usize - 24];
+    f.seek(SeekFrom::Start(size - len))?;
+    f.read_exact(&mut bits)?;
+    if checksum(&bits, meta_key)[0..20] != trailer[4..24] {
+        return Ok(None);
+    }
+    let positions: Vec<u32> = bits[..count as usize * 4]
+        .chunks_exact(4)
+        .map(|b| format.u32(b))
+        .collect();
+    if positions
+        .iter()
+        .any(|p| *p as u64 + format.meta_size() as u64 > size - len)
+    {
+        return Ok(None);
+    }
+    Ok(Some(positions))
 }
 
-// Opens the given file and attempts to find the file meta
+// Read the meta record at `pos` in the given file, pointing it at itself
+pub fn meta_at(
+    path: &Path,
+    file_index: u16,
+    pos: u32,
+    meta_key: [u8; 32],
+    format: Format,
+) -> Result<MetaEntry> {
+    let mut bits = vec![0; format.meta_size()];
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(pos as u64))?;
+    f.read_exact(&mut bits)?;
+    let mut state = MetaEntry::decode(&bits, meta_key, format)?;
+    state.meta_index = file_index;
+    state.meta_pos = pos;
+    Ok(state)
+}
+
+// Opens the given file and attempts to find the file meta.
+// Returns the latest meta entry and the location of the meta record
+// that preceded it (as stored on disk).
 pub fn recover_meta(
-    path: &PathBuf,
+    path: &Path,
     file_index: u16,
     meta_key: [u8; 32],
+    format: Format,
 ) -> Result<(MetaEntry, MetaEntry)> {
+    scan_back(path, file_index, meta_key, format, false).map(|(state, result, _)| (state, result))
+}
+
+// Find the last record in the given file that's a meta or a checkpoint.
+// Returns it as `recover_meta` does, and whether it's a checkpoint.
+pub fn recover_latest(
+    path: &Path,
+    file_index: u16,
+    meta_key: [u8; 32],
+    format: Format,
+) -> Result<(MetaEntry, MetaEntry, bool)> {
+    scan_back(path, file_index, meta_key, format, true)
+}
+
+// Search the file backwards for a meta record, or a checkpoint too if
+// `checkpoints` is set
+fn scan_back(
+    path: &Path,
+    file_index: u16,
+    meta_key: [u8; 32],
+    format: Format,
+    checkpoints: bool,
+) -> Result<(MetaEntry, MetaEntry, bool)> {
     let mut buffer = Vec::<u8>::with_capacity(SLAB_SIZE as usize);
-    let mut f = File::open(path).unwrap();
-    let mut size: u64 = 0;
-    if let Ok(m) = f.metadata() {
-        size = m.len();
-    }
+    let mut f = File::open(path)?;
+    let size = f.metadata()?.len();
 
-    let metasize = META_SIZE as u64;
+    let metasize = format.meta_size() as u64;
     let mut off = size - (size % metasize);
 
     while off >= metasize {
-        let mut pos = 0;
-        let mut size = if off >= SLAB_SIZE {
-            pos = off - SLAB_SIZE;
-            SLAB_SIZE
-        } else {
-            off
-        };
+        let pos = off.saturating_sub(SLAB_SIZE);
+        let mut size = off - pos;
 
+        buffer.clear();
         f.seek(SeekFrom::Start(pos))?;
         {
             let reference = f.by_ref();
@@ -131,7 +265,6 @@ pub fn recover_meta(
         } // drop reference here..
 
         assert!(!buffer.is_empty(), "Buffer is empty!");
-        let mut cursor = Cursor::new(&buffer);
 
         // Parse meta -
         // Now read through this window bottom->up looking for the magic key
@@ -139,24 +272,25 @@ pub fn recover_meta(
             size -= metasize;
             off -= metasize;
 
-            cursor.set_position(size);
-            let value = cursor.read_u32::<LittleEndian>()?;
-            if value != META_MAGIC {
+            let ind: usize = size as usize;
+            let magic = format.u32(&buffer[ind..]);
+            let checkpoint = magic == CHECKPOINT_MAGIC;
+            if magic != META_MAGIC && !(checkpoints && checkpoint) {
                 continue;
             }
 
-            let ind: usize = size as usize;
-            if let Ok(result) = MetaEntry::decode(&buffer[ind..ind + META_SIZE], meta_key) {
+            let bits = &buffer[ind..ind + metasize as usize];
+            if let Ok(result) = MetaEntry::decode_record(magic, bits, meta_key, format) {
                 let mut state = result.clone();
                 state.meta_index = file_index;
-                state.meta_pos = size as u32;
-                return Ok((state, result));
+                state.meta_pos = (pos + size) as u32;
+                return Ok((state, result, checkpoint));
             }
         }
     }
 
-    Err(Error::new(
-        ErrorKind::Other,
+    Err(Error::Io(io::Error::new(
+        io::ErrorKind::NotFound,
         "Didn't find it! What's a meta with you?",
-    ))
+    )))
 }
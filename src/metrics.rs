@@ -0,0 +1,141 @@
+//! Per-operation latency and read-count histograms
+//!
+//! Enabled by the `metrics` feature.  Histograms use power-of-two buckets,
+//! so percentiles are reported as the upper bound of their bucket.
+use std::time::{Duration, Instant};
+
+const BUCKETS: usize = 40;
+
+/// Histogram of u64 samples in power-of-two buckets
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        // Bucket i holds values below 2^i
+        let bucket = (64 - value.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile (0.0 to 1.0)
+    pub fn percentile(&self, q: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let bound = if i == 0 { 0 } else { (1u64 << i) - 1 };
+                return bound.min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Measurements for one kind of operation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OpMetrics {
+    /// Latency in microseconds
+    pub latency_us: Histogram,
+    /// Store reads made by each operation
+    pub store_reads: Histogram,
+}
+
+impl OpMetrics {
+    pub fn record(&mut self, probe: Probe, reads: u64) {
+        let elapsed = probe.started.elapsed();
+        self.latency_us.record(duration_micros(elapsed));
+        self.store_reads.record(reads - probe.reads);
+    }
+}
+
+/// Metrics for the main tree operations
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    pub get: OpMetrics,
+    pub prove: OpMetrics,
+    pub insert: OpMetrics,
+    pub commit: OpMetrics,
+}
+
+/// Start of an operation being measured
+#[derive(Clone, Copy, Debug)]
+pub struct Probe {
+    started: Instant,
+    reads: u64,
+}
+
+impl Probe {
+    /// `reads` is the store's read count when the operation starts
+    pub fn start(reads: u64) -> Self {
+        Probe {
+            started: Instant::now(),
+            reads,
+        }
+    }
+}
+
+fn duration_micros(d: Duration) -> u64 {
+    d.as_secs()
+        .saturating_mul(1_000_000)
+        .saturating_add(u64::from(d.subsec_micros()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_percentiles() {
+        let mut h = Histogram::default();
+        assert_eq!(h.percentile(0.99), 0);
+
+        for v in 1..=100 {
+            h.record(v);
+        }
+        h.record(5000);
+
+        assert_eq!(h.count(), 101);
+        assert_eq!(h.max(), 5000);
+        assert_eq!(h.percentile(0.5), 63);
+        assert_eq!(h.percentile(0.99), 127);
+        assert_eq!(h.percentile(1.0), 5000);
+    }
+}
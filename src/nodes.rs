@@ -1,15 +1,17 @@
 use super::hashutils::{sha3_internal, Digest};
 use super::Result;
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use errors::Error;
+use format::Format;
 use std::fmt;
-use std::io::Cursor;
-use store::KEY_SIZE;
+use std::mem;
 
 pub const INTERNAL_NODE_SIZE: usize = 76; // (2 + 4 + 32) * 2;
-pub const LEAF_NODE_SIZE: usize = 40; // 2 + 4 + 2 + 32;
+
+// Size of a node hash, whatever the width of the keys
+const HASH_SIZE: usize = 32;
 
 #[derive(PartialEq, Clone)]
-pub enum Node<'a> {
+pub enum Node {
     Empty {},
     Hash {
         pos: u32,
@@ -21,7 +23,8 @@ pub enum Node<'a> {
         index: u16,
         hash: Digest,
         key: Digest,
-        value: Option<&'a [u8]>,
+        /// Uncommitted value
+        value: Option<Vec<u8>>,
         vindex: u16,
         vpos: u32,
         vsize: u16,
@@ -30,12 +33,12 @@ pub enum Node<'a> {
         pos: u32,
         index: u16,
         hash: Digest,
-        left: Box<Node<'a>>,
-        right: Box<Node<'a>>,
+        left: Box<Node>,
+        right: Box<Node>,
     },
 }
 
-impl<'a> Node<'a> {
+impl Node {
     // Is the node a Leaf?
     pub fn is_leaf(&self) -> bool {
         match self {
@@ -63,14 +66,6 @@ impl<'a> Node<'a> {
         }
     }
 
-    pub fn is_leaf_or_internal(&self) -> bool {
-        match self {
-            Node::Leaf { .. } => true,
-            Node::Internal { .. } => true,
-            _ => false,
-        }
-    }
-
     pub fn index_and_position(&self) -> (u16, u32) {
         match self {
             Node::Leaf { pos, index, .. } => (*index, *pos),
@@ -80,11 +75,33 @@ impl<'a> Node<'a> {
         }
     }
 
+    // Record where the node lives in the store
+    pub fn set_index_and_position(&mut self, new_index: u16, new_pos: u32) {
+        match self {
+            Node::Leaf { pos, index, .. }
+            | Node::Internal { pos, index, .. }
+            | Node::Hash { pos, index, .. } => {
+                *index = new_index;
+                *pos = new_pos;
+            }
+            Node::Empty {} => {}
+        }
+    }
+
+    // Set the hash of a leaf resolved from storage
+    pub fn set_leaf_hash(&mut self, leaf_hash: Digest) {
+        if let Node::Leaf { hash, .. } = self {
+            *hash = leaf_hash;
+        }
+    }
+
     pub fn hash(&self) -> Digest {
         match self {
             Node::Empty {} => Digest([0; 32]),
             Node::Hash { hash, .. } => Digest(hash.0),
             Node::Leaf { hash, .. } => Digest(hash.0),
+            // Internal nodes built by insert carry their hash already
+            Node::Internal { hash, .. } if *hash != Digest::default() => *hash,
             Node::Internal { left, right, .. } => {
                 let lh = left.as_ref().hash();
                 let rh = right.as_ref().hash();
@@ -93,48 +110,45 @@ impl<'a> Node<'a> {
         }
     }
 
+    // Key of a Leaf
+    pub fn key(&self) -> Option<Digest> {
+        match self {
+            Node::Leaf { key, .. } => Some(*key),
+            _ => None,
+        }
+    }
+
     // Create an Empty Node
     pub fn empty() -> Self {
         Node::Empty {}
     }
 
-    // Create basic Leaf Node
-    pub fn leaf(key: Digest, value: Option<&'a [u8]>) -> Self {
-        Node::Leaf {
-            pos: 0,
-            index: 0,
-            hash: Default::default(), // Should this be an Option?
-            key,
-            value,
-            vindex: 0,
-            vpos: 0,
-            vsize: 0,
-        }
+    #[cfg(test)]
+    pub fn encode(&self, format: Format) -> Result<Vec<u8>> {
+        let mut wtr = Vec::with_capacity(INTERNAL_NODE_SIZE);
+        self.encode_into(&mut wtr, format)?;
+        Ok(wtr)
     }
 
-    pub fn encode(&self) -> Result<Vec<u8>> {
+    // Append the encoded node to `wtr` (see `format` for the layout)
+    pub fn encode_into(&self, wtr: &mut Vec<u8>, format: Format) -> Result<()> {
         match self {
             Node::Internal { left, right, .. } => {
-                let mut wtr = vec![];
-                // Do left node
                 let (lindex, lpos) = left.index_and_position();
-                // index of file
-                wtr.write_u16::<LittleEndian>(lindex * 2)?;
-                // pos
-                wtr.write_u32::<LittleEndian>(lpos)?;
-                // hash
+                format.put_u16(wtr, lindex * 2);
+                format.put_u32(wtr, lpos);
                 wtr.extend_from_slice(&(left.hash()).0);
 
-                // Do right node
                 let (rindex, rpos) = right.index_and_position();
-                // index of file
-                wtr.write_u16::<LittleEndian>(rindex)?;
-                // flags
-                wtr.write_u32::<LittleEndian>(rpos)?;
-                // hash
+                if format.doubles_right_index() {
+                    format.put_u16(wtr, rindex * 2);
+                } else {
+                    format.put_u16(wtr, rindex);
+                }
+                format.put_u32(wtr, rpos);
                 wtr.extend_from_slice(&(right.hash()).0);
 
-                Ok(wtr)
+                Ok(())
             }
             Node::Leaf {
                 vindex,
@@ -144,128 +158,150 @@ impl<'a> Node<'a> {
                 value,
                 ..
             } => {
-                let mut wtr = vec![];
                 assert!(value.is_some(), "Leaf has no value!");
 
-                value.map(|v| {
+                if let Some(ref v) = *value {
                     vsize = v.len() as u16;
-                });
+                }
 
-                // Write Node
                 // leaf value index - NOTE + 1 for leaf detection
-                wtr.write_u16::<LittleEndian>(*vindex * 2 + 1)?;
-                // leaf value position
-                wtr.write_u32::<LittleEndian>(*vpos)?;
-                // value size
-                wtr.write_u16::<LittleEndian>(vsize)?;
-                // append key
-                wtr.extend_from_slice(&key.0);
-
-                Ok(wtr)
+                format.put_u16(wtr, *vindex * 2 + 1);
+                format.put_u32(wtr, *vpos);
+                format.put_u16(wtr, vsize);
+                if format.version >= 3 {
+                    wtr.push(format.key_size);
+                    wtr.extend_from_slice(&key.0[..format.key_size as usize]);
+                } else {
+                    wtr.extend_from_slice(&key.0);
+                }
+
+                Ok(())
             }
             _ => unimplemented!(),
         }
     }
 
     // Need key size here to make sure we get the right amount of data for the key
-    pub fn decode(mut bits: Vec<u8>, is_leaf: bool) -> Result<Node<'a>> {
+    pub fn decode(bits: Vec<u8>, is_leaf: bool, format: Format) -> Result<Node> {
         if is_leaf {
             // Make a leaf
-            assert!(
-                bits.len() == LEAF_NODE_SIZE,
-                "node:decode - Not enough bits for a Leaf"
-            );
-
-            let k = bits.split_off(8);
-
-            let mut rdr = Cursor::new(bits);
-            let mut vindex = rdr.read_u16::<LittleEndian>()?;
-            assert!(vindex & 1 == 1, "Database is corrupt!");
-
-            vindex >>= 1;
-
-            let vpos = rdr.read_u32::<LittleEndian>()?;
-            let vsize = rdr.read_u16::<LittleEndian>()?;
-
-            // Extract the key
-            assert!(k.len() == 32);
+            if bits.len() != format.leaf_size() {
+                return Err(Error::Decode("Not enough bits for a Leaf"));
+            }
+            let key = if format.version >= 3 {
+                if bits[8] != format.key_size {
+                    return Err(Error::Decode("Leaf key size doesn't match the store's"));
+                }
+                let mut key = Digest::default();
+                key.0[..bits[8] as usize].copy_from_slice(&bits[9..]);
+                key
+            } else {
+                Digest::from(&bits[8..])
+            };
 
-            let mut keybits: [u8; 32] = Default::default();
-            keybits.copy_from_slice(&k);
+            let vindex = format.u16(&bits[0..]);
+            if vindex & 1 != 1 {
+                return Err(Error::Decode("Leaf is missing its leaf flag"));
+            }
 
             Ok(Node::Leaf {
                 pos: 0,
                 index: 0,
                 hash: Default::default(),
-                key: Digest(keybits),
+                key,
                 value: None,
-                vindex,
-                vpos,
-                vsize,
+                vindex: vindex >> 1,
+                vpos: format.u32(&bits[2..]),
+                vsize: format.u16(&bits[6..]),
             })
         } else {
             // Make an internal
-            assert!(
-                bits.len() == INTERNAL_NODE_SIZE,
-                format!(
-                    "node.decode - Not enough bits {:?} for an Internal",
-                    bits.len()
-                )
-            );
-
-            let mut offset = 0;
-
-            let mut left_index = LittleEndian::read_u16(&bits[offset..]);
-            offset += 2;
-            assert!(left_index & 1 == 0, "Database is corrupt!");
-
-            left_index >>= 1;
-
-            let leftnode = if left_index != 0 {
-                let left_pos = LittleEndian::read_u32(&bits[offset..]);
-                offset += 4;
-                let left_hash = &bits[offset..offset + KEY_SIZE];
-                offset += KEY_SIZE;
-                // add hashnode to left
-                Node::Hash {
-                    pos: left_pos,
-                    index: left_index,
-                    hash: Digest::from(left_hash),
-                }
-            } else {
-                offset += 4 + KEY_SIZE;
-                Node::empty()
-            };
-
-            let right_index = LittleEndian::read_u16(&bits[offset..]);
-            offset += 2;
-
-            let rightnode = if right_index != 0 {
-                let right_pos = LittleEndian::read_u32(&bits[offset..]);
-                offset += 4;
-                let right_hash = &bits[offset..offset + KEY_SIZE];
+            if bits.len() != INTERNAL_NODE_SIZE {
+                return Err(Error::Decode("Not enough bits for an Internal"));
+            }
 
-                Node::Hash {
-                    pos: right_pos,
-                    index: right_index,
-                    hash: Digest::from(right_hash),
+            let half = INTERNAL_NODE_SIZE / 2;
+            let left_index = format.u16(&bits[0..]);
+            let mut right_index = format.u16(&bits[half..]);
+            if left_index & 1 != 0 {
+                return Err(Error::Decode("Internal has a leaf flag"));
+            }
+            if format.doubles_right_index() {
+                if right_index & 1 != 0 {
+                    return Err(Error::Decode("Internal has a leaf flag"));
                 }
-            } else {
-                Node::empty()
-            };
+                right_index >>= 1;
+            }
 
             Ok(Node::Internal {
                 pos: 0,
                 index: 0,
                 hash: Default::default(),
-                left: Box::new(leftnode),
-                right: Box::new(rightnode),
+                left: Box::new(decode_child(&bits[..half], left_index >> 1, format)),
+                right: Box::new(decode_child(&bits[half..], right_index, format)),
             })
         }
     }
 }
 
-impl<'a> fmt::Debug for Node<'a> {
+// One child of an encoded internal node, already knowing its index
+fn decode_child(bits: &[u8], index: u16, format: Format) -> Node {
+    if index == 0 {
+        return Node::empty();
+    }
+    Node::Hash {
+        pos: format.u32(&bits[2..]),
+        index,
+        hash: Digest::from(&bits[6..6 + HASH_SIZE]),
+    }
+}
+
+// Most boxes a pool keeps for reuse
+const POOL_LIMIT: usize = 4096;
+
+/// Free list of the boxes that hold `Internal` children, reused across
+/// insert/commit cycles instead of going back to the allocator
+#[derive(Default)]
+pub struct NodePool {
+    // The boxes themselves are what gets reused
+    #[allow(clippy::vec_box)]
+    free: Vec<Box<Node>>,
+}
+
+impl NodePool {
+    // Box `node`, reusing a pooled box if there is one
+    pub fn boxed(&mut self, node: Node) -> Box<Node> {
+        match self.free.pop() {
+            Some(mut b) => {
+                *b = node;
+                b
+            }
+            None => Box::new(node),
+        }
+    }
+
+    // Move the node out of `b` and keep the box for reuse
+    pub fn unbox(&mut self, mut b: Box<Node>) -> Node {
+        let node = mem::replace(&mut *b, Node::Empty {});
+        self.recycle(b);
+        node
+    }
+
+    // Keep `b` for reuse, dropping what it holds
+    pub fn recycle(&mut self, mut b: Box<Node>) {
+        if self.free.len() < POOL_LIMIT {
+            *b = Node::Empty {};
+            self.free.push(b);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+}
+
+impl fmt::Debug for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Node::Empty {} => write!(f, "Node::Empty"),
@@ -281,13 +317,14 @@ impl<'a> fmt::Debug for Node<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use format::{Endianness, LEGACY_FORMAT};
     use hashutils::sha3;
 
     #[test]
     fn leaf_encode_decode() {
         let lf = Node::Leaf {
             key: sha3(b"dave"),
-            value: Some(&[1, 2, 3, 4]),
+            value: Some(vec![1, 2, 3, 4]),
             pos: 0,
             index: 1,
             hash: Default::default(),
@@ -296,10 +333,10 @@ mod tests {
             vsize: 0,
         };
 
-        let encoded_leaf = lf.encode();
+        let encoded_leaf = lf.encode(Format::default());
         assert!(encoded_leaf.is_ok());
 
-        let back = Node::decode(encoded_leaf.unwrap(), true);
+        let back = Node::decode(encoded_leaf.unwrap(), true, Format::default());
         assert!(back.is_ok());
         assert!(match back.unwrap() {
             Node::Leaf {
@@ -319,12 +356,29 @@ mod tests {
         })
     }
 
+    #[test]
+    fn pool_reuses_boxes() {
+        let mut pool = NodePool::default();
+        let b = pool.boxed(Node::empty());
+        let addr = &*b as *const Node;
+        assert!(pool.unbox(b) == Node::empty());
+        assert_eq!(pool.len(), 1);
+
+        let b = pool.boxed(Node::Hash {
+            pos: 2,
+            index: 1,
+            hash: Digest::default(),
+        });
+        assert_eq!(&*b as *const Node, addr);
+        assert_eq!(pool.len(), 0);
+    }
+
     #[test]
     fn internal_encode_decode() {
         let h: &[u8] = &[1u8; 32];
         let inner_leaf = Node::Leaf {
             key: sha3(b"dave"),
-            value: Some(&[1, 2, 3, 4]),
+            value: Some(vec![1, 2, 3, 4]),
             pos: 0,
             index: 1,
             hash: Digest::from(h),
@@ -341,10 +395,53 @@ mod tests {
             hash: Default::default(),
         };
 
-        let encoded_int = inner.encode();
+        let encoded_int = inner.encode(Format::default());
         assert!(encoded_int.is_ok());
-        let back = Node::decode(encoded_int.unwrap(), false);
+        let encoded_int = encoded_int.unwrap();
+
+        // encode_into appends to what's already there
+        let mut buf = vec![9u8];
+        inner.encode_into(&mut buf, Format::default()).unwrap();
+        assert_eq!(&buf[1..], &encoded_int[..]);
+
+        let back = Node::decode(encoded_int, false, Format::default());
         assert!(!back.unwrap().is_leaf());
-    }
 
+        // Both children's indices are doubled, in either byte order, but
+        // only the left's in the legacy format
+        let swapped = Node::Internal {
+            left: Box::new(Node::Hash {
+                pos: 40,
+                index: 3,
+                hash: Digest::from(h),
+            }),
+            right: Box::new(Node::Hash {
+                pos: 41,
+                index: 2,
+                hash: Digest::from(h),
+            }),
+            pos: 20,
+            index: 1,
+            hash: Default::default(),
+        };
+        for format in &[
+            Format::new(Endianness::Little),
+            Format::new(Endianness::Big),
+            LEGACY_FORMAT,
+        ] {
+            let bits = swapped.encode(*format).unwrap();
+            match Node::decode(bits, false, *format).unwrap() {
+                Node::Internal { left, right, .. } => {
+                    assert_eq!(left.index_and_position(), (3, 40));
+                    assert_eq!(right.index_and_position(), (2, 41));
+                    assert!(right.is_leaf());
+                }
+                _ => panic!("Expected an internal node"),
+            }
+        }
+        let bits = swapped.encode(Format::new(Endianness::Big)).unwrap();
+        assert_eq!(&bits[..2], &[0, 6]);
+        assert_eq!(&bits[38..40], &[0, 4]);
+        assert_eq!(&swapped.encode(LEGACY_FORMAT).unwrap()[38..40], &[2, 0]);
+    }
 }
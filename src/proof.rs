@@ -1,4 +1,18 @@
-use super::hashutils::{sha3_internal, sha3_leaf, sha3_value, Digest};
+use super::hashutils::{
+    hasher_for, sha3_leaf_salted, sha3_sized_root, Digest, Hasher, Installed, Sha3Hash,
+};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use errors::Error;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+#[cfg(feature = "text")]
+use text;
+
+/// Most sibling hashes a proof may carry, one per bit of a 256 bit key
+pub const MAX_PROOF_DEPTH: usize = 256;
+/// Largest value a proof may carry, as values are stored with a u16 size
+pub const MAX_PROOF_VALUE_SIZE: usize = 0xffff;
 
 /// Determine which direction to go in the Tree based on the bit value in the key.
 /// Used in tree and proof
@@ -12,14 +26,16 @@ pub fn has_bit(key: &Digest, index: usize) -> bool {
     }
 }
 
-#[derive(Eq, PartialEq, Clone, Debug)]
+#[derive(Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Debug)]
 pub enum ProofType {
     Exists,
     Collision,
     Deadend,
 }
 
-#[derive(Eq, PartialEq, Clone)]
+/// Proofs order by type (exists, collision, deadend), then sibling hashes,
+/// then key, hash and value, so batches sort the same way everywhere
+#[derive(Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Debug)]
 pub struct Proof {
     pub proof_type: ProofType,
     node_hashes: Vec<Digest>,
@@ -28,7 +44,7 @@ pub struct Proof {
     pub value: Option<Vec<u8>>,
 }
 
-impl<'a> Default for Proof {
+impl Default for Proof {
     fn default() -> Self {
         Proof {
             proof_type: ProofType::Deadend,
@@ -49,6 +65,121 @@ impl Proof {
         self.node_hashes.push(hash);
     }
 
+    // Replace the sibling hashes with a single exact-size copy of `hashes`
+    pub fn set_node_hashes(&mut self, hashes: &[Digest]) {
+        self.node_hashes = hashes.to_vec();
+    }
+
+    /// Encode the proof for sending to a peer:
+    ///
+    /// * type: u8 (0 exists, 1 collision, 2 deadend)
+    /// * depth: u16, then depth sibling hashes
+    /// * exists: value size u16 and the value
+    /// * collision: the other key and the hash of its value
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        if self.depth() > MAX_PROOF_DEPTH {
+            return Err(Error::Decode("Proof is too deep"));
+        }
+        let mut wtr = Vec::with_capacity(3 + self.depth() * 32 + 64);
+        match self.proof_type {
+            ProofType::Exists => wtr.push(0),
+            ProofType::Collision => wtr.push(1),
+            ProofType::Deadend => wtr.push(2),
+        }
+        wtr.write_u16::<LittleEndian>(self.depth() as u16)?;
+        for h in &self.node_hashes {
+            wtr.extend_from_slice(&h.0);
+        }
+        match self.proof_type {
+            ProofType::Exists => {
+                let value = self
+                    .value
+                    .as_ref()
+                    .ok_or(Error::Decode("Proof is missing its value"))?;
+                if value.len() > MAX_PROOF_VALUE_SIZE {
+                    return Err(Error::Decode("Proof value is too large"));
+                }
+                wtr.write_u16::<LittleEndian>(value.len() as u16)?;
+                wtr.extend_from_slice(value);
+            }
+            ProofType::Collision => match (self.key, self.hash) {
+                (Some(k), Some(h)) => {
+                    wtr.extend_from_slice(&k.0);
+                    wtr.extend_from_slice(&h.0);
+                }
+                _ => return Err(Error::Decode("Proof is missing its key or hash")),
+            },
+            ProofType::Deadend => {}
+        }
+        Ok(wtr)
+    }
+
+    /// Decode a proof from an untrusted peer.  Every length is checked
+    /// against what's left of `bits` before anything is allocated, and
+    /// trailing bytes are an error.
+    pub fn decode(bits: &[u8]) -> Result<Proof, Error> {
+        let mut rdr = ProofReader { bits };
+
+        let proof_type = match rdr.take(1)?[0] {
+            0 => ProofType::Exists,
+            1 => ProofType::Collision,
+            2 => ProofType::Deadend,
+            _ => return Err(Error::Decode("Unknown proof type")),
+        };
+
+        let depth = LittleEndian::read_u16(rdr.take(2)?) as usize;
+        if depth > MAX_PROOF_DEPTH {
+            return Err(Error::Decode("Proof is too deep"));
+        }
+        let hashes = rdr.take(depth * 32)?;
+
+        let mut proof = Proof {
+            proof_type,
+            node_hashes: hashes.chunks_exact(32).map(Digest::from).collect(),
+            ..Default::default()
+        };
+        match proof.proof_type {
+            ProofType::Exists => {
+                let size = LittleEndian::read_u16(rdr.take(2)?) as usize;
+                proof.value = Some(rdr.take(size)?.to_vec());
+            }
+            ProofType::Collision => {
+                proof.key = Some(Digest::from(rdr.take(32)?));
+                proof.hash = Some(Digest::from(rdr.take(32)?));
+            }
+            ProofType::Deadend => {}
+        }
+
+        if !rdr.bits.is_empty() {
+            return Err(Error::Decode("Trailing bytes after proof"));
+        }
+        Ok(proof)
+    }
+
+    /// The encoded proof as lowercase hex
+    #[cfg(feature = "text")]
+    pub fn to_hex(&self) -> Result<String, Error> {
+        Ok(text::to_hex(&self.encode()?))
+    }
+
+    /// Decode a proof sent as hex
+    #[cfg(feature = "text")]
+    pub fn from_hex(hex: &str) -> Result<Proof, Error> {
+        Proof::decode(&text::from_hex(hex)?)
+    }
+
+    /// The encoded proof as padded standard base64
+    #[cfg(feature = "text")]
+    pub fn to_base64(&self) -> Result<String, Error> {
+        Ok(text::to_base64(&self.encode()?))
+    }
+
+    /// Decode a proof sent as base64
+    #[cfg(feature = "text")]
+    pub fn from_base64(base64: &str) -> Result<Proof, Error> {
+        Proof::decode(&text::from_base64(base64)?)
+    }
+
     pub fn is_sane(&self, bits: usize) -> bool {
         match self.proof_type {
             ProofType::Exists => {
@@ -61,10 +192,12 @@ impl Proof {
                 !(self.key.is_none()
                     || self.hash.is_none()
                     || self.value.is_some()
-                    || self.key.as_ref().unwrap().0.len() != (bits >> 3)
-                    || self.hash.as_ref().unwrap().0.len() != 32)
+                    || self.hash.as_ref().unwrap().0.len() != 32
+                    || !zero_past(self.key.as_ref().unwrap(), bits))
+            }
+            ProofType::Deadend => {
+                !(self.key.is_some() || self.hash.is_some() || self.value.is_some())
             }
-            ProofType::Deadend => false,
         }
     }
 
@@ -74,10 +207,53 @@ impl Proof {
         key: Digest,
         bits: usize,
     ) -> Result<Vec<u8>, &'static str> {
+        self.verify_salted(root_hash, key, bits, None)
+    }
+
+    /// Verify against a tree whose leaves are hashed with `salt`
+    /// (see `StoreOptions::leaf_salt`)
+    pub fn verify_salted(
+        &mut self,
+        root_hash: Digest,
+        key: Digest,
+        bits: usize,
+        salt: Option<&[u8; 32]>,
+    ) -> Result<Vec<u8>, &'static str> {
+        self.check_salted(root_hash, key, bits, salt)?;
+        self.value.take().ok_or("Bad Verification")
+    }
+
+    /// Check the proof leads to `root_hash`, leaving it intact.  Unlike
+    /// `verify`, a valid proof that `key` is absent passes.  Hashes as the
+    /// tree does, with the installed provider.
+    pub fn check_salted(
+        &self,
+        root_hash: Digest,
+        key: Digest,
+        bits: usize,
+        salt: Option<&[u8; 32]>,
+    ) -> Result<(), &'static str> {
+        self.check_with(&Installed, root_hash, key, bits, salt)
+    }
+
+    /// `check_salted` for a tree built with `hasher`
+    pub fn check_with(
+        &self,
+        hasher: &dyn Hasher,
+        root_hash: Digest,
+        key: Digest,
+        bits: usize,
+        salt: Option<&[u8; 32]>,
+    ) -> Result<(), &'static str> {
         if !self.is_sane(bits) {
             return Err("Unknown");
         }
 
+        // Keys only have `bits` bits to branch on
+        if bits > MAX_PROOF_DEPTH || self.depth() > bits {
+            return Err("Proof too deep");
+        }
+
         let leaf = match self.proof_type {
             ProofType::Deadend => Digest::default(),
             ProofType::Collision => {
@@ -86,33 +262,628 @@ impl Proof {
                 }
                 let k = self.key.unwrap();
                 let h = self.hash.unwrap();
-                sha3_leaf(k, &h.0)
+                hasher.hash_leaf(salt, k, &h.0)
             }
             ProofType::Exists => {
                 let v = self.value.as_ref().unwrap();
-                sha3_value(key, v)
+                hasher.hash_value(salt, key, v)
             }
         };
 
-        let mut next = leaf;
-        let mut depth = self.depth() - 1;
+        if fold_siblings(hasher, leaf, key, &self.node_hashes) != root_hash {
+            Err("Head Mismatch")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// Is `key` zero past its first `bits` bits, as keys narrower than a
+// digest are?
+fn zero_past(key: &Digest, bits: usize) -> bool {
+    key.0
+        .get(bits >> 3..)
+        .is_some_and(|rest| rest.iter().all(|b| *b == 0))
+}
+
+// Hash up from the leaf, the sibling at depth `i` being the ith hash
+fn fold_siblings(hasher: &dyn Hasher, leaf: Digest, key: Digest, hashes: &[Digest]) -> Digest {
+    let mut next = leaf;
+    for (depth, n) in hashes.iter().enumerate().rev() {
+        if has_bit(&key, depth) {
+            next = hasher.hash_internal(*n, next)
+        } else {
+            next = hasher.hash_internal(next, *n)
+        }
+    }
+    next
+}
+
+/// A proof about a key at an earlier root, with the roots committed since.
+///
+/// Meta records aren't hashed into the roots, so the chain shows the old
+/// root is in the history as this store reports it.  An auditor wanting
+/// more than the store's word can check the roots against signed commits
+/// (see `UrkelTree::verify_commit_history`).
+#[derive(Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Debug)]
+pub struct HistoricalProof {
+    /// Proof against the oldest root in `roots`
+    pub proof: Proof,
+    /// Committed roots, from the latest back to the one proved against
+    pub roots: Vec<Digest>,
+}
+
+impl HistoricalProof {
+    /// The root the key is proved against
+    pub fn proved_root(&self) -> Option<Digest> {
+        self.roots.last().cloned()
+    }
+
+    /// Check the chain starts at `latest_root` and the proof holds at its
+    /// end.  Returns the value the key held then, or None if it was absent.
+    pub fn verify(
+        &self,
+        latest_root: Digest,
+        key: Digest,
+        bits: usize,
+    ) -> Result<Option<Vec<u8>>, &'static str> {
+        self.verify_salted(latest_root, key, bits, None)
+    }
+
+    /// Verify against a tree whose leaves are hashed with `salt`
+    pub fn verify_salted(
+        &self,
+        latest_root: Digest,
+        key: Digest,
+        bits: usize,
+        salt: Option<&[u8; 32]>,
+    ) -> Result<Option<Vec<u8>>, &'static str> {
+        if self.roots.first() != Some(&latest_root) {
+            return Err("History doesn't start at the latest root");
+        }
+        let root = self.proved_root().ok_or("Empty history")?;
+        self.proof.check_salted(root, key, bits, salt)?;
+        Ok(self.proof.value.clone())
+    }
+
+    /// Encode as the number of roots (u32), the roots and then the proof
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let proof = self.proof.encode()?;
+        let mut wtr = Vec::with_capacity(4 + self.roots.len() * 32 + proof.len());
+        wtr.write_u32::<LittleEndian>(self.roots.len() as u32)?;
+        for root in &self.roots {
+            wtr.extend_from_slice(&root.0);
+        }
+        wtr.extend_from_slice(&proof);
+        Ok(wtr)
+    }
+
+    /// Decode a historical proof from an untrusted peer
+    pub fn decode(bits: &[u8]) -> Result<HistoricalProof, Error> {
+        let mut rdr = ProofReader { bits };
+        let count = LittleEndian::read_u32(rdr.take(4)?) as usize;
+        let roots = rdr
+            .take(
+                count
+                    .checked_mul(32)
+                    .ok_or(Error::Decode("Truncated proof"))?,
+            )?
+            .chunks_exact(32)
+            .map(Digest::from)
+            .collect();
+        Ok(HistoricalProof {
+            proof: Proof::decode(rdr.bits)?,
+            roots,
+        })
+    }
+}
+
+/// A proof against a commitment to both the root and the number of leaves
+/// in the tree (`sha3_sized_root`), so the verifier learns the tree's size
+/// and can tell if entries are being withheld
+#[derive(Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Debug)]
+pub struct SizedProof {
+    pub proof: Proof,
+    /// Root the proof leads to
+    pub root: Digest,
+    /// Leaves in the tree under `root`
+    pub leaf_count: u64,
+}
+
+impl SizedProof {
+    /// Check the proof against `commitment`.  Returns the key's value, or
+    /// None if it's absent.
+    pub fn verify(
+        &self,
+        commitment: Digest,
+        key: Digest,
+        bits: usize,
+    ) -> Result<Option<Vec<u8>>, &'static str> {
+        self.verify_salted(commitment, key, bits, None)
+    }
+
+    /// Verify against a tree whose leaves are hashed with `salt`
+    pub fn verify_salted(
+        &self,
+        commitment: Digest,
+        key: Digest,
+        bits: usize,
+        salt: Option<&[u8; 32]>,
+    ) -> Result<Option<Vec<u8>>, &'static str> {
+        if sha3_sized_root(self.root, self.leaf_count) != commitment {
+            return Err("Size Mismatch");
+        }
+        self.proof.check_salted(self.root, key, bits, salt)?;
+        Ok(self.proof.value.clone())
+    }
+
+    /// Encode as the root, the leaf count (u64) and then the proof
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let proof = self.proof.encode()?;
+        let mut wtr = Vec::with_capacity(40 + proof.len());
+        wtr.extend_from_slice(&self.root.0);
+        wtr.write_u64::<LittleEndian>(self.leaf_count)?;
+        wtr.extend_from_slice(&proof);
+        Ok(wtr)
+    }
+
+    /// Decode a sized proof from an untrusted peer
+    pub fn decode(bits: &[u8]) -> Result<SizedProof, Error> {
+        let mut rdr = ProofReader { bits };
+        let root = Digest::from(rdr.take(32)?);
+        let leaf_count = LittleEndian::read_u64(rdr.take(8)?);
+        Ok(SizedProof {
+            proof: Proof::decode(rdr.bits)?,
+            root,
+            leaf_count,
+        })
+    }
+}
+
+/// Why `verify_bytes` rejected a proof
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The root isn't 32 bytes
+    BadRoot,
+    /// The key is neither 32 bytes nor, for narrower trees, `bits / 8`
+    BadKey,
+    /// The proof couldn't be decoded
+    Decode(Error),
+    /// The proof is tagged with a hasher this build doesn't know
+    UnknownHasher(u8),
+    /// The proof doesn't lead to the root
+    Invalid(&'static str),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::BadRoot => write!(f, "Root must be 32 bytes"),
+            VerifyError::BadKey => write!(f, "Key must be 32 bytes or the tree's key width"),
+            VerifyError::Decode(e) => write!(f, "Malformed proof: {}", e),
+            VerifyError::UnknownHasher(id) => write!(f, "Unknown hasher {}", id),
+            VerifyError::Invalid(msg) => write!(f, "Invalid proof: {}", msg),
+        }
+    }
+}
+
+impl error::Error for VerifyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            VerifyError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Decode and verify an encoded proof in one call, for FFI and RPC layers
+/// that only hold bytes.  Returns the key's value, or None if the proof
+/// shows it's absent.  Hashes with the installed provider.
+pub fn verify_bytes(
+    root: &[u8],
+    key: &[u8],
+    proof: &[u8],
+    bits: usize,
+) -> Result<Option<Vec<u8>>, VerifyError> {
+    verify_bytes_with(&Installed, root, key, proof, bits)
+}
+
+/// `verify_bytes` for a tree built with `hasher`
+pub fn verify_bytes_with(
+    hasher: &dyn Hasher,
+    root: &[u8],
+    key: &[u8],
+    proof: &[u8],
+    bits: usize,
+) -> Result<Option<Vec<u8>>, VerifyError> {
+    if root.len() != 32 {
+        return Err(VerifyError::BadRoot);
+    }
+    // Keys narrower than 32 bytes may be passed without their zero padding
+    if key.len() > 32 || (key.len() != 32 && key.len() * 8 != bits) {
+        return Err(VerifyError::BadKey);
+    }
+    let mut padded = Digest::default();
+    padded.0[..key.len()].copy_from_slice(key);
+    let proof = Proof::decode(proof).map_err(VerifyError::Decode)?;
+    proof
+        .check_with(hasher, Digest::from(root), padded, bits, None)
+        .map_err(VerifyError::Invalid)?;
+    Ok(proof.value)
+}
+
+/// Prefix an encoded proof with the id of the hasher its tree uses, for
+/// `verify_tagged`
+pub fn tag_proof(hasher: &dyn Hasher, proof: &[u8]) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(1 + proof.len());
+    tagged.push(hasher.id());
+    tagged.extend_from_slice(proof);
+    tagged
+}
+
+/// `verify_bytes` for a proof from `tag_proof`, verified with the hasher
+/// its tag names.  One verifier can then check proofs from trees using
+/// different hash functions.
+pub fn verify_tagged(
+    root: &[u8],
+    key: &[u8],
+    tagged: &[u8],
+    bits: usize,
+) -> Result<Option<Vec<u8>>, VerifyError> {
+    let (id, proof) = tagged
+        .split_first()
+        .ok_or(VerifyError::Decode(Error::Decode("Truncated proof")))?;
+    let hasher = hasher_for(*id).ok_or(VerifyError::UnknownHasher(*id))?;
+    verify_bytes_with(hasher, root, key, proof, bits)
+}
 
-        for n in self.node_hashes.iter().rev() {
-            if has_bit(&key, depth) {
-                next = sha3_internal(*n, next)
-            } else {
-                next = sha3_internal(next, *n)
+/// Verify an encoded proof as it's read from `rdr`, without holding the
+/// value.  An exists proof's value is hashed and copied to `value` as it
+/// arrives, so only the sibling hashes (at most 8KB) are buffered.  The
+/// value isn't proven until this returns Ok, so anything written to
+/// `value` before an error must be thrown away.
+///
+/// Reading stops at the end of the proof, leaving anything after it in
+/// `rdr`.  Returns the type of proof, `Deadend` or `Collision` showing the
+/// key is absent.  Hashes with the installed provider.
+pub fn verify_reader<R: Read, W: Write>(
+    rdr: &mut R,
+    root: Digest,
+    key: Digest,
+    bits: usize,
+    value: &mut W,
+) -> Result<ProofType, VerifyError> {
+    let mut head = [0; 3];
+    read_proof(rdr, &mut head)?;
+    let proof_type = match head[0] {
+        0 => ProofType::Exists,
+        1 => ProofType::Collision,
+        2 => ProofType::Deadend,
+        _ => return Err(VerifyError::Decode(Error::Decode("Unknown proof type"))),
+    };
+
+    let depth = LittleEndian::read_u16(&head[1..]) as usize;
+    if depth > MAX_PROOF_DEPTH {
+        return Err(VerifyError::Decode(Error::Decode("Proof is too deep")));
+    }
+    if bits > MAX_PROOF_DEPTH || depth > bits {
+        return Err(VerifyError::Invalid("Proof too deep"));
+    }
+    let mut hashes = Vec::with_capacity(depth);
+    let mut hash = [0; 32];
+    for _ in 0..depth {
+        read_proof(rdr, &mut hash)?;
+        hashes.push(Digest(hash));
+    }
+
+    let leaf = match proof_type {
+        ProofType::Exists => {
+            let mut size = [0; 2];
+            read_proof(rdr, &mut size)?;
+            let mut left = LittleEndian::read_u16(&size) as usize;
+
+            let mut hasher = Sha3Hash::new();
+            let mut chunk = [0; 1024];
+            while left > 0 {
+                let n = left.min(chunk.len());
+                read_proof(rdr, &mut chunk[..n])?;
+                hasher.update(&chunk[..n]);
+                value
+                    .write_all(&chunk[..n])
+                    .map_err(|e| VerifyError::Decode(Error::Io(e)))?;
+                left -= n;
             }
+            let vhash = hasher.finalize();
+            sha3_leaf_salted(None, key, &vhash)
+        }
+        ProofType::Collision => {
+            let mut other = [0; 32];
+            read_proof(rdr, &mut other)?;
+            read_proof(rdr, &mut hash)?;
+            if Digest(other) == key {
+                return Err(VerifyError::Invalid("Same Key"));
+            }
+            sha3_leaf_salted(None, Digest(other), &hash)
+        }
+        ProofType::Deadend => Digest::default(),
+    };
+
+    if fold_siblings(&Installed, leaf, key, &hashes) != root {
+        return Err(VerifyError::Invalid("Head Mismatch"));
+    }
+    Ok(proof_type)
+}
+
+// Fill `buf` from a streamed proof, running out being a truncated proof
+fn read_proof<R: Read>(rdr: &mut R, buf: &mut [u8]) -> Result<(), VerifyError> {
+    rdr.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => VerifyError::Decode(Error::Decode("Truncated proof")),
+        _ => VerifyError::Decode(Error::Io(e)),
+    })
+}
+
+// Bounds checked reads over an encoded proof
+struct ProofReader<'a> {
+    bits: &'a [u8],
+}
+
+impl<'a> ProofReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.bits.len() {
+            return Err(Error::Decode("Truncated proof"));
+        }
+        let (head, rest) = self.bits.split_at(len);
+        self.bits = rest;
+        Ok(head)
+    }
+}
+
+#[cfg(all(test, feature = "keccak"))]
+mod tests {
+    use super::*;
+    use hashutils::{sha3, sha3_internal, sha3_value_salted, Sha3};
+
+    fn exists_proof() -> Proof {
+        let mut proof = Proof {
+            proof_type: ProofType::Exists,
+            value: Some(b"value".to_vec()),
+            ..Default::default()
+        };
+        proof.push(sha3(b"one"));
+        proof.push(sha3(b"two"));
+        proof
+    }
+
+    #[test]
+    fn encode_decode() {
+        let proof = exists_proof();
+        assert_eq!(Proof::decode(&proof.encode().unwrap()).unwrap(), proof);
 
-            if depth > 0 {
-                depth -= 1;
+        let collision = Proof {
+            proof_type: ProofType::Collision,
+            key: Some(sha3(b"key")),
+            hash: Some(sha3(b"hash")),
+            ..Default::default()
+        };
+        assert_eq!(
+            Proof::decode(&collision.encode().unwrap()).unwrap(),
+            collision
+        );
+    }
+
+    #[test]
+    fn proofs_sort_and_dedup() {
+        use std::collections::{BTreeSet, HashSet};
+
+        let deadend = Proof::default();
+        let exists = exists_proof();
+        let mut longer = exists_proof();
+        longer.push(sha3(b"three"));
+
+        let sorted: Vec<Proof> = vec![
+            deadend.clone(),
+            longer.clone(),
+            exists.clone(),
+            exists.clone(),
+        ]
+        .into_iter()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+        assert_eq!(sorted, vec![exists.clone(), longer, deadend]);
+
+        let set: HashSet<Proof> = vec![exists.clone(), exists].into_iter().collect();
+        assert_eq!(set.len(), 1);
+    }
+
+    #[cfg(feature = "text")]
+    #[test]
+    fn text_round_trip() {
+        let proof = exists_proof();
+        assert_eq!(Proof::from_hex(&proof.to_hex().unwrap()).unwrap(), proof);
+        assert_eq!(
+            Proof::from_base64(&proof.to_base64().unwrap()).unwrap(),
+            proof
+        );
+        assert!(Proof::from_hex("0x00").is_err());
+    }
+
+    #[test]
+    fn verify_limits_depth() {
+        let key = sha3(b"key");
+
+        // A lone leaf at the root has no siblings
+        let mut proof = Proof {
+            proof_type: ProofType::Exists,
+            value: Some(b"value".to_vec()),
+            ..Default::default()
+        };
+        let root = sha3_value_salted(None, key, b"value");
+        assert_eq!(proof.clone().verify(root, key, 256).unwrap(), b"value");
+
+        for _ in 0..257 {
+            proof.push(sha3(b"sibling"));
+        }
+        assert_eq!(proof.clone().verify(root, key, 256), Err("Proof too deep"));
+        assert!(proof.verify(root, key, 1024).is_err());
+    }
+
+    #[test]
+    fn verifies_bytes() {
+        let key = sha3(b"key");
+        let root = sha3_internal(sha3_value_salted(None, key, b"value"), sha3(b"sibling"));
+        let mut proof = Proof {
+            proof_type: ProofType::Exists,
+            value: Some(b"value".to_vec()),
+            ..Default::default()
+        };
+        proof.push(sha3(b"sibling"));
+        let bits = proof.encode().unwrap();
+
+        assert_eq!(
+            verify_bytes(&root.0, &key.0, &bits, 256).unwrap(),
+            Some(b"value".to_vec())
+        );
+        match verify_bytes(&root.0[..31], &key.0, &bits, 256) {
+            Err(VerifyError::BadRoot) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        match verify_bytes(&root.0, b"key", &bits, 256) {
+            Err(VerifyError::BadKey) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        match verify_bytes(&root.0, &key.0, &bits[1..], 256) {
+            Err(VerifyError::Decode(_)) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        match verify_bytes(&key.0, &key.0, &bits, 256) {
+            Err(VerifyError::Invalid("Head Mismatch")) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        // A 160 bit key, with or without its padding
+        let mut key = sha3(b"key");
+        key.0[20..].copy_from_slice(&[0; 12]);
+        let root = sha3_internal(sha3_value_salted(None, key, b"value"), sha3(b"sibling"));
+        for narrow in &[&key.0[..20], &key.0[..]] {
+            assert_eq!(
+                verify_bytes(&root.0, narrow, &bits, 160).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+        match verify_bytes(&root.0, &key.0[..20], &bits, 256) {
+            Err(VerifyError::BadKey) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        // Wider than a digest, even when the width says so
+        match verify_bytes(&[0; 32], &[0; 40], &[0, 0, 0], 320) {
+            Err(VerifyError::BadKey) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verifies_from_a_reader() {
+        let key = sha3(b"key");
+        let root = sha3_internal(sha3_value_salted(None, key, b"value"), sha3(b"sibling"));
+        let mut proof = Proof {
+            proof_type: ProofType::Exists,
+            value: Some(b"value".to_vec()),
+            ..Default::default()
+        };
+        proof.push(sha3(b"sibling"));
+        let mut bits = proof.encode().unwrap();
+        bits.extend_from_slice(b"next");
+
+        let mut rdr = &bits[..];
+        let mut value = vec![];
+        assert_eq!(
+            verify_reader(&mut rdr, root, key, 256, &mut value).unwrap(),
+            ProofType::Exists
+        );
+        assert_eq!(value, b"value");
+        assert_eq!(rdr, b"next");
+
+        // A truncated stream or the wrong root fails
+        let len = bits.len() - 5;
+        match verify_reader(&mut &bits[..len], root, key, 256, &mut vec![]) {
+            Err(VerifyError::Decode(Error::Decode("Truncated proof"))) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        match verify_reader(&mut &bits[..], key, key, 256, &mut vec![]) {
+            Err(VerifyError::Invalid("Head Mismatch")) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verifies_with_the_tagged_hasher() {
+        // A hasher whose internal nodes hash in the other order
+        struct Swapped;
+        impl Hasher for Swapped {
+            fn id(&self) -> u8 {
+                0xfe
+            }
+            fn hash(&self, data: &[u8]) -> Digest {
+                sha3(data)
+            }
+            fn hash_leaf(&self, salt: Option<&[u8; 32]>, key: Digest, value: &[u8]) -> Digest {
+                sha3_leaf_salted(salt, key, value)
+            }
+            fn hash_internal(&self, left: Digest, right: Digest) -> Digest {
+                sha3_internal(right, left)
             }
         }
 
-        if next != root_hash {
-            Err("Head Mismatch")
-        } else {
-            self.value.take().ok_or("Bad Verification")
+        let key = sha3(b"key");
+        let leaf = sha3_value_salted(None, key, b"value");
+        let mut proof = exists_proof();
+        proof.set_node_hashes(&[sha3(b"sibling")]);
+        let bits = proof.encode().unwrap();
+
+        let root = sha3_internal(leaf, sha3(b"sibling"));
+        let swapped = sha3_internal(sha3(b"sibling"), leaf);
+        assert!(proof.check_with(&Swapped, swapped, key, 256, None).is_ok());
+        assert!(proof.check_with(&Swapped, root, key, 256, None).is_err());
+        assert!(verify_bytes_with(&Swapped, &swapped.0, &key.0, &bits, 256).is_ok());
+
+        let tagged = tag_proof(&Sha3, &bits);
+        assert_eq!(
+            verify_tagged(&root.0, &key.0, &tagged, 256).unwrap(),
+            Some(b"value".to_vec())
+        );
+        match verify_tagged(&swapped.0, &key.0, &tag_proof(&Swapped, &bits), 256) {
+            Err(VerifyError::UnknownHasher(0xfe)) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_rejects_malformed_input() {
+        let bits = exists_proof().encode().unwrap();
+
+        // Every truncation, and trailing garbage
+        for len in 0..bits.len() {
+            assert!(Proof::decode(&bits[..len]).is_err());
+        }
+        let mut long = bits.clone();
+        long.push(0);
+        assert!(Proof::decode(&long).is_err());
+
+        // A huge claimed depth fails before allocating
+        assert!(Proof::decode(&[0, 0xff, 0xff]).is_err());
+        let mut deep = vec![2, 0x01, 0x01];
+        deep.extend(vec![0; 257 * 32]);
+        assert!(Proof::decode(&deep).is_err());
+
+        assert!(Proof::decode(&[9, 0, 0]).is_err());
+
+        // Arbitrary bytes never panic
+        let mut seed = sha3(b"seed");
+        for _ in 0..1000 {
+            seed = sha3(&seed.0);
+            let len = seed.0[0] as usize % 32;
+            let _ = Proof::decode(&seed.0[..len]);
         }
     }
 }
@@ -0,0 +1,230 @@
+//! External meta file
+//!
+//! With `StoreOptions::external_meta` set, commits append their meta
+//! records to a `roots` file rather than to the data files, which then
+//! hold only nodes and values.  The file starts with two header slots,
+//! written alternately, each naming the latest record with a sequence
+//! number and checksum.  Opening takes the valid slot with the higher
+//! sequence, so a torn header write falls back to the commit before.
+//!
+//! Records in the file are addressed with the reserved file index
+//! `EXTERNAL_META_INDEX`, so they chain to each other, and to records in
+//! the data files written before the store switched over, as usual.
+use super::Result;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use format::Format;
+use hashutils::checksum;
+use metadata::MetaEntry;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use store::set_mode;
+
+pub const ROOTS_FILE_NAME: &str = "roots";
+/// File index of records in the `roots` file
+pub const EXTERNAL_META_INDEX: u16 = u16::MAX;
+const SLOT_MAGIC: u32 = 0x7274_6b6c;
+const SLOT_SIZE: usize = 36; // 4 + 8 + 4 + 20
+const CHECKSUM_SIZE: usize = 20;
+// Records start after both slots
+const RECORDS_START: u64 = 2 * SLOT_SIZE as u64;
+
+// A header slot, checksummed with the store's meta key
+fn encode_slot(sequence: u64, pos: u32, key: [u8; 32]) -> Vec<u8> {
+    let mut wtr = Vec::with_capacity(SLOT_SIZE);
+    wtr.write_u32::<LittleEndian>(SLOT_MAGIC).unwrap();
+    wtr.write_u64::<LittleEndian>(sequence).unwrap();
+    wtr.write_u32::<LittleEndian>(pos).unwrap();
+    let sum = checksum(&wtr, key);
+    wtr.extend_from_slice(&sum[..CHECKSUM_SIZE]);
+    wtr
+}
+
+// Sequence and record position of a valid slot
+fn decode_slot(bits: &[u8], key: [u8; 32]) -> Option<(u64, u32)> {
+    let body = &bits[..SLOT_SIZE - CHECKSUM_SIZE];
+    if LittleEndian::read_u32(body) != SLOT_MAGIC
+        || checksum(body, key)[..CHECKSUM_SIZE] != bits[SLOT_SIZE - CHECKSUM_SIZE..]
+    {
+        return None;
+    }
+    Some((
+        LittleEndian::read_u64(&body[4..]),
+        LittleEndian::read_u32(&body[12..]),
+    ))
+}
+
+/// The `roots` file of an open store
+pub struct RootsFile {
+    file: File,
+    /// Sequence of the latest slot written
+    sequence: u64,
+    /// Where the next record goes
+    end: u64,
+}
+
+impl RootsFile {
+    /// Open the file in `dir`, creating it if needed.  Also returns the
+    /// latest meta, if there is one.
+    pub fn open(
+        dir: &Path,
+        key: [u8; 32],
+        format: Format,
+        mode: u32,
+    ) -> Result<(RootsFile, Option<MetaEntry>)> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true);
+        set_mode(&mut options, mode);
+        let mut file = options.open(dir.join(ROOTS_FILE_NAME))?;
+
+        let len = file.metadata()?.len();
+        if len < RECORDS_START {
+            // New, or torn while being created
+            file.set_len(0)?;
+            file.write_all(&[0; 2 * SLOT_SIZE])?;
+            let roots = RootsFile {
+                file,
+                sequence: 0,
+                end: RECORDS_START,
+            };
+            return Ok((roots, None));
+        }
+
+        let mut slots = [0; 2 * SLOT_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut slots)?;
+        let head = slots
+            .chunks_exact(SLOT_SIZE)
+            .filter_map(|slot| decode_slot(slot, key))
+            .max_by_key(|(sequence, _)| *sequence);
+
+        // Anything after the latest record is from an interrupted commit
+        let (sequence, latest) = match head {
+            Some((sequence, pos)) => {
+                let mut bits = vec![0; format.meta_size()];
+                file.seek(SeekFrom::Start(pos as u64))?;
+                file.read_exact(&mut bits)?;
+                let mut meta = MetaEntry::decode(&bits, key, format)?;
+                meta.meta_index = EXTERNAL_META_INDEX;
+                meta.meta_pos = pos;
+                (sequence, Some(meta))
+            }
+            None => (0, None),
+        };
+        let end = latest.as_ref().map_or(RECORDS_START, |m| {
+            (m.meta_pos as usize + format.meta_size()) as u64
+        });
+        let roots = RootsFile {
+            file,
+            sequence,
+            end,
+        };
+        Ok((roots, latest))
+    }
+
+    /// Append `state`, chained to the record its `meta_index`/`meta_pos`
+    /// point at, and make it the latest.  Returns its position.
+    pub fn append(&mut self, state: &MetaEntry, key: [u8; 32], format: Format) -> Result<u32> {
+        let pos = self.end as u32;
+        // Records are aligned already, so drop the padding
+        let encoded = state.encode(pos, key, format)?;
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file
+            .write_all(&encoded[encoded.len() - format.meta_size()..])?;
+
+        let sequence = self.sequence + 1;
+        let slot = (sequence % 2) * SLOT_SIZE as u64;
+        self.file.seek(SeekFrom::Start(slot))?;
+        self.file.write_all(&encode_slot(sequence, pos, key))?;
+
+        self.sequence = sequence;
+        self.end += format.meta_size() as u64;
+        Ok(pos)
+    }
+
+    /// Read the record at `pos`
+    pub fn read(&mut self, pos: u32, key: [u8; 32], format: Format) -> Result<MetaEntry> {
+        let mut bits = vec![0; format.meta_size()];
+        self.file.seek(SeekFrom::Start(pos as u64))?;
+        self.file.read_exact(&mut bits)?;
+        MetaEntry::decode(&bits, key, format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use test_dir;
+
+    #[test]
+    fn torn_slot_falls_back() {
+        let dir = test_dir("torn_slot_falls_back");
+        fs::create_dir_all(&dir).unwrap();
+        let dir = Path::new(&dir);
+        let key = [7; 32];
+        let format = Format::default();
+        let meta = |root_pos| MetaEntry {
+            root_index: 1,
+            root_pos,
+            ..Default::default()
+        };
+        {
+            let (mut roots, latest) = RootsFile::open(dir, key, format, 0o600).unwrap();
+            assert!(latest.is_none());
+            roots.append(&meta(10), key, format).unwrap();
+            roots.append(&meta(20), key, format).unwrap();
+        }
+
+        let (_, latest) = RootsFile::open(dir, key, format, 0o600).unwrap();
+        assert_eq!(latest.unwrap().root_pos, 20);
+
+        // Break the slot the second append wrote
+        let path = dir.join(ROOTS_FILE_NAME);
+        let mut bits = fs::read(&path).unwrap();
+        bits[0] ^= 1;
+        fs::write(&path, &bits).unwrap();
+        let (mut roots, latest) = RootsFile::open(dir, key, format, 0o600).unwrap();
+        let latest = latest.unwrap();
+        assert_eq!(latest.root_pos, 10);
+
+        // The next append goes over the abandoned record
+        let pos = roots.append(&meta(30), key, format).unwrap();
+        assert_eq!(pos as usize, latest.meta_pos as usize + format.meta_size());
+    }
+
+    #[test]
+    fn meta_stays_out_of_data_files() {
+        use hashutils::sha3;
+        use store::StoreOptions;
+        use tree::UrkelTree;
+
+        let dir = test_dir("meta_stays_out_of_data_files");
+        let mut roots = vec![];
+        {
+            let options = StoreOptions {
+                external_meta: true,
+                ..Default::default()
+            };
+            let mut t = UrkelTree::open_with_options(&dir, options).unwrap();
+            for i in 0..3u8 {
+                t.insert_owned(sha3(&[i]), vec![i; 8]).unwrap();
+                t.commit().unwrap();
+                roots.push(t.get_root());
+            }
+        }
+
+        // Nothing but nodes and values: 3 leaves with their values, and
+        // the internal nodes written over them
+        let data = fs::read(Path::new(&dir).join("0000000001")).unwrap();
+        assert_eq!((data.len() - 3 * (41 + 8)) % 76, 0);
+
+        // The file is used without asking once it's there
+        let mut t = UrkelTree::open(&dir);
+        assert_eq!(t.get_root(), roots[2]);
+        t.revert_to_previous_commit().unwrap();
+        assert_eq!(t.get_root(), roots[1]);
+        drop(t);
+        assert_eq!(UrkelTree::open(&dir).get_root(), roots[1]);
+    }
+}
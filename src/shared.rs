@@ -0,0 +1,129 @@
+//! Handles sharing one tree across threads
+//!
+//! Every `SharedTree` cloned from another works on the same tree, staging
+//! writes into the one working tree.  Each handle picks its own
+//! `Isolation`: a `Staged` handle reads those staged writes, its own and
+//! other handles', while a `Committed` handle reads through
+//! `UrkelTree::committed` and only ever sees the latest commit.
+use super::Result;
+use hashutils::Digest;
+use proof::Proof;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tree::UrkelTree;
+
+/// What a handle's reads observe
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Isolation {
+    /// Writes staged since the last commit (read-your-writes)
+    #[default]
+    Staged,
+    /// Only the latest commit
+    Committed,
+}
+
+/// A handle on a tree shared across threads
+#[derive(Clone)]
+pub struct SharedTree {
+    tree: Arc<Mutex<UrkelTree>>,
+    isolation: Isolation,
+}
+
+impl SharedTree {
+    /// Share `tree`, returning a `Staged` handle
+    pub fn new(tree: UrkelTree) -> Self {
+        SharedTree {
+            tree: Arc::new(Mutex::new(tree)),
+            isolation: Isolation::Staged,
+        }
+    }
+
+    /// Another handle on the same tree, reading with `isolation`
+    pub fn with_isolation(&self, isolation: Isolation) -> Self {
+        SharedTree {
+            tree: Arc::clone(&self.tree),
+            isolation,
+        }
+    }
+
+    pub fn isolation(&self) -> Isolation {
+        self.isolation
+    }
+
+    /// Lock the tree for anything the handle doesn't cover.  Reads made
+    /// through the guard see staged writes whatever the handle's isolation.
+    pub fn lock(&self) -> MutexGuard<'_, UrkelTree> {
+        self.tree.lock().unwrap()
+    }
+
+    /// The root this handle reads from
+    pub fn root(&self) -> Result<Digest> {
+        let mut tree = self.lock();
+        match self.isolation {
+            Isolation::Staged => Ok(tree.get_root()),
+            Isolation::Committed => Ok(tree.committed()?.root()),
+        }
+    }
+
+    /// The value for `key` as this handle sees it
+    pub fn get(&self, key: Digest) -> Result<Option<Vec<u8>>> {
+        let mut tree = self.lock();
+        match self.isolation {
+            Isolation::Staged => tree.get(key),
+            Isolation::Committed => tree.committed()?.get(key),
+        }
+    }
+
+    /// Prove `key` against the root this handle reads from
+    pub fn prove(&self, key: Digest) -> Result<Proof> {
+        let mut tree = self.lock();
+        match self.isolation {
+            Isolation::Staged => tree.prove(key),
+            Isolation::Committed => tree.committed()?.prove(key),
+        }
+    }
+
+    /// Stage `value` for `key`
+    pub fn insert(&self, key: Digest, value: Vec<u8>) -> Result<()> {
+        self.lock().insert_owned(key, value)
+    }
+
+    /// Stage the removal of `key`
+    pub fn remove(&self, key: Digest) -> Result<bool> {
+        self.lock().remove(key)
+    }
+
+    /// Commit everything staged through any handle
+    pub fn commit(&self) -> Result<()> {
+        self.lock().commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashutils::sha3;
+    use std::thread;
+    use test_dir;
+
+    #[test]
+    fn handles_choose_their_isolation() {
+        let key = sha3(b"name-1");
+        let writer = SharedTree::new(UrkelTree::open(test_dir("handles_choose_their_isolation")));
+        let reader = writer.with_isolation(Isolation::Committed);
+
+        let staging = writer.clone();
+        thread::spawn(move || staging.insert(key, b"value-1".to_vec()))
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(writer.get(key).unwrap(), Some(b"value-1".to_vec()));
+        assert_eq!(reader.get(key).unwrap(), None);
+        assert_ne!(writer.root().unwrap(), reader.root().unwrap());
+
+        let committed = reader.clone();
+        writer.commit().unwrap();
+        let found = thread::spawn(move || committed.get(key)).join().unwrap();
+        assert_eq!(found.unwrap(), Some(b"value-1".to_vec()));
+        assert_eq!(writer.root().unwrap(), reader.root().unwrap());
+    }
+}
@@ -0,0 +1,142 @@
+//! Signed commits
+//!
+//! When the store is given a signing key, every meta record it writes is
+//! accompanied by an ed25519 signature over the commit's root, sequence
+//! number and timestamp.  Signatures are appended to a `signatures` file
+//! next to the data files, keyed by the location of the meta record.
+use super::Result;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use errors::Error;
+use hashutils::Digest;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::Path;
+use store::open_append;
+
+pub const SIGNATURE_FILE_NAME: &str = "signatures";
+const SIGNED_COMMIT_SIZE: usize = 118; // 2 + 4 + 8 + 8 + 32 + 64
+
+/// A signature over a single commit's meta record
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignedCommit {
+    pub meta_index: u16,
+    pub meta_pos: u32,
+    pub sequence: u64,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub root: Digest,
+    pub signature: [u8; 64],
+}
+
+impl SignedCommit {
+    pub fn sign(
+        key: &SigningKey,
+        meta_index: u16,
+        meta_pos: u32,
+        sequence: u64,
+        timestamp: u64,
+        root: Digest,
+    ) -> Self {
+        let mut commit = SignedCommit {
+            meta_index,
+            meta_pos,
+            sequence,
+            timestamp,
+            root,
+            signature: [0; 64],
+        };
+        commit.signature = key.sign(&commit.message()).to_bytes();
+        commit
+    }
+
+    /// Check the signature was made by `key`
+    pub fn verify(&self, key: &VerifyingKey) -> Result<()> {
+        key.verify(&self.message(), &Signature::from_bytes(&self.signature))
+            .map_err(|_| Error::Signature("Invalid commit signature"))
+    }
+
+    // The signed preimage: everything but the signature
+    fn message(&self) -> Vec<u8> {
+        let mut wtr = self.encode();
+        wtr.truncate(SIGNED_COMMIT_SIZE - 64);
+        wtr
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut wtr = Vec::with_capacity(SIGNED_COMMIT_SIZE);
+        // Writes to a Vec can't fail
+        wtr.write_u16::<LittleEndian>(self.meta_index).unwrap();
+        wtr.write_u32::<LittleEndian>(self.meta_pos).unwrap();
+        wtr.write_u64::<LittleEndian>(self.sequence).unwrap();
+        wtr.write_u64::<LittleEndian>(self.timestamp).unwrap();
+        wtr.extend_from_slice(&self.root.0);
+        wtr.extend_from_slice(&self.signature);
+        wtr
+    }
+
+    pub fn decode(bits: &[u8]) -> Result<SignedCommit> {
+        if bits.len() != SIGNED_COMMIT_SIZE {
+            return Err(Error::Decode("Signed commit has the wrong size"));
+        }
+        let mut signature = [0u8; 64];
+        signature.copy_from_slice(&bits[54..]);
+        Ok(SignedCommit {
+            meta_index: LittleEndian::read_u16(&bits[0..]),
+            meta_pos: LittleEndian::read_u32(&bits[2..]),
+            sequence: LittleEndian::read_u64(&bits[6..]),
+            timestamp: LittleEndian::read_u64(&bits[14..]),
+            root: Digest::from(&bits[22..54]),
+            signature,
+        })
+    }
+}
+
+/// Append a signed commit to the signature file in `dir`
+pub fn append_signed_commit(dir: &Path, commit: &SignedCommit, mode: u32) -> Result<()> {
+    open_append(&dir.join(SIGNATURE_FILE_NAME), mode)
+        .and_then(|mut f| f.write_all(&commit.encode()))?;
+    Ok(())
+}
+
+/// Load every signed commit in `dir`, oldest first
+pub fn load_signed_commits(dir: &Path) -> Result<Vec<SignedCommit>> {
+    let path = dir.join(SIGNATURE_FILE_NAME);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut bits = vec![];
+    OpenOptions::new()
+        .read(true)
+        .open(path)?
+        .read_to_end(&mut bits)?;
+
+    // Ignore a torn trailing record from an interrupted append
+    bits.chunks_exact(SIGNED_COMMIT_SIZE)
+        .map(SignedCommit::decode)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashutils::sha3;
+
+    #[test]
+    fn sign_encode_verify() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let commit = SignedCommit::sign(&key, 1, 72, 3, 1_500_000_000, sha3(b"root"));
+
+        let back = SignedCommit::decode(&commit.encode()).unwrap();
+        assert_eq!(back, commit);
+        assert!(back.verify(&key.verifying_key()).is_ok());
+
+        let other = SigningKey::from_bytes(&[8u8; 32]);
+        assert!(back.verify(&other.verifying_key()).is_err());
+
+        let mut tampered = back.clone();
+        tampered.sequence = 4;
+        assert!(tampered.verify(&key.verifying_key()).is_err());
+    }
+}
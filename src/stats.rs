@@ -0,0 +1,99 @@
+//! Storage statistics
+//!
+//! Write counters cover the lifetime of the open tree.  Space usage is
+//! measured by walking everything reachable from the latest commit.
+
+/// Bytes handed to the tree versus bytes appended to the data files
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteStats {
+    /// Key and value bytes passed to `insert`
+    pub logical_bytes: u64,
+    /// Bytes appended to the data files (nodes, values and meta records)
+    pub physical_bytes: u64,
+}
+
+impl WriteStats {
+    /// Physical bytes written per logical byte inserted
+    pub fn amplification(&self) -> f64 {
+        if self.logical_bytes == 0 {
+            return 0.0;
+        }
+        self.physical_bytes as f64 / self.logical_bytes as f64
+    }
+}
+
+/// Raw I/O counters of an open store, kept whether or not the `metrics`
+/// feature is on
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IoStats {
+    pub nodes_written: u64,
+    pub values_written: u64,
+    /// Bytes appended to the data files, as in `WriteStats::physical_bytes`
+    pub bytes_appended: u64,
+    /// Reads of the data files (or `StoreOptions::reader`), not counting
+    /// those answered by the block cache or a coalesced window
+    pub reads: u64,
+    /// Lookups in `StoreOptions::cache`
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub commits: u64,
+}
+
+/// Tree shape estimated from sampled descents (see
+/// `UrkelTree::estimate_stats`)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EstimatedStats {
+    /// Descents that reached a leaf
+    pub samples: usize,
+    /// Estimated number of leaves
+    pub entries: f64,
+    /// Estimated mean depth of a leaf
+    pub average_depth: f64,
+    /// Estimated mean value size in bytes
+    pub average_value_size: f64,
+}
+
+/// Space usage of a single data file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileSpace {
+    pub index: u16,
+    /// Size of the file on disk
+    pub size: u64,
+    /// Bytes reachable from the latest commit
+    pub live_bytes: u64,
+}
+
+impl FileSpace {
+    /// Bytes no longer reachable from the latest commit
+    pub fn dead_bytes(&self) -> u64 {
+        self.size.saturating_sub(self.live_bytes)
+    }
+
+    /// Fraction of the file no longer reachable, for deciding which files
+    /// are worth compacting
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.size == 0 {
+            return 0.0;
+        }
+        self.dead_bytes() as f64 / self.size as f64
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub write: WriteStats,
+    /// Every data file, oldest first
+    pub files: Vec<FileSpace>,
+}
+
+impl Stats {
+    /// Total size on disk per live byte
+    pub fn space_amplification(&self) -> f64 {
+        let live: u64 = self.files.iter().map(|f| f.live_bytes).sum();
+        if live == 0 {
+            return 0.0;
+        }
+        let size: u64 = self.files.iter().map(|f| f.size).sum();
+        size as f64 / live as f64
+    }
+}
@@ -1,103 +1,649 @@
 use super::Result;
-use metadata::{recover_meta, MetaEntry};
-use nodes::{Node, INTERNAL_NODE_SIZE, LEAF_NODE_SIZE};
+use archive::{self, ArchiveEntry};
+use backend::{FileReader, RangeReader};
+use cache::BlockCache;
+#[cfg(feature = "signing")]
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use errors::{is_transient_io, Error, RecordType};
+use format::{Endianness, Format, LEGACY_FORMAT, MAX_KEY_SIZE};
+use hashcache::{append_root_hash, load_root_hashes};
+use hashutils::{hash_id, sha3, sha3_value_salted, Digest};
+use health::ScanLimits;
+use hooks::{Operation, SlowHook};
+use manifest::{drop_seals, load_seals, seal_file, verify_seal};
+use metadata::{encode_footer, meta_at, read_footer, recover_latest, recover_meta, MetaEntry};
+use nodes::{Node, INTERNAL_NODE_SIZE};
+#[cfg(feature = "rng")]
 use rand::{thread_rng, Rng};
+use roots::{RootsFile, EXTERNAL_META_INDEX, ROOTS_FILE_NAME};
+#[cfg(feature = "signing")]
+use signing::{append_signed_commit, load_signed_commits, SignedCommit};
+use stats::{FileSpace, IoStats};
+use std::collections::HashMap;
 use std::fs;
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::mem;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(feature = "signing")]
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub const KEY_SIZE: usize = 32;
 const MAX_FILE_SIZE: usize = 0x7fff_f000; // 2gb
 
 const DEFAULT_BUFFER_SIZE: usize = 1024 * 8;
-const LOCK_FILE_NAME: &str = "urkel.lock";
+const MAX_VALUE_SIZE: usize = 0xffff;
+pub const DEFAULT_FILE_MODE: u32 = 0o600;
+pub const LOCK_FILE_NAME: &str = "lock";
+const META_KEY_FILE_NAME: &str = "meta";
+pub const VERSION_FILE_NAME: &str = "version";
+// Bytes read at once while coalescing.  Children are written before
+// their parents, so the window ends at the requested record.
+const READ_WINDOW: u32 = 1024 * 16;
+
+/// How a commit lays out the records it writes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WriteLayout {
+    /// Each leaf's value is written just before the leaf
+    #[default]
+    Interleaved,
+    /// All of a commit's values are written first, then all its nodes,
+    /// keeping the nodes of a path close together
+    Segregated,
+}
+
+/// Which committed roots the store keeps readable.  Data files no kept
+/// root reaches are deleted as commits are made.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RootRetention {
+    /// Keep every root, deleting nothing
+    #[default]
+    All,
+    /// Keep the latest n roots
+    LastN(u32),
+    /// Keep only the latest root
+    LatestOnly,
+}
+
+impl RootRetention {
+    /// Roots kept, or None for all of them
+    pub fn roots(&self) -> Option<usize> {
+        match self {
+            RootRetention::All => None,
+            RootRetention::LastN(n) => Some((*n as usize).max(1)),
+            RootRetention::LatestOnly => Some(1),
+        }
+    }
+}
+
+/// Whether opening a store may create it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Open the store, creating it if there's none
+    OpenOrCreate,
+    /// Fail with `NotFound` if there's no store
+    OpenExisting,
+    /// Fail with `AlreadyExists` if there's a store
+    CreateNew,
+}
+
+/// Check `dir` holds a store, or doesn't, as `mode` requires.  A store is
+/// there once its meta key has been written.
+pub fn check_open_mode(dir: &Path, mode: OpenMode) -> Result<()> {
+    let exists = dir.join(META_KEY_FILE_NAME).is_file();
+    match mode {
+        OpenMode::OpenExisting if !exists => Err(Error::NotFound),
+        OpenMode::CreateNew if exists => Err(Error::AlreadyExists),
+        _ => Ok(()),
+    }
+}
+
+/// Makes the meta key of a new store, in place of the thread RNG
+pub trait KeySource: Send + Sync {
+    fn new_key(&self) -> [u8; 32];
+}
+
+impl<F> KeySource for F
+where
+    F: Fn() -> [u8; 32] + Send + Sync,
+{
+    fn new_key(&self) -> [u8; 32] {
+        self()
+    }
+}
+
+/// Options used when opening a store
+#[derive(Clone, Default)]
+pub struct StoreOptions {
+    /// Remember regions found to be corrupt and fail reads of them
+    /// without going back to disk
+    pub quarantine: bool,
+    /// Times to retry a read that fails with a transient I/O error
+    pub read_retries: u32,
+    /// Wait before the first retry, doubling for each one after
+    pub read_retry_backoff: Duration,
+    /// Keep the hash of every committed root in a `hashes` file so
+    /// historical roots can be answered without reading the tree
+    pub hash_cache: bool,
+    /// Largest value the tree accepts.  Values are stored with a u16
+    /// size, so anything above 65535 (or None) means 65535.
+    pub max_value_size: Option<usize>,
+    /// Salt mixed into every leaf hash, separating this tree's leaves
+    /// from other trees'.  Must be the same every time the tree is opened.
+    pub leaf_salt: Option<[u8; 32]>,
+    /// Start a new data file once the current one would grow past this
+    /// many bytes.  None, or anything larger, means 2GB.
+    pub max_file_size: Option<usize>,
+    /// Seal each data file as it fills (see `manifest`) and check the
+    /// sealed files when opening
+    pub seal_files: bool,
+    /// Check every node read from disk hashes to what its parent
+    /// recorded.  Slow, as leaves need their values read too.
+    pub audit: bool,
+    /// Match leaf keys with a constant time comparison, for trees keyed
+    /// by digests of secrets
+    pub constant_time_keys: bool,
+    /// Permissions for the files and directories the store creates, on
+    /// Unix.  Defaults to owner only (0600, and 0700 for directories).
+    pub file_mode: Option<u32>,
+    /// Hold an exclusive lock on the directory while open, so a second
+    /// store can't append to the same files
+    pub lock: bool,
+    /// Most nodes the tree may hold in memory between commits.  Inserts
+    /// fail with `OutOfBudget` once it's reached, so one insert may go
+    /// over by the length of its path.  Bound the block cache and the
+    /// memory it uses through `BlockCache::new`.
+    pub max_dirty_nodes: Option<usize>,
+    /// Read nodes and values through this rather than the data files
+    /// in the store's directory
+    pub reader: Option<Arc<dyn RangeReader + Send + Sync>>,
+    /// Layout of the records each commit writes
+    pub layout: WriteLayout,
+    /// Cache of records read from disk.  Trees opened on the same
+    /// directory can share one.
+    pub cache: Option<Arc<BlockCache>>,
+    /// Keep `UrkelKv`'s raw keys sorted in memory, so prefix scans don't
+    /// have to look at every key
+    pub key_index: bool,
+    /// Index keys by the hash of their value (see `UrkelTree::keys_with_value`)
+    pub value_index: bool,
+    /// Keep a running count of the tree's leaves, so `UrkelTree::leaf_count`
+    /// and `sized_root` don't have to walk the tree.  Stores from format
+    /// version 4 record the count with each commit and always keep it.
+    pub count_leaves: bool,
+    /// Committed roots to keep.  After each commit, data files only older
+    /// roots reach are deleted, and the history ends at the oldest root
+    /// kept, so saved versions of older roots may no longer load.
+    pub retention: RootRetention,
+    /// Overwrite data files with zeros and sync them before compaction or
+    /// retention deletes them, so superseded values can't be read back
+    /// from the disk blocks they leave behind
+    pub scrub_deleted: bool,
+    /// Check the store's integrity when the tree is opened, within these
+    /// limits, and keep the result (see `UrkelTree::health_report`)
+    pub startup_scan: Option<ScanLimits>,
+    /// Byte order of the node and meta records of a new store.  An
+    /// existing store keeps the order it was created with.
+    pub endianness: Endianness,
+    /// Width in bytes of a new store's keys, 32 if unset.  Keys must be
+    /// zero past it.  Opening an existing store of a different width fails.
+    pub key_size: Option<u8>,
+    /// During a commit, flush what's been written and append a checkpoint
+    /// every this many bytes.  A tree opened after a crash part way
+    /// through a commit reuses the subtrees the checkpoint lists rather
+    /// than writing them again.
+    pub checkpoint_bytes: Option<usize>,
+    /// Write each commit's meta record to a `roots` file rather than the
+    /// data files (see `roots`).  Once a store has the file it keeps
+    /// using it.
+    pub external_meta: bool,
+    /// The key checksumming meta records.  A store created with it only
+    /// keeps its hash, so it must be given every time the store opens.
+    pub meta_key: Option<[u8; 32]>,
+    /// Makes the meta key of a new store, when one isn't given
+    pub key_source: Option<Arc<dyn KeySource>>,
+    /// Sign the meta record of every commit with this key
+    #[cfg(feature = "signing")]
+    pub signing_key: Option<SigningKey>,
+    /// Require the latest commit to be signed by this key when opening
+    #[cfg(feature = "signing")]
+    pub verifying_key: Option<VerifyingKey>,
+}
+
+// A run of bytes read from a data file
+struct ReadWindow {
+    index: u16,
+    start: u32,
+    bytes: Vec<u8>,
+}
+
+impl ReadWindow {
+    fn get(&self, index: u16, pos: u32, size: usize) -> Option<Vec<u8>> {
+        if index != self.index || pos < self.start {
+            return None;
+        }
+        let offset = (pos - self.start) as usize;
+        self.bytes.get(offset..offset + size).map(|b| b.to_vec())
+    }
+}
+
+/// A record found to be corrupt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuarantinedRegion {
+    pub index: u16,
+    pub pos: u32,
+    pub record: RecordType,
+}
 
-// To add:
-// currentMeta and lastMeta
 pub struct Store {
     buffer: Vec<u8>,
     index: u16,
     pos: usize,
     dir: PathBuf,
-    files: Vec<StoreFile>,
     key: [u8; 32],
+    /// How records are encoded, from the version file
+    format: Format,
+    /// Meta of the latest commit
     state: MetaEntry,
+    /// Meta of the commit before `state`
     last_state: MetaEntry,
+    options: StoreOptions,
+    /// Regions found to be corrupt
+    quarantine: Vec<QuarantinedRegion>,
+    /// Callback for slow operations
+    slow_hook: Option<SlowHook>,
+    /// I/O since the store was opened
+    io: IoStats,
+    /// Read whole windows of a file rather than single records
+    coalesce: bool,
+    /// The last window read while coalescing
+    window: Option<ReadWindow>,
+    /// Root hashes by location, when `hash_cache` is on
+    root_hashes: HashMap<(u16, u32), Digest>,
+    /// Buffers for data files that filled up since the last flush
+    full: Vec<(u16, Vec<u8>)>,
+    /// Positions of the meta records known to be in the current data
+    /// file, for its footer
+    metas: Vec<u32>,
+    /// Bytes appended since the last commit or checkpoint
+    since_checkpoint: u64,
+    /// Why the last checkpoint failed, returned by the commit
+    checkpoint_error: Option<Error>,
+    /// Where meta records go, with `external_meta`
+    roots: Option<RootsFile>,
+    /// Held for as long as the store is open, when `lock` is set
+    _lock: Option<File>,
+    /// Source of node and value reads
+    reader: Arc<dyn RangeReader + Send + Sync>,
+    /// Sequence number of the latest signed commit
+    #[cfg(feature = "signing")]
+    sequence: u64,
 }
 
-impl Default for Store {
-    fn default() -> Self {
-        Store::open("./data")
+impl Store {
+    #[cfg(test)]
+    pub fn open<P: AsRef<Path>>(dir: P) -> Self {
+        Store::open_with_options(dir, StoreOptions::default()).expect("Failed to open the store")
     }
-}
 
-impl Store {
-    // Open should seek to the end of the file to get current position
-    pub fn open(dir: &str) -> Self {
-        let path = PathBuf::from(dir);
+    // Open should seek to the end of the file to get current position.
+    // Every file is found relative to `dir` and no path is persisted, so
+    // the directory can be moved between opens.
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, options: StoreOptions) -> Result<Self> {
+        let path = dir.as_ref().to_path_buf();
+        let mode = options.file_mode.unwrap_or(DEFAULT_FILE_MODE);
+        create_dirs(&path, mode)?;
+
+        let lock = if options.lock {
+            Some(lock_dir(&path, mode)?)
+        } else {
+            None
+        };
 
         // Load or create meta key
-        let store_key = load_or_create_meta_key(dir).expect("Can't access meta file!");
-        let logfiles = find_data_files(&path).unwrap();
+        let store_key = load_or_create_meta_key(&path, mode, &options)?;
+        let format = load_format(&path, &options, mode)?;
+        let reader = match options.reader {
+            Some(ref reader) => reader.clone(),
+            None => Arc::new(FileReader::new(path.clone())) as Arc<dyn RangeReader + Send + Sync>,
+        };
+        let logfiles = find_data_files(&path)?;
 
-        if logfiles.is_empty() {
-            // This is a new store
-            Store {
-                buffer: Vec::<u8>::with_capacity(DEFAULT_BUFFER_SIZE),
-                index: 1,
-                pos: 0,
-                dir: path,
-                files: logfiles,
-                key: store_key,
-                state: MetaEntry::default(),
-                last_state: MetaEntry::default(),
-            }
-        } else {
+        let mut store = Store {
+            buffer: Vec::<u8>::with_capacity(DEFAULT_BUFFER_SIZE),
+            index: 1,
+            pos: 0,
+            dir: path,
+            key: store_key,
+            format,
+            state: MetaEntry::default(),
+            last_state: MetaEntry::default(),
+            options,
+            quarantine: vec![],
+            slow_hook: None,
+            io: IoStats::default(),
+            coalesce: false,
+            window: None,
+            root_hashes: HashMap::new(),
+            full: vec![],
+            metas: vec![],
+            since_checkpoint: 0,
+            checkpoint_error: None,
+            roots: None,
+            _lock: lock,
+            reader,
+            #[cfg(feature = "signing")]
+            sequence: 0,
+        };
+
+        let mut latest = None;
+        if store.options.external_meta || store.dir.join(ROOTS_FILE_NAME).exists() {
+            let (roots, state) = RootsFile::open(&store.dir, store_key, format, mode)?;
+            store.roots = Some(roots);
+            latest = state;
+        }
+
+        if !logfiles.is_empty() {
             // Get the latest index, and seek to the end to get the last pos
             let index = logfiles[0].index;
-            let mut f =
-                get_file_handle(&get_data_file_path(&path, index), false).expect("Failed on file");
-            let size = f.seek(SeekFrom::End(0)).unwrap();
+            let mut f = get_file_handle(&get_data_file_path(&store.dir, index))?;
+            store.index = index;
+            store.pos = f.seek(SeekFrom::End(0))? as usize;
 
             // Load the meta
-            let (newstate, oldstate) = load_state(&logfiles, &path, store_key);
+            let state = match latest {
+                Some(state) => state,
+                None => load_state(&logfiles, &store.dir, store_key, format),
+            };
+            store.last_state = store.previous_meta(&state)?;
+            if state.meta_index == index {
+                store.metas.push(state.meta_pos);
+            }
+            store.state = state;
+        }
 
-            Store {
-                buffer: Vec::<u8>::with_capacity(DEFAULT_BUFFER_SIZE),
-                index,
-                pos: size as usize,
-                dir: path,
-                files: logfiles,
-                key: store_key,
-                state: newstate,
-                last_state: oldstate,
+        if store.options.seal_files {
+            for seal in load_seals(&store.dir)? {
+                let path = get_data_file_path(&store.dir, seal.index);
+                verify_seal(&seal, &path, store_key)?;
             }
         }
+
+        if store.options.hash_cache {
+            store.root_hashes = load_root_hashes(&store.dir, store_key)?;
+        }
+
+        #[cfg(feature = "signing")]
+        store.load_signatures()?;
+
+        Ok(store)
     }
 
-    fn write_bytes(&mut self, bits: &[u8]) {
-        // TODO: Check filesize here. Create new index file if we're getting full
-        if self.buffer.len() + bits.len() >= MAX_FILE_SIZE {
-            // Close and flush the current file
-            // Create a new one
-            // update the index number
-            // Update the cursor pos
+    pub fn max_value_size(&self) -> usize {
+        self.options
+            .max_value_size
+            .map_or(MAX_VALUE_SIZE, |max| max.min(MAX_VALUE_SIZE))
+    }
+
+    pub fn leaf_salt(&self) -> Option<[u8; 32]> {
+        self.options.leaf_salt
+    }
+
+    pub fn file_mode(&self) -> u32 {
+        self.options.file_mode.unwrap_or(DEFAULT_FILE_MODE)
+    }
+
+    pub fn max_dirty_nodes(&self) -> Option<usize> {
+        self.options.max_dirty_nodes
+    }
+
+    pub fn constant_time_keys(&self) -> bool {
+        self.options.constant_time_keys
+    }
+
+    pub fn layout(&self) -> WriteLayout {
+        self.options.layout
+    }
+
+    pub fn checkpoint_bytes(&self) -> Option<usize> {
+        self.options.checkpoint_bytes
+    }
+
+    pub fn startup_scan(&self) -> Option<ScanLimits> {
+        self.options.startup_scan
+    }
+
+    /// Meta of the latest commit
+    pub fn state(&self) -> &MetaEntry {
+        &self.state
+    }
+
+    /// Meta of the commit before the latest one
+    pub fn last_state(&self) -> &MetaEntry {
+        &self.last_state
+    }
+
+    /// The meta record at the given location, or the empty tree's for 0/0
+    pub fn meta_at(&mut self, index: u16, pos: u32) -> Result<MetaEntry> {
+        if index == 0 {
+            return Ok(MetaEntry::default());
         }
-        for v in bits {
-            self.buffer.push(*v);
-            self.pos += 1;
+        self.read_meta(index, pos)
+    }
+
+    /// Read the meta record stored at the given location
+    fn read_meta(&mut self, index: u16, pos: u32) -> Result<MetaEntry> {
+        let (key, format) = (self.key, self.format);
+        if index == EXTERNAL_META_INDEX {
+            return match self.roots {
+                Some(ref mut roots) => roots.read(pos, key, format),
+                None => Err(Error::Decode(
+                    "Meta is in a roots file the store doesn't have",
+                )),
+            }
+            .map_err(|e| Error::storage(index, pos, RecordType::Meta, e));
         }
+        let result = self
+            .read(index, pos, format.meta_size(), RecordType::Meta)
+            .and_then(|bits| {
+                MetaEntry::decode(&bits, key, format)
+                    .map_err(|e| Error::storage(index, pos, RecordType::Meta, e))
+            });
+        self.quarantine_on_corruption(result)
+    }
+
+    /// Fire `callback` whenever a read, hash pass or commit takes longer
+    /// than `threshold`
+    pub fn set_slow_hook(&mut self, hook: SlowHook) {
+        self.slow_hook = Some(hook);
+    }
+
+    /// Report how long an operation took to the slow hook, if any
+    pub fn report_elapsed(&self, operation: Operation, started: Instant) {
+        if let Some(ref hook) = self.slow_hook {
+            hook.check(operation, started.elapsed());
+        }
+    }
+
+    /// Regions found to be corrupt so far
+    pub fn quarantined(&self) -> &[QuarantinedRegion] {
+        &self.quarantine
+    }
+
+    /// Forget the quarantined regions, e.g. once they've been repaired
+    pub fn clear_quarantine(&mut self) {
+        self.quarantine.clear();
+    }
+
+    // Record the region of a corrupt record, if we're quarantining
+    fn quarantine_on_corruption<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(Error::Storage {
+            index,
+            pos,
+            record,
+            ref cause,
+        }) = result
+        {
+            let region = QuarantinedRegion { index, pos, record };
+            if self.options.quarantine
+                && cause.is_corruption()
+                && !self.quarantine.contains(&region)
+            {
+                self.quarantine.push(region);
+            }
+        }
+        result
+    }
+
+    /// Load the meta that preceded `meta` in the commit chain, or an empty
+    /// meta if `meta` was the first commit.
+    pub fn previous_meta(&mut self, meta: &MetaEntry) -> Result<MetaEntry> {
+        let mut previous = MetaEntry::default();
+        if let Some(current) = self.read_meta_at(meta)? {
+            if current.has_previous() {
+                previous = match self.read_meta(current.meta_index, current.meta_pos) {
+                    // Retention deleted the file, ending the history here
+                    Err(Error::Storage { ref cause, .. })
+                        if self.options.retention != RootRetention::All && is_not_found(cause) =>
+                    {
+                        return Ok(MetaEntry::default())
+                    }
+                    result => result?,
+                };
+                previous.meta_index = current.meta_index;
+                previous.meta_pos = current.meta_pos;
+            }
+        }
+        Ok(previous)
+    }
+
+    // Read back the on-disk record for an in-memory meta
+    fn read_meta_at(&mut self, meta: &MetaEntry) -> Result<Option<MetaEntry>> {
+        if meta.meta_index == 0 {
+            return Ok(None);
+        }
+        self.read_meta(meta.meta_index, meta.meta_pos).map(Some)
+    }
+
+    /// Resolve the root of the given meta as a hashnode
+    pub fn get_root_node(&mut self, meta: &MetaEntry) -> Result<Node> {
+        if meta.root_index == 0 {
+            return Ok(Node::empty());
+        }
+        if let Some(&hash) = self.root_hashes.get(&(meta.root_index, meta.root_pos)) {
+            return Ok(Node::Hash {
+                index: meta.root_index,
+                pos: meta.root_pos,
+                hash,
+            });
+        }
+        let node = self.resolve(meta.root_index, meta.root_pos, meta.root_leaf)?;
+        let hash = match node {
+            Node::Leaf {
+                key,
+                vindex,
+                vpos,
+                vsize,
+                ..
+            } => {
+                let value = self.retrieve(vindex, vpos, vsize)?;
+                sha3_value_salted(self.options.leaf_salt.as_ref(), key, &value)
+            }
+            _ => node.hash(),
+        };
+        Ok(Node::Hash {
+            index: meta.root_index,
+            pos: meta.root_pos,
+            hash,
+        })
+    }
+
+    // Move on to a new data file if `len` more bytes won't fit in this
+    // one.  The full file's buffer, ending with its footer, is written out
+    // on the next flush.
+    fn make_room(&mut self, len: usize) {
+        let max = self
+            .options
+            .max_file_size
+            .map_or(MAX_FILE_SIZE, |max| max.min(MAX_FILE_SIZE));
+        if self.pos > 0 && self.pos + len > max {
+            if self.roots.is_none() {
+                let metas = mem::take(&mut self.metas);
+                let footer = encode_footer(&metas, self.key, self.format);
+                self.buffer.extend_from_slice(&footer);
+                self.io.bytes_appended += footer.len() as u64;
+            }
+            let buffer = mem::replace(&mut self.buffer, Vec::with_capacity(DEFAULT_BUFFER_SIZE));
+            self.full.push((self.index, buffer));
+            self.index += 1;
+            self.pos = 0;
+        }
+    }
+
+    fn write_bytes(&mut self, bits: &[u8]) {
+        self.buffer.extend_from_slice(bits);
+        self.appended(bits.len());
+    }
+
+    // Account for `len` bytes just added to the buffer
+    fn appended(&mut self, len: usize) {
+        self.pos += len;
+        self.io.bytes_appended += len as u64;
+        self.since_checkpoint += len as u64;
+    }
+
+    /// Reads made since the store was opened
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn reads(&self) -> u64 {
+        self.io.reads
+    }
+
+    /// Bytes appended to the data files since the store was opened
+    pub fn physical_bytes(&self) -> u64 {
+        self.io.bytes_appended
+    }
+
+    /// I/O counters since the store was opened
+    pub fn io_stats(&self) -> IoStats {
+        self.io.clone()
+    }
+
+    /// Size of every data file, oldest first.  Live bytes are left at
+    /// zero for the caller to fill in.
+    pub fn file_space(&self) -> Result<Vec<FileSpace>> {
+        let mut files: Vec<FileSpace> = find_data_files(&self.dir)?
+            .iter()
+            .map(|f| FileSpace {
+                index: f.index,
+                size: f.size,
+                live_bytes: 0,
+            })
+            .collect();
+        files.reverse();
+        Ok(files)
     }
 
     // Write node to buffer and eventually to file.   Note, this needs to mutate the node
     // to update it's position and index
     // Called from tree.write()
     pub fn write_node(&mut self, node: &mut Node) {
+        self.make_room(if node.is_leaf() {
+            self.format.leaf_size()
+        } else {
+            INTERNAL_NODE_SIZE
+        });
         let start_pos = self.pos;
-        let bits = node.encode().expect("Failed to decode node");
+
+        // Encode straight into the buffer
+        let start = self.buffer.len();
+        node.encode_into(&mut self.buffer, self.format)
+            .expect("Failed to encode node");
+        let len = self.buffer.len() - start;
 
         match node {
             Node::Internal {
@@ -119,13 +665,16 @@ impl Store {
             _ => unimplemented!(),
         }
 
-        // Write to buffer
-        self.write_bytes(bits.as_slice());
+        self.appended(len);
+        self.io.nodes_written += 1;
     }
 
     /// Write a Leaf value
     pub fn write_value(&mut self, node: &mut Node) {
         assert!(node.is_leaf());
+        if let Node::Leaf { value: Some(v), .. } = node {
+            self.make_room(v.len());
+        }
         let start_pos = self.pos;
 
         match node {
@@ -135,101 +684,605 @@ impl Store {
                 ref mut vindex,
                 ref mut vsize,
                 ..
-            } => value.map(|v| {
-                let size = v.len();
-                *vpos = start_pos as u32;
-                *vindex = self.index;
-                *vsize = size as u16;
-                self.write_bytes(v);
-            }),
+            } => {
+                if let Some(v) = value {
+                    *vpos = start_pos as u32;
+                    *vindex = self.index;
+                    *vsize = v.len() as u16;
+                    self.write_bytes(v);
+                    self.io.values_written += 1;
+                }
+            }
             _ => unimplemented!(),
         };
     }
 
-    // Read from file
-    fn read(&mut self, index: u16, pos: u32, size: usize) -> Result<Vec<u8>> {
-        let mut f = get_file_handle(&get_data_file_path(&self.dir, index), false)?;
+    // Read from file.  Failures carry the location and what we expected there.
+    fn read(&mut self, index: u16, pos: u32, size: usize, record: RecordType) -> Result<Vec<u8>> {
+        if self.options.quarantine
+            && self
+                .quarantine
+                .iter()
+                .any(|r| r.index == index && r.pos == pos)
+        {
+            return Err(Error::storage(index, pos, record, Error::Quarantined));
+        }
+
+        if let Some(ref cache) = self.options.cache {
+            if let Some(bytes) = cache.get(index, pos, size) {
+                self.io.cache_hits += 1;
+                return Ok(bytes);
+            }
+            self.io.cache_misses += 1;
+        }
+
+        if let Some(bytes) = self.window.as_ref().and_then(|w| w.get(index, pos, size)) {
+            return Ok(bytes);
+        }
+
+        // While coalescing, read the window leading up to the record too
+        let start = if self.coalesce {
+            (pos + size as u32).saturating_sub(READ_WINDOW)
+        } else {
+            pos
+        };
+
+        let started = Instant::now();
+        self.io.reads += 1;
+        let mut buffer = vec![0; (pos - start) as usize + size];
+        let mut attempt = 0;
+        loop {
+            let result = self.reader.read_range(index, start.into(), &mut buffer);
 
-        let mut buffer = vec![0; size];
-        f.seek(SeekFrom::Start(pos.into()))?;
-        f.read_exact(&mut buffer)?;
+            match result {
+                Ok(()) => {
+                    self.report_elapsed(Operation::Read { index, pos, record }, started);
+                    let bytes = if self.coalesce {
+                        let bytes = buffer[(pos - start) as usize..].to_vec();
+                        self.window = Some(ReadWindow {
+                            index,
+                            start,
+                            bytes: buffer,
+                        });
+                        bytes
+                    } else {
+                        buffer
+                    };
+                    if let Some(ref cache) = self.options.cache {
+                        cache.insert(index, pos, &bytes);
+                    }
+                    return Ok(bytes);
+                }
+                Err(ref e) if attempt < self.options.read_retries && is_transient_io(e) => {
+                    thread::sleep(self.options.read_retry_backoff * 2u32.pow(attempt.min(16)));
+                    attempt += 1;
+                }
+                Err(e) => {
+                    self.report_elapsed(Operation::Read { index, pos, record }, started);
+                    return Err(Error::storage(index, pos, record, e));
+                }
+            }
+        }
+    }
 
-        Ok(buffer)
+    // Turn read coalescing on or off.  Turning it off drops the window.
+    pub fn set_coalescing(&mut self, on: bool) {
+        self.coalesce = on;
+        if !on {
+            self.window = None;
+        }
     }
 
     // Resolve hashnode -> node
-    pub fn resolve<'a>(&mut self, index: u16, pos: u32, leaf: bool) -> Result<Node<'a>> {
+    // The returned node carries the index/pos it was read from
+    pub fn resolve(&mut self, index: u16, pos: u32, leaf: bool) -> Result<Node> {
         let p = pos >> 1; // Divide out real position as it's store as pos * 2 ...
-        if leaf {
-            self.read(index, p, LEAF_NODE_SIZE)
-                .and_then(|n| Node::decode(n, true))
+        let (size, record) = if leaf {
+            (self.format.leaf_size(), RecordType::Leaf)
         } else {
-            self.read(index, p, INTERNAL_NODE_SIZE)
-                .and_then(|n| Node::decode(n, false))
+            (INTERNAL_NODE_SIZE, RecordType::Internal)
+        };
+        let format = self.format;
+        let result = self.read(index, p, size, record).and_then(|bits| {
+            Node::decode(bits, leaf, format).map_err(|e| Error::storage(index, p, record, e))
+        });
+        let mut node = self.quarantine_on_corruption(result)?;
+        node.set_index_and_position(index, pos);
+        Ok(node)
+    }
+
+    // Resolve `node` if it's a hash node, keeping its hash
+    pub fn expand(&mut self, node: Node) -> Result<Node> {
+        match node {
+            Node::Hash { index, pos, hash } => self.resolve_expecting(index, pos, hash),
+            n => Ok(n),
+        }
+    }
+
+    // Resolve the node a parent recorded with `hash`.  Leaves take the
+    // hash on trust, unless auditing.
+    pub fn resolve_expecting(&mut self, index: u16, pos: u32, hash: Digest) -> Result<Node> {
+        if self.options.audit {
+            return self.resolve_checked(index, pos, hash);
+        }
+        let mut resolved = self.resolve(index, pos, pos & 1 == 1)?;
+        resolved.set_leaf_hash(hash);
+        Ok(resolved)
+    }
+
+    // Resolve a node and check it hashes to what its parent recorded
+    pub fn resolve_checked(&mut self, index: u16, pos: u32, hash: Digest) -> Result<Node> {
+        let mut resolved = self.resolve(index, pos, pos & 1 == 1)?;
+        let actual = match resolved {
+            Node::Leaf {
+                key,
+                vindex,
+                vpos,
+                vsize,
+                ..
+            } => {
+                let value = self.retrieve(vindex, vpos, vsize)?;
+                sha3_value_salted(self.options.leaf_salt.as_ref(), key, &value)
+            }
+            _ => resolved.hash(),
+        };
+        if actual != hash {
+            return Err(Error::HashMismatch {
+                index,
+                pos: pos >> 1,
+                expected: hash,
+                actual,
+            });
         }
+        resolved.set_leaf_hash(hash);
+        Ok(resolved)
     }
 
     // Get *value* for leaf
     pub fn retrieve(&mut self, vindex: u16, vpos: u32, vsize: u16) -> Result<Vec<u8>> {
-        self.read(vindex, vpos, vsize as usize)
+        let result = self.read(vindex, vpos, vsize as usize, RecordType::Value);
+        self.quarantine_on_corruption(result)
     }
 
-    pub fn commit(&mut self, root_node: Option<&Node>) -> Result<()> {
+    /// Commit `root_node`, recording `leaf_count` leaves under it if the
+    /// format has room for them
+    pub fn commit(&mut self, root_node: Option<&Node>, leaf_count: u64) -> Result<()> {
+        if let Some(e) = self.checkpoint_error.take() {
+            return Err(e);
+        }
         // - Write meta data and buffer to current index file
         if let Some(n) = root_node {
             let is_leaf = n.is_leaf();
             let (index, pos) = n.index_and_position();
 
-            self.state.root_index = index;
-            self.state.root_pos = pos;
-            self.state.root_leaf = is_leaf;
+            let mut state = self.state.clone();
+            state.root_index = index;
+            state.root_pos = pos;
+            state.root_leaf = is_leaf;
+            state.leaf_count = leaf_count;
             //TODO: Set state.root_node
 
-            // Write to file
-            if let Ok(encoded) = self.state.encode(self.pos as u32, self.key) {
-                self.state.meta_index = self.index;
-                self.state.meta_pos = self.pos as u32;
+            let last = self.state.clone();
+            self.write_meta(state, last, n.hash())?;
+            self.io.commits += 1;
+        };
+
+        Ok(())
+    }
 
-                // Write metaroot to buffer
-                self.write_bytes(&encoded);
-                // Write all of the buffer to file
-                self.write_to_file()?;
+    /// Has a commit written enough since the last checkpoint to make
+    /// another?
+    pub fn checkpoint_due(&self) -> bool {
+        self.options
+            .checkpoint_bytes
+            .is_some_and(|n| self.since_checkpoint >= n as u64)
+    }
 
-                /*return get_file_handle(&get_data_file_path(&self.dir, self.index), true)
-                    .and_then(|mut f| f.write_all(&self.buffer))
-                    .and_then(|_| {
-                        self.buffer.clear();
-                        self.pos = 0;
-                        Ok(())
-                    });*/
+    /// Flush what's been written and append a checkpoint listing the
+    /// internal nodes at `subtrees`, each of which must have been written
+    /// with everything under it.  A failure is returned by the commit.
+    pub fn checkpoint(&mut self, subtrees: &[(u16, u32)]) {
+        if self.checkpoint_error.is_none() {
+            if let Err(e) = self.write_checkpoint(subtrees) {
+                self.checkpoint_error = Some(e);
             }
+        }
+        self.since_checkpoint = 0;
+    }
+
+    // One record per subtree, each pointing back at the one before and
+    // the first at the latest commit's meta
+    fn write_checkpoint(&mut self, subtrees: &[(u16, u32)]) -> Result<()> {
+        let mut link = (self.state.meta_index, self.state.meta_pos);
+        for &(root_index, root_pos) in subtrees {
+            let record = MetaEntry {
+                meta_index: link.0,
+                meta_pos: link.1,
+                root_index,
+                root_pos,
+                root_leaf: false,
+                leaf_count: 0,
+            };
+            self.make_room(self.format.meta_size() * 2);
+            let encoded = record.encode_checkpoint(self.pos as u32, self.key, self.format)?;
+            link = (
+                self.index,
+                (self.pos + encoded.len() - self.format.meta_size()) as u32,
+            );
+            self.write_bytes(&encoded);
+        }
+        self.write_to_file()
+    }
+
+    /// Subtrees listed by a checkpoint written after the latest commit,
+    /// as the index and position of each.  Empty if the latest record in
+    /// the store is a commit's meta.
+    pub fn checkpointed(&mut self) -> Result<Vec<(u16, u32)>> {
+        let latest = (self.state.meta_index, self.state.meta_pos);
+        // The commit's data starts no earlier than its root's file
+        let first = if latest.0 == EXTERNAL_META_INDEX {
+            self.state.root_index
+        } else {
+            latest.0
         };
+        let mut index = self.index;
+        let mut found = None;
+        while index >= first.max(1) {
+            let path = get_data_file_path(&self.dir, index);
+            if let Ok((_, record, checkpoint)) = recover_latest(&path, index, self.key, self.format)
+            {
+                if checkpoint {
+                    found = Some(record);
+                }
+                break;
+            }
+            index -= 1;
+        }
 
-        Err(Error::new(ErrorKind::Other, "Failed on commit"))
+        let mut subtrees = vec![];
+        let mut record = match found {
+            Some(record) => record,
+            None => return Ok(subtrees),
+        };
+        loop {
+            subtrees.push((record.root_index, record.root_pos));
+            if (record.meta_index, record.meta_pos) == latest {
+                return Ok(subtrees);
+            }
+            if record.meta_index == 0 {
+                return Err(Error::Decode("Checkpoint doesn't follow the latest commit"));
+            }
+            record = self.read_checkpoint(record.meta_index, record.meta_pos)?;
+        }
     }
 
-    fn write_to_file(&mut self) -> Result<()> {
-        get_file_handle(&get_data_file_path(&self.dir, self.index), true)
-            .and_then(|mut f| f.write_all(&self.buffer))
-            .and_then(|_| {
-                self.buffer.clear();
-                self.pos = 0;
-                Ok(())
+    fn read_checkpoint(&mut self, index: u16, pos: u32) -> Result<MetaEntry> {
+        let (key, format) = (self.key, self.format);
+        self.read(index, pos, format.meta_size(), RecordType::Meta)
+            .and_then(|bits| {
+                MetaEntry::decode_checkpoint(&bits, key, format)
+                    .map_err(|e| Error::storage(index, pos, RecordType::Meta, e))
             })
     }
+
+    /// Index of the data file being appended to
+    pub fn current_index(&self) -> u16 {
+        self.index
+    }
+
+    /// Commit `root_node`, which must reach nothing in data files numbered
+    /// `up_to_index` or lower, then delete those files.  The new meta
+    /// record starts a fresh history, as the commits before it may need
+    /// the deleted files.  Returns how many files were deleted.
+    pub fn compact_files(&mut self, root_node: &Node, up_to_index: u16) -> Result<usize> {
+        let up_to_index = up_to_index.min(self.index - 1);
+        let (index, pos) = root_node.index_and_position();
+        let mut state = self.state.clone();
+        state.root_index = index;
+        state.root_pos = pos;
+        state.root_leaf = root_node.is_leaf();
+        self.write_meta(state, MetaEntry::default(), root_node.hash())?;
+
+        // Nothing refers to the files once the meta is written, so a crash
+        // from here on only leaves them behind
+        let old: Vec<u16> = find_data_files(&self.dir)?
+            .iter()
+            .map(|f| f.index)
+            .filter(|i| *i <= up_to_index)
+            .collect();
+        self.remove_data_files(&old)?;
+        Ok(old.len())
+    }
+
+    /// Delete data files no commit worth keeping reaches.  The file being
+    /// appended to is never deleted.
+    pub fn remove_data_files(&mut self, indices: &[u16]) -> Result<()> {
+        let indices: Vec<u16> = indices
+            .iter()
+            .cloned()
+            .filter(|i| *i < self.index)
+            .collect();
+        if self.options.seal_files {
+            drop_seals(&self.dir, &indices, self.file_mode())?;
+        }
+        for index in &indices {
+            let path = get_data_file_path(&self.dir, *index);
+            let result = if self.options.scrub_deleted {
+                scrub_file(&path).and_then(|_| fs::remove_file(&path))
+            } else {
+                fs::remove_file(&path)
+            };
+            match result {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        if self.options.scrub_deleted && !indices.is_empty() {
+            sync_dir(&self.dir)?;
+        }
+        self.window = None;
+        Ok(())
+    }
+
+    /// Data files on disk, oldest first
+    pub fn data_files(&self) -> Result<Vec<u16>> {
+        let mut files: Vec<u16> = find_data_files(&self.dir)?
+            .iter()
+            .map(|f| f.index)
+            .collect();
+        files.reverse();
+        Ok(files)
+    }
+
+    /// Pack the store's files, as of its latest commit, into an archive
+    /// at `path`
+    pub fn export_archive(&self, path: &Path) -> Result<Vec<ArchiveEntry>> {
+        let mode = self.options.file_mode.unwrap_or(DEFAULT_FILE_MODE);
+        archive::write_archive(&self.dir, path, mode)
+    }
+
+    /// Unpack the archive at `path` into the new store directory `dir`
+    pub fn import_archive(path: &Path, dir: &Path, mode: u32) -> Result<Vec<ArchiveEntry>> {
+        archive::read_archive(path, dir, mode)
+    }
+
+    /// A store reading the same files as of the latest commit, for
+    /// snapshots.  It doesn't hold the lock and mustn't be written to.
+    pub fn snapshot(&self) -> Store {
+        Store {
+            buffer: vec![],
+            index: self.index,
+            pos: self.pos,
+            dir: self.dir.clone(),
+            key: self.key,
+            format: self.format,
+            state: self.state.clone(),
+            last_state: self.last_state.clone(),
+            options: self.options.clone(),
+            quarantine: self.quarantine.clone(),
+            slow_hook: None,
+            io: IoStats::default(),
+            coalesce: false,
+            window: None,
+            root_hashes: HashMap::new(),
+            full: vec![],
+            metas: vec![],
+            since_checkpoint: 0,
+            checkpoint_error: None,
+            roots: None,
+            _lock: None,
+            reader: self.reader.clone(),
+            #[cfg(feature = "signing")]
+            sequence: self.sequence,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Width of the store's keys in bytes
+    pub fn key_size(&self) -> u8 {
+        self.format.key_size
+    }
+
+    /// Size of the store's leaf records
+    pub fn leaf_size(&self) -> usize {
+        self.format.leaf_size()
+    }
+
+    /// Size of the store's meta records
+    pub fn meta_size(&self) -> usize {
+        self.format.meta_size()
+    }
+
+    /// Do the store's meta records carry the tree's leaf count?
+    pub fn records_leaf_count(&self) -> bool {
+        self.format.records_leaf_count()
+    }
+
+    pub fn retention(&self) -> RootRetention {
+        self.options.retention
+    }
+
+    /// Make the commit before the latest one current again.
+    ///
+    /// A new meta record is appended pointing at the previous root.  It
+    /// chains back to the meta before that, so the reverted commit drops
+    /// out of the history and reverting again keeps walking backwards.
+    pub fn revert(&mut self) -> Result<()> {
+        if self.state.meta_index == 0 || self.last_state.meta_index == 0 {
+            return Err(Error::NoPreviousCommit);
+        }
+
+        let previous = self.last_state.clone();
+        let before_previous = self.previous_meta(&previous)?;
+        let root = self.get_root_node(&previous)?.hash();
+        self.write_meta(previous, before_previous, root)
+    }
+
+    // Append a meta record for `state`, chained to the record of `last`,
+    // and flush the buffer.  On success they become the current/last state.
+    fn write_meta(&mut self, mut state: MetaEntry, last: MetaEntry, root: Digest) -> Result<()> {
+        state.meta_index = last.meta_index;
+        state.meta_pos = last.meta_pos;
+
+        if self.roots.is_some() {
+            // The nodes go out before the record that points at them
+            self.write_to_file()?;
+            let roots = self.roots.as_mut().unwrap();
+            state.meta_pos = roots.append(&state, self.key, self.format)?;
+            state.meta_index = EXTERNAL_META_INDEX;
+        } else {
+            // Room for the record and its alignment padding
+            self.make_room(self.format.meta_size() * 2);
+            let encoded = state.encode(self.pos as u32, self.key, self.format)?;
+
+            // Position of the record itself, after the alignment padding
+            state.meta_index = self.index;
+            state.meta_pos = (self.pos + encoded.len() - self.format.meta_size()) as u32;
+
+            // Write metaroot to buffer
+            self.write_bytes(&encoded);
+            // Write all of the buffer to file
+            self.write_to_file()?;
+            self.metas.push(state.meta_pos);
+        }
+        self.since_checkpoint = 0;
+
+        #[cfg(feature = "signing")]
+        self.sign_commit(&state, root)?;
+
+        if self.options.hash_cache && state.root_index != 0 {
+            let loc = (state.root_index, state.root_pos);
+            if !self.root_hashes.contains_key(&loc) {
+                append_root_hash(&self.dir, loc.0, loc.1, root, self.key, self.file_mode())?;
+                self.root_hashes.insert(loc, root);
+            }
+        }
+
+        self.state = state;
+        self.last_state = last;
+        Ok(())
+    }
+
+    fn write_to_file(&mut self) -> Result<()> {
+        // Finish off any files that filled up, sealing them if asked to
+        for (index, buffer) in mem::take(&mut self.full) {
+            let path = get_data_file_path(&self.dir, index);
+            open_append(&path, self.file_mode()).and_then(|mut f| f.write_all(&buffer))?;
+            if self.options.seal_files {
+                seal_file(&self.dir, &path, index, self.key, self.file_mode())?;
+            }
+        }
+
+        open_append(&get_data_file_path(&self.dir, self.index), self.file_mode())
+            .and_then(|mut f| f.write_all(&self.buffer))?;
+        self.buffer.clear();
+        Ok(())
+    }
 }
 
-fn load_state(files: &[StoreFile], dir: &PathBuf, key: [u8; 32]) -> (MetaEntry, MetaEntry) {
+#[cfg(feature = "signing")]
+impl Store {
+    // Pick up the sequence number and, if asked to, check the latest
+    // commit was signed by the expected key
+    fn load_signatures(&mut self) -> Result<()> {
+        let signed = load_signed_commits(&self.dir)?;
+        self.sequence = signed.last().map_or(0, |c| c.sequence);
+
+        if let Some(key) = self.options.verifying_key {
+            if self.state.meta_index != 0 {
+                let state = self.state.clone();
+                self.verify_signed(&signed, &state, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sign_commit(&mut self, state: &MetaEntry, root: Digest) -> Result<()> {
+        if let Some(ref key) = self.options.signing_key {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let commit = SignedCommit::sign(
+                key,
+                state.meta_index,
+                state.meta_pos,
+                self.sequence + 1,
+                timestamp,
+                root,
+            );
+            append_signed_commit(&self.dir, &commit, self.file_mode())?;
+            self.sequence = commit.sequence;
+        }
+        Ok(())
+    }
+
+    // Find and check the signature for the meta, including that it signs
+    // the root actually stored for that commit
+    fn verify_signed(
+        &mut self,
+        signed: &[SignedCommit],
+        meta: &MetaEntry,
+        key: &VerifyingKey,
+    ) -> Result<SignedCommit> {
+        let commit = signed
+            .iter()
+            .rev()
+            .find(|c| c.meta_index == meta.meta_index && c.meta_pos == meta.meta_pos)
+            .ok_or(Error::Signature("Commit is not signed"))?;
+        commit.verify(key)?;
+
+        if self.get_root_node(meta)?.hash() != commit.root {
+            return Err(Error::Signature(
+                "Signed root doesn't match the stored root",
+            ));
+        }
+        Ok(commit.clone())
+    }
+
+    /// Verify the signature of every commit in the meta chain, newest first
+    pub fn verify_history(&mut self, key: &VerifyingKey) -> Result<Vec<SignedCommit>> {
+        let signed = load_signed_commits(&self.dir)?;
+        let mut history = vec![];
+
+        let mut meta = self.state.clone();
+        while meta.meta_index != 0 {
+            history.push(self.verify_signed(&signed, &meta, key)?);
+            meta = self.previous_meta(&meta)?;
+        }
+        Ok(history)
+    }
+}
+
+// Find the latest meta, reading the footers of full files rather than
+// scanning them where it can
+fn load_state(files: &[StoreFile], dir: &Path, key: [u8; 32], format: Format) -> MetaEntry {
     let mut file_index = files[0].index;
     while file_index >= 1 {
         let fname = get_data_file_path(dir, file_index);
-        // Load meta returning new/last
-        if let Ok((st, old)) = recover_meta(&fname, file_index, key) {
-            return (st, old);
+        if let Ok(Some(positions)) = read_footer(&fname, key, format) {
+            match positions.last() {
+                None => {
+                    file_index -= 1;
+                    continue;
+                }
+                Some(pos) => {
+                    if let Ok(st) = meta_at(&fname, file_index, *pos, key, format) {
+                        return st;
+                    }
+                }
+            }
+        }
+        // Load the latest meta
+        if let Ok((st, _)) = recover_meta(&fname, file_index, key, format) {
+            return st;
         }
         file_index -= 1;
     }
-    (MetaEntry::default(), MetaEntry::default())
+    MetaEntry::default()
 }
 
 //struct StoreWriter {}
@@ -243,16 +1296,21 @@ fn load_state(files: &[StoreFile], dir: &PathBuf, key: [u8; 32]) -> (MetaEntry,
 #[derive(Debug)]
 struct StoreFile {
     index: u16,
-    name: String,
     size: u64,
 }
 
 // Return filenum if valid, else 0
+// Data files are named with exactly ten ASCII digits, which reads the
+// same on case-insensitive filesystems and rules out names like
+// "+000000001" that `from_str` would otherwise accept
 fn valid_data_filename(val: &str) -> u32 {
-    if val.len() < 10 {
+    if val.len() != 10 || !val.bytes().all(|b| b.is_ascii_digit()) {
         return 0;
     }
-    u32::from_str(val).unwrap_or(0)
+    match u32::from_str(val) {
+        Ok(n) if n <= u32::from(u16::MAX) => n,
+        _ => 0,
+    }
 }
 
 fn find_data_files(path: &Path) -> Result<Vec<StoreFile>> {
@@ -265,11 +1323,9 @@ fn find_data_files(path: &Path) -> Result<Vec<StoreFile>> {
             if let Some(name) = file.file_name().to_str() {
                 let filenum = valid_data_filename(name);
                 if filenum > 0 {
-                    let size = file.metadata()?.len();
                     data_files.push(StoreFile {
                         index: filenum as u16,
-                        name: String::from(name),
-                        size,
+                        size: file.metadata()?.len(),
                     });
                 }
             }
@@ -277,66 +1333,586 @@ fn find_data_files(path: &Path) -> Result<Vec<StoreFile>> {
     }
 
     // Sort to the latest index is the first element
-    data_files.sort_by(|a, b| b.index.cmp(&a.index));
+    data_files.sort_by_key(|f| std::cmp::Reverse(f.index));
     Ok(data_files)
 }
 
+// A read of a data file that isn't there
+fn is_not_found(e: &Error) -> bool {
+    match e {
+        Error::Io(e) => e.kind() == io::ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
 fn get_data_file_path(path: &Path, file_id: u16) -> PathBuf {
     let file_id = format!("{:010}", file_id);
     path.join(file_id)
 }
 
-pub fn get_file_handle(path: &Path, write: bool) -> Result<File> {
-    if write {
-        OpenOptions::new().append(true).create(true).open(path)
-    } else {
-        OpenOptions::new().read(true).open(path)
+// Take the lock file in `dir`.  The standard library locks with flock on
+// Unix and LockFileEx on Windows, and either way the lock goes with the
+// handle, so it's released when the store is dropped or the process dies.
+fn lock_dir(dir: &Path, mode: u32) -> Result<File> {
+    let f = open_append(&dir.join(LOCK_FILE_NAME), mode)?;
+    match f.try_lock() {
+        Ok(()) => Ok(f),
+        Err(TryLockError::WouldBlock) => Err(Error::Locked),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+pub fn get_file_handle(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).open(path)
+}
+
+// Overwrite a file with zeros and flush it to disk
+fn scrub_file(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let zeros = vec![0; 64 * 1024];
+    let mut left = file.metadata()?.len();
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    file.sync_all()
+}
+
+// Flush a directory's entries, so files removed from it stay removed
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()?;
     }
+    Ok(())
+}
+
+/// Open `path` for appending, creating it with `mode` on Unix
+pub fn open_append(path: &Path, mode: u32) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    set_mode(&mut options, mode);
+    options.open(path)
+}
+
+#[cfg(unix)]
+pub fn set_mode(options: &mut OpenOptions, mode: u32) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(mode);
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_options: &mut OpenOptions, _mode: u32) {}
+
+// Create `path` and any missing parents.  On Unix new directories get
+// `mode` plus search permission wherever it grants read.
+pub fn create_dirs(path: &Path, mode: u32) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(mode | ((mode & 0o444) >> 2));
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    builder.create(path)
 }
 
 /// Load or create the meta file that holds the key used for the checksum
 /// in the meta root.
+#[cfg(feature = "rng")]
 pub fn random_key() -> [u8; 32] {
     let mut arr = [0; 32];
     thread_rng().fill(&mut arr[..]);
     arr
 }
 
-fn load_or_create_meta_key(dir: &str) -> Result<[u8; 32]> {
-    let path = Path::new(dir).join("meta");
-    if path.exists() {
-        // Read the key if the meta file exists
-        OpenOptions::new().read(true).open(path).and_then(|mut f| {
-            let mut buffer = [0; 32];
-            f.read_exact(&mut buffer)?;
-            Ok(buffer)
-        })
+// Read the store's format from its version file, recording the current
+// version with the byte order and key size in `options` for a new store.
+// The meta key has just been written if the store is new.
+fn load_format(dir: &Path, options: &StoreOptions, mode: u32) -> Result<Format> {
+    let key_size = options.key_size.unwrap_or(MAX_KEY_SIZE);
+    if key_size == 0 || key_size > MAX_KEY_SIZE {
+        return Err(Error::UnsupportedKeySize(key_size));
+    }
+    let path = dir.join(VERSION_FILE_NAME);
+    let format = if !path.exists() {
+        if !find_data_files(dir)?.is_empty() {
+            LEGACY_FORMAT
+        } else {
+            let format = Format {
+                hash_id: hash_id(),
+                ..Format::with_key_size(options.endianness, key_size)
+            };
+            let mut file_options = OpenOptions::new();
+            file_options.create(true).truncate(true).write(true);
+            set_mode(&mut file_options, mode);
+            file_options
+                .open(path)
+                .and_then(|mut f| f.write_all(&format.encode()))?;
+            format
+        }
     } else {
-        // Create a new key and meta file
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(path)
-            .and_then(|mut f| {
-                let k = random_key();
-                f.write_all(&k)?;
-                Ok(k)
-            })
+        Format::decode(&fs::read(path)?)?
+    };
+    if options.key_size.is_some() && format.key_size != key_size {
+        return Err(Error::KeySizeMismatch {
+            found: format.key_size,
+            expected: key_size,
+        });
+    }
+    if format.hash_id != hash_id() {
+        return Err(Error::HashFunctionMismatch {
+            found: format.hash_id,
+            expected: hash_id(),
+        });
     }
+    Ok(format)
+}
+
+// Marks a meta file holding a hash of a key kept outside the store
+const EXTERNAL_KEY_FLAG: u8 = 1;
+
+// Read the store's meta key, or check the one supplied against it.  A new
+// store's key is supplied, so only its hash is written, or made by the
+// key source.
+fn load_or_create_meta_key(dir: &Path, mode: u32, options: &StoreOptions) -> Result<[u8; 32]> {
+    let path = dir.join(META_KEY_FILE_NAME);
+    if path.exists() {
+        let bits = fs::read(path)?;
+        return match (bits.len(), options.meta_key) {
+            (32, supplied) => {
+                let mut key = [0; 32];
+                key.copy_from_slice(&bits);
+                match supplied {
+                    Some(k) if k != key => Err(Error::MetaKeyMismatch),
+                    _ => Ok(key),
+                }
+            }
+            (33, Some(k)) if bits[0] == EXTERNAL_KEY_FLAG => {
+                if sha3(&k).0[..] == bits[1..] {
+                    Ok(k)
+                } else {
+                    Err(Error::MetaKeyMismatch)
+                }
+            }
+            (33, None) if bits[0] == EXTERNAL_KEY_FLAG => Err(Error::MissingMetaKey),
+            _ => Err(Error::Decode("Malformed meta key file")),
+        };
+    }
+
+    let (key, bits) = match options.meta_key {
+        Some(k) => {
+            let mut bits = vec![EXTERNAL_KEY_FLAG];
+            bits.extend_from_slice(&sha3(&k).0);
+            (k, bits)
+        }
+        None => {
+            let k = match options.key_source {
+                Some(ref source) => source.new_key(),
+                #[cfg(feature = "rng")]
+                None => random_key(),
+                #[cfg(not(feature = "rng"))]
+                None => return Err(Error::NoKeySource),
+            };
+            (k, k.to_vec())
+        }
+    };
+    let mut file_options = OpenOptions::new();
+    file_options.create(true).truncate(true).write(true);
+    set_mode(&mut file_options, mode);
+    file_options
+        .open(path)
+        .and_then(|mut f| f.write_all(&bits))?;
+    Ok(key)
 }
 
 #[cfg(test)]
 mod tests {
     use super::recover_meta;
+    use errors::{Error, RecordType};
     use std::path::PathBuf;
-    use store::{get_data_file_path, load_or_create_meta_key};
+    use store::{get_data_file_path, load_or_create_meta_key, Store, StoreOptions};
+    use test_dir;
+    use tree::UrkelTree;
 
     #[test]
     fn file_newreading() {
-        let meta_key = load_or_create_meta_key("./data").expect("Can't access meta file!");
-        let path = &get_data_file_path(&PathBuf::from("./data"), 1);
-        let result = recover_meta(path, 1, meta_key);
+        let dir = test_dir("file_newreading");
+        {
+            let mut t = UrkelTree::open(&dir);
+            t.insert(::hashutils::sha3(b"name-1"), b"value-1").unwrap();
+            t.commit().unwrap();
+        }
+
+        let meta_key = load_or_create_meta_key(
+            ::std::path::Path::new(&dir),
+            0o600,
+            &StoreOptions::default(),
+        )
+        .expect("Can't access meta file!");
+        let path = &get_data_file_path(&PathBuf::from(&dir), 1);
+        let result = recover_meta(path, 1, meta_key, Default::default());
         assert!(result.is_ok());
         println!("Meta: {:?}", result);
     }
+
+    #[test]
+    fn full_files_have_footers() {
+        use metadata::{meta_at, read_footer};
+        use std::fs;
+
+        let dir = test_dir("full_files_have_footers");
+        let options = || StoreOptions {
+            max_file_size: Some(1024),
+            ..Default::default()
+        };
+        let mut roots = vec![];
+        {
+            let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+            for i in 0..40 {
+                t.insert_owned(::hashutils::sha3(&[i]), vec![i; 20])
+                    .unwrap();
+                if i % 10 == 9 {
+                    t.commit().unwrap();
+                    roots.push(t.get_root());
+                }
+            }
+        }
+
+        let path = PathBuf::from(&dir);
+        let key = load_or_create_meta_key(&path, 0o600, &StoreOptions::default()).unwrap();
+        let files = Store::open(&dir).data_files().unwrap();
+        let (tail, full) = files.split_last().unwrap();
+        let mut metas = 0;
+        for index in full {
+            let file = get_data_file_path(&path, *index);
+            let positions = read_footer(&file, key, Default::default())
+                .unwrap()
+                .unwrap();
+            for pos in &positions {
+                assert!(meta_at(&file, *index, *pos, key, Default::default()).is_ok());
+            }
+            metas += positions.len();
+        }
+        assert!(metas > 0);
+        assert!(
+            read_footer(&get_data_file_path(&path, *tail), key, Default::default())
+                .unwrap()
+                .is_none()
+        );
+
+        // Without the file being appended to, the latest meta comes from
+        // the footers
+        fs::remove_file(get_data_file_path(&path, *tail)).unwrap();
+        let t = UrkelTree::open_with_options(&dir, options()).unwrap();
+        assert!(roots.contains(&t.get_root()));
+    }
+
+    #[test]
+    fn meta_key_can_be_supplied() {
+        use std::fs;
+        use std::sync::Arc;
+
+        let dir = test_dir("meta_key_can_be_supplied");
+        let options = StoreOptions {
+            key_source: Some(Arc::new(|| [9; 32])),
+            ..Default::default()
+        };
+        drop(Store::open_with_options(&dir, options).unwrap());
+        assert_eq!(fs::read(PathBuf::from(&dir).join("meta")).unwrap(), [9; 32]);
+
+        // A supplied key stays out of the directory
+        let dir = test_dir("meta_key_can_be_supplied_b");
+        let with_key = |key| StoreOptions {
+            meta_key: Some(key),
+            ..Default::default()
+        };
+        {
+            let mut t = UrkelTree::open_with_options(&dir, with_key([3; 32])).unwrap();
+            t.insert(::hashutils::sha3(b"name-1"), b"value-1").unwrap();
+            t.commit().unwrap();
+        }
+        let bits = fs::read(PathBuf::from(&dir).join("meta")).unwrap();
+        assert!(!bits.windows(32).any(|w| w == [3; 32]));
+
+        let mut t = UrkelTree::open_with_options(&dir, with_key([3; 32])).unwrap();
+        assert_eq!(
+            t.get(::hashutils::sha3(b"name-1")).unwrap(),
+            Some(Vec::from("value-1"))
+        );
+        drop(t);
+        match UrkelTree::open_with_options(&dir, StoreOptions::default()) {
+            Err(Error::MissingMetaKey) => {}
+            other => panic!("Expected MissingMetaKey, got {:?}", other.err()),
+        }
+        match UrkelTree::open_with_options(&dir, with_key([4; 32])) {
+            Err(Error::MetaKeyMismatch) => {}
+            other => panic!("Expected MetaKeyMismatch, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn read_errors_carry_location() {
+        let dir = test_dir("read_errors_carry_location");
+        {
+            let mut t = UrkelTree::open(&dir);
+            t.insert(::hashutils::sha3(b"name-1"), b"value-1").unwrap();
+            t.commit().unwrap();
+        }
+
+        let mut store = Store::open(&dir);
+        // Past the end of the file
+        match store.resolve(1, 4000 * 2, false) {
+            Err(Error::Storage {
+                index: 1,
+                pos: 4000,
+                record: RecordType::Internal,
+                ..
+            }) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        // There is no second data file, which retrying won't fix
+        match store.retrieve(2, 0, 4) {
+            Err(
+                e @ Error::Storage {
+                    index: 2,
+                    record: RecordType::Value,
+                    ..
+                },
+            ) => assert!(!e.is_transient()),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn full_files_are_sealed() {
+        use std::fs::OpenOptions;
+        use std::io::Write;
+
+        let dir = test_dir("full_files_are_sealed");
+        let options = || StoreOptions {
+            max_file_size: Some(1024),
+            seal_files: true,
+            ..Default::default()
+        };
+        let keys: Vec<_> = (0..40)
+            .map(|i| ::hashutils::sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        {
+            let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+            for (i, k) in keys.iter().enumerate() {
+                t.insert(*k, b"value").unwrap();
+                if i % 10 == 9 {
+                    t.commit().unwrap();
+                }
+            }
+        }
+
+        let seals = ::manifest::load_seals(&PathBuf::from(&dir)).unwrap();
+        assert!(seals.len() > 1);
+        assert_eq!(seals[0].index, 1);
+
+        {
+            let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+            for k in &keys {
+                assert_eq!(t.get(*k).unwrap(), Some(Vec::from("value")));
+            }
+        }
+
+        // Appending to a sealed file breaks its seal
+        OpenOptions::new()
+            .append(true)
+            .open(get_data_file_path(&PathBuf::from(&dir), 1))
+            .and_then(|mut f| f.write_all(&[0]))
+            .unwrap();
+        match UrkelTree::open_with_options(&dir, options()) {
+            Err(Error::SealBroken { index: 1 }) => {}
+            other => panic!("Expected a broken seal, got {:?}", other.err()),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn created_files_are_owner_only() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("created_files_are_owner_only");
+        let data = PathBuf::from(&dir).join("data");
+        {
+            let options = StoreOptions {
+                hash_cache: true,
+                ..Default::default()
+            };
+            let mut t = UrkelTree::open_with_options(data.to_str().unwrap(), options).unwrap();
+            t.insert(::hashutils::sha3(b"name-1"), b"value-1").unwrap();
+            t.commit().unwrap();
+        }
+        let mode = |p: PathBuf| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(data.clone()), 0o700);
+        assert_eq!(mode(PathBuf::from(&dir)), 0o700);
+        for name in &["meta", "0000000001", "hashes"] {
+            assert_eq!(mode(data.join(name)), 0o600, "{}", name);
+        }
+
+        let shared = test_dir("created_files_with_mode");
+        let options = StoreOptions {
+            file_mode: Some(0o640),
+            ..Default::default()
+        };
+        let mut t = UrkelTree::open_with_options(&shared, options).unwrap();
+        t.insert(::hashutils::sha3(b"name-1"), b"value-1").unwrap();
+        t.commit().unwrap();
+        assert_eq!(mode(PathBuf::from(&shared)), 0o750);
+        assert_eq!(mode(PathBuf::from(&shared).join("meta")), 0o640);
+    }
+
+    #[test]
+    fn data_filenames() {
+        use super::valid_data_filename;
+
+        assert_eq!(valid_data_filename("0000000001"), 1);
+        assert_eq!(valid_data_filename("0000065535"), 65535);
+        assert_eq!(valid_data_filename("0000065536"), 0);
+        assert_eq!(valid_data_filename("+000000001"), 0);
+        assert_eq!(valid_data_filename("00000000001"), 0);
+        assert_eq!(valid_data_filename("0000000001.tmp"), 0);
+        assert_eq!(valid_data_filename("meta"), 0);
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        let dir = test_dir("lock_is_exclusive");
+        let options = || StoreOptions {
+            lock: true,
+            ..Default::default()
+        };
+        {
+            let _held = Store::open_with_options(&dir, options()).unwrap();
+            match Store::open_with_options(&dir, options()) {
+                Err(Error::Locked) => {}
+                other => panic!("Expected Locked, got {:?}", other.err()),
+            }
+            // Stores that don't ask for the lock aren't stopped
+            assert!(Store::open_with_options(&dir, StoreOptions::default()).is_ok());
+        }
+        assert!(Store::open_with_options(&dir, options()).is_ok());
+    }
+
+    #[test]
+    fn reads_go_through_the_injected_reader() {
+        use backend::RangeReader;
+        use std::collections::HashMap;
+        use std::fs;
+        use std::io;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // Serves the data files from memory, as a remote copy would
+        struct MemoryReader {
+            files: HashMap<u16, Vec<u8>>,
+            calls: AtomicUsize,
+        }
+
+        impl RangeReader for MemoryReader {
+            fn read_range(&self, index: u16, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                let start = pos as usize;
+                let file = self.files.get(&index).ok_or(io::ErrorKind::NotFound)?;
+                let bytes = file
+                    .get(start..start + buf.len())
+                    .ok_or(io::ErrorKind::UnexpectedEof)?;
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+        }
+
+        let dir = test_dir("reads_go_through_the_injected_reader");
+        let key = ::hashutils::sha3(b"name-1");
+        {
+            let mut t = UrkelTree::open(&dir);
+            t.insert(key, b"value-1").unwrap();
+            t.insert(::hashutils::sha3(b"name-2"), b"value-2").unwrap();
+            t.commit().unwrap();
+        }
+
+        let path = get_data_file_path(&PathBuf::from(&dir), 1);
+        let mut files = HashMap::new();
+        files.insert(1, fs::read(&path).unwrap());
+        let reader = Arc::new(MemoryReader {
+            files,
+            calls: AtomicUsize::new(0),
+        });
+        let options = StoreOptions {
+            reader: Some(reader.clone()),
+            ..Default::default()
+        };
+        let mut t = UrkelTree::open_with_options(&dir, options).unwrap();
+        let opened = reader.calls.load(Ordering::SeqCst);
+        assert!(opened > 0);
+        assert_eq!(t.get(key).unwrap(), Some(Vec::from("value-1")));
+        assert!(reader.calls.load(Ordering::SeqCst) > opened);
+    }
+
+    #[test]
+    fn store_survives_being_moved() {
+        let dir = test_dir("store_survives_being_moved");
+        let moved = test_dir("store_survives_being_moved_to");
+        let key = ::hashutils::sha3(b"name-1");
+        let options = || StoreOptions {
+            hash_cache: true,
+            seal_files: true,
+            max_file_size: Some(256),
+            ..Default::default()
+        };
+        let root = {
+            let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+            for i in 0..10 {
+                t.insert(::hashutils::sha3(&[i]), b"value").unwrap();
+            }
+            t.insert(key, b"value-1").unwrap();
+            t.commit().unwrap();
+            t.get_root()
+        };
+
+        // Sandboxed apps see their container move between launches
+        ::std::fs::rename(&dir, &moved).unwrap();
+        let mut t = UrkelTree::open_with_options(PathBuf::from(&moved), options()).unwrap();
+        assert_eq!(t.get_root(), root);
+        assert_eq!(t.get(key).unwrap(), Some(Vec::from("value-1")));
+    }
+
+    #[test]
+    fn root_hashes_are_cached() {
+        let dir = test_dir("root_hashes_are_cached");
+        let options = || StoreOptions {
+            hash_cache: true,
+            ..Default::default()
+        };
+        let (first, second) = {
+            let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+            t.insert(::hashutils::sha3(b"name-1"), b"value-1").unwrap();
+            t.commit().unwrap();
+            let first = t.get_root();
+            t.insert(::hashutils::sha3(b"name-2"), b"value-2").unwrap();
+            t.commit().unwrap();
+            (first, t.get_root())
+        };
+
+        // Both roots are answered without touching the data files
+        let mut store = Store::open_with_options(&dir, options()).unwrap();
+        let (state, last) = (store.state().clone(), store.last_state().clone());
+        let reads = store.reads();
+        assert_eq!(store.get_root_node(&state).unwrap().hash(), second);
+        assert_eq!(store.get_root_node(&last).unwrap().hash(), first);
+        assert_eq!(store.reads(), reads);
+
+        // Without the option the roots come from the tree
+        let mut store = Store::open(&dir);
+        let reads = store.reads();
+        assert_eq!(store.get_root_node(&state).unwrap().hash(), second);
+        assert!(store.reads() > reads);
+    }
 }
@@ -0,0 +1,254 @@
+//! Deterministic test utilities
+//!
+//! For testing code built on the tree: `KvGen` turns a seed into the same
+//! keys, values and operations on every platform, `Model` is a plain
+//! `BTreeMap` holding what the tree should, and `check_against_model`
+//! runs a seeded sequence of operations against both, comparing them at
+//! every commit.
+//!
+//! ```ignore
+//! let mut tree = UrkelTree::open(dir);
+//! let model = testing::check_against_model(&mut tree, b"seed", 1000)?;
+//! ```
+use errors::Error;
+use hashutils::{sha3, Digest};
+use std::collections::BTreeMap;
+use std::fmt;
+use tree::UrkelTree;
+
+/// Keys, values and operations drawn from a seed
+pub struct KvGen {
+    state: Digest,
+}
+
+impl KvGen {
+    pub fn new(seed: &[u8]) -> Self {
+        KvGen { state: sha3(seed) }
+    }
+
+    fn next(&mut self) -> Digest {
+        self.state = sha3(&self.state.0);
+        self.state
+    }
+
+    // Below `n`, which must be positive
+    fn below(&mut self, n: usize) -> usize {
+        let r = self.next();
+        let mut x = 0usize;
+        for b in &r.0[..8] {
+            x = (x << 8) | *b as usize;
+        }
+        x % n
+    }
+
+    pub fn key(&mut self) -> Digest {
+        self.next()
+    }
+
+    /// A value of 1 to 64 bytes
+    pub fn value(&mut self) -> Vec<u8> {
+        let len = 1 + self.below(64);
+        let mut value = self.next().0.to_vec();
+        value.extend_from_slice(&self.next().0);
+        value.truncate(len);
+        value
+    }
+
+    /// An operation against `model`: mostly inserts of new keys, then
+    /// overwrites, removes of present and absent keys, and commits
+    pub fn action(&mut self, model: &Model) -> Action {
+        let keys = model.len();
+        match self.below(10) {
+            0..=3 => Action::Insert(self.key(), self.value()),
+            4 | 5 if keys > 0 => {
+                let key = model.nth_key(self.below(keys));
+                Action::Insert(key, self.value())
+            }
+            6 | 7 if keys > 0 => Action::Remove(model.nth_key(self.below(keys))),
+            8 => Action::Remove(self.key()),
+            _ => Action::Commit,
+        }
+    }
+}
+
+/// One step of a run
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Insert(Digest, Vec<u8>),
+    Remove(Digest),
+    Commit,
+}
+
+/// What the tree should hold
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Model {
+    pub entries: BTreeMap<Digest, Vec<u8>>,
+}
+
+impl Model {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &Digest) -> Option<&Vec<u8>> {
+        self.entries.get(key)
+    }
+
+    fn nth_key(&self, n: usize) -> Digest {
+        *self.entries.keys().nth(n).unwrap()
+    }
+
+    pub fn apply(&mut self, action: &Action) {
+        match action {
+            Action::Insert(key, value) => {
+                self.entries.insert(*key, value.clone());
+            }
+            Action::Remove(key) => {
+                self.entries.remove(key);
+            }
+            Action::Commit => {}
+        }
+    }
+}
+
+/// Apply `action` to `tree`
+pub fn apply(tree: &mut UrkelTree, action: &Action) -> Result<(), Error> {
+    match action {
+        Action::Insert(key, value) => tree.insert_owned(*key, value.clone()),
+        Action::Remove(key) => tree.remove(*key).map(|_| ()),
+        Action::Commit => tree.commit(),
+    }
+}
+
+/// Why a run failed
+#[derive(Debug)]
+pub enum Failure {
+    /// The tree returned an error
+    Tree { step: usize, error: Error },
+    /// The tree and the model disagree about `key`
+    Mismatch {
+        step: usize,
+        key: Digest,
+        expected: Option<Vec<u8>>,
+        found: Option<Vec<u8>>,
+    },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Tree { step, error } => write!(f, "Step {}: {}", step, error),
+            Failure::Mismatch {
+                step,
+                key,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Step {}: key {:?} should be {:?}, found {:?}",
+                step, key, expected, found
+            ),
+        }
+    }
+}
+
+/// Check `tree` holds exactly what `model` does, reading every key back
+/// and walking the tree in key order
+pub fn compare(tree: &mut UrkelTree, model: &Model, step: usize) -> Result<(), Failure> {
+    let tree_error = |error| Failure::Tree { step, error };
+    for (key, value) in &model.entries {
+        let found = tree.get(*key).map_err(tree_error)?;
+        if found.as_ref() != Some(value) {
+            return Err(Failure::Mismatch {
+                step,
+                key: *key,
+                expected: Some(value.clone()),
+                found,
+            });
+        }
+    }
+
+    let mut expected = model.entries.iter();
+    for pair in tree.iter() {
+        let (key, found) = pair.map_err(tree_error)?;
+        match expected.next() {
+            Some((k, _)) if *k == key => {}
+            // Either a key the model doesn't have or one out of order
+            _ => {
+                return Err(Failure::Mismatch {
+                    step,
+                    key,
+                    expected: model.get(&key).cloned(),
+                    found: Some(found),
+                })
+            }
+        }
+    }
+    if let Some((key, value)) = expected.next() {
+        return Err(Failure::Mismatch {
+            step,
+            key: *key,
+            expected: Some(value.clone()),
+            found: None,
+        });
+    }
+    Ok(())
+}
+
+/// Run `steps` operations drawn from `seed` against `tree` and a model,
+/// comparing them at every commit and once more at the end.  The tree
+/// should start empty.  Returns the final model.
+pub fn check_against_model(
+    tree: &mut UrkelTree,
+    seed: &[u8],
+    steps: usize,
+) -> Result<Model, Failure> {
+    let mut gen = KvGen::new(seed);
+    let mut model = Model::default();
+    for step in 0..steps {
+        let action = gen.action(&model);
+        apply(tree, &action).map_err(|error| Failure::Tree { step, error })?;
+        model.apply(&action);
+        if action == Action::Commit {
+            compare(tree, &model, step)?;
+        }
+    }
+    compare(tree, &model, steps)?;
+    Ok(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_dir;
+
+    #[test]
+    fn tree_matches_model() {
+        let mut tree = UrkelTree::open(test_dir("tree_matches_model"));
+        let model = check_against_model(&mut tree, b"seed", 500).unwrap();
+        assert!(!model.is_empty());
+
+        // The same seed gives the same run
+        let mut other = UrkelTree::open(test_dir("tree_matches_model_b"));
+        assert_eq!(
+            check_against_model(&mut other, b"seed", 500).unwrap(),
+            model
+        );
+        assert_eq!(other.get_root(), tree.get_root());
+
+        // And a tree that's drifted from the model is caught
+        let (key, _) = model.entries.iter().next().unwrap();
+        tree.remove(*key).unwrap();
+        match compare(&mut tree, &model, 0) {
+            Err(Failure::Mismatch { key: k, found, .. }) => {
+                assert_eq!(k, *key);
+                assert_eq!(found, None);
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Hex and base64 for carrying proofs as strings
+//!
+//! Hex is lowercase without a `0x` prefix; either case decodes.  Base64
+//! is the standard alphabet with padding (RFC 4648), decoded strictly so
+//! each proof has exactly one text form.
+use errors::Error;
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn to_hex(bits: &[u8]) -> String {
+    let mut out = String::with_capacity(bits.len() * 2);
+    for b in bits {
+        out.push(HEX[(b >> 4) as usize] as char);
+        out.push(HEX[(b & 0xf) as usize] as char);
+    }
+    out
+}
+
+fn hex_digit(c: u8) -> Result<u8, Error> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(Error::Decode("Invalid hex digit")),
+    }
+}
+
+pub fn from_hex(text: &str) -> Result<Vec<u8>, Error> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(2) {
+        return Err(Error::Decode("Odd length hex"));
+    }
+    text.chunks_exact(2)
+        .map(|pair| Ok(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+        .collect()
+}
+
+pub fn to_base64(bits: &[u8]) -> String {
+    let mut out = String::with_capacity(bits.len().div_ceil(3) * 4);
+    for chunk in bits.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_digit(c: u8) -> Result<u32, Error> {
+    match c {
+        b'A'..=b'Z' => Ok((c - b'A') as u32),
+        b'a'..=b'z' => Ok((c - b'a' + 26) as u32),
+        b'0'..=b'9' => Ok((c - b'0' + 52) as u32),
+        b'+' => Ok(62),
+        b'/' => Ok(63),
+        _ => Err(Error::Decode("Invalid base64 character")),
+    }
+}
+
+pub fn from_base64(text: &str) -> Result<Vec<u8>, Error> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return Err(Error::Decode("Base64 length isn't a multiple of 4"));
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let quads = text.len() / 4;
+    for (q, quad) in text.chunks_exact(4).enumerate() {
+        // Padding may only end the last quad
+        let pad = quad.iter().rev().take_while(|c| **c == b'=').count();
+        if pad > 2 || (pad > 0 && q + 1 != quads) {
+            return Err(Error::Decode("Misplaced base64 padding"));
+        }
+        let mut n = 0u32;
+        for c in &quad[..4 - pad] {
+            n = n << 6 | base64_digit(*c)?;
+        }
+        n <<= 6 * pad as u32;
+        // Bits under the padding must be zero for the encoding to be canonical
+        if n & ((1 << (8 * pad)) - 1) != 0 {
+            return Err(Error::Decode("Non-canonical base64"));
+        }
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - pad]);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        // RFC 4648 test vectors
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (plain, encoded) in &cases {
+            assert_eq!(to_base64(plain.as_bytes()), *encoded);
+            assert_eq!(from_base64(encoded).unwrap(), plain.as_bytes());
+        }
+        assert_eq!(to_hex(&[0x00, 0xab, 0xff]), "00abff");
+        assert_eq!(from_hex("00ABff").unwrap(), vec![0x00, 0xab, 0xff]);
+
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+        assert!(from_base64("Zg=").is_err());
+        assert!(from_base64("Zh==").is_err());
+        assert!(from_base64("Zg==Zg==").is_err());
+        assert!(from_base64("Z===").is_err());
+        assert!(from_base64("Zm9v!A==").is_err());
+    }
+}
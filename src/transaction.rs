@@ -0,0 +1,181 @@
+//! Transactions over a tree
+//!
+//! A `Transaction` stages inserts and removes apart from the tree, where
+//! reads through the transaction see them but the tree doesn't.
+//! Committing applies them all with `UrkelTree::apply`'s all-or-nothing
+//! semantics and commits the tree.  Dropping the transaction instead
+//! discards them, leaving the tree untouched.
+//!
+//! A `Savepoint` marks the staged changes at some point, and rolling back
+//! to it undoes the ones staged since while keeping those before.
+use super::Result;
+use hashutils::Digest;
+use std::collections::BTreeMap;
+use tree::UrkelTree;
+
+/// A point in a transaction to roll back to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Savepoint(usize);
+
+/// Changes staged against a tree (see `UrkelTree::transaction`)
+pub struct Transaction<'t> {
+    tree: &'t mut UrkelTree,
+    /// Value to set for each changed key, or `None` to remove it
+    staged: BTreeMap<Digest, Option<Vec<u8>>>,
+    /// Each change staged, with what was staged for its key before
+    undo: Vec<(Digest, Option<Option<Vec<u8>>>)>,
+}
+
+impl<'t> Transaction<'t> {
+    pub fn new(tree: &'t mut UrkelTree) -> Self {
+        Transaction {
+            tree,
+            staged: BTreeMap::new(),
+            undo: vec![],
+        }
+    }
+
+    /// Stage setting `key` to `value`
+    pub fn insert(&mut self, key: Digest, value: Vec<u8>) {
+        self.stage(key, Some(value));
+    }
+
+    /// Stage removing `key`
+    pub fn remove(&mut self, key: Digest) {
+        self.stage(key, None);
+    }
+
+    fn stage(&mut self, key: Digest, value: Option<Vec<u8>>) {
+        let before = self.staged.insert(key, value);
+        self.undo.push((key, before));
+    }
+
+    /// Mark the changes staged so far
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint(self.undo.len())
+    }
+
+    /// Undo the changes staged since `savepoint`.  It can be rolled back
+    /// to again, but rolling back to an earlier savepoint drops the later
+    /// ones.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        while self.undo.len() > savepoint.0 {
+            let (key, before) = self.undo.pop().unwrap();
+            match before {
+                Some(value) => self.staged.insert(key, value),
+                None => self.staged.remove(&key),
+            };
+        }
+    }
+
+    /// The value of `key` with the staged changes applied
+    pub fn get(&mut self, key: Digest) -> Result<Option<Vec<u8>>> {
+        match self.staged.get(&key) {
+            Some(value) => Ok(value.clone()),
+            None => self.tree.get(key),
+        }
+    }
+
+    /// Keys with a staged change
+    pub fn len(&self) -> usize {
+        self.staged.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.staged.is_empty()
+    }
+
+    /// Apply the staged changes and commit the tree, along with anything
+    /// changed on the tree itself.  If a change can't be applied, none are
+    /// and nothing is committed.
+    pub fn commit(self) -> Result<()> {
+        let changes = self.staged.into_iter().collect();
+        self.tree.apply_changes(changes)?;
+        self.tree.commit()
+    }
+
+    /// Drop the staged changes
+    pub fn discard(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Error;
+    use hashutils::sha3;
+    use store::StoreOptions;
+    use test_dir;
+
+    #[test]
+    fn commits_all_or_nothing() {
+        let keys: Vec<Digest> = (0..3u8).map(|i| sha3(&[i])).collect();
+        let options = StoreOptions {
+            max_value_size: Some(8),
+            ..Default::default()
+        };
+        let mut t =
+            UrkelTree::open_with_options(test_dir("commits_all_or_nothing"), options).unwrap();
+        t.insert(keys[0], b"value-0").unwrap();
+        t.commit().unwrap();
+        let root = t.get_root();
+
+        // Discarded
+        let mut tx = t.transaction();
+        tx.insert(keys[1], b"value-1".to_vec());
+        tx.remove(keys[0]);
+        assert_eq!(tx.get(keys[0]).unwrap(), None);
+        assert_eq!(tx.get(keys[1]).unwrap(), Some(b"value-1".to_vec()));
+        drop(tx);
+        assert_eq!(t.get_root(), root);
+
+        // Failed part way through
+        let mut tx = t.transaction();
+        tx.remove(keys[0]);
+        tx.insert(keys[2], vec![0; 9]);
+        match tx.commit() {
+            Err(Error::ValueTooLarge { .. }) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(t.get_root(), root);
+        assert_eq!(t.get(keys[0]).unwrap(), Some(b"value-0".to_vec()));
+
+        let mut tx = t.transaction();
+        tx.remove(keys[0]);
+        tx.insert(keys[1], b"value-1".to_vec());
+        assert_eq!(tx.len(), 2);
+        tx.commit().unwrap();
+        assert_eq!(t.get(keys[0]).unwrap(), None);
+        assert_eq!(t.get(keys[1]).unwrap(), Some(b"value-1".to_vec()));
+        assert_eq!(t.committed().unwrap().root(), t.get_root());
+    }
+
+    #[test]
+    fn rolls_back_to_savepoints() {
+        let keys: Vec<Digest> = (0..3u8).map(|i| sha3(&[i])).collect();
+        let mut t = UrkelTree::open(test_dir("rolls_back_to_savepoints"));
+        t.insert(keys[0], b"value-0").unwrap();
+        t.commit().unwrap();
+
+        let mut tx = t.transaction();
+        tx.insert(keys[1], b"value-1".to_vec());
+        let first = tx.savepoint();
+        tx.insert(keys[1], b"value-2".to_vec());
+        tx.remove(keys[0]);
+        let second = tx.savepoint();
+        tx.insert(keys[2], b"value-2".to_vec());
+
+        tx.rollback_to(second);
+        assert_eq!(tx.get(keys[2]).unwrap(), None);
+        assert_eq!(tx.get(keys[0]).unwrap(), None);
+        tx.rollback_to(first);
+        assert_eq!(tx.get(keys[0]).unwrap(), Some(b"value-0".to_vec()));
+        assert_eq!(tx.get(keys[1]).unwrap(), Some(b"value-1".to_vec()));
+        assert_eq!(tx.len(), 1);
+        tx.rollback_to(first);
+        assert_eq!(tx.len(), 1);
+
+        tx.commit().unwrap();
+        assert_eq!(t.get(keys[0]).unwrap(), Some(b"value-0".to_vec()));
+        assert_eq!(t.get(keys[1]).unwrap(), Some(b"value-1".to_vec()));
+    }
+}
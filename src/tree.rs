@@ -1,353 +1,5053 @@
-use hashutils::{sha3, sha3_value, Digest};
-use nodes::Node;
-use proof::{has_bit, Proof, ProofType};
-use store::Store;
+use archive::ArchiveEntry;
+#[cfg(feature = "signing")]
+use ed25519_dalek::VerifyingKey;
+use expiry::{split_expiring, ExpiryNotes};
+use hashutils::{ct_eq, sha3, sha3_internal, sha3_sized_root, sha3_value_salted, Digest};
+use health::{self, HealthReport, ScanLimits};
+use hooks::{
+    CommitHook, CommitInfo, KeyChange, MergeOperator, Operation, RootUpdate, SlowHook,
+    SlowOperation,
+};
+use map::AuthenticatedMap;
+use metadata::MetaEntry;
+#[cfg(feature = "metrics")]
+use metrics::{Metrics, Probe};
+use nodes::{Node, NodePool, INTERNAL_NODE_SIZE};
+use proof::{has_bit, HistoricalProof, Proof, ProofType, SizedProof};
+use roots::EXTERNAL_META_INDEX;
+#[cfg(feature = "signing")]
+use signing::SignedCommit;
+use stats::{EstimatedStats, FileSpace, IoStats, Stats, WriteStats};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use store::{
+    check_open_mode, OpenMode, QuarantinedRegion, RootRetention, Store, StoreOptions, WriteLayout,
+    DEFAULT_FILE_MODE,
+};
+use transaction::Transaction;
+use valueindex::ValueIndex;
+use {Error, Result};
 
-/// Base-2 Merkle Trie
-#[derive(Default)]
-pub struct UrkelTree<'a> {
-    /// Root Node
-    root: Option<Node<'a>>,
-    /// Size in bits of the digest
-    keysize: usize,
-    /// FF Store
-    store: Store,
+/// Leading key bits `UrkelTree::rebuild_from_leaves` splits leaves by
+pub const REBUILD_SPLIT_BITS: u8 = 4;
+
+/// A page of keys from `UrkelTree::list`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page {
+    /// Keys in order
+    pub keys: Vec<Digest>,
+    /// Pass as `start_after` for the next page, `None` once there are no
+    /// more keys
+    pub next: Option<Digest>,
 }
 
-impl<'a> UrkelTree<'a> {
-    pub fn new() -> Self {
-        UrkelTree {
-            root: Some(Node::empty()),
-            keysize: 256,
-            store: Default::default(),
-        }
-    }
+/// One change in a batch passed to `UrkelTree::apply`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op<'a> {
+    /// Set the value of a key
+    Put(Digest, &'a [u8]),
+    /// Remove a key, if it's there
+    Delete(Digest),
+}
 
-    /// Return the root hash of the tree or zeros for None
-    pub fn get_root(&self) -> Digest {
-        self.root.as_ref().map_or(Digest::default(), |r| r.hash())
-    }
+// A registered `watch` on a key
+struct Watcher {
+    key: Digest,
+    sender: Sender<KeyChange>,
+}
 
-    /// Insert a new key/value pair into the Tree
-    pub fn insert(&mut self, nkey: Digest, value: &'a [u8]) {
-        let mut depth = 0;
-        let mut to_hash = Vec::<Node>::new();
-        let leaf_hash = sha3_value(nkey, value);
+/// The first place two trees differ, found by `UrkelTree::diff`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    /// Branches taken from the root, `true` for right
+    pub path: Vec<bool>,
+    /// Hash of the node in this tree
+    pub ours: Digest,
+    /// Hash of the node in the other tree
+    pub theirs: Digest,
+}
 
-        let mut root = self.root.take().unwrap();
-        loop {
-            match root {
-                Node::Empty {} => break,
-                Node::Hash { index, pos, .. } => {
-                    // Reach back to storage and convert the hash node to a leaf or internal
-                    root = self
-                        .store
-                        .resolve(index, pos, root.is_leaf())
-                        .expect("Failed to resolve Hashnode");
-                }
-                Node::Leaf {
-                    key, value, hash, ..
-                } => {
-                    if nkey == key {
-                        if leaf_hash == hash {
-                            self.root = Some(root);
-                            return;
+/// Key/value pairs of a tree in key order, read from the store as needed
+pub struct Iter<'t> {
+    tree: &'t mut UrkelTree,
+    /// Subtrees still to visit, leftmost on top
+    stack: Vec<Node>,
+}
+
+impl<'t> Iterator for Iter<'t> {
+    type Item = Result<(Digest, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            match node {
+                Node::Empty {} => {}
+                Node::Hash { index, pos, hash } => {
+                    match self.tree.store.resolve_expecting(index, pos, hash) {
+                        Ok(resolved) => self.stack.push(resolved),
+                        Err(e) => {
+                            // Nothing sensible follows a hole in the tree
+                            self.stack.clear();
+                            return Some(Err(e));
                         }
-                        break;
                     }
+                }
+                Node::Internal { left, right, .. } => {
+                    self.stack.push(*right);
+                    self.stack.push(*left);
+                }
+                Node::Leaf { key, .. } => {
+                    return Some(self.tree.leaf_value(&node).map(|value| (key, value)));
+                }
+            }
+        }
+        None
+    }
+}
 
-                    while has_bit(&nkey, depth) == has_bit(&key, depth) {
-                        to_hash.push(Node::Empty {});
-                        depth += 1;
-                    }
+impl<'t> IntoIterator for &'t mut UrkelTree {
+    type Item = Result<(Digest, Vec<u8>)>;
+    type IntoIter = Iter<'t>;
 
-                    to_hash.push(Node::leaf(key, value));
+    fn into_iter(self) -> Iter<'t> {
+        self.iter()
+    }
+}
 
-                    depth += 1;
-                    break;
-                }
-                Node::Internal { left, right, .. } => {
-                    if depth == self.keysize {
-                        panic!("Insert: missing node at depth {}", depth);
-                    }
+/// Keys that differ between two commits in key order, found by
+/// `UrkelTree::diff_roots`.  Subtrees the commits share are skipped
+/// without being read.
+pub struct Changes<'t> {
+    tree: &'t mut UrkelTree,
+    /// Old and new subtrees still to compare, with their depth, leftmost
+    /// on top
+    stack: Vec<(Node, Node, usize)>,
+}
 
-                    if has_bit(&nkey, depth) {
-                        to_hash.push(*left);
-                        root = *right;
+impl<'t> Changes<'t> {
+    fn compare(&mut self, old: Node, new: Node, depth: usize) -> Result<Option<KeyChange>> {
+        if old.hash() == new.hash() {
+            return Ok(None);
+        }
+        let old = self.tree.store.expand(old)?;
+        let new = self.tree.store.expand(new)?;
+        match (old, new) {
+            (
+                Node::Internal {
+                    left: l1,
+                    right: r1,
+                    ..
+                },
+                Node::Internal {
+                    left: l2,
+                    right: r2,
+                    ..
+                },
+            ) => {
+                self.stack.push((*r1, *r2, depth + 1));
+                self.stack.push((*l1, *l2, depth + 1));
+                Ok(None)
+            }
+            // The other side's leaf, if any, goes down with the half of
+            // the subtree its key falls in
+            (Node::Internal { left, right, .. }, other) => {
+                let (l2, r2) = self.split(other, depth);
+                self.stack.push((*right, r2, depth + 1));
+                self.stack.push((*left, l2, depth + 1));
+                Ok(None)
+            }
+            (other, Node::Internal { left, right, .. }) => {
+                let (l1, r1) = self.split(other, depth);
+                self.stack.push((r1, *right, depth + 1));
+                self.stack.push((l1, *left, depth + 1));
+                Ok(None)
+            }
+            (old @ Node::Leaf { .. }, new @ Node::Leaf { .. }) => {
+                let (k1, k2) = (old.key().unwrap(), new.key().unwrap());
+                if k1 != k2 {
+                    // Different keys, each changed on its own
+                    let (first, second) = if k1.0 < k2.0 {
+                        ((old, Node::empty()), (Node::empty(), new))
                     } else {
-                        to_hash.push(*right);
-                        root = *left
-                    }
-                    depth += 1;
+                        ((Node::empty(), new), (old, Node::empty()))
+                    };
+                    self.stack.push((second.0, second.1, depth));
+                    self.stack.push((first.0, first.1, depth));
+                    return Ok(None);
                 }
+                Ok(Some(KeyChange {
+                    key: k1,
+                    old: Some(self.tree.leaf_value(&old)?),
+                    new: Some(self.tree.leaf_value(&new)?),
+                }))
             }
+            (old @ Node::Leaf { .. }, _) => Ok(Some(KeyChange {
+                key: old.key().unwrap(),
+                old: Some(self.tree.leaf_value(&old)?),
+                new: None,
+            })),
+            (_, new) => Ok(Some(KeyChange {
+                key: new.key().unwrap(),
+                old: None,
+                new: Some(self.tree.leaf_value(&new)?),
+            })),
         }
+    }
 
-        // Start with a leaf of the new K/V
-        let mut new_root = Node::Leaf {
-            pos: 0,
-            index: 0,
-            hash: leaf_hash,
-            key: nkey,
-            value: Some(value),
-            vindex: 0,
-            vpos: 0,
-            vsize: 0,
-        };
-
-        // Walk the tree bottom up to form the new root
-        for n in to_hash.into_iter().rev() {
-            depth -= 1;
-            if has_bit(&nkey, depth) {
-                new_root = Node::Internal {
-                    left: Box::new(n),
-                    right: Box::new(new_root),
-                    index: 0,
-                    pos: 0,
-                    hash: Default::default(),
-                };
-            } else {
-                new_root = Node::Internal {
-                    left: Box::new(new_root),
-                    right: Box::new(n),
-                    index: 0,
-                    pos: 0,
-                    hash: Default::default(),
-                };
-            }
+    // A leaf or empty node, as the halves of a subtree split at `depth`
+    fn split(&self, node: Node, depth: usize) -> (Node, Node) {
+        match node.key() {
+            Some(key) if has_bit(&key, depth) => (Node::empty(), node),
+            Some(_) => (node, Node::empty()),
+            None => (Node::empty(), Node::empty()),
         }
-        // Set the new root
-        self.root = Some(new_root);
     }
+}
 
-    /// Get a value (if it exists) for a given key
-    pub fn get(&mut self, nkey: Digest) -> Option<Vec<u8>> {
-        let mut depth = 0;
-        // Clone here to deal with borrowing issues for resolve().
-        // If current is a ref, the return from 'resolve' has a lifetime
-        // issue.  Ideally walking the tree should be ref...
-        let mut current = self.root.clone().unwrap();
-        loop {
-            match current {
-                Node::Leaf {
-                    key,
-                    value,
-                    vindex,
-                    vpos,
-                    vsize,
-                    ..
-                } => {
-                    if nkey != key {
-                        return None;
-                    }
-                    if value.is_some() {
-                        return value.and_then(|v| Some(Vec::from(v)));
-                    }
+impl<'t> Iterator for Changes<'t> {
+    type Item = Result<KeyChange>;
 
-                    match self.store.retrieve(vindex, vpos, vsize) {
-                        Ok(v) => return Some(v),
-                        _ => return None,
-                    }
-                }
-                Node::Internal { left, right, .. } => {
-                    if has_bit(&nkey, depth) {
-                        current = *right;
-                    } else {
-                        current = *left;
-                    }
-                    depth += 1;
-                }
-                Node::Hash { index, pos, .. } => {
-                    let is_leaf = current.is_leaf();
-                    current = self
-                        .store
-                        .resolve(index, pos, is_leaf)
-                        .expect("Failed to resolve Hashnode");
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((old, new, depth)) = self.stack.pop() {
+            match self.compare(old, new, depth) {
+                Ok(None) => {}
+                Ok(Some(change)) => return Some(Ok(change)),
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(e));
                 }
-                _ => return None,
             }
         }
+        None
     }
+}
 
-    /// Prove a key does/does not exist in the Tree
-    pub fn prove(&mut self, nkey: Digest) -> Option<Proof> {
-        let mut depth = 0;
-        let mut proof = Proof::default();
+/// Reads of a tree as of its latest commit, ignoring changes staged
+/// since (see `UrkelTree::committed`)
+pub struct CommittedView<'t> {
+    tree: &'t mut UrkelTree,
+    root: Node,
+}
 
-        // Again the clone...same reason as get()
-        let mut current = self.root.clone().unwrap();
-        loop {
-            match current {
-                Node::Empty {} => break,
-                Node::Hash { index, pos, .. } => {
-                    let is_leaf = current.is_leaf();
-                    current = self
-                        .store
-                        .resolve(index, pos, is_leaf)
-                        .expect("Failed to resolve Hashnode");
-                }
-                Node::Internal { left, right, .. } => {
-                    if depth == self.keysize {
-                        panic!("Proof: missing node at depth {}", depth);
-                    }
+impl<'t> CommittedView<'t> {
+    /// Root hash of the latest commit
+    pub fn root(&self) -> Digest {
+        self.root.hash()
+    }
 
-                    if has_bit(&nkey, depth) {
-                        proof.push(left.hash());
-                        current = *right;
-                    } else {
-                        proof.push(right.hash());
-                        current = *left;
-                    }
+    /// The committed value for `nkey`
+    pub fn get(&mut self, nkey: Digest) -> Result<Option<Vec<u8>>> {
+        let root = self.root.clone();
+        self.tree.get_from(root, nkey)
+    }
 
-                    depth += 1;
-                }
-                Node::Leaf {
-                    key,
-                    vindex,
-                    vpos,
-                    vsize,
-                    ..
-                } => {
-                    let val = self
-                        .store
-                        .retrieve(vindex, vpos, vsize)
-                        .expect("Missing leaf value");
+    /// Prove `nkey` against the committed root
+    pub fn prove(&mut self, nkey: Digest) -> Result<Proof> {
+        let root = self.root.clone();
+        self.tree.build_proof(root, nkey)
+    }
 
-                    if nkey == key {
-                        proof.proof_type = ProofType::Exists;
-                        proof.value = Some(val);
-                    } else {
-                        proof.proof_type = ProofType::Collision;
-                        proof.key = Some(key);
-                        proof.hash = Some(sha3(&val));
-                    }
-                    break;
-                }
-            }
+    /// Iterate over the committed key/value pairs in key order
+    pub fn iter<'v>(&'v mut self) -> Iter<'v> {
+        Iter {
+            stack: vec![self.root.clone()],
+            tree: self.tree,
         }
+    }
+}
 
-        Some(proof)
+/// A read-only tree pinned to the commit it was taken at (see
+/// `UrkelTree::snapshot`)
+pub struct Snapshot {
+    tree: UrkelTree,
+}
+
+impl Snapshot {
+    /// Root hash of the commit
+    pub fn root(&self) -> Digest {
+        self.tree.get_root()
     }
 
-    // Commit subtree to storage and set a new Hashnode root.
-    pub fn commit(&mut self) {
-        // newroot is a node::hash
-        let newroot = self.root.take().map(|t| self.write(t));
-        self.store.commit(newroot.as_ref()).unwrap();
-        self.root = newroot;
+    /// The value of `nkey` as of the commit
+    pub fn get(&mut self, nkey: Digest) -> Result<Option<Vec<u8>>> {
+        self.tree.get(nkey)
     }
 
-    fn write(&mut self, mut node: Node<'a>) -> Node<'a> {
-        match node {
-            Node::Empty {} => Node::empty(),
-            Node::Internal {
-                pos,
-                index,
-                hash,
-                left,
-                right,
-            } => {
-                // Go left recursively
-                let left_result = self.write(*left);
-                // ...then right
-                let right_result = self.write(*right);
+    /// Prove `nkey` against the commit's root
+    pub fn prove(&mut self, nkey: Digest) -> Result<Proof> {
+        self.tree.prove(nkey)
+    }
 
-                // Now construct a new entry
-                let mut tempnode = Node::Internal {
-                    pos,
-                    index,
-                    hash,
-                    left: Box::new(left_result),
-                    right: Box::new(right_result),
-                };
+    /// Iterate over the commit's key/value pairs in key order
+    pub fn iter(&mut self) -> Iter<'_> {
+        self.tree.iter()
+    }
+}
 
-                // Calc hash for the hashnode
-                let hashed = tempnode.hash();
+impl<'t> IntoIterator for &'t mut Snapshot {
+    type Item = Result<(Digest, Vec<u8>)>;
+    type IntoIter = Iter<'t>;
 
-                // Only store if we haven't already
-                if index == 0 {
-                    self.store.write_node(&mut tempnode);
-                }
+    fn into_iter(self) -> Iter<'t> {
+        self.iter()
+    }
+}
 
-                let (newindex, newpos) = tempnode.index_and_position();
+/// Base-2 Merkle Trie
+pub struct UrkelTree {
+    /// Root Node
+    root: Option<Node>,
+    /// Size in bits of the digest
+    keysize: usize,
+    /// FF Store
+    store: Store,
+    /// Run around every commit
+    commit_hooks: Vec<Box<dyn CommitHook + Send>>,
+    /// Keys inserted since the last commit
+    changed: BTreeSet<[u8; 32]>,
+    /// Key and value bytes inserted since the tree was opened
+    logical_bytes: u64,
+    #[cfg(feature = "metrics")]
+    metrics: Metrics,
+    /// Subscribers to changes of particular keys
+    watchers: Vec<Watcher>,
+    /// Subscribers to every committed root
+    root_subscribers: Vec<Sender<RootUpdate>>,
+    /// Reused by insert for the siblings along the path
+    path_scratch: Vec<Node>,
+    /// Reused by insert and remove for the index, position and hash of
+    /// the internal nodes along the path
+    parent_scratch: Vec<(u16, u32, Digest)>,
+    /// Reused by prove for the sibling hashes
+    proof_scratch: Vec<Digest>,
+    /// Boxes for internal node children, reused across inserts and commits
+    pool: NodePool,
+    /// Nodes inserted or rebuilt since the last commit
+    dirty_nodes: usize,
+    /// Keys by value hash, when `value_index` is set
+    value_index: Option<ValueIndex>,
+    /// Combines the operands given to `merge_value`
+    merge_operator: Option<Box<dyn MergeOperator + Send>>,
+    /// Operands not yet folded into their keys' values, oldest first
+    merges: BTreeMap<[u8; 32], Vec<Vec<u8>>>,
+    /// Leaves in the working tree, when `count_leaves` is set
+    leaf_count: Option<u64>,
+    /// Keys holding expiring values, for `purge_expired`
+    expiry: ExpiryNotes,
+    /// Result of the scan made when opening, if `startup_scan` was set
+    health: Option<HealthReport>,
+    /// Live bytes per data file as of the latest commit, kept up to date
+    /// by each commit once `stats` has counted them
+    live: Option<BTreeMap<u16, u64>>,
+    /// Live bytes per data file of the commits before the latest one kept
+    /// by `StoreOptions::retention`, newest first, once counted
+    retained: Option<VecDeque<BTreeMap<u16, u64>>>,
+    /// Subtrees written by a commit that was interrupted after a
+    /// checkpoint, by hash, for the next commit to reuse
+    salvaged: HashMap<Digest, (u16, u32)>,
+    /// Subtrees written by the commit in progress whose parents haven't
+    /// been yet, when `StoreOptions::checkpoint_bytes` is set
+    completed: Option<Vec<(u16, u32)>>,
+}
 
-                // Now it *should* be stored
-                assert!(!tempnode.should_save(), "Didn't persist the node");
+impl UrkelTree {
+    /// Open the tree stored in `dir`, starting from its latest commit
+    pub fn open<P: AsRef<Path>>(dir: P) -> Self {
+        UrkelTree::open_with_options(dir, StoreOptions::default()).expect("Failed to open the tree")
+    }
 
-                // Return brand spanking new HashNode
-                Node::Hash {
-                    pos: newpos,
-                    index: newindex,
-                    hash: hashed,
-                }
-            }
-            Node::Leaf { .. } => {
-                // Write the value for the leaf node...
-                // ...then the node itself
-                self.store.write_value(&mut node);
-                self.store.write_node(&mut node);
+    /// Open the tree stored in `dir` with the given store options,
+    /// creating an empty store if there's none
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, options: StoreOptions) -> Result<Self> {
+        UrkelTree::open_in_mode(dir, options, OpenMode::OpenOrCreate)
+    }
 
-                // the index should be set!
-                assert!(!node.should_save(), "Didn't persist the node");
+    /// Same as `open_with_options`, for callers that want to say so
+    pub fn open_or_create<P: AsRef<Path>>(dir: P, options: StoreOptions) -> Result<Self> {
+        UrkelTree::open_with_options(dir, options)
+    }
 
-                // TODO: Cleanup aisle 5
-                // get the updated index/pos
-                let (newindex, newpos) = node.index_and_position();
-                let hashed = node.hash();
-                Node::Hash {
-                    pos: newpos,
-                    index: newindex,
-                    hash: hashed,
-                }
+    /// Open the tree stored in `dir`, failing with `NotFound` rather than
+    /// creating an empty store, e.g. when a volume didn't mount
+    pub fn open_existing<P: AsRef<Path>>(dir: P, options: StoreOptions) -> Result<Self> {
+        UrkelTree::open_in_mode(dir, options, OpenMode::OpenExisting)
+    }
+
+    /// Create an empty store in `dir`, failing with `AlreadyExists` if
+    /// there's one there
+    pub fn create_new<P: AsRef<Path>>(dir: P, options: StoreOptions) -> Result<Self> {
+        UrkelTree::open_in_mode(dir, options, OpenMode::CreateNew)
+    }
+
+    /// Restore the archive at `archive` (see `export_archive`) into `dir`,
+    /// which must be empty or not exist, and open the tree it holds
+    pub fn import_archive<P: AsRef<Path>, Q: AsRef<Path>>(
+        archive: P,
+        dir: Q,
+        options: StoreOptions,
+    ) -> Result<Self> {
+        let mode = options.file_mode.unwrap_or(DEFAULT_FILE_MODE);
+        Store::import_archive(archive.as_ref(), dir.as_ref(), mode)?;
+        UrkelTree::open_existing(dir, options)
+    }
+
+    /// Build a tree from `leaves` in a new store at `dir` and commit it,
+    /// the fast way to restore an export.  Leaves are split into
+    /// `1 << REBUILD_SPLIT_BITS` groups by their first bits, whose subtrees
+    /// are hashed on their own threads before being joined under the root.
+    /// A later value for a repeated key replaces the earlier one.
+    pub fn rebuild_from_leaves<P, I>(dir: P, options: StoreOptions, leaves: I) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (Digest, Vec<u8>)>,
+    {
+        let mut tree = UrkelTree::create_new(dir, options)?;
+        let max = tree.store.max_value_size();
+        let mut groups: Vec<BTreeMap<Digest, Vec<u8>>> = (0..1 << REBUILD_SPLIT_BITS)
+            .map(|_| BTreeMap::new())
+            .collect();
+        for (key, value) in leaves {
+            tree.check_key(&key)?;
+            if value.len() > max {
+                return Err(Error::ValueTooLarge {
+                    size: value.len(),
+                    max,
+                });
             }
-            Node::Hash { .. } => {
-                assert!(!node.should_save());
-                node
+            tree.logical_bytes += (key.0.len() + value.len()) as u64;
+            if let Some(ref mut index) = tree.value_index {
+                index.set(key, sha3(&value));
             }
+            groups[(key.0[0] >> (8 - REBUILD_SPLIT_BITS)) as usize].insert(key, value);
         }
-    }
-}
+        let total = groups.iter().map(|g| g.len()).sum::<usize>();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let salt = tree.store.leaf_salt();
+        let subtrees: Vec<Node> = thread::scope(|scope| {
+            let handles: Vec<_> = groups
+                .into_iter()
+                .map(|group| {
+                    scope.spawn(move || {
+                        let leaves: Vec<(Digest, Vec<u8>)> = group.into_iter().collect();
+                        build_subtree(leaves, REBUILD_SPLIT_BITS as usize, salt.as_ref())
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("Subtree builder panicked"))
+                .collect()
+        });
 
-    #[test]
-    fn tree_basics() {
-        let mut t = UrkelTree::new();
-        let key1 = sha3(b"name-1");
-        let key2 = sha3(b"name-2");
+        tree.root = Some(join_subtrees(subtrees));
+        if let Some(ref mut count) = tree.leaf_count {
+            *count = total as u64;
+        }
+        tree.commit()?;
+        Ok(tree)
+    }
 
-        t.insert(key1, b"value-1");
+    fn open_in_mode<P: AsRef<Path>>(dir: P, options: StoreOptions, open: OpenMode) -> Result<Self> {
+        check_open_mode(dir.as_ref(), open)?;
+        let mode = options.file_mode.unwrap_or(DEFAULT_FILE_MODE);
+        let value_index = if options.value_index {
+            Some(ValueIndex::open(dir.as_ref(), mode)?)
+        } else {
+            None
+        };
+        let expiry = ExpiryNotes::open(dir.as_ref(), mode)?;
+        let count_leaves = options.count_leaves;
+        let mut store = Store::open_with_options(dir, options)?;
+        let state = store.state().clone();
+        let root = store.get_root_node(&state)?;
 
-        for i in 3..40 {
-            let k = sha3(format!("name-{}", i).as_bytes());
-            t.insert(k, &[2u8; 20]);
+        let mut tree = UrkelTree::with_store(store, root, expiry);
+        tree.value_index = value_index;
+        if count_leaves || tree.store.records_leaf_count() {
+            tree.leaf_count = Some(0);
         }
+        if tree.store.checkpoint_bytes().is_some() {
+            tree.salvage();
+        }
+        if let Some(limits) = tree.store.startup_scan() {
+            tree.health = Some(health::scan(&mut tree.store, &limits));
+        }
+        tree.reset_leaf_count(&state)?;
+        if tree.value_index.as_ref().is_some_and(|i| i.is_empty()) {
+            // Turned on for a tree that already has keys
+            tree.rebuild_value_index()?;
+        }
+        Ok(tree)
+    }
 
-        t.insert(key2, b"value-2");
-
-        t.commit();
+    // A tree over `store` starting from `root`, with nothing else set up
+    fn with_store(store: Store, root: Node, expiry: ExpiryNotes) -> Self {
+        UrkelTree {
+            root: Some(root),
+            keysize: store.key_size() as usize * 8,
+            store,
+            commit_hooks: vec![],
+            changed: BTreeSet::new(),
+            logical_bytes: 0,
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::default(),
+            watchers: vec![],
+            root_subscribers: vec![],
+            path_scratch: vec![],
+            parent_scratch: vec![],
+            proof_scratch: vec![],
+            pool: NodePool::default(),
+            dirty_nodes: 0,
+            value_index: None,
+            expiry,
+            merge_operator: None,
+            merges: BTreeMap::new(),
+            leaf_count: None,
+            health: None,
+            live: None,
+            retained: None,
+            salvaged: HashMap::new(),
+            completed: None,
+        }
+    }
 
-        assert!(t.get_root() != Digest::default());
+    /// A read-only snapshot of the latest commit that doesn't borrow the
+    /// tree, so it can be read from another thread while this one goes on
+    /// changing and committing.  It reads the same data files, so it fails
+    /// once retention or compaction removes ones it needs.
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        let state = self.store.state().clone();
+        let root = self.store.get_root_node(&state)?;
+        Ok(self.snapshot_at(root))
+    }
 
-        assert_eq!(t.get(key1), Some(Vec::from("value-1")));
-        assert_eq!(t.get(key2), Some(Vec::from("value-2")));
+    /// A read-only snapshot of the earlier commit whose root is `root`,
+    /// found by walking back through the commits
+    pub fn checkout(&mut self, root: Digest) -> Result<Snapshot> {
+        let node = self.find_root(root)?;
+        Ok(self.snapshot_at(node))
+    }
 
-        // Test good proof
-        let prf = t.prove(key2);
-        assert!(prf.is_some());
-        if let Some(pt) = prf {
-            assert!(pt.proof_type == ProofType::Exists);
-            assert!(pt.value == Some(Vec::from("value-2")));
+    fn snapshot_at(&self, root: Node) -> Snapshot {
+        let mode = self.store.file_mode();
+        let expiry = ExpiryNotes::empty(self.store.dir(), mode);
+        Snapshot {
+            tree: UrkelTree::with_store(self.store.snapshot(), root, expiry),
         }
+    }
 
-        // Test deadend (doesn't exist)
-        let noproof = t.prove(sha3(b"doesn't exist"));
-        assert!(noproof.is_some());
-        if let Some(np) = noproof {
-            assert!(np.proof_type == ProofType::Deadend);
-            assert!(np.key.is_none());
+    // The root node of the commit whose root is `root`
+    fn find_root(&mut self, root: Digest) -> Result<Node> {
+        let mut meta = self.store.state().clone();
+        loop {
+            let node = self.store.get_root_node(&meta)?;
+            if node.hash() == root {
+                return Ok(node);
+            }
+            if meta.meta_index == 0 {
+                return Err(Error::UnknownRoot { root });
+            }
+            meta = self.store.previous_meta(&meta)?;
         }
     }
+
+    // Pick up the subtrees listed by a checkpoint after the latest commit.
+    // Anything unreadable is written again instead.
+    fn salvage(&mut self) {
+        for (index, pos) in self.store.checkpointed().unwrap_or_default() {
+            if let Ok(node) = self.store.resolve(index, pos, false) {
+                self.salvaged.insert(node.hash(), (index, pos));
+            }
+        }
+    }
+
+    /// Subtrees left by an interrupted commit that the next commit can
+    /// reuse (see `StoreOptions::checkpoint_bytes`)
+    pub fn salvaged_subtrees(&self) -> usize {
+        self.salvaged.len()
+    }
+
+    /// Number of leaves in the working tree.  Stores from before format
+    /// version 4 don't record it, so for them this walks the tree, reading
+    /// every internal node, unless `StoreOptions::count_leaves` is set.
+    pub fn leaf_count(&mut self) -> Result<u64> {
+        match self.leaf_count {
+            Some(count) => Ok(count),
+            None => {
+                let root = self.root.clone().unwrap();
+                self.count_leaves(root)
+            }
+        }
+    }
+
+    /// Number of keys in the working tree (see `leaf_count`)
+    pub fn len(&mut self) -> Result<u64> {
+        self.leaf_count()
+    }
+
+    /// Does the working tree hold no keys?
+    pub fn is_empty(&self) -> bool {
+        matches!(self.root, Some(Node::Empty {}))
+    }
+
+    /// Commitment to both the root and the number of leaves, for verifiers
+    /// that need to know the tree's size (see `prove_sized`)
+    pub fn sized_root(&mut self) -> Result<Digest> {
+        let leaf_count = self.leaf_count()?;
+        Ok(sha3_sized_root(self.get_root(), leaf_count))
+    }
+
+    /// Prove a key against `sized_root`
+    pub fn prove_sized(&mut self, nkey: Digest) -> Result<SizedProof> {
+        let leaf_count = self.leaf_count()?;
+        let proof = self.prove(nkey)?;
+        Ok(SizedProof {
+            proof,
+            root: self.get_root(),
+            leaf_count,
+        })
+    }
+
+    // Count the leaves under `node`.  Hash nodes record whether they're a
+    // leaf, so only internal nodes are read.
+    fn count_leaves(&mut self, node: Node) -> Result<u64> {
+        match node {
+            Node::Empty {} => Ok(0),
+            Node::Leaf { .. } => Ok(1),
+            Node::Hash { .. } if node.is_leaf() => Ok(1),
+            Node::Hash { hash, .. } => {
+                let (index, pos) = node.index_and_position();
+                let node = self.store.resolve_expecting(index, pos, hash)?;
+                self.count_leaves(node)
+            }
+            Node::Internal { left, right, .. } => {
+                Ok(self.count_leaves(*left)? + self.count_leaves(*right)?)
+            }
+        }
+    }
+
+    // Set the count of the working tree's leaves, if they're being counted,
+    // to that of the commit `meta` loaded.  Formats that don't record it
+    // have them counted afresh.
+    fn reset_leaf_count(&mut self, meta: &MetaEntry) -> Result<()> {
+        if self.leaf_count.is_some() {
+            if self.store.records_leaf_count() {
+                self.leaf_count = Some(meta.leaf_count);
+            } else {
+                let root = self.root.clone().unwrap();
+                self.leaf_count = Some(self.count_leaves(root)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the meta chain and sampled paths of the committed tree, within
+    /// `limits`.  Problems are reported rather than returned as errors.
+    pub fn scan(&mut self, limits: &ScanLimits) -> HealthReport {
+        health::scan(&mut self.store, limits)
+    }
+
+    /// The scan made when the tree was opened, if `StoreOptions::startup_scan`
+    /// was set
+    pub fn health_report(&self) -> Option<&HealthReport> {
+        self.health.as_ref()
+    }
+
+    /// Keys holding a value whose `sha3` is `value_hash`, in key order.
+    /// `None` unless `StoreOptions::value_index` is set.
+    pub fn keys_with_value(&self, value_hash: Digest) -> Option<Vec<Digest>> {
+        self.value_index
+            .as_ref()
+            .map(|index| index.keys(value_hash))
+    }
+
+    // Index the values of every key in the working tree afresh
+    fn rebuild_value_index(&mut self) -> Result<()> {
+        if let Some(mut index) = self.value_index.take() {
+            let mut entries = vec![];
+            for pair in self.iter() {
+                let (key, value) = pair?;
+                entries.push((key, sha3(&value)));
+            }
+            let result = index.rebuild(entries);
+            self.value_index = Some(index);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Return the root hash of the tree or zeros for None
+    pub fn get_root(&self) -> Digest {
+        match self.root {
+            Some(Node::Hash { hash, .. }) => hash,
+            Some(ref root) => {
+                let started = Instant::now();
+                let hash = root.hash();
+                self.store.report_elapsed(Operation::Hash, started);
+                hash
+            }
+            None => Digest::default(),
+        }
+    }
+
+    /// A view reading only what's been committed, while the tree itself
+    /// keeps reading its staged changes too
+    pub fn committed(&mut self) -> Result<CommittedView<'_>> {
+        let state = self.store.state().clone();
+        let root = self.store.get_root_node(&state)?;
+        Ok(CommittedView { tree: self, root })
+    }
+
+    /// Pack the store, as of the latest commit, into a single archive file
+    /// at `path`.  Uncommitted changes aren't included.
+    pub fn export_archive<P: AsRef<Path>>(&self, path: P) -> Result<Vec<ArchiveEntry>> {
+        self.store.export_archive(path.as_ref())
+    }
+
+    /// Start a transaction staging changes apart from the tree until it's
+    /// committed
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Nodes held in memory waiting for the next commit
+    pub fn dirty_nodes(&self) -> usize {
+        self.dirty_nodes
+    }
+
+    /// Return the root `commit()` would produce for the pending changes,
+    /// without writing anything.  Queued merges are folded in on a copy of
+    /// the path, which reads the values they merge into.
+    pub fn compute_root(&mut self) -> Result<Digest> {
+        if self.merges.is_empty() {
+            Ok(self.get_root())
+        } else {
+            self.merged_root()
+        }
+    }
+
+    /// Call `callback` whenever a store read, hash pass or commit takes
+    /// longer than `threshold`
+    pub fn on_slow_operation<F>(&mut self, threshold: Duration, callback: F)
+    where
+        F: Fn(&SlowOperation) + Send + Sync + 'static,
+    {
+        self.store.set_slow_hook(SlowHook {
+            threshold,
+            callback: Box::new(callback),
+        });
+    }
+
+    /// Register a hook run before and after every commit
+    pub fn on_commit<H>(&mut self, hook: H)
+    where
+        H: CommitHook + Send + 'static,
+    {
+        self.commit_hooks.push(Box::new(hook));
+    }
+
+    /// Nodes and values written, reads and cache lookups made and commits
+    /// since the tree was opened.  Cheap, so tests can assert I/O budgets.
+    pub fn io_stats(&self) -> IoStats {
+        self.store.io_stats()
+    }
+
+    /// Write amplification since the tree was opened, and live versus
+    /// dead bytes in each data file as of the latest commit.  The first
+    /// call walks the committed tree; after that commits track the
+    /// bytes they leave dead, so later calls don't.
+    pub fn stats(&mut self) -> Result<Stats> {
+        let mut files = self.store.file_space()?;
+        let live = self.latest_live()?;
+        for f in &mut files {
+            f.live_bytes = live.get(&f.index).cloned().unwrap_or(0);
+        }
+
+        Ok(Stats {
+            write: WriteStats {
+                logical_bytes: self.logical_bytes,
+                physical_bytes: self.store.physical_bytes(),
+            },
+            files,
+        })
+    }
+
+    /// Estimate the number of leaves, their mean depth and mean value size
+    /// from `samples` descents, taking a pseudo-random branch wherever both
+    /// children are non-empty.  A leaf reached with probability `p` stands
+    /// for `1 / p` leaves, which makes the estimates unbiased.  The
+    /// branches are drawn from the sample number, so the same tree always
+    /// gives the same estimates.
+    pub fn estimate_stats(&mut self, samples: usize) -> Result<EstimatedStats> {
+        let root = self.root.clone().unwrap();
+        let mut stats = EstimatedStats::default();
+        let (mut weights, mut depths, mut sizes) = (0.0, 0.0, 0.0);
+        for n in 0..samples as u64 {
+            let bits = sha3(&n.to_le_bytes());
+            let mut current = root.clone();
+            let (mut depth, mut weight) = (0, 1.0);
+            let size = loop {
+                match current {
+                    Node::Empty {} => break None,
+                    Node::Hash { index, pos, hash } => {
+                        current = self.store.resolve_expecting(index, pos, hash)?;
+                    }
+                    Node::Leaf {
+                        ref value, vsize, ..
+                    } => break Some(value.as_ref().map_or(vsize as usize, |v| v.len())),
+                    Node::Internal { left, right, .. } => {
+                        current = match (*left, *right) {
+                            (Node::Empty {}, only) | (only, Node::Empty {}) => only,
+                            (left, right) => {
+                                weight *= 2.0;
+                                if has_bit(&bits, depth) {
+                                    right
+                                } else {
+                                    left
+                                }
+                            }
+                        };
+                        depth += 1;
+                    }
+                }
+            };
+            match size {
+                Some(size) => {
+                    stats.samples += 1;
+                    weights += weight;
+                    depths += weight * depth as f64;
+                    sizes += weight * size as f64;
+                }
+                // The tree is empty
+                None => break,
+            }
+        }
+        if stats.samples > 0 {
+            stats.entries = weights / stats.samples as f64;
+            stats.average_depth = depths / weights;
+            stats.average_value_size = sizes / weights;
+        }
+        Ok(stats)
+    }
+
+    // Add the stored size of everything under a committed node
+    fn count_live(&mut self, node: &Node, files: &mut [FileSpace]) -> Result<()> {
+        if let Node::Hash { index, pos, .. } = *node {
+            match self.store.resolve(index, pos, node.is_leaf())? {
+                Node::Internal { left, right, .. } => {
+                    add_live(files, index, INTERNAL_NODE_SIZE as u64);
+                    self.count_live(&left, files)?;
+                    self.count_live(&right, files)?;
+                }
+                Node::Leaf { vindex, vsize, .. } => {
+                    add_live(files, index, self.store.leaf_size() as u64);
+                    add_live(files, vindex, u64::from(vsize));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Live bytes per data file of the latest commit, counting them if
+    // they aren't being tracked yet
+    fn latest_live(&mut self) -> Result<BTreeMap<u16, u64>> {
+        if self.live.is_none() {
+            let state = self.store.state().clone();
+            self.live = Some(self.count_live_at(&state)?);
+        }
+        Ok(self.live.clone().unwrap())
+    }
+
+    // Stored bytes per data file that the commit `meta` reaches
+    fn count_live_at(&mut self, meta: &MetaEntry) -> Result<BTreeMap<u16, u64>> {
+        let mut files = self.store.file_space()?;
+        if meta.meta_index != 0 {
+            add_live(&mut files, meta.meta_index, self.store.meta_size() as u64);
+            let root = self.store.get_root_node(meta)?;
+            self.count_live(&root, &mut files)?;
+        }
+        Ok(files.iter().map(|f| (f.index, f.live_bytes)).collect())
+    }
+
+    // Delete the data files none of the roots kept by the retention policy
+    // reach.  `previous` is the live bytes of the commit before the latest,
+    // if they were being tracked.
+    fn enforce_retention(&mut self, previous: Option<BTreeMap<u16, u64>>) -> Result<()> {
+        let keep = match self.store.retention().roots() {
+            Some(keep) => keep,
+            None => return Ok(()),
+        };
+        match (self.retained.as_mut(), previous) {
+            (Some(retained), Some(previous)) => {
+                retained.push_front(previous);
+                retained.truncate(keep - 1);
+            }
+            _ => self.retained = None,
+        }
+
+        let state = self.store.state().clone();
+        if self.retained.is_none() {
+            // Count the kept roots before the latest, once
+            let mut retained = VecDeque::new();
+            let mut meta = self.store.previous_meta(&state)?;
+            while retained.len() + 1 < keep && meta.meta_index != 0 {
+                retained.push_back(self.count_live_at(&meta)?);
+                meta = self.store.previous_meta(&meta)?;
+            }
+            self.retained = Some(retained);
+        }
+
+        let latest = self.latest_live()?;
+        let retained = self.retained.as_ref().unwrap();
+        let reached = |index: &u16| {
+            latest.get(index).is_some_and(|b| *b > 0)
+                || retained
+                    .iter()
+                    .any(|r| r.get(index).is_some_and(|b| *b > 0))
+        };
+        let dead: Vec<u16> = self
+            .store
+            .data_files()?
+            .into_iter()
+            .filter(|i| !reached(i))
+            .collect();
+        if dead.is_empty() {
+            return Ok(());
+        }
+        self.store.remove_data_files(&dead)?;
+        self.live = None;
+        Ok(())
+    }
+
+    // Move the live bytes counted for the commit `old` over to the latest
+    // commit: whatever only `old` reached is now dead
+    fn track_live(&mut self, old: &MetaEntry) -> Result<()> {
+        let mut live = match self.live.take() {
+            Some(live) => live,
+            None => return Ok(()),
+        };
+        let new = self.store.state().clone();
+        // Records in the roots file aren't in any data file
+        if old.meta_index != 0 && old.meta_index != EXTERNAL_META_INDEX {
+            shift_live(
+                &mut live,
+                old.meta_index,
+                self.store.meta_size() as u64,
+                false,
+            );
+        }
+        if new.meta_index != EXTERNAL_META_INDEX {
+            shift_live(
+                &mut live,
+                new.meta_index,
+                self.store.meta_size() as u64,
+                true,
+            );
+        }
+
+        let before = self.store.get_root_node(old)?;
+        let after = self.store.get_root_node(&new)?;
+        self.move_live(before, after, &mut live)?;
+        self.live = Some(live);
+        Ok(())
+    }
+
+    // Walk two committed trees together below where their hashes differ,
+    // taking the first's nodes off `live` and adding the second's
+    fn move_live(&mut self, old: Node, new: Node, live: &mut BTreeMap<u16, u64>) -> Result<()> {
+        if old.hash() == new.hash() {
+            return Ok(());
+        }
+        let (old_left, old_right) = self.stored_children(old, live, false)?;
+        let (new_left, new_right) = self.stored_children(new, live, true)?;
+        self.move_live(old_left, new_left, live)?;
+        self.move_live(old_right, new_right, live)
+    }
+
+    // Count a committed node in or out of `live`, returning its children
+    fn stored_children(
+        &mut self,
+        node: Node,
+        live: &mut BTreeMap<u16, u64>,
+        adding: bool,
+    ) -> Result<(Node, Node)> {
+        if let Node::Hash { index, pos, .. } = node {
+            match self.store.resolve(index, pos, node.is_leaf())? {
+                Node::Internal { left, right, .. } => {
+                    shift_live(live, index, INTERNAL_NODE_SIZE as u64, adding);
+                    return Ok((*left, *right));
+                }
+                Node::Leaf { vindex, vsize, .. } => {
+                    shift_live(live, index, self.store.leaf_size() as u64, adding);
+                    shift_live(live, vindex, u64::from(vsize), adding);
+                }
+                _ => {}
+            }
+        }
+        Ok((Node::empty(), Node::empty()))
+    }
+
+    /// Reclaim the space in data files numbered `up_to_index` and older.
+    /// Whatever the latest commit still reaches in them is rewritten to the
+    /// current file, along with the nodes above it, and committed; then the
+    /// files are deleted.  The file being appended to is never touched.
+    ///
+    /// Pending changes are committed first.  Reads every node of the
+    /// committed tree.  Commits before the compaction may need the deleted
+    /// files, so they're dropped from the history: there's no previous
+    /// root to revert to and saved versions from before can't be loaded.
+    /// Returns how many files were deleted.
+    pub fn compact_files(&mut self, up_to_index: u16) -> Result<usize> {
+        if self.dirty_nodes > 0 {
+            self.commit()?;
+        }
+        let up_to_index = up_to_index.min(self.store.current_index() - 1);
+        let state = self.store.state().clone();
+        if up_to_index == 0 || state.meta_index == 0 {
+            return Ok(0);
+        }
+
+        let root = self.store.get_root_node(&state)?;
+        let moved = self.relocate(root, up_to_index)?;
+        let newroot = self.write(moved);
+        let removed = self.store.compact_files(&newroot, up_to_index)?;
+        self.root = Some(newroot);
+        self.live = None;
+        self.retained = None;
+        Ok(removed)
+    }
+
+    // Unstore every committed node under `node` that lives in a file
+    // numbered `up_to_index` or lower, and every node above those, so
+    // `write` stores them afresh.  Values in those files are read back
+    // into their leaves.
+    fn relocate(&mut self, node: Node, up_to_index: u16) -> Result<Node> {
+        let (index, pos, hash) = match node {
+            Node::Hash { index, pos, hash } => (index, pos, hash),
+            n => return Ok(n),
+        };
+        match self.store.resolve_expecting(index, pos, hash)? {
+            Node::Internal { left, right, .. } => {
+                let left = self.relocate(*left, up_to_index)?;
+                let right = self.relocate(*right, up_to_index)?;
+                let moved = |n: &Node| !matches!(n, Node::Hash { .. } | Node::Empty {});
+                if index > up_to_index && !moved(&left) && !moved(&right) {
+                    return Ok(node);
+                }
+                Ok(Node::Internal {
+                    index: 0,
+                    pos: 0,
+                    hash,
+                    left: self.pool.boxed(left),
+                    right: self.pool.boxed(right),
+                })
+            }
+            Node::Leaf {
+                key,
+                vindex,
+                vpos,
+                vsize,
+                ..
+            } if vindex <= up_to_index => {
+                let value = self.store.retrieve(vindex, vpos, vsize)?;
+                Ok(Node::Leaf {
+                    index: 0,
+                    pos: 0,
+                    hash,
+                    key,
+                    value: Some(value),
+                    vindex: 0,
+                    vpos: 0,
+                    vsize: 0,
+                })
+            }
+            Node::Leaf {
+                key,
+                vindex,
+                vpos,
+                vsize,
+                ..
+            } if index <= up_to_index => Ok(Node::Leaf {
+                index: 0,
+                pos: 0,
+                hash,
+                key,
+                value: None,
+                vindex,
+                vpos,
+                vsize,
+            }),
+            _ => Ok(node),
+        }
+    }
+
+    /// Latency and store read histograms for get, prove, insert and commit
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn reset_metrics(&mut self) {
+        self.metrics = Metrics::default();
+    }
+
+    /// Watch a key.  Every commit that changes its value sends the old and
+    /// new values to the returned receiver.
+    pub fn watch(&mut self, key: Digest) -> Receiver<KeyChange> {
+        let (sender, receiver) = channel();
+        self.watchers.push(Watcher { key, sender });
+        receiver
+    }
+
+    /// Subscribe to commits.  Every commit sends its root and meta record
+    /// location, numbered by the commits made since the tree was opened
+    /// (so numbering restarts with each process), to the returned
+    /// receiver.
+    pub fn subscribe_roots(&mut self) -> Receiver<RootUpdate> {
+        let (sender, receiver) = channel();
+        self.root_subscribers.push(sender);
+        receiver
+    }
+
+    /// Return the root hash of the commit before the latest one, if any
+    pub fn previous_root(&mut self) -> Option<Digest> {
+        let last = self.store.last_state().clone();
+        if last.meta_index == 0 {
+            return None;
+        }
+        self.store.get_root_node(&last).ok().map(|n| n.hash())
+    }
+
+    /// Revert to the commit before the latest one, discarding any
+    /// uncommitted changes.  The revert is itself recorded as a commit.
+    pub fn revert_to_previous_commit(&mut self) -> Result<()> {
+        self.store.revert()?;
+        self.live = None;
+        self.retained = None;
+        let state = self.store.state().clone();
+        self.root = Some(self.store.get_root_node(&state)?);
+        self.changed.clear();
+        self.dirty_nodes = 0;
+        self.merges.clear();
+        self.reset_leaf_count(&state)?;
+        self.rebuild_value_index()
+    }
+
+    /// Empty the working tree, discarding any uncommitted changes and the
+    /// expiries noted by `insert_expiring`.  The next commit records the
+    /// empty root; earlier commits and their data files are left alone.
+    pub fn clear(&mut self) -> Result<()> {
+        self.root = Some(Node::empty());
+        for watcher in &self.watchers {
+            self.changed.insert(watcher.key.0);
+        }
+        self.dirty_nodes = 0;
+        self.merges.clear();
+        if let Some(ref mut count) = self.leaf_count {
+            *count = 0;
+        }
+        self.expiry.clear()?;
+        self.rebuild_value_index()
+    }
+
+    /// Details of the latest commit, or of the empty tree if there's none
+    pub fn last_commit(&mut self) -> Result<CommitInfo> {
+        let state = self.store.state().clone();
+        let root = self.store.get_root_node(&state)?.hash();
+        Ok(CommitInfo {
+            root,
+            meta_index: state.meta_index,
+            meta_pos: state.meta_pos,
+        })
+    }
+
+    /// Make an earlier commit the working tree, discarding any uncommitted
+    /// changes.  Unlike a revert nothing is written; the next commit just
+    /// builds on it.
+    pub fn load_commit(&mut self, info: &CommitInfo) -> Result<()> {
+        let meta = self.store.meta_at(info.meta_index, info.meta_pos)?;
+        let root = self.store.get_root_node(&meta)?;
+        if root.hash() != info.root {
+            return Err(Error::HashMismatch {
+                index: meta.root_index,
+                pos: meta.root_pos,
+                expected: info.root,
+                actual: root.hash(),
+            });
+        }
+        self.root = Some(root);
+        self.changed.clear();
+        self.dirty_nodes = 0;
+        self.merges.clear();
+        self.reset_leaf_count(&meta)?;
+        self.rebuild_value_index()
+    }
+
+    /// Check every commit in the root history was signed by `key`.
+    /// Returns the signed commits, newest first.
+    #[cfg(feature = "signing")]
+    pub fn verify_commit_history(&mut self, key: &VerifyingKey) -> Result<Vec<SignedCommit>> {
+        self.store.verify_history(key)
+    }
+
+    /// Does `other` hold exactly the same nodes as this tree?
+    pub fn structurally_equal(&mut self, other: &mut UrkelTree) -> Result<bool> {
+        Ok(self.diff(other)?.is_none())
+    }
+
+    /// Walk this tree and `other` together and report the first node
+    /// that differs, or None if the trees are identical
+    pub fn diff(&mut self, other: &mut UrkelTree) -> Result<Option<Divergence>> {
+        let ours = self.root.clone().unwrap_or_else(Node::empty);
+        let theirs = other.root.clone().unwrap_or_else(Node::empty);
+        self.diff_nodes(other, ours, theirs, &mut vec![])
+    }
+
+    /// Iterate over the keys whose values differ between the commits with
+    /// roots `old` and `new`, in key order
+    pub fn diff_roots<'t>(&'t mut self, old: Digest, new: Digest) -> Result<Changes<'t>> {
+        let old = self.find_root(old)?;
+        let new = self.find_root(new)?;
+        Ok(Changes {
+            tree: self,
+            stack: vec![(old, new, 0)],
+        })
+    }
+
+    fn diff_nodes(
+        &mut self,
+        other: &mut UrkelTree,
+        ours: Node,
+        theirs: Node,
+        path: &mut Vec<bool>,
+    ) -> Result<Option<Divergence>> {
+        if ours.hash() == theirs.hash() {
+            return Ok(None);
+        }
+        match (self.store.expand(ours)?, other.store.expand(theirs)?) {
+            (
+                Node::Internal {
+                    left: l1,
+                    right: r1,
+                    ..
+                },
+                Node::Internal {
+                    left: l2,
+                    right: r2,
+                    ..
+                },
+            ) => {
+                path.push(false);
+                if let Some(d) = self.diff_nodes(other, *l1, *l2, path)? {
+                    return Ok(Some(d));
+                }
+                path.pop();
+                path.push(true);
+                if let Some(d) = self.diff_nodes(other, *r1, *r2, path)? {
+                    return Ok(Some(d));
+                }
+                path.pop();
+                Ok(None)
+            }
+            (ours, theirs) => Ok(Some(Divergence {
+                path: path.clone(),
+                ours: ours.hash(),
+                theirs: theirs.hash(),
+            })),
+        }
+    }
+
+    // Keys can't have bits set past the store's key size
+    fn check_key(&self, key: &Digest) -> Result<()> {
+        if key.0[self.keysize / 8..].iter().any(|b| *b != 0) {
+            return Err(Error::KeyTooWide {
+                key_size: (self.keysize / 8) as u8,
+            });
+        }
+        Ok(())
+    }
+
+    /// Width of the tree's keys in bits (see `StoreOptions::key_size`)
+    pub fn key_bits(&self) -> usize {
+        self.keysize
+    }
+
+    /// The key a raw key is stored under: its sha3 hash, cut to the tree's
+    /// key width.  Verifiers of `prove_raw` proofs check against this.
+    pub fn hash_key(&self, raw: &[u8]) -> Digest {
+        let mut key = sha3(raw);
+        for b in &mut key.0[self.keysize / 8..] {
+            *b = 0;
+        }
+        key
+    }
+
+    /// Insert a value under the hash of `raw` (see `hash_key`)
+    pub fn insert_raw(&mut self, raw: &[u8], value: &[u8]) -> Result<()> {
+        let key = self.hash_key(raw);
+        self.insert(key, value)
+    }
+
+    /// Get the value inserted with `insert_raw`
+    pub fn get_raw(&mut self, raw: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = self.hash_key(raw);
+        self.get(key)
+    }
+
+    /// Prove the hash of `raw` does/does not exist in the tree
+    pub fn prove_raw(&mut self, raw: &[u8]) -> Result<Proof> {
+        let key = self.hash_key(raw);
+        self.prove(key)
+    }
+
+    /// Regions found to be corrupt (see `StoreOptions::quarantine`)
+    pub fn quarantined(&self) -> &[QuarantinedRegion] {
+        self.store.quarantined()
+    }
+
+    /// Forget the quarantined regions, e.g. once they've been repaired
+    pub fn clear_quarantine(&mut self) {
+        self.store.clear_quarantine()
+    }
+
+    // Convert a hashnode to the leaf or internal it points to.
+    // Corruption is reported against the key being looked up.
+    fn resolve(&mut self, nkey: Digest, node: &Node) -> Result<Node> {
+        let (index, pos) = node.index_and_position();
+        self.store
+            .resolve_expecting(index, pos, node.hash())
+            .map_err(|e| e.for_key(nkey))
+    }
+
+    // Does a leaf's key match the one being looked up?
+    fn same_key(&self, nkey: &Digest, key: &Digest) -> bool {
+        if self.store.constant_time_keys() {
+            ct_eq(nkey, key)
+        } else {
+            nkey == key
+        }
+    }
+
+    // Value of a leaf, from memory or the store
+    fn leaf_value(&mut self, node: &Node) -> Result<Vec<u8>> {
+        match node {
+            Node::Leaf { value: Some(v), .. } => Ok(v.to_vec()),
+            Node::Leaf {
+                key,
+                vindex,
+                vpos,
+                vsize,
+                ..
+            } => self
+                .store
+                .retrieve(*vindex, *vpos, *vsize)
+                .map_err(|e| e.for_key(*key)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Insert a new key/value pair into the Tree, copying the value
+    pub fn insert(&mut self, nkey: Digest, value: &[u8]) -> Result<()> {
+        self.put(nkey, value.to_vec())
+    }
+
+    /// Insert a key/value pair, handing the value over to the tree
+    pub fn insert_owned(&mut self, nkey: Digest, value: Vec<u8>) -> Result<()> {
+        self.put(nkey, value)
+    }
+
+    /// Set `nkey` to `new`, or remove it if `new` is `None`, but only if
+    /// its current value is `expected` (`None` for absent).  Otherwise
+    /// fails with `Error::CompareFailed` carrying the current value.  The
+    /// check is made on the write's own walk down the tree.
+    pub fn compare_and_swap(
+        &mut self,
+        nkey: Digest,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let condition = if self.merge_operator.is_some() && self.merges.contains_key(&nkey.0) {
+            // The queued operands have to be folded in to know the value
+            let current = self.get(nkey)?;
+            if current.as_deref() != expected {
+                return Err(Error::CompareFailed { key: nkey, current });
+            }
+            Condition::Always
+        } else {
+            // Checked on the write's own walk
+            Condition::IfValue(expected)
+        };
+        let outcome = match new {
+            Some(value) => self.write_leaf(nkey, value, condition)?,
+            None => self.remove_where(nkey, condition)?,
+        };
+        match outcome {
+            Outcome::Mismatch(current) => Err(Error::CompareFailed { key: nkey, current }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Insert every key/value pair in `other`'s working tree.  Where both
+    /// trees hold a key with different values, `resolve` is passed the key,
+    /// this tree's value and then `other`'s, and returns the value to keep.
+    /// Returns how many keys changed.
+    pub fn merge_from<F>(&mut self, other: &mut UrkelTree, mut resolve: F) -> Result<usize>
+    where
+        F: FnMut(Digest, &[u8], &[u8]) -> Vec<u8>,
+    {
+        let mut changed = 0;
+        for pair in other.iter() {
+            let (key, theirs) = pair?;
+            let value = match self.get(key)? {
+                None => theirs,
+                Some(ours) if ours == theirs => continue,
+                Some(ours) => {
+                    let value = resolve(key, &ours, &theirs);
+                    if value == ours {
+                        continue;
+                    }
+                    value
+                }
+            };
+            self.insert_owned(key, value)?;
+            changed += 1;
+        }
+        Ok(changed)
+    }
+
+    /// `merge_from` the earlier commit whose root is `root`
+    pub fn merge_from_root<F>(&mut self, root: Digest, resolve: F) -> Result<usize>
+    where
+        F: FnMut(Digest, &[u8], &[u8]) -> Vec<u8>,
+    {
+        let mut snapshot = self.checkout(root)?;
+        self.merge_from(&mut snapshot.tree, resolve)
+    }
+
+    /// Combine `merge_value` operands with this operator
+    pub fn set_merge_operator<M>(&mut self, operator: M)
+    where
+        M: MergeOperator + Send + 'static,
+    {
+        self.merge_operator = Some(Box::new(operator));
+    }
+
+    /// Queue `operand` to be merged into the value of `nkey` by the merge
+    /// operator.  `get` sees the merged value straight away; the tree
+    /// itself only changes when the commit folds the operands in, so until
+    /// then roots and proofs don't reflect them.  Inserting or removing the
+    /// key drops its queued operands.
+    pub fn merge_value(&mut self, nkey: Digest, operand: Vec<u8>) -> Result<()> {
+        if self.merge_operator.is_none() {
+            return Err(Error::NoMergeOperator);
+        }
+        self.merges.entry(nkey.0).or_default().push(operand);
+        Ok(())
+    }
+
+    // Fold queued operands into `existing`
+    fn fold_merges(&self, nkey: Digest, existing: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        match (self.merges.get(&nkey.0), self.merge_operator.as_ref()) {
+            (Some(operands), Some(operator)) => operands.iter().fold(existing, |value, operand| {
+                Some(operator.merge(nkey, value.as_deref(), operand))
+            }),
+            _ => existing,
+        }
+    }
+
+    // Insert the merged values of every key with queued operands
+    fn resolve_merges(&mut self) -> Result<()> {
+        for key in self.merges.keys().cloned().collect::<Vec<_>>() {
+            let key = Digest(key);
+            let existing = self.get_from(self.root.clone().unwrap(), key)?;
+            if let Some(value) = self.fold_merges(key, existing) {
+                self.insert_owned(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The root resolving the queued merges would give, leaving the tree
+    // and the merges as they are
+    fn merged_root(&mut self) -> Result<Digest> {
+        let root = self.root.clone();
+        let changed = self.changed.clone();
+        let merges = self.merges.clone();
+        let (logical_bytes, dirty_nodes, leaf_count) =
+            (self.logical_bytes, self.dirty_nodes, self.leaf_count);
+        let value_index = self.value_index.take();
+
+        let result = self.resolve_merges().map(|_| self.get_root());
+
+        self.root = root;
+        self.changed = changed;
+        self.merges = merges;
+        self.logical_bytes = logical_bytes;
+        self.dirty_nodes = dirty_nodes;
+        self.leaf_count = leaf_count;
+        self.value_index = value_index;
+        result
+    }
+
+    fn put(&mut self, nkey: Digest, value: Vec<u8>) -> Result<()> {
+        self.write_leaf(nkey, value, Condition::Always).map(|_| ())
+    }
+
+    // Insert `value` if `condition` holds
+    fn write_leaf(
+        &mut self,
+        nkey: Digest,
+        value: Vec<u8>,
+        condition: Condition,
+    ) -> Result<Outcome> {
+        self.check_key(&nkey)?;
+        if let Condition::IfAbsent = condition {
+            if self.merge_operator.is_some() && self.merges.contains_key(&nkey.0) {
+                return Ok(Outcome::Present);
+            }
+        }
+        if let Some(budget) = self.store.max_dirty_nodes() {
+            if self.dirty_nodes >= budget {
+                return Err(Error::OutOfBudget {
+                    nodes: self.dirty_nodes,
+                    budget,
+                });
+            }
+        }
+        let max = self.store.max_value_size();
+        if value.len() > max {
+            return Err(Error::ValueTooLarge {
+                size: value.len(),
+                max,
+            });
+        }
+
+        #[cfg(feature = "metrics")]
+        let probe = Probe::start(self.store.reads());
+
+        let size = (nkey.0.len() + value.len()) as u64;
+        let value_hash = self.value_index.as_ref().map(|_| sha3(&value));
+        let result = self.insert_leaf(nkey, value, condition);
+        if let Ok(Outcome::Done(added)) = result {
+            self.merges.remove(&nkey.0);
+            self.changed.insert(nkey.0);
+            self.logical_bytes += size;
+            if let (true, Some(count)) = (added, self.leaf_count.as_mut()) {
+                *count += 1;
+            }
+            if let (Some(index), Some(hash)) = (self.value_index.as_mut(), value_hash) {
+                index.set(nkey, hash);
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        self.metrics.insert.record(probe, self.store.reads());
+        result
+    }
+
+    /// Insert `value` for `nkey` only if the tree doesn't already hold the
+    /// key, checking as part of the insert's walk.  Returns whether it was
+    /// inserted.
+    pub fn insert_if_absent(&mut self, nkey: Digest, value: Vec<u8>) -> Result<bool> {
+        match self.write_leaf(nkey, value, Condition::IfAbsent)? {
+            Outcome::Done(_) => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    /// Insert a value made by `expiry::expiring_value`.  The expiry is
+    /// part of the value, so proofs show it, and `purge_expired` removes
+    /// the key once it's due.
+    pub fn insert_expiring(&mut self, nkey: Digest, value: &[u8]) -> Result<()> {
+        let (expires_at, _) = split_expiring(value)?;
+        self.insert(nkey, value)?;
+        self.expiry.note(nkey, expires_at)
+    }
+
+    /// Remove every key whose expiring value is due by `now`, and commit.
+    /// Any other uncommitted changes go into the same commit.  Returns the
+    /// number of keys removed.
+    pub fn purge_expired(&mut self, now: u64) -> Result<usize> {
+        let mut due = vec![];
+        for (expires_at, key) in self.expiry.due(now) {
+            // Skip keys that have since been changed or removed
+            let current = match self.get(key)? {
+                Some(value) => split_expiring(&value).ok().map(|(e, _)| e),
+                None => None,
+            };
+            if current == Some(expires_at) {
+                due.push(key);
+            }
+        }
+        let removed = self.remove_batch(&due)?;
+        if removed > 0 {
+            self.commit()?;
+        }
+        self.expiry.forget_due(now)?;
+        Ok(removed)
+    }
+
+    // Insert a leaf if `condition` holds.  Otherwise, or if the key
+    // already has the value, the path is left as it was.
+    fn insert_leaf(
+        &mut self,
+        nkey: Digest,
+        value: Vec<u8>,
+        condition: Condition,
+    ) -> Result<Outcome> {
+        let mut depth = 0;
+        let mut added = true;
+        let mut to_hash = mem::take(&mut self.path_scratch);
+        let mut parents = mem::take(&mut self.parent_scratch);
+        let leaf_hash = sha3_value_salted(self.store.leaf_salt().as_ref(), nkey, &value);
+
+        let mut root = self.root.take().unwrap();
+        let stop = loop {
+            match root {
+                Node::Empty {} => break refuse_absent(condition),
+                Node::Hash { .. } => {
+                    // Reach back to storage and convert the hash node to a leaf or internal
+                    match self.resolve(nkey, &root) {
+                        Ok(node) => root = node,
+                        Err(e) => break Some(Err(e)),
+                    }
+                }
+                Node::Leaf {
+                    key, hash, index, ..
+                } => {
+                    if self.same_key(&nkey, &key) {
+                        let stop = match condition {
+                            Condition::Always if hash == leaf_hash => {
+                                Some(Ok(Outcome::Done(false)))
+                            }
+                            Condition::Always => None,
+                            Condition::IfAbsent => Some(Ok(Outcome::Present)),
+                            Condition::IfValue(expected) => {
+                                self.refuse_present(nkey, &root, expected)
+                            }
+                        };
+                        if stop.is_none() {
+                            added = false;
+                            if index == 0 {
+                                // Replacing a leaf that was never written
+                                self.dirty_nodes -= 1;
+                            }
+                        }
+                        break stop;
+                    }
+
+                    let stop = refuse_absent(condition);
+                    if stop.is_none() {
+                        while has_bit(&nkey, depth) == has_bit(&key, depth) {
+                            to_hash.push(Node::Empty {});
+                            depth += 1;
+                        }
+                        to_hash.push(mem::replace(&mut root, Node::Empty {}));
+                        depth += 1;
+                    }
+                    break stop;
+                }
+                Node::Internal {
+                    left,
+                    right,
+                    index,
+                    pos,
+                    hash,
+                } => {
+                    if depth == self.keysize {
+                        panic!("Insert: missing node at depth {}", depth);
+                    }
+                    if index == 0 {
+                        // Rebuilt below
+                        self.dirty_nodes -= 1;
+                    }
+                    parents.push((index, pos, hash));
+
+                    if has_bit(&nkey, depth) {
+                        to_hash.push(self.pool.unbox(left));
+                        root = self.pool.unbox(right);
+                    } else {
+                        to_hash.push(self.pool.unbox(right));
+                        root = self.pool.unbox(left);
+                    }
+                    depth += 1;
+                }
+            }
+        };
+
+        if let Some(result) = stop {
+            // Put the path back as it was
+            self.root = Some(self.restore(nkey, depth, root, &mut to_hash, &mut parents));
+            self.path_scratch = to_hash;
+            self.parent_scratch = parents;
+            return result;
+        }
+        parents.clear();
+        self.parent_scratch = parents;
+
+        // Start with a leaf of the new K/V
+        let leaf = Node::Leaf {
+            pos: 0,
+            index: 0,
+            hash: leaf_hash,
+            key: nkey,
+            value: Some(value),
+            vindex: 0,
+            vpos: 0,
+            vsize: 0,
+        };
+
+        // Set the new root
+        self.dirty_nodes += to_hash.len() + 1;
+        self.root = Some(rebuild(nkey, depth, leaf, &mut to_hash, &mut self.pool));
+        self.path_scratch = to_hash;
+        Ok(Outcome::Done(added))
+    }
+
+    // Stop a conditional write that found `leaf` holding the key, unless
+    // it holds `expected`.  Values are compared by hash, so a match isn't
+    // read.
+    fn refuse_present(
+        &mut self,
+        nkey: Digest,
+        leaf: &Node,
+        expected: Option<&[u8]>,
+    ) -> Option<Result<Outcome>> {
+        if let (Some(expected), Node::Leaf { hash, .. }) = (expected, leaf) {
+            if sha3_value_salted(self.store.leaf_salt().as_ref(), nkey, expected) == *hash {
+                return None;
+            }
+        }
+        Some(
+            self.leaf_value(leaf)
+                .map(|current| Outcome::Mismatch(Some(current))),
+        )
+    }
+
+    // Put back the internal nodes a walk took apart, as they were before,
+    // above `bottom`
+    fn restore(
+        &mut self,
+        nkey: Digest,
+        mut depth: usize,
+        bottom: Node,
+        to_hash: &mut Vec<Node>,
+        parents: &mut Vec<(u16, u32, Digest)>,
+    ) -> Node {
+        self.dirty_nodes += parents.iter().filter(|(index, _, _)| *index == 0).count();
+        let mut node = bottom;
+        while let (Some(sibling), Some((index, pos, hash))) = (to_hash.pop(), parents.pop()) {
+            depth -= 1;
+            let (left, right) = if has_bit(&nkey, depth) {
+                (sibling, node)
+            } else {
+                (node, sibling)
+            };
+            node = Node::Internal {
+                left: self.pool.boxed(left),
+                right: self.pool.boxed(right),
+                index,
+                pos,
+                hash,
+            };
+        }
+        node
+    }
+
+    /// Remove a key from the Tree, returning whether it was there
+    pub fn remove(&mut self, nkey: Digest) -> Result<bool> {
+        match self.remove_where(nkey, Condition::Always)? {
+            Outcome::Done(removed) => Ok(removed),
+            _ => Ok(false),
+        }
+    }
+
+    // Remove `nkey` if `condition` holds
+    fn remove_where(&mut self, nkey: Digest, condition: Condition) -> Result<Outcome> {
+        let outcome = self.remove_leaf(nkey, condition)?;
+        if let Outcome::Done(_) = outcome {
+            self.merges.remove(&nkey.0);
+        }
+        if let Outcome::Done(true) = outcome {
+            self.changed.insert(nkey.0);
+            if let Some(ref mut count) = self.leaf_count {
+                *count -= 1;
+            }
+            if let Some(ref mut index) = self.value_index {
+                index.remove(nkey);
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Remove many keys at once, returning how many were there.  Each node
+    /// on the paths to the keys is rehashed once, rather than once per key
+    /// as separate `remove` calls would.  If a node can't be read, none of
+    /// the keys are removed.
+    pub fn remove_batch(&mut self, keys: &[Digest]) -> Result<usize> {
+        let mut sorted = keys.to_vec();
+        sorted.sort_by_key(|k| k.0);
+        sorted.dedup();
+
+        // Work on a copy so a failed read leaves the tree as it was
+        let root = self.root.clone().unwrap();
+        let dirty_nodes = self.dirty_nodes;
+        let mut removed = vec![];
+        match self.remove_leaves(root, 0, &sorted, &mut removed) {
+            Ok(root) => self.root = Some(root),
+            Err(e) => {
+                self.dirty_nodes = dirty_nodes;
+                return Err(e);
+            }
+        }
+
+        if let Some(ref mut count) = self.leaf_count {
+            *count -= removed.len() as u64;
+        }
+        for key in &removed {
+            self.merges.remove(&key.0);
+            self.changed.insert(key.0);
+            if let Some(ref mut index) = self.value_index {
+                index.remove(*key);
+            }
+        }
+        Ok(removed.len())
+    }
+
+    /// Apply `ops` in order, as a block's state changes would be.  Either
+    /// every op is applied or, if one fails, none are and the working tree
+    /// is as it was.  Nothing is committed.
+    pub fn apply(&mut self, ops: &[Op]) -> Result<()> {
+        let changes = ops
+            .iter()
+            .map(|op| match *op {
+                Op::Put(key, value) => (key, Some(value.to_vec())),
+                Op::Delete(key) => (key, None),
+            })
+            .collect();
+        self.apply_changes(changes)
+    }
+
+    /// `apply` for changes given as a value to set, or `None` to remove
+    pub(crate) fn apply_changes(&mut self, changes: Vec<(Digest, Option<Vec<u8>>)>) -> Result<()> {
+        let root = self.root.clone();
+        let dirty_nodes = self.dirty_nodes;
+        let changed = self.changed.clone();
+        let logical_bytes = self.logical_bytes;
+        let merges = self.merges.clone();
+        let leaf_count = self.leaf_count;
+
+        // Index the values only once the whole batch is in
+        let mut index = self.value_index.take();
+        let indexed: Vec<(Digest, Option<Digest>)> = match index {
+            Some(_) => changes
+                .iter()
+                .map(|(key, value)| (*key, value.as_ref().map(|v| sha3(v))))
+                .collect(),
+            None => vec![],
+        };
+        let result = changes
+            .into_iter()
+            .try_for_each(|(key, value)| match value {
+                Some(value) => self.put(key, value),
+                None => self.remove(key).map(|_| ()),
+            });
+        if let Err(e) = result {
+            self.root = root;
+            self.dirty_nodes = dirty_nodes;
+            self.changed = changed;
+            self.logical_bytes = logical_bytes;
+            self.merges = merges;
+            self.leaf_count = leaf_count;
+            self.value_index = index;
+            return Err(e);
+        }
+
+        if let Some(ref mut index) = index {
+            for (key, hash) in indexed {
+                match hash {
+                    Some(hash) => index.set(key, hash),
+                    None => index.remove(key),
+                }
+            }
+        }
+        self.value_index = index;
+        Ok(())
+    }
+
+    // Remove the sorted `keys` from the subtree at `node`, noting the ones
+    // found in `removed`.  Collapses as `remove_leaf` does.
+    fn remove_leaves(
+        &mut self,
+        node: Node,
+        depth: usize,
+        keys: &[Digest],
+        removed: &mut Vec<Digest>,
+    ) -> Result<Node> {
+        if keys.is_empty() {
+            return Ok(node);
+        }
+        match node {
+            Node::Empty {} => Ok(node),
+            Node::Hash { .. } => {
+                let resolved = self.resolve(keys[0], &node)?;
+                self.remove_leaves(resolved, depth, keys, removed)
+            }
+            Node::Leaf { key, index, .. } => {
+                if !keys.iter().any(|k| self.same_key(k, &key)) {
+                    return Ok(node);
+                }
+                if index == 0 {
+                    // Removing a leaf that was never written
+                    self.dirty_nodes -= 1;
+                }
+                removed.push(key);
+                Ok(Node::Empty {})
+            }
+            Node::Internal {
+                left,
+                right,
+                index,
+                pos,
+                hash,
+            } => {
+                if depth == self.keysize {
+                    panic!("Remove: missing node at depth {}", depth);
+                }
+                let found = removed.len();
+                let split = keys.partition_point(|k| !has_bit(k, depth));
+                let left = self.pool.unbox(left);
+                let left = self.remove_leaves(left, depth + 1, &keys[..split], removed)?;
+                let right = self.pool.unbox(right);
+                let right = self.remove_leaves(right, depth + 1, &keys[split..], removed)?;
+
+                if removed.len() == found {
+                    // Nothing removed below: keep the node as it was
+                    return Ok(Node::Internal {
+                        left: self.pool.boxed(left),
+                        right: self.pool.boxed(right),
+                        index,
+                        pos,
+                        hash,
+                    });
+                }
+                if index == 0 {
+                    // Rebuilt below
+                    self.dirty_nodes -= 1;
+                }
+                match (left, right) {
+                    (Node::Empty {}, Node::Empty {}) => Ok(Node::Empty {}),
+                    (Node::Empty {}, other) | (other, Node::Empty {}) if other.is_leaf() => {
+                        Ok(other)
+                    }
+                    (left, right) => {
+                        self.dirty_nodes += 1;
+                        Ok(Node::Internal {
+                            hash: sha3_internal(left.hash(), right.hash()),
+                            left: self.pool.boxed(left),
+                            right: self.pool.boxed(right),
+                            index: 0,
+                            pos: 0,
+                        })
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_leaf(&mut self, nkey: Digest, condition: Condition) -> Result<Outcome> {
+        let mut depth = 0;
+        let mut to_hash = mem::take(&mut self.path_scratch);
+        let mut parents = mem::take(&mut self.parent_scratch);
+        let absent = |condition| refuse_absent(condition).unwrap_or(Ok(Outcome::Done(false)));
+
+        let mut root = self.root.take().unwrap();
+        let stop = loop {
+            match root {
+                Node::Empty {} => break Some(absent(condition)),
+                Node::Hash { .. } => match self.resolve(nkey, &root) {
+                    Ok(node) => root = node,
+                    Err(e) => break Some(Err(e)),
+                },
+                Node::Leaf { key, index, .. } => {
+                    if !self.same_key(&nkey, &key) {
+                        break Some(absent(condition));
+                    }
+                    let stop = match condition {
+                        Condition::IfValue(expected) => self.refuse_present(nkey, &root, expected),
+                        _ => None,
+                    };
+                    if stop.is_none() && index == 0 {
+                        // Removing a leaf that was never written
+                        self.dirty_nodes -= 1;
+                    }
+                    break stop;
+                }
+                Node::Internal {
+                    left,
+                    right,
+                    index,
+                    pos,
+                    hash,
+                } => {
+                    if depth == self.keysize {
+                        panic!("Remove: missing node at depth {}", depth);
+                    }
+                    if index == 0 {
+                        // Rebuilt below
+                        self.dirty_nodes -= 1;
+                    }
+                    parents.push((index, pos, hash));
+
+                    if has_bit(&nkey, depth) {
+                        to_hash.push(self.pool.unbox(left));
+                        root = self.pool.unbox(right);
+                    } else {
+                        to_hash.push(self.pool.unbox(right));
+                        root = self.pool.unbox(left);
+                    }
+                    depth += 1;
+                }
+            }
+        };
+
+        if let Some(result) = stop {
+            // Not removed: put the path back as it was
+            self.root = Some(self.restore(nkey, depth, root, &mut to_hash, &mut parents));
+            self.path_scratch = to_hash;
+            self.parent_scratch = parents;
+            return result;
+        }
+        parents.clear();
+        self.parent_scratch = parents;
+
+        // A subtree left holding a single leaf is replaced by that leaf,
+        // so the tree has the shape it would if the key was never inserted
+        let mut bottom = Node::Empty {};
+        while let Some(sibling) = to_hash.pop() {
+            let single = match (&bottom, &sibling) {
+                (Node::Empty {}, other) | (other, Node::Empty {}) => other.is_leaf(),
+                _ => false,
+            };
+            if !single {
+                to_hash.push(sibling);
+                break;
+            }
+            if let Node::Empty {} = bottom {
+                bottom = sibling;
+            }
+            depth -= 1;
+        }
+
+        self.dirty_nodes += to_hash.len();
+        self.root = Some(rebuild(nkey, depth, bottom, &mut to_hash, &mut self.pool));
+        self.path_scratch = to_hash;
+        Ok(Outcome::Done(true))
+    }
+
+    /// Get a value (if it exists) for a given key
+    pub fn get(&mut self, nkey: Digest) -> Result<Option<Vec<u8>>> {
+        // Clone here to deal with borrowing issues for resolve().
+        // If current is a ref, the return from 'resolve' has a lifetime
+        // issue.  Ideally walking the tree should be ref...
+        #[cfg(feature = "metrics")]
+        let probe = Probe::start(self.store.reads());
+
+        let root = self.root.clone().unwrap();
+        let result = self
+            .get_from(root, nkey)
+            .map(|value| self.fold_merges(nkey, value));
+
+        #[cfg(feature = "metrics")]
+        self.metrics.get.record(probe, self.store.reads());
+        result
+    }
+
+    /// Get the value for `nkey` as of the earlier commit whose root is
+    /// `root`.  The working root is left alone.
+    pub fn get_at(&mut self, root: Digest, nkey: Digest) -> Result<Option<Vec<u8>>> {
+        let node = self.find_root(root)?;
+        self.get_from(node, nkey)
+    }
+
+    /// Get the values for a batch of keys, in the order given.  The keys
+    /// are looked up together in one walk, so nodes on shared paths are
+    /// only read once.
+    pub fn multi_get(&mut self, nkeys: &[Digest]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut wanted: Vec<(Digest, usize)> = nkeys.iter().cloned().zip(0..).collect();
+        wanted.sort_by_key(|(key, _)| key.0);
+        let mut found = vec![None; nkeys.len()];
+        let root = self.root.clone().unwrap();
+        self.gather(root, 0, &wanted, &mut found)?;
+        Ok(nkeys
+            .iter()
+            .zip(found)
+            .map(|(key, value)| self.fold_merges(*key, value))
+            .collect())
+    }
+
+    // Fill in `found` for the `wanted` keys, sorted, under `node`
+    fn gather(
+        &mut self,
+        node: Node,
+        depth: usize,
+        wanted: &[(Digest, usize)],
+        found: &mut [Option<Vec<u8>>],
+    ) -> Result<()> {
+        if wanted.is_empty() {
+            return Ok(());
+        }
+        match node {
+            Node::Leaf { key, .. } => {
+                let mut value = None;
+                for (nkey, i) in wanted {
+                    if self.same_key(nkey, &key) {
+                        if value.is_none() {
+                            value = Some(self.leaf_value(&node)?);
+                        }
+                        found[*i] = value.clone();
+                    }
+                }
+                Ok(())
+            }
+            Node::Internal { left, right, .. } => {
+                let split = wanted.partition_point(|(nkey, _)| !has_bit(nkey, depth));
+                self.gather(*left, depth + 1, &wanted[..split], found)?;
+                self.gather(*right, depth + 1, &wanted[split..], found)
+            }
+            Node::Hash { .. } => {
+                let resolved = self.resolve(wanted[0].0, &node)?;
+                self.gather(resolved, depth, wanted, found)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the tree holds `nkey`, without reading its value
+    pub fn contains_key(&mut self, nkey: Digest) -> Result<bool> {
+        // Queued merge operands always leave a value behind
+        if self.merge_operator.is_some() && self.merges.contains_key(&nkey.0) {
+            return Ok(true);
+        }
+        let root = self.root.clone().unwrap();
+        Ok(self.find_leaf(root, nkey)?.is_some())
+    }
+
+    // Walk down from `root` to the value for `nkey`
+    fn get_from(&mut self, root: Node, nkey: Digest) -> Result<Option<Vec<u8>>> {
+        match self.find_leaf(root, nkey)? {
+            Some(leaf) => self.leaf_value(&leaf).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Walk down from `root` to the leaf for `nkey`
+    fn find_leaf(&mut self, root: Node, nkey: Digest) -> Result<Option<Node>> {
+        let mut depth = 0;
+        let mut current = root;
+        loop {
+            match current {
+                Node::Leaf { key, .. } => {
+                    if !self.same_key(&nkey, &key) {
+                        return Ok(None);
+                    }
+                    return Ok(Some(current));
+                }
+                Node::Internal { left, right, .. } => {
+                    if has_bit(&nkey, depth) {
+                        current = *right;
+                    } else {
+                        current = *left;
+                    }
+                    depth += 1;
+                }
+                Node::Hash { .. } => {
+                    current = self.resolve(nkey, &current)?;
+                }
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// Smallest key in the tree
+    pub fn first_key(&mut self) -> Result<Option<Digest>> {
+        Ok(self.edge_leaf(false)?.map(|(key, _)| key))
+    }
+
+    /// Largest key in the tree
+    pub fn last_key(&mut self) -> Result<Option<Digest>> {
+        Ok(self.edge_leaf(true)?.map(|(key, _)| key))
+    }
+
+    /// Smallest key in the tree, with its value
+    pub fn first_entry(&mut self) -> Result<Option<(Digest, Vec<u8>)>> {
+        match self.edge_leaf(false)? {
+            Some((key, leaf)) => Ok(Some((key, self.leaf_value(&leaf)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Largest key in the tree, with its value
+    pub fn last_entry(&mut self) -> Result<Option<(Digest, Vec<u8>)>> {
+        match self.edge_leaf(true)? {
+            Some((key, leaf)) => Ok(Some((key, self.leaf_value(&leaf)?))),
+            None => Ok(None),
+        }
+    }
+
+    // The leaf at the end of the tree's leftmost (or rightmost) path
+    fn edge_leaf(&mut self, rightmost: bool) -> Result<Option<(Digest, Node)>> {
+        let root = self.root.clone().unwrap();
+        self.edge_from(root, rightmost)
+    }
+
+    // The leaf at the end of the leftmost (or rightmost) non-empty path
+    // down from `node`
+    fn edge_from(&mut self, node: Node, rightmost: bool) -> Result<Option<(Digest, Node)>> {
+        let mut current = node;
+        loop {
+            match current {
+                Node::Empty {} => return Ok(None),
+                Node::Leaf { key, .. } => return Ok(Some((key, current))),
+                Node::Hash { index, pos, hash } => {
+                    current = self.store.resolve_expecting(index, pos, hash)?;
+                }
+                Node::Internal { left, right, .. } => {
+                    let (near, far) = if rightmost {
+                        (right, left)
+                    } else {
+                        (left, right)
+                    };
+                    current = match *near {
+                        Node::Empty {} => *far,
+                        near => near,
+                    };
+                }
+            }
+        }
+    }
+
+    /// The entry with the smallest key greater than `key`
+    pub fn entry_after(&mut self, key: Digest) -> Result<Option<(Digest, Vec<u8>)>> {
+        let root = self.root.clone().unwrap();
+        match self.neighbour(root, 0, &key, true)? {
+            Some((key, leaf)) => Ok(Some((key, self.leaf_value(&leaf)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// The entry with the largest key less than `key`
+    pub fn entry_before(&mut self, key: Digest) -> Result<Option<(Digest, Vec<u8>)>> {
+        let root = self.root.clone().unwrap();
+        match self.neighbour(root, 0, &key, false)? {
+            Some((key, leaf)) => Ok(Some((key, self.leaf_value(&leaf)?))),
+            None => Ok(None),
+        }
+    }
+
+    // The leaf under `node` with the nearest key after (or before) `key`
+    fn neighbour(
+        &mut self,
+        node: Node,
+        depth: usize,
+        key: &Digest,
+        after: bool,
+    ) -> Result<Option<(Digest, Node)>> {
+        match node {
+            Node::Empty {} => Ok(None),
+            Node::Hash { index, pos, hash } => {
+                let resolved = self.store.resolve_expecting(index, pos, hash)?;
+                self.neighbour(resolved, depth, key, after)
+            }
+            Node::Leaf { key: found, .. } => {
+                if (after && found > *key) || (!after && found < *key) {
+                    Ok(Some((found, node)))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Internal { left, right, .. } => {
+                // Search the side `key` falls in, then fall back to the
+                // nearest end of the other side if that lies past it
+                let (near, far) = match (has_bit(key, depth), after) {
+                    (false, true) => (left, Some(right)),
+                    (true, false) => (right, Some(left)),
+                    (false, false) => (left, None),
+                    (true, true) => (right, None),
+                };
+                if let Some(found) = self.neighbour(*near, depth + 1, key, after)? {
+                    return Ok(Some(found));
+                }
+                match far {
+                    Some(far) => self.edge_from(*far, !after),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// List up to `limit` keys in order, starting after `start_after` or
+    /// from the first key.  A zero limit lists nothing.
+    pub fn list(&mut self, start_after: Option<Digest>, limit: usize) -> Result<Page> {
+        let mut keys = Vec::with_capacity(limit);
+        let mut last = start_after;
+        loop {
+            let root = self.root.clone().unwrap();
+            let found = match last {
+                Some(key) => self.neighbour(root, 0, &key, true)?,
+                None => self.edge_from(root, false)?,
+            };
+            let key = match found {
+                Some((key, _)) => key,
+                None => return Ok(Page { keys, next: None }),
+            };
+            if keys.len() == limit {
+                // There's more past this page
+                let next = keys.last().cloned();
+                return Ok(Page { keys, next });
+            }
+            keys.push(key);
+            last = Some(key);
+        }
+    }
+
+    /// Iterate over the key/value pairs in the tree, including uncommitted
+    /// changes, in key order
+    pub fn iter<'t>(&'t mut self) -> Iter<'t> {
+        let stack = self.root.clone().into_iter().collect();
+        Iter { tree: self, stack }
+    }
+
+    /// Prove a key does/does not exist in the Tree
+    pub fn prove(&mut self, nkey: Digest) -> Result<Proof> {
+        #[cfg(feature = "metrics")]
+        let probe = Probe::start(self.store.reads());
+
+        // Again the clone...same reason as get()
+        let root = self.root.clone().unwrap();
+        let result = self.build_proof(root, nkey);
+
+        #[cfg(feature = "metrics")]
+        self.metrics.prove.record(probe, self.store.reads());
+        result
+    }
+
+    /// Prove what `nkey` held at the earlier commit whose root is `root`,
+    /// along with the roots committed since.  Walks back through the
+    /// commits one meta record at a time.
+    pub fn prove_historical(&mut self, root: Digest, nkey: Digest) -> Result<HistoricalProof> {
+        let mut roots = vec![];
+        let mut meta = self.store.state().clone();
+        loop {
+            let node = self.store.get_root_node(&meta)?;
+            roots.push(node.hash());
+            if node.hash() == root {
+                let proof = self.build_proof(node, nkey)?;
+                return Ok(HistoricalProof { proof, roots });
+            }
+            if meta.meta_index == 0 {
+                return Err(Error::UnknownRoot { root });
+            }
+            meta = self.store.previous_meta(&meta)?;
+        }
+    }
+
+    /// Prove `nkey` against the earlier commit whose root is `root`.  Unlike
+    /// `prove_historical`, this doesn't carry the roots linking it to the
+    /// latest commit.
+    pub fn prove_at(&mut self, root: Digest, nkey: Digest) -> Result<Proof> {
+        let node = self.find_root(root)?;
+        self.build_proof(node, nkey)
+    }
+
+    fn build_proof(&mut self, root: Node, nkey: Digest) -> Result<Proof> {
+        let mut proof = Proof::default();
+        let mut siblings = mem::take(&mut self.proof_scratch);
+        siblings.clear();
+
+        // The path mostly lives close together, read it in windows
+        self.store.set_coalescing(true);
+        let result = self.walk_proof(root, nkey, &mut proof, &mut siblings);
+        self.store.set_coalescing(false);
+        proof.set_node_hashes(&siblings);
+        self.proof_scratch = siblings;
+        result.map(|_| proof)
+    }
+
+    // Fill in `proof` and collect the sibling hashes on the way to `nkey`
+    fn walk_proof(
+        &mut self,
+        root: Node,
+        nkey: Digest,
+        proof: &mut Proof,
+        siblings: &mut Vec<Digest>,
+    ) -> Result<()> {
+        let mut depth = 0;
+        let mut current = root;
+        loop {
+            match current {
+                Node::Empty {} => break,
+                Node::Hash { .. } => {
+                    current = self.resolve(nkey, &current)?;
+                }
+                Node::Internal { left, right, .. } => {
+                    if depth == self.keysize {
+                        panic!("Proof: missing node at depth {}", depth);
+                    }
+
+                    if has_bit(&nkey, depth) {
+                        siblings.push(left.hash());
+                        current = *right;
+                    } else {
+                        siblings.push(right.hash());
+                        current = *left;
+                    }
+
+                    depth += 1;
+                }
+                Node::Leaf { key, .. } => {
+                    let val = self.leaf_value(&current).map_err(|e| e.for_key(nkey))?;
+
+                    if self.same_key(&nkey, &key) {
+                        proof.proof_type = ProofType::Exists;
+                        proof.value = Some(val);
+                    } else {
+                        proof.proof_type = ProofType::Collision;
+                        proof.key = Some(key);
+                        proof.hash = Some(sha3(&val));
+                    }
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write the values of every new leaf, ahead of any node
+    fn write_values(&mut self, node: &mut Node) {
+        match node {
+            Node::Internal {
+                index: 0,
+                left,
+                right,
+                ..
+            } => {
+                self.write_values(left);
+                self.write_values(right);
+            }
+            Node::Leaf {
+                index: 0,
+                vindex: 0,
+                ..
+            } => self.store.write_value(node),
+            _ => {}
+        }
+    }
+
+    // Commit subtree to storage and set a new Hashnode root.
+    pub fn commit(&mut self) -> Result<()> {
+        #[cfg(feature = "metrics")]
+        let probe = Probe::start(self.store.reads());
+
+        let result = self.write_commit();
+
+        #[cfg(feature = "metrics")]
+        self.metrics.commit.record(probe, self.store.reads());
+        result
+    }
+
+    fn write_commit(&mut self) -> Result<()> {
+        let started = Instant::now();
+        if !self.commit_hooks.is_empty() {
+            // Hooks see the merges folded in, but a veto leaves them queued
+            let pending = self.compute_root()?;
+            for hook in &mut self.commit_hooks {
+                hook.pre_commit(pending).map_err(Error::CommitVetoed)?;
+            }
+        }
+        self.resolve_merges()?;
+
+        // Values of the watched keys this commit touches, as committed
+        let watched = self.watched_changes()?;
+
+        if self.store.layout() == WriteLayout::Segregated {
+            if let Some(mut root) = self.root.take() {
+                self.write_values(&mut root);
+                self.root = Some(root);
+            }
+        }
+
+        // newroot is a node::hash
+        let committed = self.store.state().clone();
+        if self.store.checkpoint_bytes().is_some() {
+            self.completed = Some(vec![]);
+        }
+        let newroot = self.root.take().map(|t| self.write(t));
+        self.completed = None;
+        self.dirty_nodes = 0;
+        let leaf_count = self.leaf_count.unwrap_or(0);
+        let result = self.store.commit(newroot.as_ref(), leaf_count);
+        self.root = newroot;
+
+        let root = self.get_root();
+        self.store
+            .report_elapsed(Operation::Commit { root }, started);
+        result?;
+        self.salvaged.clear();
+        let previous_live = match self.store.retention() {
+            RootRetention::All => None,
+            _ => self.live.clone(),
+        };
+        if self.track_live(&committed).is_err() {
+            // The next `stats` counts them afresh
+            self.live = None;
+        }
+        self.enforce_retention(previous_live)?;
+        if let Some(ref mut index) = self.value_index {
+            index.flush()?;
+        }
+
+        let state = self.store.state();
+        let info = CommitInfo {
+            root,
+            meta_index: state.meta_index,
+            meta_pos: state.meta_pos,
+        };
+        for hook in &mut self.commit_hooks {
+            hook.post_commit(&info);
+        }
+        let update = RootUpdate {
+            sequence: self.store.io_stats().commits,
+            root,
+            meta_index: info.meta_index,
+            meta_pos: info.meta_pos,
+        };
+        self.root_subscribers
+            .retain(|sender| sender.send(update).is_ok());
+
+        self.changed.clear();
+        self.notify_watchers(watched)
+    }
+
+    // Intersect the keys changed since the last commit with the watched
+    // keys, returning their committed values
+    fn watched_changes(&mut self) -> Result<Vec<(Digest, Option<Vec<u8>>)>> {
+        let keys: Vec<Digest> = self
+            .watchers
+            .iter()
+            .map(|w| w.key)
+            .filter(|k| self.changed.contains(&k.0))
+            .collect();
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let state = self.store.state().clone();
+        let committed = self.store.get_root_node(&state)?;
+        let mut values = vec![];
+        for key in keys {
+            if values.iter().any(|(k, _)| *k == key) {
+                continue;
+            }
+            let old = self.get_from(committed.clone(), key)?;
+            values.push((key, old));
+        }
+        Ok(values)
+    }
+
+    // Send the old and new values of the watched keys that changed,
+    // dropping watchers whose receiver has gone away
+    fn notify_watchers(&mut self, watched: Vec<(Digest, Option<Vec<u8>>)>) -> Result<()> {
+        for (key, old) in watched {
+            let new = self.get(key)?;
+            if old == new {
+                continue;
+            }
+            let change = KeyChange { key, old, new };
+            self.watchers
+                .retain(|w| w.key != key || w.sender.send(change.clone()).is_ok());
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, mut node: Node) -> Node {
+        match node {
+            Node::Empty {} => Node::empty(),
+            Node::Internal {
+                pos,
+                index,
+                hash,
+                mut left,
+                mut right,
+            } => {
+                if index == 0 {
+                    if let Some(&(index, pos)) = self.salvaged.get(&hash) {
+                        // Written by an interrupted commit
+                        self.pool.recycle(left);
+                        self.pool.recycle(right);
+                        return Node::Hash { pos, index, hash };
+                    }
+                }
+                let depth = self.completed.as_ref().map_or(0, |c| c.len());
+
+                // Go left recursively, writing the result back in the same box
+                *left = self.write_child(mem::replace(&mut *left, Node::empty()));
+                // ...then right
+                *right = self.write_child(mem::replace(&mut *right, Node::empty()));
+
+                // Now construct a new entry
+                let mut tempnode = Node::Internal {
+                    pos,
+                    index,
+                    hash,
+                    left,
+                    right,
+                };
+
+                // Calc hash for the hashnode
+                let hashed = tempnode.hash();
+
+                // Only store if we haven't already
+                if index == 0 {
+                    self.store.write_node(&mut tempnode);
+                    if let Some(ref mut completed) = self.completed {
+                        completed.truncate(depth);
+                    }
+                    self.checkpoint_if_due();
+                }
+
+                let (newindex, newpos) = tempnode.index_and_position();
+
+                // Now it *should* be stored
+                assert!(!tempnode.should_save(), "Didn't persist the node");
+
+                if let Node::Internal { left, right, .. } = tempnode {
+                    self.pool.recycle(left);
+                    self.pool.recycle(right);
+                }
+
+                // Return brand spanking new HashNode
+                Node::Hash {
+                    pos: newpos,
+                    index: newindex,
+                    hash: hashed,
+                }
+            }
+            Node::Leaf {
+                index, pos, hash, ..
+            } if index != 0 => {
+                // Already stored
+                Node::Hash { pos, index, hash }
+            }
+            Node::Leaf { vindex, .. } => {
+                // Write the value for the leaf node, unless it went out
+                // ahead of the nodes...
+                if vindex == 0 {
+                    self.store.write_value(&mut node);
+                }
+                // ...then the node itself
+                self.store.write_node(&mut node);
+
+                // the index should be set!
+                assert!(!node.should_save(), "Didn't persist the node");
+
+                // TODO: Cleanup aisle 5
+                // get the updated index/pos
+                let (newindex, newpos) = node.index_and_position();
+                let hashed = node.hash();
+                Node::Hash {
+                    pos: newpos,
+                    index: newindex,
+                    hash: hashed,
+                }
+            }
+            Node::Hash { .. } => {
+                assert!(!node.should_save());
+                node
+            }
+        }
+    }
+
+    // Write a child, noting it as completed if it's an internal node
+    // written now
+    fn write_child(&mut self, node: Node) -> Node {
+        let fresh = node.should_save() && !node.is_leaf();
+        let written = self.write(node);
+        if let Some(ref mut completed) = self.completed {
+            if fresh {
+                completed.push(written.index_and_position());
+            }
+        }
+        written
+    }
+
+    fn checkpoint_if_due(&mut self) {
+        if self.store.checkpoint_due() {
+            let completed = self.completed.take().unwrap_or_default();
+            self.store.checkpoint(&completed);
+            self.completed = Some(completed);
+        }
+    }
+}
+
+impl AuthenticatedMap for UrkelTree {
+    type Proof = Proof;
+
+    fn root(&self) -> Digest {
+        self.get_root()
+    }
+
+    fn get(&mut self, key: Digest) -> Result<Option<Vec<u8>>> {
+        UrkelTree::get(self, key)
+    }
+
+    fn insert(&mut self, key: Digest, value: &[u8]) -> Result<()> {
+        UrkelTree::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: Digest) -> Result<bool> {
+        UrkelTree::remove(self, key)
+    }
+
+    fn prove(&mut self, key: Digest) -> Result<Proof> {
+        UrkelTree::prove(self, key)
+    }
+
+    fn verify(&self, root: Digest, key: Digest, proof: &Proof) -> Result<Option<Vec<u8>>> {
+        let salt = self.store.leaf_salt();
+        proof
+            .check_salted(root, key, self.keysize, salt.as_ref())
+            .map_err(Error::InvalidProof)?;
+        match proof.proof_type {
+            ProofType::Exists => Ok(proof.value.clone()),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn shift_live(live: &mut BTreeMap<u16, u64>, index: u16, bytes: u64, adding: bool) {
+    let count = live.entry(index).or_insert(0);
+    if adding {
+        *count += bytes;
+    } else {
+        *count = count.saturating_sub(bytes);
+    }
+}
+
+fn add_live(files: &mut [FileSpace], index: u16, bytes: u64) {
+    if let Some(f) = files.iter_mut().find(|f| f.index == index) {
+        f.live_bytes += bytes;
+    }
+}
+
+// Build the subtree at `depth` holding `leaves`, which are sorted and
+// share their first `depth` bits
+fn build_subtree(
+    mut leaves: Vec<(Digest, Vec<u8>)>,
+    depth: usize,
+    salt: Option<&[u8; 32]>,
+) -> Node {
+    match leaves.len() {
+        0 => Node::empty(),
+        1 => {
+            let (key, value) = leaves.pop().unwrap();
+            Node::Leaf {
+                pos: 0,
+                index: 0,
+                hash: sha3_value_salted(salt, key, &value),
+                key,
+                value: Some(value),
+                vindex: 0,
+                vpos: 0,
+                vsize: 0,
+            }
+        }
+        _ => {
+            let split = leaves.partition_point(|(key, _)| !has_bit(key, depth));
+            let right = leaves.split_off(split);
+            let left = build_subtree(leaves, depth + 1, salt);
+            let right = build_subtree(right, depth + 1, salt);
+            internal(left, right)
+        }
+    }
+}
+
+// Join the subtrees built for each group of leaves, in key order, under
+// one root.  A lone leaf rises to where it has no sibling, as insert
+// would leave it.
+fn join_subtrees(mut subtrees: Vec<Node>) -> Node {
+    while subtrees.len() > 1 {
+        let mut pairs = subtrees.into_iter();
+        let mut joined = vec![];
+        while let (Some(left), Some(right)) = (pairs.next(), pairs.next()) {
+            joined.push(match (left, right) {
+                (Node::Empty {}, Node::Empty {}) => Node::empty(),
+                (leaf @ Node::Leaf { .. }, Node::Empty {})
+                | (Node::Empty {}, leaf @ Node::Leaf { .. }) => leaf,
+                (left, right) => internal(left, right),
+            });
+        }
+        subtrees = joined;
+    }
+    subtrees.pop().unwrap_or_else(Node::empty)
+}
+
+fn internal(left: Node, right: Node) -> Node {
+    Node::Internal {
+        pos: 0,
+        index: 0,
+        hash: sha3_internal(left.hash(), right.hash()),
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+// When a write to a key goes ahead
+#[derive(Clone, Copy)]
+enum Condition<'e> {
+    Always,
+    /// Only if the key is absent
+    IfAbsent,
+    /// Only if the key holds this value (None: absent)
+    IfValue(Option<&'e [u8]>),
+}
+
+// What a write's walk did
+enum Outcome {
+    /// Written.  Whether an insert added a leaf, or a removal took one away.
+    Done(bool),
+    /// Not written, as the key is present
+    Present,
+    /// Not written, as the key holds this value instead (None: absent)
+    Mismatch(Option<Vec<u8>>),
+}
+
+// Stop a conditional write that found the key absent, unless it may be
+fn refuse_absent(condition: Condition) -> Option<Result<Outcome>> {
+    match condition {
+        Condition::IfValue(Some(_)) => Some(Ok(Outcome::Mismatch(None))),
+        _ => None,
+    }
+}
+
+// Hang `bottom` back under the siblings collected on the way down to
+// `depth`, forming the new root.  Leaves `to_hash` empty.
+fn rebuild(
+    nkey: Digest,
+    mut depth: usize,
+    bottom: Node,
+    to_hash: &mut Vec<Node>,
+    pool: &mut NodePool,
+) -> Node {
+    let mut new_root = bottom;
+    while let Some(n) = to_hash.pop() {
+        depth -= 1;
+        // Hash as we go so only the touched path is ever rehashed
+        let (left, right) = if has_bit(&nkey, depth) {
+            (n, new_root)
+        } else {
+            (new_root, n)
+        };
+        let hash = sha3_internal(left.hash(), right.hash());
+        new_root = Node::Internal {
+            left: pool.boxed(left),
+            right: pool.boxed(right),
+            index: 0,
+            pos: 0,
+            hash,
+        };
+    }
+    new_root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use test_dir;
+
+    #[test]
+    fn tree_basics() {
+        let mut t = UrkelTree::open(test_dir("tree_basics"));
+        let key1 = sha3(b"name-1");
+        let key2 = sha3(b"name-2");
+
+        t.insert(key1, b"value-1").unwrap();
+
+        for i in 3..40 {
+            let k = sha3(format!("name-{}", i).as_bytes());
+            t.insert(k, &[2u8; 20]).unwrap();
+        }
+
+        t.insert(key2, b"value-2").unwrap();
+
+        t.commit().unwrap();
+
+        assert!(t.get_root() != Digest::default());
+
+        assert_eq!(t.get(key1).unwrap(), Some(Vec::from("value-1")));
+        assert_eq!(t.get(key2).unwrap(), Some(Vec::from("value-2")));
+
+        // Test good proof
+        let prf = t.prove(key2);
+        assert!(prf.is_ok());
+        if let Ok(pt) = prf {
+            assert!(pt.proof_type == ProofType::Exists);
+            assert!(pt.value == Some(Vec::from("value-2")));
+        }
+
+        // Test deadend (doesn't exist)
+        let noproof = t.prove(sha3(b"doesn't exist"));
+        assert!(noproof.is_ok());
+        if let Ok(np) = noproof {
+            assert!(np.proof_type == ProofType::Deadend);
+            assert!(np.key.is_none());
+        }
+    }
+
+    #[test]
+    fn compute_root_matches_commit() {
+        let mut t = UrkelTree::open(test_dir("compute_root_matches_commit"));
+        assert_eq!(t.compute_root().unwrap(), Digest::default());
+
+        t.insert(sha3(b"name-1"), b"value-1").unwrap();
+        t.commit().unwrap();
+        let first = t.get_root();
+        let committed = t.store.state().clone();
+
+        t.insert(sha3(b"name-2"), b"value-2").unwrap();
+        let pending = t.compute_root().unwrap();
+        assert!(pending != first);
+        assert_eq!(t.store.state(), &committed);
+
+        t.commit().unwrap();
+        assert_eq!(t.get_root(), pending);
+
+        // Queued merges are only applied by the commit
+        t.set_merge_operator(|_: Digest, existing: Option<&[u8]>, operand: &[u8]| {
+            let mut value = existing.unwrap_or_default().to_vec();
+            value.extend_from_slice(operand);
+            value
+        });
+        t.merge_value(sha3(b"name-1"), b"-more".to_vec()).unwrap();
+        let pending = t.compute_root().unwrap();
+        assert_ne!(pending, t.get_root());
+        assert_eq!(t.dirty_nodes(), 0);
+        t.commit().unwrap();
+        assert_eq!(t.get_root(), pending);
+        assert_eq!(
+            t.get(sha3(b"name-1")).unwrap(),
+            Some(b"value-1-more".to_vec())
+        );
+    }
+
+    #[test]
+    fn diff_finds_first_divergence() {
+        let mut a = UrkelTree::open(test_dir("diff_a"));
+        let mut b = UrkelTree::open(test_dir("diff_b"));
+        assert!(a.structurally_equal(&mut b).unwrap());
+
+        for i in 0..20 {
+            let k = sha3(format!("name-{}", i).as_bytes());
+            a.insert(k, b"value").unwrap();
+            b.insert(k, b"value").unwrap();
+        }
+        a.commit().unwrap();
+        assert!(a.structurally_equal(&mut b).unwrap());
+
+        let key = sha3(b"name-7");
+        b.insert(key, b"changed").unwrap();
+        b.commit().unwrap();
+
+        let d = a.diff(&mut b).unwrap().unwrap();
+        assert!(d.ours != d.theirs);
+        for (depth, right) in d.path.iter().enumerate() {
+            assert_eq!(*right, has_bit(&key, depth));
+        }
+        assert_eq!(d.ours, sha3_value_salted(None, key, b"value"));
+        assert_eq!(d.theirs, sha3_value_salted(None, key, b"changed"));
+    }
+
+    #[test]
+    fn scratch_buffers_are_reused() {
+        let mut t = UrkelTree::open(test_dir("scratch_buffers_are_reused"));
+        let keys: Vec<Digest> = (0..50)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        for k in &keys {
+            t.insert(*k, b"value").unwrap();
+        }
+        let root = t.get_root();
+
+        // Re-inserting an unchanged value leaves the tree as it was
+        t.insert(keys[10], b"value").unwrap();
+        assert_eq!(t.get_root(), root);
+        assert!(t.path_scratch.is_empty() && t.path_scratch.capacity() > 0);
+
+        t.commit().unwrap();
+        let mut p1 = t.prove(keys[1]).unwrap();
+        let mut p2 = t.prove(keys[2]).unwrap();
+        assert!(t.proof_scratch.capacity() > 0);
+        assert!(p1.verify(root, keys[1], 256).is_ok());
+        assert!(p2.verify(root, keys[2], 256).is_ok());
+    }
+
+    #[test]
+    fn commit_recycles_node_boxes() {
+        let mut t = UrkelTree::open(test_dir("commit_recycles_node_boxes"));
+        for i in 0..30 {
+            t.insert(sha3(format!("name-{}", i).as_bytes()), b"value")
+                .unwrap();
+        }
+        t.commit().unwrap();
+        let pooled = t.pool.len();
+        assert!(pooled > 0);
+
+        t.insert(sha3(b"name-100"), b"value").unwrap();
+        assert!(t.pool.len() < pooled);
+        t.commit().unwrap();
+        assert_eq!(t.get(sha3(b"name-5")).unwrap(), Some(Vec::from("value")));
+    }
+
+    #[test]
+    fn trees_share_a_block_cache() {
+        use cache::BlockCache;
+        use std::sync::Arc;
+
+        let dir = test_dir("trees_share_a_block_cache");
+        let key = sha3(b"name-1");
+        {
+            let mut t = UrkelTree::open(&dir);
+            t.insert(key, b"value-1").unwrap();
+            t.insert(sha3(b"name-2"), b"value-2").unwrap();
+            t.commit().unwrap();
+        }
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let options = || StoreOptions {
+            cache: Some(cache.clone()),
+            ..Default::default()
+        };
+        let mut a = UrkelTree::open_with_options(&dir, options()).unwrap();
+        let mut b = UrkelTree::open_with_options(&dir, options()).unwrap();
+
+        assert_eq!(a.get(key).unwrap(), Some(Vec::from("value-1")));
+        let misses = cache.misses();
+        assert!(cache.size() > 0);
+
+        let reads = b.store.reads();
+        assert_eq!(b.get(key).unwrap(), Some(Vec::from("value-1")));
+        assert_eq!(b.store.reads(), reads);
+        assert_eq!(cache.misses(), misses);
+        assert!(cache.hits() > 0);
+    }
+
+    #[test]
+    fn proofs_coalesce_reads() {
+        let dir = test_dir("proofs_coalesce_reads");
+        let keys: Vec<Digest> = (0..200)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        {
+            let mut t = UrkelTree::open(&dir);
+            for k in &keys {
+                t.insert(*k, b"value").unwrap();
+            }
+            t.commit().unwrap();
+        }
+
+        let mut t = UrkelTree::open(&dir);
+        let root = t.get_root();
+        let reads = t.store.reads();
+        t.get(keys[42]).unwrap();
+        let get_reads = t.store.reads() - reads;
+
+        let reads = t.store.reads();
+        let mut proof = t.prove(keys[42]).unwrap();
+        let prove_reads = t.store.reads() - reads;
+
+        assert!(get_reads > 2);
+        assert!(prove_reads < get_reads);
+        assert_eq!(proof.verify(root, keys[42], 256).unwrap(), b"value");
+    }
+
+    #[test]
+    fn insert_hashes_incrementally() {
+        // Hash of `node` ignoring any cached internal hashes
+        fn full_hash(node: &Node) -> Digest {
+            match node {
+                Node::Internal { left, right, .. } => {
+                    sha3_internal(full_hash(left), full_hash(right))
+                }
+                n => n.hash(),
+            }
+        }
+
+        let mut t = UrkelTree::open(test_dir("insert_hashes_incrementally"));
+        for i in 0..40 {
+            t.insert(sha3(format!("name-{}", i).as_bytes()), b"value")
+                .unwrap();
+            match t.root {
+                Some(Node::Internal { hash, .. }) if i > 0 => {
+                    assert_eq!(hash, full_hash(t.root.as_ref().unwrap()))
+                }
+                _ => assert!(i == 0),
+            }
+        }
+
+        let pending = t.get_root();
+        t.commit().unwrap();
+        assert_eq!(t.get_root(), pending);
+    }
+
+    #[test]
+    fn segregated_layout_writes_values_first() {
+        let dir = test_dir("segregated_layout_writes_values_first");
+        let options = || StoreOptions {
+            layout: WriteLayout::Segregated,
+            ..Default::default()
+        };
+        let keys: Vec<Digest> = (0..20)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        {
+            let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+            for k in &keys {
+                t.insert(*k, b"value").unwrap();
+            }
+            t.commit().unwrap();
+        }
+
+        let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+        let mut last_value = 0;
+        let mut first_node = u32::MAX;
+        for k in &keys {
+            assert_eq!(t.get(*k).unwrap(), Some(Vec::from("value")));
+
+            // Walk down to the key's leaf
+            let mut node = t.root.clone().unwrap();
+            let mut depth = 0;
+            loop {
+                node = match t.store.expand(node).unwrap() {
+                    Node::Internal { left, right, .. } => {
+                        depth += 1;
+                        if has_bit(k, depth - 1) {
+                            *right
+                        } else {
+                            *left
+                        }
+                    }
+                    leaf => {
+                        if let Node::Leaf { pos, vpos, .. } = leaf {
+                            last_value = last_value.max(vpos);
+                            first_node = first_node.min(pos >> 1);
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        assert!(last_value < first_node);
+    }
+
+    #[test]
+    fn values_over_the_limit_are_rejected() {
+        let options = StoreOptions {
+            max_value_size: Some(8),
+            ..Default::default()
+        };
+        let dir = test_dir("values_over_the_limit_are_rejected");
+        let mut t = UrkelTree::open_with_options(&dir, options).unwrap();
+        let key = sha3(b"name-1");
+
+        t.insert(key, &[1; 8]).unwrap();
+        match t.insert(key, &[2; 9]) {
+            Err(Error::ValueTooLarge { size: 9, max: 8 }) => {}
+            other => panic!("Expected ValueTooLarge, got {:?}", other),
+        }
+        assert_eq!(t.get(key).unwrap(), Some(vec![1; 8]));
+
+        // The u16 size encoding caps every tree
+        let big = vec![0; 0x10000];
+        let mut t = UrkelTree::open(test_dir("values_over_u16"));
+        assert!(t.insert(key, &big).is_err());
+    }
+
+    #[test]
+    fn salted_leaves() {
+        let salt = [5u8; 32];
+        let key = sha3(b"name-1");
+        let options = StoreOptions {
+            leaf_salt: Some(salt),
+            ..Default::default()
+        };
+        let mut salted = UrkelTree::open_with_options(test_dir("salted"), options).unwrap();
+        let mut plain = UrkelTree::open(test_dir("unsalted"));
+        for t in [&mut salted, &mut plain].iter_mut() {
+            t.insert(key, b"value-1").unwrap();
+            t.insert(sha3(b"name-2"), b"value-2").unwrap();
+            t.commit().unwrap();
+        }
+
+        let root = salted.get_root();
+        assert!(root != plain.get_root());
+
+        let proof = salted.prove(key).unwrap();
+        assert!(proof.clone().verify(root, key, 256).is_err());
+        assert_eq!(
+            proof
+                .clone()
+                .verify_salted(root, key, 256, Some(&salt))
+                .unwrap(),
+            b"value-1"
+        );
+        assert!(proof
+            .clone()
+            .verify_salted(root, key, 256, Some(&[6u8; 32]))
+            .is_err());
+    }
+
+    #[test]
+    fn audit_mode_catches_silent_corruption() {
+        use std::fs;
+
+        let dir = test_dir("audit_mode_catches_silent_corruption");
+        let key1 = sha3(b"name-1");
+        {
+            let mut t = UrkelTree::open(&dir);
+            t.insert(key1, b"value-1").unwrap();
+            t.insert(sha3(b"name-2"), b"value-2").unwrap();
+            t.commit().unwrap();
+        }
+
+        // Change key1's value in place
+        let path = ::std::path::Path::new(&dir).join("0000000001");
+        let mut bits = fs::read(&path).unwrap();
+        let at = bits.windows(7).position(|w| w == b"value-1").unwrap();
+        bits[at..at + 7].copy_from_slice(b"VALUE-1");
+        fs::write(&path, bits).unwrap();
+
+        let mut t = UrkelTree::open(&dir);
+        assert_eq!(t.get(key1).unwrap(), Some(Vec::from("VALUE-1")));
+
+        let options = StoreOptions {
+            audit: true,
+            ..Default::default()
+        };
+        let mut t = UrkelTree::open_with_options(&dir, options).unwrap();
+        match t.get(key1) {
+            Err(e @ Error::HashMismatch { .. }) => assert!(e.is_corruption()),
+            other => panic!("Expected a hash mismatch, got {:?}", other),
+        }
+        assert_eq!(t.get(sha3(b"name-2")).unwrap(), Some(Vec::from("value-2")));
+    }
+
+    #[test]
+    fn constant_time_key_matching() {
+        let options = StoreOptions {
+            constant_time_keys: true,
+            ..Default::default()
+        };
+        let dir = test_dir("constant_time_key_matching");
+        let mut t = UrkelTree::open_with_options(&dir, options).unwrap();
+        let key = sha3(b"name-1");
+        t.insert(key, b"value-1").unwrap();
+        t.insert(key, b"value-2").unwrap();
+        t.commit().unwrap();
+
+        assert_eq!(t.get(key).unwrap(), Some(Vec::from("value-2")));
+        assert_eq!(t.get(sha3(b"name-2")).unwrap(), None);
+        assert_eq!(t.prove(key).unwrap().proof_type, ProofType::Exists);
+
+        let mut other = key;
+        other.0[31] ^= 1;
+        assert!(ct_eq(&key, &key) && !ct_eq(&key, &other));
+    }
+
+    // Count the nodes that haven't been written
+    fn count_dirty(node: &Node) -> usize {
+        match node {
+            Node::Internal {
+                index: 0,
+                left,
+                right,
+                ..
+            } => 1 + count_dirty(left) + count_dirty(right),
+            Node::Leaf { index: 0, .. } => 1,
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn remove_matches_never_inserting() {
+        let keys: Vec<Digest> = (0..40)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        let (gone, kept) = keys.split_at(15);
+
+        let mut expected = UrkelTree::open(test_dir("remove_expected"));
+        for k in kept {
+            expected.insert(*k, b"value").unwrap();
+        }
+
+        let dir = test_dir("remove_matches_never_inserting");
+        let mut t = UrkelTree::open(&dir);
+        for k in &keys {
+            t.insert(*k, b"value").unwrap();
+        }
+
+        // Uncommitted leaves
+        for k in &gone[..5] {
+            assert!(t.remove(*k).unwrap());
+            assert_eq!(t.dirty_nodes(), count_dirty(t.root.as_ref().unwrap()));
+        }
+        assert!(!t.remove(gone[0]).unwrap());
+        t.commit().unwrap();
+
+        // ...and ones resolved from the store
+        for k in &gone[5..] {
+            assert!(t.remove(*k).unwrap());
+            assert_eq!(t.get(*k).unwrap(), None);
+        }
+        assert_eq!(t.get_root(), expected.get_root());
+        t.commit().unwrap();
+        assert_eq!(t.get_root(), expected.get_root());
+        assert_eq!(t.get(kept[0]).unwrap(), Some(Vec::from("value")));
+
+        // The collapsed structure is what was written
+        drop(t);
+        let mut t = UrkelTree::open(&dir);
+        assert_eq!(t.get_root(), expected.get_root());
+        assert_eq!(t.get(gone[7]).unwrap(), None);
+        assert_eq!(t.get(kept[3]).unwrap(), Some(Vec::from("value")));
+
+        for k in kept {
+            assert!(t.remove(*k).unwrap());
+        }
+        assert_eq!(t.get_root(), Digest::default());
+        t.commit().unwrap();
+        drop(t);
+        let mut t = UrkelTree::open(&dir);
+        assert_eq!(t.get_root(), Digest::default());
+        assert_eq!(t.iter().count(), 0);
+    }
+
+    #[test]
+    fn remove_batch_matches_removing_one_by_one() {
+        let keys: Vec<Digest> = (0..60)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        let (gone, kept) = keys.split_at(25);
+
+        let mut expected = UrkelTree::open(test_dir("remove_batch_expected"));
+        for k in kept {
+            expected.insert(*k, b"value").unwrap();
+        }
+
+        let mut t = UrkelTree::open(test_dir("remove_batch_matches_removing_one_by_one"));
+        for k in &keys[..40] {
+            t.insert(*k, b"value").unwrap();
+        }
+        t.commit().unwrap();
+        for k in &keys[40..] {
+            t.insert(*k, b"value").unwrap();
+        }
+
+        // Committed and uncommitted leaves, a duplicate and a missing key
+        let mut batch = gone.to_vec();
+        batch.push(gone[3]);
+        batch.push(sha3(b"missing"));
+        assert_eq!(t.remove_batch(&batch).unwrap(), gone.len());
+        assert_eq!(t.dirty_nodes(), count_dirty(t.root.as_ref().unwrap()));
+        assert_eq!(t.get_root(), expected.get_root());
+        assert_eq!(t.get(gone[0]).unwrap(), None);
+
+        assert_eq!(t.remove_batch(gone).unwrap(), 0);
+        t.commit().unwrap();
+        assert_eq!(t.get_root(), expected.get_root());
+
+        assert_eq!(t.remove_batch(kept).unwrap(), kept.len());
+        assert_eq!(t.get_root(), Digest::default());
+    }
+
+    #[test]
+    fn apply_is_all_or_nothing() {
+        let options = StoreOptions {
+            max_value_size: Some(8),
+            value_index: true,
+            ..Default::default()
+        };
+        let mut t =
+            UrkelTree::open_with_options(test_dir("apply_is_all_or_nothing"), options).unwrap();
+        let (a, b, c) = (sha3(b"a"), sha3(b"b"), sha3(b"c"));
+        t.insert(a, b"old").unwrap();
+        t.commit().unwrap();
+
+        t.apply(&[Op::Put(b, b"one"), Op::Delete(a), Op::Put(c, b"two")])
+            .unwrap();
+        let root = t.get_root();
+        assert_eq!(t.get(a).unwrap(), None);
+        assert_eq!(t.keys_with_value(sha3(b"two")), Some(vec![c]));
+
+        // The oversized value fails the whole batch
+        let big = [0; 9];
+        match t.apply(&[Op::Delete(b), Op::Put(a, b"new"), Op::Put(c, &big)]) {
+            Err(Error::ValueTooLarge { .. }) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(t.get_root(), root);
+        assert_eq!(t.get(b).unwrap(), Some(b"one".to_vec()));
+        assert_eq!(t.keys_with_value(sha3(b"new")), Some(vec![]));
+        assert_eq!(t.dirty_nodes(), count_dirty(t.root.as_ref().unwrap()));
+
+        t.commit().unwrap();
+        assert_eq!(t.get_root(), root);
+    }
+
+    #[test]
+    fn proves_keys_at_earlier_roots() {
+        let mut t = UrkelTree::open(test_dir("proves_keys_at_earlier_roots"));
+        let key = sha3(b"name-1");
+        t.insert(key, b"old").unwrap();
+        t.insert(sha3(b"name-2"), b"other").unwrap();
+        t.commit().unwrap();
+        let old_root = t.get_root();
+        t.insert(key, b"new").unwrap();
+        t.commit().unwrap();
+        t.remove(key).unwrap();
+        t.commit().unwrap();
+        let latest = t.get_root();
+
+        let proof = t.prove_historical(old_root, key).unwrap();
+        assert_eq!(proof.roots.len(), 3);
+        assert_eq!(proof.proved_root(), Some(old_root));
+        let proof = HistoricalProof::decode(&proof.encode().unwrap()).unwrap();
+        assert_eq!(proof.verify(latest, key, 256), Ok(Some(Vec::from("old"))));
+        assert!(proof.verify(old_root, key, 256).is_err());
+
+        // Absent now
+        let now = t.prove_historical(latest, key).unwrap();
+        assert_eq!(now.roots, vec![latest]);
+        assert_eq!(now.verify(latest, key, 256), Ok(None));
+
+        // The empty tree before the first commit is in the history too
+        let empty = t.prove_historical(Digest::default(), key).unwrap();
+        assert_eq!(empty.roots.len(), 4);
+
+        match t.prove_historical(sha3(b"elsewhere"), key) {
+            Err(Error::UnknownRoot { .. }) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sized_roots_commit_to_the_leaf_count() {
+        let dir = test_dir("sized_roots_commit_to_the_leaf_count");
+        let counted = || StoreOptions {
+            count_leaves: true,
+            ..Default::default()
+        };
+        let keys: Vec<Digest> = (0..30)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        {
+            let mut t = UrkelTree::open_with_options(&dir, counted()).unwrap();
+            for k in &keys {
+                t.insert(*k, b"value").unwrap();
+            }
+            t.insert(keys[0], b"changed").unwrap();
+            t.remove(keys[1]).unwrap();
+            t.remove_batch(&keys[2..5]).unwrap();
+            assert_eq!(t.leaf_count().unwrap(), 26);
+            t.commit().unwrap();
+        }
+
+        // Counted on open, and the same as walking the tree
+        let mut t = UrkelTree::open_with_options(&dir, counted()).unwrap();
+        assert_eq!(t.leaf_count().unwrap(), 26);
+        let commitment = t.sized_root().unwrap();
+        let proof = t.prove_sized(keys[0]).unwrap();
+        drop(t);
+        let mut walked = UrkelTree::open(&dir);
+        assert_eq!(walked.leaf_count().unwrap(), 26);
+        assert_eq!(walked.sized_root().unwrap(), commitment);
+
+        let proof = SizedProof::decode(&proof.encode().unwrap()).unwrap();
+        assert_eq!(
+            proof.verify(commitment, keys[0], 256),
+            Ok(Some(Vec::from("changed")))
+        );
+        let mut short = proof.clone();
+        short.leaf_count -= 1;
+        assert!(short.verify(commitment, keys[0], 256).is_err());
+    }
+
+    #[test]
+    fn iterates_in_key_order() {
+        let dir = test_dir("iterates_in_key_order");
+        let mut keys: Vec<Digest> = (0..20)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        let mut t = UrkelTree::open(&dir);
+        assert_eq!(t.iter().count(), 0);
+        for k in &keys[..10] {
+            t.insert(*k, b"old").unwrap();
+        }
+        t.commit().unwrap();
+        for k in &keys[10..] {
+            t.insert(*k, b"new").unwrap();
+        }
+
+        // Committed and uncommitted pairs alike
+        let pairs: Vec<(Digest, Vec<u8>)> = t.iter().map(|r| r.unwrap()).collect();
+        let mut looped = 0;
+        for pair in &mut t {
+            assert_eq!(pair.unwrap(), pairs[looped]);
+            looped += 1;
+        }
+        assert_eq!(looped, pairs.len());
+        keys.sort_by_key(|k| k.0);
+        assert_eq!(pairs.iter().map(|p| p.0).collect::<Vec<_>>(), keys);
+        assert_eq!(pairs.iter().filter(|p| p.1 == b"new").count(), 10);
+
+        // Reopened, everything is read back from the store as it's reached
+        t.commit().unwrap();
+        drop(t);
+        let mut t = UrkelTree::open(&dir);
+        let reopened: Vec<(Digest, Vec<u8>)> = t.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(reopened, pairs);
+    }
+
+    #[test]
+    fn committed_view_ignores_staged_changes() {
+        let key1 = sha3(b"name-1");
+        let key2 = sha3(b"name-2");
+        let mut t = UrkelTree::open(test_dir("committed_view_ignores_staged_changes"));
+        assert_eq!(t.committed().unwrap().get(key1).unwrap(), None);
+
+        t.insert(key1, b"value-1").unwrap();
+        t.commit().unwrap();
+        let committed_root = t.get_root();
+        t.insert(key1, b"value-2").unwrap();
+        t.insert(key2, b"value-2").unwrap();
+
+        let mut view = t.committed().unwrap();
+        assert_eq!(view.root(), committed_root);
+        assert_eq!(view.get(key1).unwrap(), Some(Vec::from("value-1")));
+        assert_eq!(view.get(key2).unwrap(), None);
+        assert_eq!(view.iter().count(), 1);
+        let proof = view.prove(key2).unwrap();
+        assert!(proof.check_salted(committed_root, key2, 256, None).is_ok());
+
+        // The working tree still sees its own writes
+        assert_eq!(t.get(key1).unwrap(), Some(Vec::from("value-2")));
+        assert_ne!(t.get_root(), committed_root);
+    }
+
+    #[test]
+    fn snapshots_stay_pinned() {
+        use std::thread;
+
+        let keys: Vec<Digest> = (0..20u8).map(|i| sha3(&[i])).collect();
+        let mut t = UrkelTree::open(test_dir("snapshots_stay_pinned"));
+        for k in &keys[..10] {
+            t.insert(*k, b"old").unwrap();
+        }
+        t.commit().unwrap();
+        let root = t.get_root();
+        t.insert(keys[10], b"staged").unwrap();
+
+        let mut snapshot = t.snapshot().unwrap();
+        assert_eq!(snapshot.root(), root);
+        let (read, absent) = (keys[3], keys[15]);
+        let reader = thread::spawn(move || {
+            let found = snapshot.get(read).unwrap();
+            let proof = snapshot.prove(absent).unwrap();
+            let mut count = 0;
+            for entry in &mut snapshot {
+                entry.unwrap();
+                count += 1;
+            }
+            (found, proof, count)
+        });
+
+        for k in &keys[10..] {
+            t.insert(*k, b"new").unwrap();
+        }
+        t.insert(keys[3], b"new").unwrap();
+        t.commit().unwrap();
+
+        let (found, proof, count) = reader.join().unwrap();
+        assert_eq!(found, Some(Vec::from("old")));
+        assert!(proof.check_salted(root, keys[15], 256, None).is_ok());
+        assert_eq!(count, 10);
+        assert_eq!(t.get(keys[3]).unwrap(), Some(Vec::from("new")));
+    }
+
+    #[test]
+    fn checks_out_earlier_roots() {
+        let key = sha3(b"name-1");
+        let mut t = UrkelTree::open(test_dir("checks_out_earlier_roots"));
+        let mut roots = vec![t.get_root()];
+        for i in 0..3u8 {
+            t.insert_owned(key, vec![i]).unwrap();
+            t.insert_owned(sha3(&[i]), vec![i]).unwrap();
+            t.commit().unwrap();
+            roots.push(t.get_root());
+        }
+
+        let mut first = t.checkout(roots[1]).unwrap();
+        assert_eq!(first.root(), roots[1]);
+        assert_eq!(first.get(key).unwrap(), Some(vec![0]));
+        assert_eq!(first.iter().count(), 2);
+        let proof = first.prove(sha3(&[2])).unwrap();
+        assert!(proof.check_salted(roots[1], sha3(&[2]), 256, None).is_ok());
+        assert_eq!(t.checkout(roots[0]).unwrap().get(key).unwrap(), None);
+        match t.checkout(sha3(b"never")) {
+            Err(Error::UnknownRoot { .. }) => {}
+            other => panic!("Unexpected {:?}", other.err().map(|e| e.to_string())),
+        }
+
+        // The tree itself stays where it was
+        assert_eq!(t.get_root(), roots[3]);
+        assert_eq!(t.get(key).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn reads_at_earlier_roots() {
+        let key = sha3(b"name-1");
+        let mut t = UrkelTree::open(test_dir("reads_at_earlier_roots"));
+        let mut roots = vec![];
+        for i in 0..3u8 {
+            t.insert_owned(key, vec![i]).unwrap();
+            t.commit().unwrap();
+            roots.push(t.get_root());
+        }
+        t.remove(key).unwrap();
+
+        for (i, root) in roots.iter().enumerate() {
+            assert_eq!(t.get_at(*root, key).unwrap(), Some(vec![i as u8]));
+        }
+        assert_eq!(t.get_at(roots[0], sha3(b"other")).unwrap(), None);
+        assert!(t.get_at(sha3(b"never"), key).is_err());
+        assert_eq!(t.get(key).unwrap(), None);
+    }
+
+    #[test]
+    fn proves_at_earlier_roots() {
+        let key = sha3(b"name-1");
+        let mut t = UrkelTree::open(test_dir("proves_at_earlier_roots"));
+        let mut roots = vec![];
+        for i in 0..3u8 {
+            t.insert_owned(key, vec![i]).unwrap();
+            t.insert_owned(sha3(&[i]), vec![i]).unwrap();
+            t.commit().unwrap();
+            roots.push(t.get_root());
+        }
+
+        for (i, root) in roots.iter().enumerate() {
+            let mut proof = t.prove_at(*root, key).unwrap();
+            assert!(proof
+                .check_salted(roots[(i + 1) % 3], key, 256, None)
+                .is_err());
+            assert_eq!(proof.verify(*root, key, 256).unwrap(), vec![i as u8]);
+        }
+        // Keys added later are proven absent
+        let proof = t.prove_at(roots[0], sha3(&[2])).unwrap();
+        assert!(proof.check_salted(roots[0], sha3(&[2]), 256, None).is_ok());
+        match t.prove_at(sha3(b"never"), key) {
+            Err(Error::UnknownRoot { .. }) => {}
+            other => panic!("Unexpected {:?}", other.err().map(|e| e.to_string())),
+        }
+    }
+
+    #[test]
+    fn contains_without_reading_values() {
+        let dir = test_dir("contains_without_reading_values");
+        let key = sha3(b"name-1");
+        let mut t = UrkelTree::open(&dir);
+        assert!(!t.contains_key(key).unwrap());
+        t.insert(key, &[1; 100]).unwrap();
+        assert!(t.contains_key(key).unwrap());
+        t.commit().unwrap();
+        drop(t);
+
+        let mut t = UrkelTree::open(&dir);
+        let reads = t.io_stats().reads;
+        assert!(t.contains_key(key).unwrap());
+        let walk = t.io_stats().reads - reads;
+        // The same walk, and only a get goes on to read the value
+        t.get(key).unwrap();
+        assert_eq!(t.io_stats().reads - reads, 2 * walk + 1);
+        assert!(!t.contains_key(sha3(b"name-2")).unwrap());
+        t.remove(key).unwrap();
+        assert!(!t.contains_key(key).unwrap());
+    }
+
+    #[test]
+    fn multi_get_reads_shared_paths_once() {
+        let dir = test_dir("multi_get_reads_shared_paths_once");
+        let keys: Vec<Digest> = (0..50u8).map(|i| sha3(&[i])).collect();
+        let mut t = UrkelTree::open(&dir);
+        for (i, key) in keys.iter().enumerate().skip(10) {
+            t.insert_owned(*key, vec![i as u8]).unwrap();
+        }
+        t.commit().unwrap();
+        drop(t);
+
+        // Out of order, repeated and missing keys alike
+        let mut wanted = keys.clone();
+        wanted.reverse();
+        wanted.push(keys[20]);
+        let mut t = UrkelTree::open(&dir);
+        let reads = t.io_stats().reads;
+        let values = t.multi_get(&wanted).unwrap();
+        let batched = t.io_stats().reads - reads;
+        for (key, value) in wanted.iter().zip(&values) {
+            assert_eq!(*value, t.get(*key).unwrap());
+        }
+        assert_eq!(values[0], Some(vec![49]));
+        assert_eq!(values[49], None);
+        assert_eq!(values[50], Some(vec![20]));
+        assert!(batched < t.io_stats().reads - reads - batched);
+    }
+
+    #[test]
+    fn diffs_between_roots() {
+        let mut t = UrkelTree::open(test_dir("diffs_between_roots"));
+        for i in 0..20u8 {
+            t.insert_owned(sha3(&[i]), vec![i]).unwrap();
+        }
+        t.commit().unwrap();
+        let old = t.get_root();
+
+        t.insert_owned(sha3(&[3]), vec![33]).unwrap();
+        t.remove(sha3(&[5])).unwrap();
+        t.insert_owned(sha3(&[30]), vec![30]).unwrap();
+        t.commit().unwrap();
+        let new = t.get_root();
+
+        let mut expected = vec![
+            KeyChange {
+                key: sha3(&[3]),
+                old: Some(vec![3]),
+                new: Some(vec![33]),
+            },
+            KeyChange {
+                key: sha3(&[5]),
+                old: Some(vec![5]),
+                new: None,
+            },
+            KeyChange {
+                key: sha3(&[30]),
+                old: None,
+                new: Some(vec![30]),
+            },
+        ];
+        expected.sort_by_key(|c| c.key.0);
+        let changes: Vec<KeyChange> = t
+            .diff_roots(old, new)
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(changes, expected);
+
+        // Backwards, and against the empty tree
+        let undone: Vec<KeyChange> = t
+            .diff_roots(new, old)
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(undone.len(), 3);
+        assert_eq!(undone[0].old, expected[0].new);
+        let everything = t.diff_roots(Digest::default(), new).unwrap();
+        assert_eq!(
+            everything
+                .filter(|c| c.as_ref().unwrap().old.is_none())
+                .count(),
+            20
+        );
+        assert_eq!(t.diff_roots(new, new).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn merges_shards() {
+        let mut a = UrkelTree::open(test_dir("merges_shards_a"));
+        let mut b = UrkelTree::open(test_dir("merges_shards_b"));
+        let mut whole = UrkelTree::open(test_dir("merges_shards_whole"));
+        for i in 0..20u8 {
+            let shard = if i % 2 == 0 { &mut a } else { &mut b };
+            shard.insert_owned(sha3(&[i]), vec![i]).unwrap();
+            whole.insert_owned(sha3(&[i]), vec![i]).unwrap();
+        }
+        // In both, with the larger value winning
+        a.insert_owned(sha3(&[1]), vec![100]).unwrap();
+        whole.insert_owned(sha3(&[1]), vec![100]).unwrap();
+        a.insert_owned(sha3(&[2]), vec![200]).unwrap();
+        b.insert_owned(sha3(&[2]), vec![2]).unwrap();
+        whole.insert_owned(sha3(&[2]), vec![200]).unwrap();
+        a.commit().unwrap();
+        let before = a.get_root();
+
+        let larger = |_key: Digest, ours: &[u8], theirs: &[u8]| ours.max(theirs).to_vec();
+        assert_eq!(a.merge_from(&mut b, larger).unwrap(), 9);
+        assert_eq!(a.get_root(), whole.get_root());
+        assert_eq!(a.merge_from(&mut b, larger).unwrap(), 0);
+
+        // And from an earlier commit, keeping ours
+        a.insert_owned(sha3(&[2]), vec![3]).unwrap();
+        a.remove(sha3(&[4])).unwrap();
+        let changed = a
+            .merge_from_root(before, |_, ours, _| ours.to_vec())
+            .unwrap();
+        assert_eq!(changed, 1);
+        assert_eq!(a.get(sha3(&[4])).unwrap(), Some(vec![4]));
+        assert_eq!(a.get(sha3(&[2])).unwrap(), Some(vec![3]));
+    }
+
+    #[test]
+    fn clears_to_the_empty_tree() {
+        let dir = test_dir("clears_to_the_empty_tree");
+        let key = sha3(b"name-1");
+        let mut t = UrkelTree::open(&dir);
+        let watch = t.watch(key);
+        t.insert(key, b"value-1").unwrap();
+        t.commit().unwrap();
+        let full = t.get_root();
+
+        t.insert(sha3(b"name-2"), b"value-2").unwrap();
+        t.clear().unwrap();
+        assert!(t.is_empty());
+        assert_eq!(t.get(key).unwrap(), None);
+        t.commit().unwrap();
+        assert_eq!(t.get_root(), Digest::default());
+        assert_eq!(t.len().unwrap(), 0);
+        let change = watch.try_iter().last().unwrap();
+        assert_eq!(change.new, None);
+        drop(t);
+
+        // Reopened empty, with the history still there
+        let mut t = UrkelTree::open(&dir);
+        assert!(t.is_empty());
+        assert_eq!(t.previous_root(), Some(full));
+        assert_eq!(t.get_at(full, key).unwrap(), Some(b"value-1".to_vec()));
+    }
+
+    #[test]
+    fn inserts_only_when_absent() {
+        let key = sha3(b"name-1");
+        let mut t = UrkelTree::open(test_dir("inserts_only_when_absent"));
+        let watch = t.watch(key);
+        assert!(t.insert_if_absent(key, b"value-1".to_vec()).unwrap());
+        for i in 2..50 {
+            let name = format!("name-{}", i);
+            t.insert(sha3(name.as_bytes()), b"value").unwrap();
+        }
+        t.commit().unwrap();
+        let root = t.get_root();
+        assert_eq!(watch.try_iter().count(), 1);
+
+        // The path to the key is left as committed
+        let written = t.io_stats().nodes_written;
+        assert!(!t.insert_if_absent(key, b"value-2".to_vec()).unwrap());
+        assert_eq!(t.get(key).unwrap(), Some(b"value-1".to_vec()));
+        assert_eq!(t.dirty_nodes(), 0);
+        t.commit().unwrap();
+        assert_eq!(t.io_stats().nodes_written, written);
+        assert_eq!(t.get_root(), root);
+        assert_eq!(watch.try_iter().count(), 0);
+        assert_eq!(t.len().unwrap(), 49);
+
+        // So is the path a pending insert dirtied
+        t.insert(sha3(b"name-50"), b"value").unwrap();
+        let dirty = t.dirty_nodes();
+        assert!(!t
+            .insert_if_absent(sha3(b"name-50"), b"value-2".to_vec())
+            .unwrap());
+        assert!(!t.insert_if_absent(key, b"value-2".to_vec()).unwrap());
+        assert_eq!(t.dirty_nodes(), dirty);
+        t.commit().unwrap();
+        assert_eq!(t.get(sha3(b"name-50")).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn outlives_inserted_values() {
+        struct Holder {
+            tree: UrkelTree,
+        }
+        let mut holder = Holder {
+            tree: UrkelTree::open(test_dir("outlives_inserted_values")),
+        };
+        {
+            let value = format!("value-{}", 1).into_bytes();
+            holder.tree.insert(sha3(b"name-1"), &value).unwrap();
+        }
+        assert_eq!(
+            holder.tree.get(sha3(b"name-1")).unwrap(),
+            Some(b"value-1".to_vec())
+        );
+    }
+
+    #[test]
+    fn hashes_raw_keys() {
+        let mut t = UrkelTree::open(test_dir("hashes_raw_keys"));
+        t.insert_raw(b"name-1", b"value-1").unwrap();
+        assert_eq!(t.get(sha3(b"name-1")).unwrap(), Some(b"value-1".to_vec()));
+        assert_eq!(t.get_raw(b"name-1").unwrap(), Some(b"value-1".to_vec()));
+        assert_eq!(t.get_raw(b"name-2").unwrap(), None);
+        let root = t.get_root();
+        let mut proof = t.prove_raw(b"name-1").unwrap();
+        let key = t.hash_key(b"name-1");
+        assert_eq!(proof.verify(root, key, 256).unwrap(), b"value-1".to_vec());
+
+        // Cut to fit narrower keys
+        let options = StoreOptions {
+            key_size: Some(20),
+            ..Default::default()
+        };
+        let mut t =
+            UrkelTree::open_with_options(test_dir("hashes_raw_keys_narrow"), options).unwrap();
+        t.insert_raw(b"name-1", b"value-1").unwrap();
+        assert_eq!(t.hash_key(b"name-1").0[..20], sha3(b"name-1").0[..20]);
+        assert_eq!(t.get_raw(b"name-1").unwrap(), Some(b"value-1".to_vec()));
+    }
+
+    #[test]
+    fn first_and_last_keys() {
+        let mut t = UrkelTree::open(test_dir("first_and_last_keys"));
+        assert_eq!(t.first_key().unwrap(), None);
+        assert_eq!(t.last_entry().unwrap(), None);
+
+        let mut keys: Vec<Digest> = (0..20)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        for k in &keys {
+            t.insert(*k, b"value").unwrap();
+        }
+        t.commit().unwrap();
+        keys.sort_by_key(|k| k.0);
+
+        assert_eq!(t.first_key().unwrap(), Some(keys[0]));
+        assert_eq!(t.last_key().unwrap(), Some(keys[19]));
+        assert_eq!(
+            t.first_entry().unwrap(),
+            Some((keys[0], Vec::from("value")))
+        );
+
+        // Only one key left: it's both ends
+        for k in &keys[1..] {
+            t.remove(*k).unwrap();
+        }
+        assert_eq!(t.last_key().unwrap(), Some(keys[0]));
+    }
+
+    #[test]
+    fn lists_keys_a_page_at_a_time() {
+        let mut t = UrkelTree::open(test_dir("lists_keys_a_page_at_a_time"));
+        let mut keys: Vec<Digest> = (0..25)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+        for k in &keys {
+            t.insert(*k, b"value").unwrap();
+        }
+        t.commit().unwrap();
+        keys.sort_by_key(|k| k.0);
+
+        let mut listed = vec![];
+        let mut start_after = None;
+        let mut pages = 0;
+        loop {
+            let page = t.list(start_after, 10).unwrap();
+            listed.extend(page.keys);
+            pages += 1;
+            match page.next {
+                Some(next) => start_after = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(listed, keys);
+        assert_eq!(pages, 3);
+
+        // An exactly full last page says there's nothing more
+        assert_eq!(t.list(Some(keys[14]), 10).unwrap().next, None);
+        assert!(t.list(None, 0).unwrap().keys.is_empty());
+    }
+
+    #[test]
+    fn value_index_tracks_commits() {
+        let dir = test_dir("value_index_tracks_commits");
+        let options = || StoreOptions {
+            value_index: true,
+            ..Default::default()
+        };
+        let (a, b, c) = (sha3(b"name-1"), sha3(b"name-2"), sha3(b"name-3"));
+        let shared = sha3(b"shared");
+        let sorted = |mut keys: Vec<Digest>| {
+            keys.sort_by_key(|k| k.0);
+            keys
+        };
+        {
+            let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+            t.insert(a, b"shared").unwrap();
+            t.insert(b, b"shared").unwrap();
+            t.insert(c, b"shared").unwrap();
+            t.commit().unwrap();
+            t.insert(c, b"other").unwrap();
+            t.remove(b).unwrap();
+            t.commit().unwrap();
+        }
+
+        let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+        assert_eq!(t.keys_with_value(shared), Some(vec![a]));
+        assert_eq!(t.keys_with_value(sha3(b"other")), Some(vec![c]));
+
+        // Reverting rebuilds it from the tree
+        t.revert_to_previous_commit().unwrap();
+        assert_eq!(t.keys_with_value(shared), Some(sorted(vec![a, b, c])));
+        assert_eq!(t.keys_with_value(sha3(b"other")), Some(vec![]));
+
+        let plain = UrkelTree::open(test_dir("value_index_off"));
+        assert_eq!(plain.keys_with_value(shared), None);
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let dir = test_dir("compare_and_swap");
+        let mut t = UrkelTree::open(&dir);
+        let key = sha3(b"name-1");
+
+        t.compare_and_swap(key, None, Some(b"one".to_vec()))
+            .unwrap();
+        for i in 2..50 {
+            let name = format!("name-{}", i);
+            t.insert(sha3(name.as_bytes()), b"value").unwrap();
+        }
+        t.commit().unwrap();
+        let root = t.get_root();
+        match t.compare_and_swap(key, Some(b"zero"), Some(b"two".to_vec())) {
+            Err(Error::CompareFailed { key: k, current }) => {
+                assert_eq!(k, key);
+                assert_eq!(current, Some(b"one".to_vec()));
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert!(t.compare_and_swap(key, None, None).is_err());
+        match t.compare_and_swap(sha3(b"name-0"), Some(b"one"), None) {
+            Err(Error::CompareFailed { current: None, .. }) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        // Failed swaps leave the tree as it was
+        assert_eq!(t.dirty_nodes(), 0);
+        t.commit().unwrap();
+        assert_eq!(t.get_root(), root);
+
+        t.compare_and_swap(key, Some(b"one"), Some(b"two".to_vec()))
+            .unwrap();
+        assert_eq!(t.get(key).unwrap(), Some(b"two".to_vec()));
+        t.commit().unwrap();
+        assert_eq!(t.get(key).unwrap(), Some(b"two".to_vec()));
+        drop(t);
+
+        // One walk, the one a get takes, without reading the value
+        let mut t = UrkelTree::open(&dir);
+        let reads = t.io_stats().reads;
+        t.compare_and_swap(key, Some(b"two"), None).unwrap();
+        let swap = t.io_stats().reads - reads;
+        assert_eq!(t.get(key).unwrap(), None);
+        drop(t);
+        let mut t = UrkelTree::open(&dir);
+        let reads = t.io_stats().reads;
+        t.get(key).unwrap();
+        assert_eq!(t.io_stats().reads - reads, swap + 1);
+    }
+
+    #[test]
+    fn merges_counters() {
+        let mut t = UrkelTree::open(test_dir("merges_counters"));
+        let key = sha3(b"counter");
+        match t.merge_value(key, vec![1]) {
+            Err(Error::NoMergeOperator) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        // A little endian u64 counter
+        t.set_merge_operator(|_: Digest, existing: Option<&[u8]>, operand: &[u8]| {
+            let count =
+                existing.map_or(0, LittleEndian::read_u64) + LittleEndian::read_u64(operand);
+            let mut bits = vec![0; 8];
+            LittleEndian::write_u64(&mut bits, count);
+            bits
+        });
+        let add = |n: u64| {
+            let mut bits = vec![0; 8];
+            LittleEndian::write_u64(&mut bits, n);
+            bits
+        };
+
+        t.merge_value(key, add(2)).unwrap();
+        t.merge_value(key, add(3)).unwrap();
+        assert_eq!(t.get(key).unwrap(), Some(add(5)));
+        let before = t.get_root();
+        t.commit().unwrap();
+        assert_ne!(t.get_root(), before);
+        assert_eq!(t.prove(key).unwrap().value, Some(add(5)));
+
+        t.merge_value(key, add(1)).unwrap();
+        t.insert_owned(key, add(10)).unwrap();
+        t.merge_value(key, add(4)).unwrap();
+        t.commit().unwrap();
+        assert_eq!(t.get(key).unwrap(), Some(add(14)));
+    }
+
+    #[test]
+    fn authenticated_map() {
+        // Written against the trait, as a framework would
+        fn check<M: AuthenticatedMap>(map: &mut M, key: Digest, value: &[u8]) {
+            map.insert(key, value).unwrap();
+            let root = map.root();
+            let proof = map.prove(key).unwrap();
+            assert_eq!(map.verify(root, key, &proof).unwrap(), Some(value.to_vec()));
+            assert!(map.verify(sha3(b"other"), key, &proof).is_err());
+
+            assert!(map.remove(key).unwrap());
+            assert_eq!(map.get(key).unwrap(), None);
+            let root = map.root();
+            let proof = map.prove(key).unwrap();
+            assert_eq!(map.verify(root, key, &proof).unwrap(), None);
+        }
+
+        let mut t = UrkelTree::open(test_dir("authenticated_map"));
+        t.insert(sha3(b"name-1"), b"value-1").unwrap();
+        check(&mut t, sha3(b"name-2"), b"value-2");
+    }
+
+    #[test]
+    fn dirty_node_budget() {
+        let options = StoreOptions {
+            max_dirty_nodes: Some(60),
+            ..Default::default()
+        };
+        let dir = test_dir("dirty_node_budget");
+        let mut t = UrkelTree::open_with_options(&dir, options).unwrap();
+        let keys: Vec<Digest> = (0..40)
+            .map(|i| sha3(format!("name-{}", i).as_bytes()))
+            .collect();
+
+        let mut inserted = 0;
+        for k in &keys {
+            match t.insert(*k, b"value") {
+                Ok(()) => inserted += 1,
+                Err(Error::OutOfBudget { nodes, budget: 60 }) => {
+                    assert!(nodes >= 60);
+                    break;
+                }
+                Err(e) => panic!("Unexpected error {:?}", e),
+            }
+            assert_eq!(t.dirty_nodes(), count_dirty(t.root.as_ref().unwrap()));
+        }
+        assert!(inserted < keys.len());
+
+        t.commit().unwrap();
+        assert_eq!(t.dirty_nodes(), 0);
+        for k in &keys[inserted..inserted + 5] {
+            t.insert(*k, b"value").unwrap();
+            t.insert(*k, b"other").unwrap();
+            assert_eq!(t.dirty_nodes(), count_dirty(t.root.as_ref().unwrap()));
+        }
+    }
+
+    #[test]
+    fn revert_to_previous_commit() {
+        let dir = test_dir("revert_to_previous_commit");
+        let key1 = sha3(b"name-1");
+        let key2 = sha3(b"name-2");
+
+        let mut t = UrkelTree::open(&dir);
+        assert!(t.previous_root().is_none());
+        assert!(t.revert_to_previous_commit().is_err());
+
+        t.insert(key1, b"value-1").unwrap();
+        t.commit().unwrap();
+        let first = t.get_root();
+        assert!(t.previous_root().is_none());
+
+        t.insert(key2, b"value-2").unwrap();
+        t.commit().unwrap();
+        assert_eq!(t.previous_root(), Some(first));
+
+        t.revert_to_previous_commit().unwrap();
+        assert_eq!(t.get_root(), first);
+        assert_eq!(t.get(key1).unwrap(), Some(Vec::from("value-1")));
+        assert_eq!(t.get(key2).unwrap(), None);
+
+        // The revert survives a reopen
+        let mut reopened = UrkelTree::open(&dir);
+        assert_eq!(reopened.get_root(), first);
+        assert_eq!(reopened.get(key1).unwrap(), Some(Vec::from("value-1")));
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn signed_commits() {
+        use ed25519_dalek::SigningKey;
+
+        let dir = test_dir("signed_commits");
+        let key = SigningKey::from_bytes(&[3u8; 32]);
+        let signed_options = || StoreOptions {
+            signing_key: Some(key.clone()),
+            verifying_key: Some(key.verifying_key()),
+            ..Default::default()
+        };
+
+        let mut t = UrkelTree::open_with_options(&dir, signed_options()).unwrap();
+        t.insert(sha3(b"name-1"), b"value-1").unwrap();
+        t.commit().unwrap();
+        t.insert(sha3(b"name-2"), b"value-2").unwrap();
+        t.commit().unwrap();
+        let root = t.get_root();
+
+        let history = t.verify_commit_history(&key.verifying_key()).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].root, root);
+        assert_eq!(history[0].sequence, 2);
+
+        // Reopening checks the latest signature
+        assert!(UrkelTree::open_with_options(&dir, signed_options()).is_ok());
+
+        // ...and rejects the store for anyone else's key
+        let other = SigningKey::from_bytes(&[4u8; 32]);
+        let wrong = StoreOptions {
+            signing_key: None,
+            verifying_key: Some(other.verifying_key()),
+            ..Default::default()
+        };
+        assert!(UrkelTree::open_with_options(&dir, wrong).is_err());
+    }
+
+    #[test]
+    fn corrupt_nodes_are_quarantined() {
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+
+        let dir = test_dir("corrupt_nodes_are_quarantined");
+        let key1 = sha3(b"name-1");
+        let key2 = sha3(b"name-2");
+        assert!(has_bit(&key1, 0) != has_bit(&key2, 0));
+        {
+            let mut t = UrkelTree::open(&dir);
+            t.insert(key1, b"value-1").unwrap();
+            t.insert(key2, b"value-2").unwrap();
+            t.commit().unwrap();
+        }
+
+        let options = StoreOptions {
+            quarantine: true,
+            ..Default::default()
+        };
+        let mut t = UrkelTree::open_with_options(&dir, options).unwrap();
+
+        // Wipe the leaf flag of key1's leaf node
+        let root = t.root.clone().unwrap();
+        let bad = match t.resolve(key1, &root).unwrap() {
+            Node::Internal { right, .. } if has_bit(&key1, 0) => *right,
+            Node::Internal { left, .. } => *left,
+            _ => panic!("Expected an internal root"),
+        };
+        let (index, pos) = bad.index_and_position();
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(::std::path::Path::new(&dir).join("0000000001"))
+            .unwrap();
+        f.seek(SeekFrom::Start(u64::from(pos >> 1))).unwrap();
+        f.write_all(&[0, 0]).unwrap();
+
+        match t.get(key1) {
+            Err(Error::Corrupt { key, .. }) => assert_eq!(key, key1),
+            other => panic!("Expected corruption, got {:?}", other),
+        }
+        assert_eq!(t.quarantined().len(), 1);
+        assert_eq!(t.quarantined()[0].index, index);
+
+        // The rest of the tree is still usable
+        assert_eq!(t.get(key2).unwrap(), Some(Vec::from("value-2")));
+
+        // Quarantined regions fail without another read, and so do writes
+        // that need them, leaving the tree as it was
+        assert!(t.prove(key1).unwrap_err().is_corruption());
+        let root = t.get_root();
+        assert!(t.insert(key1, b"new value").is_err());
+        assert_eq!(t.get_root(), root);
+        assert_eq!(t.get(key2).unwrap(), Some(Vec::from("value-2")));
+    }
+
+    #[test]
+    fn slow_operation_hook() {
+        use std::sync::{Arc, Mutex};
+
+        let dir = test_dir("slow_operation_hook");
+        let key1 = sha3(b"name-1");
+        {
+            let mut t = UrkelTree::open(&dir);
+            t.insert(key1, b"value-1").unwrap();
+            t.commit().unwrap();
+        }
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let mut t = UrkelTree::open(&dir);
+        {
+            let seen = seen.clone();
+            // Everything is slow with a zero threshold
+            t.on_slow_operation(Duration::from_secs(0), move |op| {
+                seen.lock().unwrap().push(op.operation.clone())
+            });
+        }
+
+        t.get(key1).unwrap();
+        t.insert(sha3(b"name-2"), b"value-2").unwrap();
+        let root = t.get_root();
+        t.commit().unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert!(seen
+            .iter()
+            .any(|op| matches!(op, Operation::Read { index: 1, .. })));
+        assert!(seen.contains(&Operation::Hash));
+        assert_eq!(seen.last(), Some(&Operation::Commit { root }));
+    }
+
+    #[test]
+    fn commit_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        struct Recorder {
+            veto: Arc<Mutex<bool>>,
+            commits: Arc<Mutex<Vec<CommitInfo>>>,
+        }
+
+        impl CommitHook for Recorder {
+            fn pre_commit(&mut self, _pending_root: Digest) -> ::std::result::Result<(), String> {
+                if *self.veto.lock().unwrap() {
+                    return Err(String::from("not today"));
+                }
+                Ok(())
+            }
+
+            fn post_commit(&mut self, info: &CommitInfo) {
+                self.commits.lock().unwrap().push(info.clone());
+            }
+        }
+
+        let dir = test_dir("commit_hooks");
+        let veto = Arc::new(Mutex::new(true));
+        let commits = Arc::new(Mutex::new(vec![]));
+
+        let mut t = UrkelTree::open(&dir);
+        t.on_commit(Recorder {
+            veto: veto.clone(),
+            commits: commits.clone(),
+        });
+
+        t.insert(sha3(b"name-1"), b"value-1").unwrap();
+        let pending = t.get_root();
+        match t.commit() {
+            Err(Error::CommitVetoed(reason)) => assert_eq!(reason, "not today"),
+            other => panic!("Expected a veto, got {:?}", other),
+        }
+        assert!(commits.lock().unwrap().is_empty());
+        assert_eq!(UrkelTree::open(&dir).get_root(), Digest::default());
+
+        // Nothing was lost by the veto
+        *veto.lock().unwrap() = false;
+        t.commit().unwrap();
+        let commits = commits.lock().unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].root, pending);
+        assert_eq!(commits[0].meta_index, 1);
+        assert_eq!(UrkelTree::open(&dir).get_root(), pending);
+    }
+
+    #[test]
+    fn vetoed_commits_keep_merges_queued() {
+        use std::sync::{Arc, Mutex};
+
+        struct Veto(Arc<Mutex<Vec<Digest>>>);
+
+        impl CommitHook for Veto {
+            fn pre_commit(&mut self, pending_root: Digest) -> ::std::result::Result<(), String> {
+                let mut seen = self.0.lock().unwrap();
+                seen.push(pending_root);
+                if seen.len() == 1 {
+                    return Err(String::from("not yet"));
+                }
+                Ok(())
+            }
+        }
+
+        let key = sha3(b"counter");
+        let seen = Arc::new(Mutex::new(vec![]));
+        let mut t = UrkelTree::open(test_dir("vetoed_commits_keep_merges_queued"));
+        t.set_merge_operator(|_: Digest, existing: Option<&[u8]>, operand: &[u8]| {
+            let mut value = existing.unwrap_or_default().to_vec();
+            value.extend_from_slice(operand);
+            value
+        });
+        t.insert(sha3(b"name-1"), b"value-1").unwrap();
+        t.merge_value(key, b"a".to_vec()).unwrap();
+        t.on_commit(Veto(seen.clone()));
+
+        let staged = t.get_root();
+        assert!(t.commit().is_err());
+        assert_eq!(t.get_root(), staged);
+        assert!(t.contains_key(key).unwrap());
+        assert_eq!(t.get(key).unwrap(), Some(b"a".to_vec()));
+
+        // The root the hook was shown is the one committed
+        t.commit().unwrap();
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0], seen[1]);
+        assert_ne!(seen[0], staged);
+        assert_eq!(t.get_root(), seen[0]);
+        assert_eq!(t.get(key).unwrap(), Some(b"a".to_vec()));
+    }
+
+    #[test]
+    fn subscribers_see_every_root() {
+        let dir = test_dir("subscribers_see_every_root");
+        let mut t = UrkelTree::open(&dir);
+        let roots = t.subscribe_roots();
+        let dropped = t.subscribe_roots();
+        drop(dropped);
+
+        for i in 0..3u8 {
+            t.insert_owned(sha3(&[i]), vec![i]).unwrap();
+            t.commit().unwrap();
+        }
+        let updates: Vec<_> = roots.try_iter().collect();
+        assert_eq!(
+            updates.iter().map(|u| u.sequence).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert_eq!(updates[2].root, t.get_root());
+        assert_eq!(t.root_subscribers.len(), 1);
+        drop(t);
+
+        // Numbering starts again, but the meta records stay apart
+        let mut t = UrkelTree::open(&dir);
+        let roots = t.subscribe_roots();
+        t.insert_owned(sha3(&[3]), vec![3]).unwrap();
+        t.commit().unwrap();
+        let update = roots.try_recv().unwrap();
+        assert_eq!(update.sequence, 1);
+        assert!(updates
+            .iter()
+            .all(|u| (u.meta_index, u.meta_pos) != (update.meta_index, update.meta_pos)));
+    }
+
+    #[test]
+    fn estimates_from_samples() {
+        let mut t = UrkelTree::open(test_dir("estimates_from_samples"));
+        assert_eq!(t.estimate_stats(10).unwrap(), EstimatedStats::default());
+
+        for i in 0..1000u32 {
+            t.insert_owned(sha3(&i.to_le_bytes()), vec![0; 10 + (i % 2) as usize * 20])
+                .unwrap();
+            if i == 500 {
+                t.commit().unwrap();
+            }
+        }
+        let stats = t.estimate_stats(2000).unwrap();
+        assert_eq!(stats.samples, 2000);
+        assert!((stats.entries - 1000.0).abs() < 150.0, "{:?}", stats);
+        assert!((stats.average_value_size - 20.0).abs() < 3.0, "{:?}", stats);
+        // log2(1000) is about 10
+        assert!(stats.average_depth > 8.0 && stats.average_depth < 14.0);
+    }
+
+    #[test]
+    fn watch_keys() {
+        let dir = test_dir("watch_keys");
+        let key1 = sha3(b"name-1");
+        let key2 = sha3(b"name-2");
+
+        let mut t = UrkelTree::open(&dir);
+        let changes = t.watch(key1);
+
+        t.insert(key1, b"value-1").unwrap();
+        t.insert(key2, b"value-2").unwrap();
+        t.commit().unwrap();
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            KeyChange {
+                key: key1,
+                old: None,
+                new: Some(Vec::from("value-1")),
+            }
+        );
+        assert!(changes.try_recv().is_err());
+
+        // Unwatched keys and unchanged values don't notify
+        t.insert(key2, b"value-3").unwrap();
+        t.insert(key1, b"value-1").unwrap();
+        t.commit().unwrap();
+        assert!(changes.try_recv().is_err());
+
+        t.insert(key1, b"value-4").unwrap();
+        t.commit().unwrap();
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.old, Some(Vec::from("value-1")));
+        assert_eq!(change.new, Some(Vec::from("value-4")));
+
+        // Dropped subscriptions are cleaned up
+        drop(changes);
+        t.insert(key1, b"value-5").unwrap();
+        t.commit().unwrap();
+        assert!(t.watchers.is_empty());
+    }
+
+    #[test]
+    fn amplification_stats() {
+        let dir = test_dir("amplification_stats");
+        let key1 = sha3(b"name-1");
+
+        let mut t = UrkelTree::open(&dir);
+        t.insert(key1, b"value-1").unwrap();
+        t.insert(sha3(b"name-2"), b"value-2").unwrap();
+        t.commit().unwrap();
+
+        let stats = t.stats().unwrap();
+        assert_eq!(stats.write.logical_bytes, 2 * 39);
+        assert!(stats.write.amplification() > 1.0);
+        assert_eq!(stats.files.len(), 1);
+        // Everything but the meta padding is live
+        let live = stats.files[0].live_bytes;
+        assert!(stats.files[0].dead_bytes() < t.store.meta_size() as u64);
+        assert_eq!(
+            live,
+            (INTERNAL_NODE_SIZE + 2 * t.store.leaf_size() + 14 + t.store.meta_size()) as u64
+        );
+
+        // Overwriting leaves the old leaf, its value and the old root dead
+        t.insert(key1, b"value-3").unwrap();
+        t.commit().unwrap();
+        let stats = t.stats().unwrap();
+        assert_eq!(stats.write.logical_bytes, 3 * 39);
+        assert_eq!(stats.files[0].live_bytes, live);
+        let dead = INTERNAL_NODE_SIZE + t.store.leaf_size() + 7;
+        assert!(stats.files[0].dead_bytes() >= dead as u64);
+        assert!(stats.space_amplification() > 1.0);
+    }
+
+    #[test]
+    fn compaction_reclaims_old_files() {
+        use std::path::PathBuf;
+
+        let dir = test_dir("compaction_reclaims_old_files");
+        let options = || StoreOptions {
+            max_file_size: Some(2048),
+            seal_files: true,
+            ..Default::default()
+        };
+        let keys: Vec<Digest> = (0..60u8).map(|i| sha3(&[i])).collect();
+        let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+        for round in 0..3u8 {
+            for k in &keys {
+                t.insert_owned(*k, vec![round; 20]).unwrap();
+            }
+            t.commit().unwrap();
+        }
+        let root = t.get_root();
+        let tail = t.store.current_index();
+        assert!(tail > 3);
+
+        assert_eq!(t.compact_files(2).unwrap(), 2);
+        assert_eq!(t.get_root(), root);
+        assert!(t.previous_root().is_none());
+        let data = PathBuf::from(&dir);
+        assert!(!data.join("0000000001").exists());
+        assert!(!data.join("0000000002").exists());
+        assert!(data.join("0000000003").exists());
+        drop(t);
+
+        // Sealed files left behind still check out, and every key reads
+        let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+        assert_eq!(t.get_root(), root);
+        for k in &keys {
+            assert_eq!(t.get(*k).unwrap(), Some(vec![2; 20]));
+        }
+
+        // The file being appended to is never removed
+        let tail = t.store.current_index();
+        t.compact_files(u16::MAX).unwrap();
+        assert!(data.join(format!("{:010}", tail)).exists());
+        for k in &keys {
+            assert_eq!(t.get(*k).unwrap(), Some(vec![2; 20]));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scrubs_deleted_files() {
+        use std::fs;
+        use std::path::PathBuf;
+
+        let dir = test_dir("scrubs_deleted_files");
+        let options = StoreOptions {
+            max_file_size: Some(1024),
+            scrub_deleted: true,
+            ..Default::default()
+        };
+        let mut t = UrkelTree::open_with_options(&dir, options).unwrap();
+        for round in 0..3u8 {
+            for i in 0..20u8 {
+                t.insert_owned(sha3(&[i]), vec![round; 20]).unwrap();
+            }
+            t.commit().unwrap();
+        }
+
+        // A second link keeps the blocks of the first file to look at
+        let data = PathBuf::from(&dir);
+        let kept = data.join("kept");
+        fs::hard_link(data.join("0000000001"), &kept).unwrap();
+        assert!(fs::read(&kept).unwrap().iter().any(|b| *b != 0));
+        assert_eq!(t.compact_files(1).unwrap(), 1);
+        assert!(!data.join("0000000001").exists());
+        assert!(fs::read(&kept).unwrap().iter().all(|b| *b == 0));
+        assert_eq!(t.get(sha3(&[7])).unwrap(), Some(vec![2; 20]));
+    }
+
+    #[test]
+    fn retention_deletes_unreachable_files() {
+        use std::path::PathBuf;
+
+        let dir = test_dir("retention_deletes_unreachable_files");
+        let options = |retention| StoreOptions {
+            max_file_size: Some(1024),
+            retention,
+            ..Default::default()
+        };
+        let keys: Vec<Digest> = (0..20u8).map(|i| sha3(&[i])).collect();
+        let count = || {
+            ::std::fs::read_dir(&dir)
+                .unwrap()
+                .filter(|e| e.as_ref().unwrap().file_name().len() == 10)
+                .count()
+        };
+
+        let mut roots = vec![];
+        {
+            let mut t =
+                UrkelTree::open_with_options(&dir, options(RootRetention::LastN(2))).unwrap();
+            for round in 0..6u8 {
+                for k in &keys {
+                    t.insert_owned(*k, vec![round; 30]).unwrap();
+                }
+                t.commit().unwrap();
+                roots.push(t.get_root());
+            }
+            // The previous root is kept, the ones before aren't
+            assert_eq!(t.previous_root(), Some(roots[4]));
+            assert!(!PathBuf::from(&dir).join("0000000001").exists());
+        }
+
+        let mut t = UrkelTree::open_with_options(&dir, options(RootRetention::LatestOnly)).unwrap();
+        assert_eq!(t.get_root(), roots[5]);
+        let before = count();
+        t.insert_owned(keys[0], vec![9; 30]).unwrap();
+        t.commit().unwrap();
+        assert!(count() <= before);
+        drop(t);
+
+        let mut t = UrkelTree::open_with_options(&dir, options(RootRetention::LatestOnly)).unwrap();
+        assert_eq!(t.get(keys[0]).unwrap(), Some(vec![9; 30]));
+        for k in &keys[1..] {
+            assert_eq!(t.get(*k).unwrap(), Some(vec![5; 30]));
+        }
+    }
+
+    #[test]
+    fn open_modes() {
+        use std::fs;
+        use std::path::PathBuf;
+
+        let dir = test_dir("open_modes");
+        match UrkelTree::open_existing(&dir, StoreOptions::default()) {
+            Err(Error::NotFound) => {}
+            other => panic!("Expected NotFound, got {:?}", other.err()),
+        }
+        assert!(!PathBuf::from(&dir).exists());
+
+        {
+            let mut t = UrkelTree::create_new(&dir, StoreOptions::default()).unwrap();
+            t.insert(sha3(b"name-1"), b"value-1").unwrap();
+            t.commit().unwrap();
+        }
+        match UrkelTree::create_new(&dir, StoreOptions::default()) {
+            Err(Error::AlreadyExists) => {}
+            other => panic!("Expected AlreadyExists, got {:?}", other.err()),
+        }
+        let mut t = UrkelTree::open_existing(&dir, StoreOptions::default()).unwrap();
+        assert_eq!(t.get(sha3(b"name-1")).unwrap(), Some(Vec::from("value-1")));
+        drop(t);
+
+        fs::write(PathBuf::from(&dir).join("version"), 5u32.to_le_bytes()).unwrap();
+        match UrkelTree::open_or_create(&dir, StoreOptions::default()) {
+            Err(Error::WrongVersion {
+                found: 5,
+                supported: 4,
+            }) => {}
+            other => panic!("Expected WrongVersion, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn commits_record_the_leaf_count() {
+        use std::fs;
+        use std::path::PathBuf;
+
+        let dir = test_dir("commits_record_the_leaf_count");
+        let mut t = UrkelTree::open(&dir);
+        assert!(t.is_empty());
+        assert_eq!(t.len().unwrap(), 0);
+        for i in 0..10u8 {
+            t.insert_owned(sha3(&[i]), vec![i]).unwrap();
+        }
+        t.remove(sha3(&[0])).unwrap();
+        t.commit().unwrap();
+        t.remove(sha3(&[1])).unwrap();
+        t.commit().unwrap();
+        drop(t);
+
+        // Read back with the commit rather than counted
+        let mut t = UrkelTree::open(&dir);
+        let reads = t.io_stats().reads;
+        assert_eq!(t.len().unwrap(), 8);
+        assert_eq!(t.io_stats().reads, reads);
+        assert!(!t.is_empty());
+        t.revert_to_previous_commit().unwrap();
+        assert_eq!(t.len().unwrap(), 9);
+
+        // Stores from before version 4 still count them by walking
+        let old = test_dir("commits_record_the_leaf_count_v3");
+        fs::create_dir_all(&old).unwrap();
+        fs::write(PathBuf::from(&old).join("version"), [3, 0, 0, 0, 0, 32]).unwrap();
+        let mut t = UrkelTree::open(&old);
+        for i in 0..3u8 {
+            t.insert_owned(sha3(&[i]), vec![i]).unwrap();
+            t.commit().unwrap();
+        }
+        drop(t);
+        let mut t = UrkelTree::open(&old);
+        assert_eq!(t.store.meta_size(), 36);
+        assert_eq!(t.len().unwrap(), 3);
+        assert_eq!(t.get(sha3(&[2])).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn narrow_keys() {
+        let dir = test_dir("narrow_keys");
+        let narrow = |i: u8| {
+            let mut key = sha3(&[i]);
+            key.0[20..].copy_from_slice(&[0; 12]);
+            key
+        };
+        let with_size = |key_size| StoreOptions {
+            key_size: Some(key_size),
+            ..Default::default()
+        };
+        let root = {
+            let mut t = UrkelTree::open_with_options(&dir, with_size(20)).unwrap();
+            assert_eq!(t.key_bits(), 160);
+            for i in 0..20u8 {
+                t.insert_owned(narrow(i), vec![i; 4]).unwrap();
+            }
+            match t.insert(sha3(b"wide"), b"value") {
+                Err(Error::KeyTooWide { key_size: 20 }) => {}
+                other => panic!("Expected KeyTooWide, got {:?}", other),
+            }
+            t.commit().unwrap();
+            assert_eq!(t.store.leaf_size(), 29);
+            t.get_root()
+        };
+
+        // The width comes from the store once it's there
+        let mut t = UrkelTree::open(&dir);
+        assert_eq!(t.get_root(), root);
+        assert_eq!(t.key_bits(), 160);
+        assert_eq!(t.get(narrow(3)).unwrap(), Some(vec![3; 4]));
+        let mut proof = t.prove(narrow(3)).unwrap();
+        assert_eq!(proof.verify(root, narrow(3), 160).unwrap(), vec![3; 4]);
+
+        // Absent keys ending at another leaf prove so at the same width
+        let absent = (20..=255u8)
+            .map(|i| (narrow(i), t.prove(narrow(i)).unwrap()))
+            .find(|(_, proof)| proof.proof_type == ProofType::Collision);
+        let (key, proof) = absent.expect("No collision among the absent keys");
+        use hashutils::Sha3;
+        proof.check_with(&Sha3, root, key, 160, None).unwrap();
+        let mut wide = proof.clone();
+        wide.key.as_mut().unwrap().0[31] = 1;
+        assert!(wide.check_with(&Sha3, root, key, 160, None).is_err());
+        drop(t);
+
+        match UrkelTree::open_with_options(&dir, with_size(32)) {
+            Err(Error::KeySizeMismatch {
+                found: 20,
+                expected: 32,
+            }) => {}
+            other => panic!("Expected KeySizeMismatch, got {:?}", other.err()),
+        }
+        match UrkelTree::open_with_options(test_dir("narrow_keys_b"), with_size(33)) {
+            Err(Error::UnsupportedKeySize(33)) => {}
+            other => panic!("Expected UnsupportedKeySize, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn refuses_stores_written_with_another_hash() {
+        use format::{Endianness, Format};
+        use std::fs;
+        use store::VERSION_FILE_NAME;
+
+        let dir = test_dir("refuses_stores_written_with_another_hash");
+        fs::create_dir_all(&dir).unwrap();
+        let format = Format {
+            hash_id: 1,
+            ..Format::new(Endianness::Little)
+        };
+        fs::write(Path::new(&dir).join(VERSION_FILE_NAME), format.encode()).unwrap();
+        match UrkelTree::open_with_options(&dir, Default::default()) {
+            Err(Error::HashFunctionMismatch {
+                found: 1,
+                expected: 0,
+            }) => {}
+            other => panic!("Expected HashFunctionMismatch, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn checkpoints_salvage_interrupted_commits() {
+        use std::fs::{self, OpenOptions};
+        use std::path::PathBuf;
+
+        let dir = test_dir("checkpoints_salvage_interrupted_commits");
+        let options = || StoreOptions {
+            checkpoint_bytes: Some(4096),
+            ..Default::default()
+        };
+        let keys: Vec<Digest> = (0..400u32).map(|i| sha3(&i.to_le_bytes())).collect();
+        let size = || -> u64 {
+            fs::read_dir(&dir)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.file_name().unwrap().len() == 10)
+                .map(|p| fs::metadata(p).unwrap().len())
+                .sum()
+        };
+
+        let (base, full, info) = {
+            let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+            t.insert(keys[0], b"base").unwrap();
+            t.commit().unwrap();
+            let base = t.get_root();
+            for k in &keys {
+                t.insert(*k, b"value").unwrap();
+            }
+            t.commit().unwrap();
+            (base, t.get_root(), t.last_commit().unwrap())
+        };
+
+        // Lose the meta of the second commit, as if it crashed just before
+        let path = PathBuf::from(&dir).join(format!("{:010}", info.meta_index));
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_len(info.meta_pos as u64))
+            .unwrap();
+        let before = size();
+
+        let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+        assert_eq!(t.get_root(), base);
+        assert!(t.salvaged_subtrees() > 0);
+        for k in &keys {
+            t.insert(*k, b"value").unwrap();
+        }
+        t.commit().unwrap();
+        assert_eq!(t.get_root(), full);
+        assert_eq!(t.salvaged_subtrees(), 0);
+        // Most of the tree wasn't written again
+        assert!(size() - before < before / 2);
+        for k in &keys {
+            assert_eq!(t.get(*k).unwrap(), Some(Vec::from("value")));
+        }
+    }
+
+    #[test]
+    fn io_stats_count_store_work() {
+        use cache::BlockCache;
+        use std::sync::Arc;
+
+        let dir = test_dir("io_stats_count_store_work");
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let options = || StoreOptions {
+            cache: Some(cache.clone()),
+            ..Default::default()
+        };
+        {
+            let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+            t.insert(sha3(b"name-1"), b"value-1").unwrap();
+            t.insert(sha3(b"name-2"), b"value-2").unwrap();
+            t.commit().unwrap();
+            let io = t.io_stats();
+            assert_eq!((io.nodes_written, io.values_written, io.commits), (3, 2, 1));
+            assert_eq!(io.bytes_appended, t.stats().unwrap().write.physical_bytes);
+        }
+
+        let mut t = UrkelTree::open_with_options(&dir, options()).unwrap();
+        let before = t.io_stats();
+        t.get(sha3(b"name-1")).unwrap();
+        let first = t.io_stats();
+        t.get(sha3(b"name-1")).unwrap();
+        let second = t.io_stats();
+        assert!(first.reads > before.reads);
+        assert!(first.cache_misses > before.cache_misses);
+        assert_eq!(second.reads, first.reads);
+        assert!(second.cache_hits > first.cache_hits);
+        assert_eq!(second.nodes_written, 0);
+    }
+
+    #[test]
+    fn rebuild_matches_inserts() {
+        let leaves: Vec<(Digest, Vec<u8>)> = (0..300u32)
+            .map(|i| (sha3(&i.to_le_bytes()), i.to_le_bytes().to_vec()))
+            .collect();
+        let mut expected = UrkelTree::open(test_dir("rebuild_matches_inserts_a"));
+        for (k, v) in &leaves {
+            expected.insert_owned(*k, v.clone()).unwrap();
+        }
+        expected.commit().unwrap();
+
+        // Repeats replace, and the order leaves come in doesn't matter
+        let mut shuffled = leaves.clone();
+        shuffled.reverse();
+        shuffled.insert(0, (leaves[7].0, b"old".to_vec()));
+        let dir = test_dir("rebuild_matches_inserts_b");
+        let mut t =
+            UrkelTree::rebuild_from_leaves(&dir, StoreOptions::default(), shuffled).unwrap();
+        assert_eq!(t.get_root(), expected.get_root());
+        assert_eq!(t.get(leaves[7].0).unwrap(), Some(leaves[7].1.clone()));
+        drop(t);
+        assert_eq!(UrkelTree::open(&dir).get_root(), expected.get_root());
+
+        // A single leaf is the root, whichever group it fell in
+        let one = vec![leaves[0].clone()];
+        let t = UrkelTree::rebuild_from_leaves(
+            test_dir("rebuild_matches_inserts_c"),
+            StoreOptions::default(),
+            one,
+        )
+        .unwrap();
+        let mut single = UrkelTree::open(test_dir("rebuild_matches_inserts_d"));
+        single
+            .insert_owned(leaves[0].0, leaves[0].1.clone())
+            .unwrap();
+        assert_eq!(t.get_root(), single.get_root());
+    }
+
+    #[test]
+    fn big_endian_store() {
+        use format::Endianness;
+        use std::fs;
+        use std::path::PathBuf;
+
+        let dir = test_dir("big_endian_store");
+        let keys: Vec<Digest> = (0..50u8).map(|i| sha3(&[i])).collect();
+        let root = {
+            let options = StoreOptions {
+                endianness: Endianness::Big,
+                ..Default::default()
+            };
+            let mut t = UrkelTree::open_with_options(&dir, options).unwrap();
+            for (i, k) in keys.iter().enumerate() {
+                t.insert_owned(*k, vec![i as u8; 10]).unwrap();
+            }
+            t.commit().unwrap();
+            t.get_root()
+        };
+        let version = fs::read(PathBuf::from(&dir).join("version")).unwrap();
+        assert_eq!(version, [4, 0, 0, 0, 1, 32]);
+
+        // The option only applies to new stores
+        let mut t = UrkelTree::open(&dir);
+        assert_eq!(t.get_root(), root);
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(t.get(*k).unwrap(), Some(vec![i as u8; 10]));
+        }
+    }
+
+    #[test]
+    fn commits_track_live_bytes() {
+        let dir = test_dir("commits_track_live_bytes");
+        let keys: Vec<Digest> = (0..30u8).map(|i| sha3(&[i])).collect();
+        let mut t = UrkelTree::open(&dir);
+        for k in &keys {
+            t.insert(*k, b"value-1").unwrap();
+        }
+        t.commit().unwrap();
+        // Only the meta padding is dead
+        assert!(t.stats().unwrap().files[0].dead_bytes() < t.store.meta_size() as u64);
+
+        for k in &keys[..10] {
+            t.insert(*k, b"value-22").unwrap();
+        }
+        for k in &keys[10..15] {
+            t.remove(*k).unwrap();
+        }
+        t.commit().unwrap();
+        t.insert(keys[20], b"value-333").unwrap();
+        t.commit().unwrap();
+        let tracked = t.stats().unwrap();
+        assert!(tracked.files[0].garbage_ratio() > 0.1);
+        drop(t);
+
+        // A fresh walk agrees with what the commits tracked
+        let counted = UrkelTree::open(&dir).stats().unwrap();
+        assert_eq!(counted.files, tracked.files);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn operation_metrics() {
+        let dir = test_dir("operation_metrics");
+        let key1 = sha3(b"name-1");
+        {
+            let mut t = UrkelTree::open(&dir);
+            t.insert(key1, b"value-1").unwrap();
+            t.insert(sha3(b"name-2"), b"value-2").unwrap();
+            t.commit().unwrap();
+        }
+
+        let mut t = UrkelTree::open(&dir);
+        t.get(key1).unwrap();
+        t.get(key1).unwrap();
+        t.prove(key1).unwrap();
+        t.insert(sha3(b"name-3"), b"value-3").unwrap();
+        t.commit().unwrap();
+
+        let m = t.metrics();
+        assert_eq!(m.get.latency_us.count(), 2);
+        assert_eq!(m.prove.latency_us.count(), 1);
+        assert_eq!(m.insert.latency_us.count(), 1);
+        assert_eq!(m.commit.latency_us.count(), 1);
+        // Root internal, leaf and its value
+        assert_eq!(m.get.store_reads.max(), 3);
+
+        t.reset_metrics();
+        assert_eq!(t.metrics().get.latency_us.count(), 0);
+    }
 }
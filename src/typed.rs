@@ -0,0 +1,201 @@
+//! Typed access to a tree
+//!
+//! `UrkelMap` stores values of one type under keys of another.  A
+//! `ValueCodec` turns values into the bytes the tree holds and back, and a
+//! `KeyHasher` picks the digest each key is stored under.  Proofs are the
+//! tree's own, so a verifier needs the same codec and hasher:
+//!
+//! ```ignore
+//! let mut map = UrkelMap::new(tree, U64Codec, Sha3Keys);
+//! map.insert("balance", &10)?;
+//! let proof = map.prove("balance")?;
+//! ```
+use super::Result;
+use byteorder::{ByteOrder, LittleEndian};
+use errors::Error;
+use hashutils::{sha3, Digest};
+use map::AuthenticatedMap;
+use proof::Proof;
+use std::marker::PhantomData;
+use tree::UrkelTree;
+
+/// Encodes values of type `V` for the tree
+pub trait ValueCodec<V> {
+    fn encode(&self, value: &V) -> Vec<u8>;
+    fn decode(&self, bits: &[u8]) -> Result<V>;
+}
+
+/// Picks the digest a key of type `K` is stored under
+pub trait KeyHasher<K: ?Sized> {
+    fn hash(&self, key: &K) -> Digest;
+}
+
+impl<K: ?Sized, F> KeyHasher<K> for F
+where
+    F: Fn(&K) -> Digest,
+{
+    fn hash(&self, key: &K) -> Digest {
+        self(key)
+    }
+}
+
+/// Values stored as they are
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BytesCodec;
+
+impl ValueCodec<Vec<u8>> for BytesCodec {
+    fn encode(&self, value: &Vec<u8>) -> Vec<u8> {
+        value.clone()
+    }
+
+    fn decode(&self, bits: &[u8]) -> Result<Vec<u8>> {
+        Ok(bits.to_vec())
+    }
+}
+
+/// Values stored as 8 little endian bytes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct U64Codec;
+
+impl ValueCodec<u64> for U64Codec {
+    fn encode(&self, value: &u64) -> Vec<u8> {
+        value.to_le_bytes().to_vec()
+    }
+
+    fn decode(&self, bits: &[u8]) -> Result<u64> {
+        if bits.len() != 8 {
+            return Err(Error::Decode("Value isn't a u64"));
+        }
+        Ok(LittleEndian::read_u64(bits))
+    }
+}
+
+/// Keys stored under the sha3 hash of their bytes.  Trees with keys
+/// narrower than 32 bytes need a hasher that fits them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha3Keys;
+
+impl<K: AsRef<[u8]> + ?Sized> KeyHasher<K> for Sha3Keys {
+    fn hash(&self, key: &K) -> Digest {
+        sha3(key.as_ref())
+    }
+}
+
+/// A tree holding values of type `V` under keys of type `K`
+pub struct UrkelMap<K: ?Sized, V, C, H> {
+    tree: UrkelTree,
+    codec: C,
+    hasher: H,
+    marker: PhantomData<fn(&K) -> V>,
+}
+
+impl<K: ?Sized, V, C, H> UrkelMap<K, V, C, H>
+where
+    C: ValueCodec<V>,
+    H: KeyHasher<K>,
+{
+    pub fn new(tree: UrkelTree, codec: C, hasher: H) -> Self {
+        UrkelMap {
+            tree,
+            codec,
+            hasher,
+            marker: PhantomData,
+        }
+    }
+
+    /// The digest `key` is stored under
+    pub fn key_hash(&self, key: &K) -> Digest {
+        self.hasher.hash(key)
+    }
+
+    /// The value for `key`, if any
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        let hash = self.hasher.hash(key);
+        match self.tree.get(hash)? {
+            Some(bits) => self.codec.decode(&bits).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the value for `key`
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+        let hash = self.hasher.hash(key);
+        self.tree.insert_owned(hash, self.codec.encode(value))
+    }
+
+    /// Remove `key`, returning whether it was there
+    pub fn remove(&mut self, key: &K) -> Result<bool> {
+        let hash = self.hasher.hash(key);
+        self.tree.remove(hash)
+    }
+
+    /// Prove the value, or absence, of `key`
+    pub fn prove(&mut self, key: &K) -> Result<Proof> {
+        let hash = self.hasher.hash(key);
+        self.tree.prove(hash)
+    }
+
+    /// Check `proof` for `key` against `root`, returning the proven value
+    /// or `None` if the proof shows the key is absent
+    pub fn verify(&self, root: Digest, key: &K, proof: &Proof) -> Result<Option<V>> {
+        let hash = self.hasher.hash(key);
+        match AuthenticatedMap::verify(&self.tree, root, hash, proof)? {
+            Some(bits) => self.codec.decode(&bits).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn root(&self) -> Digest {
+        self.tree.get_root()
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        self.tree.commit()
+    }
+
+    /// The tree underneath, for anything the map doesn't cover
+    pub fn tree(&mut self) -> &mut UrkelTree {
+        &mut self.tree
+    }
+
+    pub fn into_tree(self) -> UrkelTree {
+        self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_dir;
+
+    #[test]
+    fn typed_values_round_trip() {
+        let tree = UrkelTree::open(test_dir("typed_values_round_trip"));
+        let mut map: UrkelMap<str, u64, _, _> = UrkelMap::new(tree, U64Codec, Sha3Keys);
+        map.insert("alice", &10).unwrap();
+        map.insert("bob", &20).unwrap();
+        map.commit().unwrap();
+        assert_eq!(map.get("alice").unwrap(), Some(10));
+        assert_eq!(map.get("carol").unwrap(), None);
+
+        let root = map.root();
+        let proof = map.prove("bob").unwrap();
+        assert_eq!(map.verify(root, "bob", &proof).unwrap(), Some(20));
+        let absent = map.prove("carol").unwrap();
+        assert_eq!(map.verify(root, "carol", &absent).unwrap(), None);
+        assert!(map.remove("alice").unwrap());
+
+        // Keys hashed by a closure, and bytes that won't decode
+        let mut tree = map.into_tree();
+        tree.insert(sha3(&7u32.to_le_bytes()), b"short").unwrap();
+        let by_id = |id: &u32| sha3(&id.to_le_bytes());
+        let mut map: UrkelMap<u32, u64, _, _> = UrkelMap::new(tree, U64Codec, by_id);
+        match map.get(&7) {
+            Err(Error::Decode(_)) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        let mut raw: UrkelMap<u32, Vec<u8>, _, _> =
+            UrkelMap::new(map.into_tree(), BytesCodec, by_id);
+        assert_eq!(raw.get(&7).unwrap(), Some(b"short".to_vec()));
+    }
+}
@@ -0,0 +1,142 @@
+//! Index from value hashes to the keys holding them
+//!
+//! With `StoreOptions::value_index` set, the tree tracks which keys hold
+//! each value (by `sha3(value)`), for deduplication and garbage analysis.
+//! Changes are appended to a `values` log at every commit, each entry
+//! giving the hash a key now holds, or zeros once it's removed.  The log
+//! is rewritten from scratch whenever the index has to be rebuilt.
+use super::Result;
+use hashutils::Digest;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use store::{open_append, set_mode};
+
+pub const VALUE_INDEX_FILE_NAME: &str = "values";
+const ENTRY_SIZE: usize = 64; // 32 + 32
+const REMOVED: [u8; 32] = [0; 32];
+
+/// Keys by value hash, kept in step with the working tree
+pub struct ValueIndex {
+    path: PathBuf,
+    mode: u32,
+    /// Keys by the hash of the value they hold
+    by_value: HashMap<[u8; 32], BTreeSet<[u8; 32]>>,
+    /// Value hash by key
+    by_key: HashMap<[u8; 32], [u8; 32]>,
+    /// Log entries not yet committed
+    pending: Vec<u8>,
+}
+
+impl ValueIndex {
+    /// Load the index in `dir`
+    pub fn open(dir: &Path, mode: u32) -> Result<Self> {
+        let mut index = ValueIndex {
+            path: dir.join(VALUE_INDEX_FILE_NAME),
+            mode,
+            by_value: HashMap::new(),
+            by_key: HashMap::new(),
+            pending: vec![],
+        };
+        if !index.path.exists() {
+            return Ok(index);
+        }
+
+        let mut bits = vec![];
+        OpenOptions::new()
+            .read(true)
+            .open(&index.path)?
+            .read_to_end(&mut bits)?;
+
+        // Ignore a torn trailing entry from an interrupted append
+        for entry in bits.chunks_exact(ENTRY_SIZE) {
+            let mut key = [0; 32];
+            let mut hash = [0; 32];
+            key.copy_from_slice(&entry[..32]);
+            hash.copy_from_slice(&entry[32..]);
+            index.apply(key, hash);
+        }
+        Ok(index)
+    }
+
+    /// Record that `key` now holds a value hashing to `hash`
+    pub fn set(&mut self, key: Digest, hash: Digest) {
+        if self.by_key.get(&key.0) != Some(&hash.0) {
+            self.apply(key.0, hash.0);
+            self.log(key.0, hash.0);
+        }
+    }
+
+    /// Record that `key` was removed
+    pub fn remove(&mut self, key: Digest) {
+        if self.by_key.contains_key(&key.0) {
+            self.apply(key.0, REMOVED);
+            self.log(key.0, REMOVED);
+        }
+    }
+
+    /// Does the index hold no keys?
+    pub fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+
+    /// Keys holding a value hashing to `hash`, in key order
+    pub fn keys(&self, hash: Digest) -> Vec<Digest> {
+        self.by_value
+            .get(&hash.0)
+            .map(|keys| keys.iter().map(|k| Digest(*k)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Append the changes since the last flush to the log
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            open_append(&self.path, self.mode).and_then(|mut f| f.write_all(&self.pending))?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+
+    /// Replace the index, and its log, with the given key/value hashes
+    pub fn rebuild<I: IntoIterator<Item = (Digest, Digest)>>(&mut self, entries: I) -> Result<()> {
+        self.by_value.clear();
+        self.by_key.clear();
+        self.pending.clear();
+        for (key, hash) in entries {
+            self.apply(key.0, hash.0);
+            self.log(key.0, hash.0);
+        }
+
+        let mut options = OpenOptions::new();
+        options.create(true).truncate(true).write(true);
+        set_mode(&mut options, self.mode);
+        options.open(&self.path)?.write_all(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn apply(&mut self, key: [u8; 32], hash: [u8; 32]) {
+        if let Some(old) = self.by_key.remove(&key) {
+            let now_unused = match self.by_value.get_mut(&old) {
+                Some(keys) => {
+                    keys.remove(&key);
+                    keys.is_empty()
+                }
+                None => false,
+            };
+            if now_unused {
+                self.by_value.remove(&old);
+            }
+        }
+        if hash != REMOVED {
+            self.by_key.insert(key, hash);
+            self.by_value.entry(hash).or_default().insert(key);
+        }
+    }
+
+    fn log(&mut self, key: [u8; 32], hash: [u8; 32]) {
+        self.pending.extend_from_slice(&key);
+        self.pending.extend_from_slice(&hash);
+    }
+}
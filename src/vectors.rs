@@ -0,0 +1,187 @@
+//! Deterministic test vectors
+//!
+//! A `Generator` turns a seed into a reproducible run of inserts, removes
+//! and commits against a tree, recording the root at each commit and
+//! encoded proofs for a few keys.  `write_vectors` prints a run as lines
+//! of hex another implementation can replay and check itself against:
+//!
+//! ```text
+//! insert <key> <value>
+//! remove <key>
+//! commit <root>
+//! proof <key> <encoded proof>
+//! ```
+//!
+//! Randomness comes from chaining sha3 over the seed, so the same seed
+//! gives the same run on every platform and with any `rand` version.
+use super::Result;
+use hashutils::{sha3, Digest};
+use std::io::Write;
+use tree::UrkelTree;
+
+/// One step of a generated run
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    Insert {
+        key: Digest,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Digest,
+    },
+    /// The root after committing, and `(key, encoded proof)` against it
+    /// for a present and an absent key
+    Commit {
+        root: Digest,
+        proofs: Vec<(Digest, Vec<u8>)>,
+    },
+}
+
+/// Produces the same run of operations for the same seed
+pub struct Generator {
+    state: Digest,
+    /// Keys inserted and not since removed, in insertion order
+    keys: Vec<Digest>,
+}
+
+impl Generator {
+    pub fn new(seed: &[u8]) -> Self {
+        Generator {
+            state: sha3(seed),
+            keys: vec![],
+        }
+    }
+
+    fn next(&mut self) -> Digest {
+        self.state = sha3(&self.state.0);
+        self.state
+    }
+
+    // Index into the live keys chosen by `r`
+    fn pick(&self, r: &Digest) -> usize {
+        let mut n = 0usize;
+        for b in &r.0[24..32] {
+            n = (n << 8) | *b as usize;
+        }
+        n % self.keys.len()
+    }
+
+    /// Apply `steps` operations to `tree`, then commit.  The tree should
+    /// start empty, for the roots to match another run from the seed.
+    pub fn run(&mut self, tree: &mut UrkelTree, steps: usize) -> Result<Vec<Step>> {
+        let mut out = Vec::with_capacity(steps + 1);
+        for _ in 0..steps {
+            let r = self.next();
+            let step = match r.0[0] % 8 {
+                5 if !self.keys.is_empty() => {
+                    // Overwrite an existing key
+                    let key = self.keys[self.pick(&r)];
+                    let value = self.value();
+                    tree.insert_owned(key, value.clone())?;
+                    Step::Insert { key, value }
+                }
+                6 if !self.keys.is_empty() => {
+                    let key = self.keys.remove(self.pick(&r));
+                    tree.remove(key)?;
+                    Step::Remove { key }
+                }
+                7 => self.commit(tree)?,
+                _ => {
+                    let key = self.next();
+                    let value = self.value();
+                    tree.insert_owned(key, value.clone())?;
+                    self.keys.push(key);
+                    Step::Insert { key, value }
+                }
+            };
+            out.push(step);
+        }
+        out.push(self.commit(tree)?);
+        Ok(out)
+    }
+
+    // A value of 1 to 64 bytes
+    fn value(&mut self) -> Vec<u8> {
+        let a = self.next();
+        let b = self.next();
+        let len = 1 + (a.0[0] % 64) as usize;
+        let mut value = a.0.to_vec();
+        value.extend_from_slice(&b.0);
+        value.truncate(len);
+        value
+    }
+
+    fn commit(&mut self, tree: &mut UrkelTree) -> Result<Step> {
+        tree.commit()?;
+        let r = self.next();
+        let mut keys = vec![];
+        if !self.keys.is_empty() {
+            keys.push(self.keys[self.pick(&r)]);
+        }
+        keys.push(self.next());
+
+        let mut proofs = Vec::with_capacity(keys.len());
+        for key in keys {
+            proofs.push((key, tree.prove(key)?.encode()?));
+        }
+        Ok(Step::Commit {
+            root: tree.get_root(),
+            proofs,
+        })
+    }
+}
+
+fn hex(bits: &[u8]) -> String {
+    bits.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Write `steps` in the line format described above
+pub fn write_vectors<W: Write>(steps: &[Step], mut out: W) -> Result<()> {
+    for step in steps {
+        match step {
+            Step::Insert { key, value } => writeln!(out, "insert {} {}", hex(&key.0), hex(value))?,
+            Step::Remove { key } => writeln!(out, "remove {}", hex(&key.0))?,
+            Step::Commit { root, proofs } => {
+                writeln!(out, "commit {}", hex(&root.0))?;
+                for (key, proof) in proofs {
+                    writeln!(out, "proof {} {}", hex(&key.0), hex(proof))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proof::Proof;
+    use test_dir;
+
+    #[test]
+    fn runs_are_reproducible() {
+        let mut a = UrkelTree::open(test_dir("vectors_a"));
+        let mut b = UrkelTree::open(test_dir("vectors_b"));
+        let steps = Generator::new(b"seed").run(&mut a, 200).unwrap();
+        assert_eq!(Generator::new(b"seed").run(&mut b, 200).unwrap(), steps);
+
+        let mut c = UrkelTree::open(test_dir("vectors_c"));
+        assert_ne!(Generator::new(b"other").run(&mut c, 200).unwrap(), steps);
+
+        // Every recorded proof checks against its commit's root
+        for step in &steps {
+            if let Step::Commit { root, proofs } = step {
+                for (key, bits) in proofs {
+                    let proof = Proof::decode(bits).unwrap();
+                    assert!(proof.check_salted(*root, *key, 256, None).is_ok());
+                }
+            }
+        }
+
+        let mut lines = vec![];
+        write_vectors(&steps, &mut lines).unwrap();
+        let text = String::from_utf8(lines).unwrap();
+        assert!(text.lines().any(|l| l.starts_with("commit ")));
+        assert!(text.lines().all(|l| l.split(' ').count() <= 3));
+    }
+}
@@ -0,0 +1,44 @@
+//! Just enough to check proofs
+//!
+//! Light clients only need digests, the hash functions and proofs.  This
+//! module gathers them in one place, none of them touch the store, and
+//! with `default-features = false` nothing else that could is built:
+//!
+//! ```toml
+//! urkel-rs = { version = "0.1", default-features = false }
+//! ```
+#[cfg(feature = "blake3")]
+pub use blake3::{blake3, Blake3, BLAKE3_ID};
+pub use envelope::Envelope;
+pub use errors::Error;
+pub use hashutils::{
+    hasher_for, sha3, sha3_internal, sha3_leaf_salted, sha3_sized_root, sha3_value_salted, Digest,
+    Hasher, Sha3, SHA3_ID,
+};
+pub use proof::{
+    has_bit, tag_proof, verify_bytes, verify_bytes_with, verify_reader, verify_tagged,
+    HistoricalProof, Proof, ProofType, SizedProof, VerifyError, MAX_PROOF_DEPTH,
+    MAX_PROOF_VALUE_SIZE,
+};
+
+#[cfg(all(test, feature = "store"))]
+mod tests {
+    use super::*;
+    use test_dir;
+    use tree::UrkelTree;
+
+    #[test]
+    fn verifies_an_encoded_proof() {
+        let mut t = UrkelTree::open(test_dir("verifies_an_encoded_proof"));
+        let key = sha3(b"name-1");
+        t.insert(key, b"value-1").unwrap();
+        t.commit().unwrap();
+        let bits = t.prove(key).unwrap().encode().unwrap();
+        let root = t.get_root();
+
+        // All a client holds is the root and the encoded proof
+        let mut proof = Proof::decode(&bits).unwrap();
+        assert_eq!(proof.proof_type, ProofType::Exists);
+        assert_eq!(proof.verify(root, key, 256), Ok(Vec::from("value-1")));
+    }
+}
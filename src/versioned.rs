@@ -0,0 +1,249 @@
+//! IAVL style versioned access to a tree
+//!
+//! Cosmos SDK applications drive their state through `set`/`get`/`remove`
+//! and number each `save_version`.  `VersionedTree` maps those versions
+//! onto commits, recording each one's meta record location and root in a
+//! `versions` file in the store's directory.  Entries are checked against
+//! the meta record they point to when loaded, so a damaged log can't
+//! silently load the wrong root.
+use super::Result;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use errors::Error;
+use hashutils::Digest;
+use hooks::CommitInfo;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use store::{open_append, StoreOptions, DEFAULT_FILE_MODE};
+use tree::UrkelTree;
+
+pub const VERSIONS_FILE_NAME: &str = "versions";
+const ENTRY_SIZE: usize = 47; // 8 + 2 + 4 + 32 + 1
+
+// An entry saving `version`, or deleting it if `info` is None
+fn encode(version: u64, info: Option<&CommitInfo>) -> Vec<u8> {
+    let mut wtr = Vec::with_capacity(ENTRY_SIZE);
+    wtr.write_u64::<LittleEndian>(version).unwrap();
+    match info {
+        Some(info) => {
+            wtr.write_u16::<LittleEndian>(info.meta_index).unwrap();
+            wtr.write_u32::<LittleEndian>(info.meta_pos).unwrap();
+            wtr.extend_from_slice(&info.root.0);
+            wtr.push(0);
+        }
+        None => {
+            wtr.extend_from_slice(&[0; 38]);
+            wtr.push(1);
+        }
+    }
+    wtr
+}
+
+// Replay the versions log in `dir`
+fn load_versions(dir: &Path) -> Result<BTreeMap<u64, CommitInfo>> {
+    let path = dir.join(VERSIONS_FILE_NAME);
+    let mut versions = BTreeMap::new();
+    if !path.exists() {
+        return Ok(versions);
+    }
+
+    let mut bits = vec![];
+    OpenOptions::new()
+        .read(true)
+        .open(path)?
+        .read_to_end(&mut bits)?;
+
+    // Ignore a torn trailing entry from an interrupted append
+    for entry in bits.chunks_exact(ENTRY_SIZE) {
+        let version = LittleEndian::read_u64(&entry[0..]);
+        if entry[ENTRY_SIZE - 1] == 1 {
+            versions.remove(&version);
+            continue;
+        }
+        let info = CommitInfo {
+            meta_index: LittleEndian::read_u16(&entry[8..]),
+            meta_pos: LittleEndian::read_u32(&entry[10..]),
+            root: Digest::from(&entry[14..46]),
+        };
+        versions.insert(version, info);
+    }
+    Ok(versions)
+}
+
+/// A tree whose commits are numbered versions that can be loaded and
+/// deleted
+pub struct VersionedTree {
+    tree: UrkelTree,
+    dir: PathBuf,
+    mode: u32,
+    /// Saved versions that haven't been deleted
+    versions: BTreeMap<u64, CommitInfo>,
+    /// Version the working tree is based on
+    version: u64,
+}
+
+impl VersionedTree {
+    /// Open the tree in `dir` at its latest saved version
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        VersionedTree::open_with_options(dir, StoreOptions::default())
+    }
+
+    /// Open the tree in `dir` at its latest saved version, with the given
+    /// store options
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, options: StoreOptions) -> Result<Self> {
+        let mode = options.file_mode.unwrap_or(DEFAULT_FILE_MODE);
+        let tree = UrkelTree::open_with_options(&dir, options)?;
+        let dir = dir.as_ref().to_path_buf();
+        let versions = load_versions(&dir)?;
+
+        let mut versioned = VersionedTree {
+            tree,
+            dir,
+            mode,
+            versions,
+            version: 0,
+        };
+        // A commit made after the last saved version isn't one
+        if let Some(&latest) = versioned.versions.keys().next_back() {
+            versioned.load_version(latest)?;
+        }
+        Ok(versioned)
+    }
+
+    /// Set the value for `key` in the working tree
+    pub fn set(&mut self, key: Digest, value: &[u8]) -> Result<()> {
+        self.tree.insert(key, value)
+    }
+
+    /// The value for `key` in the working tree
+    pub fn get(&mut self, key: Digest) -> Result<Option<Vec<u8>>> {
+        self.tree.get(key)
+    }
+
+    /// Remove `key` from the working tree, returning whether it was there
+    pub fn remove(&mut self, key: Digest) -> Result<bool> {
+        self.tree.remove(key)
+    }
+
+    /// Commit the working tree as the next version.  After loading an
+    /// older version, any later ones are deleted so its successor can be
+    /// saved in their place.
+    pub fn save_version(&mut self) -> Result<(Digest, u64)> {
+        let version = self.version + 1;
+        let later: Vec<u64> = self.versions.range(version..).map(|(v, _)| *v).collect();
+        for v in later {
+            self.delete_version(v)?;
+        }
+
+        self.tree.commit()?;
+        let info = self.tree.last_commit()?;
+        self.append(version, Some(&info))?;
+        self.versions.insert(version, info.clone());
+        self.version = version;
+        Ok((info.root, version))
+    }
+
+    /// Make `version` the working tree, discarding uncommitted changes
+    pub fn load_version(&mut self, version: u64) -> Result<()> {
+        let info = self
+            .versions
+            .get(&version)
+            .cloned()
+            .ok_or(Error::UnknownVersion { version })?;
+        self.tree.load_commit(&info)?;
+        self.version = version;
+        Ok(())
+    }
+
+    /// Forget `version`.  Its nodes stay in the data files, as there's no
+    /// compaction to reclaim them.
+    pub fn delete_version(&mut self, version: u64) -> Result<()> {
+        if version == self.version {
+            return Err(Error::ActiveVersion { version });
+        }
+        if !self.versions.contains_key(&version) {
+            return Err(Error::UnknownVersion { version });
+        }
+        self.append(version, None)?;
+        self.versions.remove(&version);
+        Ok(())
+    }
+
+    /// Version the working tree is based on, 0 before the first save
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Saved versions that haven't been deleted, oldest first
+    pub fn versions(&self) -> Vec<u64> {
+        self.versions.keys().cloned().collect()
+    }
+
+    /// The underlying tree, e.g. for proofs against the working tree
+    pub fn tree(&mut self) -> &mut UrkelTree {
+        &mut self.tree
+    }
+
+    fn append(&self, version: u64, info: Option<&CommitInfo>) -> Result<()> {
+        open_append(&self.dir.join(VERSIONS_FILE_NAME), self.mode)
+            .and_then(|mut f| f.write_all(&encode(version, info)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashutils::sha3;
+    use test_dir;
+
+    #[test]
+    fn save_load_and_delete_versions() {
+        let dir = test_dir("save_load_and_delete_versions");
+        let key1 = sha3(b"name-1");
+        let key2 = sha3(b"name-2");
+
+        let (root1, root2) = {
+            let mut t = VersionedTree::open(&dir).unwrap();
+            assert_eq!(t.version(), 0);
+            t.set(key1, b"value-1").unwrap();
+            let (root1, v1) = t.save_version().unwrap();
+            t.set(key2, b"value-2").unwrap();
+            t.remove(key1).unwrap();
+            let (root2, v2) = t.save_version().unwrap();
+            assert_eq!((v1, v2), (1, 2));
+            (root1, root2)
+        };
+
+        // Reopens at the latest version
+        let mut t = VersionedTree::open(&dir).unwrap();
+        assert_eq!(t.version(), 2);
+        assert_eq!(t.tree().get_root(), root2);
+        assert_eq!(t.get(key1).unwrap(), None);
+
+        t.load_version(1).unwrap();
+        assert_eq!(t.tree().get_root(), root1);
+        assert_eq!(t.get(key1).unwrap(), Some(Vec::from("value-1")));
+        match t.delete_version(1) {
+            Err(Error::ActiveVersion { version: 1 }) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        // Saving on top of version 1 replaces version 2
+        t.set(key2, b"other").unwrap();
+        let (_, v) = t.save_version().unwrap();
+        assert_eq!(v, 2);
+        assert_eq!(t.get(key2).unwrap(), Some(Vec::from("other")));
+
+        t.delete_version(1).unwrap();
+        assert_eq!(t.versions(), vec![2]);
+        match t.load_version(1) {
+            Err(Error::UnknownVersion { version: 1 }) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        let t = VersionedTree::open(&dir).unwrap();
+        assert_eq!(t.versions(), vec![2]);
+    }
+}
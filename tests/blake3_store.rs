@@ -0,0 +1,50 @@
+//! Installing a provider changes hashing for the whole process, so this
+//! runs apart from the unit tests
+#![cfg(all(feature = "blake3", feature = "store", feature = "rng"))]
+extern crate urkel_rs;
+
+use std::fs;
+use std::path::Path;
+use urkel_rs::blake3::{blake3, Blake3, BLAKE3_ID};
+use urkel_rs::{hash_id, set_sha3_provider, tree::UrkelTree, Error};
+
+#[test]
+fn stores_record_their_hash() {
+    let dir = std::env::temp_dir().join(format!("urkel-rs-blake3-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let written = {
+        let mut t = UrkelTree::open(&dir);
+        t.insert(blake3(b"name-1"), b"value-1").unwrap();
+        t.commit().unwrap();
+        t.get_root()
+    };
+
+    assert!(set_sha3_provider(&Blake3));
+    assert_eq!(hash_id(), BLAKE3_ID);
+    // Written with SHA3, so not to be opened with BLAKE3
+    match UrkelTree::open_with_options(&dir, Default::default()) {
+        Err(Error::HashFunctionMismatch {
+            found: 0,
+            expected: BLAKE3_ID,
+        }) => {}
+        other => panic!("Expected HashFunctionMismatch, got {:?}", other.err()),
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+    let mut t = UrkelTree::open(&dir);
+    let key = blake3(b"name-1");
+    t.insert(key, b"value-1").unwrap();
+    t.commit().unwrap();
+    let root = t.get_root();
+    assert_ne!(root, written);
+    t.prove(key)
+        .unwrap()
+        .check_with(&Blake3, root, key, 256, None)
+        .unwrap();
+    drop(t);
+
+    let version = fs::read(Path::new(&dir).join("version")).unwrap();
+    assert_eq!(version.last(), Some(&BLAKE3_ID));
+    UrkelTree::open(&dir);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,82 @@
+//! Installing a provider changes hashing for the whole process, so this
+//! runs apart from the unit tests
+#![cfg(all(feature = "blake3", feature = "store", feature = "rng"))]
+extern crate urkel_rs;
+
+use std::fs;
+use urkel_rs::blake3::{blake3, Blake3};
+use urkel_rs::envelope::Envelope;
+use urkel_rs::tree::UrkelTree;
+use urkel_rs::verify::{
+    tag_proof, verify_bytes, verify_reader, verify_tagged, ProofType, Sha3, VerifyError,
+};
+use urkel_rs::{set_sha3_provider, AuthenticatedMap};
+
+#[test]
+fn verifiers_hash_with_the_installed_provider() {
+    assert!(set_sha3_provider(&Blake3));
+    let dir = std::env::temp_dir().join(format!("urkel-rs-verifiers-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut t = UrkelTree::open(&dir);
+    for i in 0..20u8 {
+        t.insert(blake3(&[i]), &[i; 10]).unwrap();
+    }
+    t.commit().unwrap();
+    let (key, value) = (blake3(&[7]), vec![7; 10]);
+    let root = t.get_root();
+
+    let proof = t.prove(key).unwrap();
+    proof.check_with(&Blake3, root, key, 256, None).unwrap();
+    proof.check_salted(root, key, 256, None).unwrap();
+    assert_eq!(proof.clone().verify(root, key, 256).unwrap(), value);
+    assert_eq!(
+        AuthenticatedMap::verify(&t, root, key, &proof).unwrap(),
+        Some(value.clone())
+    );
+
+    let bits = proof.encode().unwrap();
+    assert_eq!(
+        verify_bytes(&root.0, &key.0, &bits, 256).unwrap(),
+        Some(value.clone())
+    );
+    assert_eq!(
+        verify_tagged(&root.0, &key.0, &tag_proof(&Blake3, &bits), 256).unwrap(),
+        Some(value.clone())
+    );
+    let mut streamed = vec![];
+    assert_eq!(
+        verify_reader(&mut &bits[..], root, key, 256, &mut streamed).unwrap(),
+        ProofType::Exists
+    );
+    assert_eq!(streamed, value);
+    let envelope = Envelope::wrap(&Blake3, 256, &proof).unwrap();
+    assert_eq!(envelope.verify(root, key).unwrap(), Some(value.clone()));
+
+    let commitment = t.sized_root().unwrap();
+    let sized = t.prove_sized(key).unwrap();
+    assert_eq!(
+        sized.verify(commitment, key, 256).unwrap(),
+        Some(value.clone())
+    );
+    let historical = t.prove_historical(root, key).unwrap();
+    assert_eq!(historical.verify(root, key, 256).unwrap(), Some(value));
+
+    // A proof tagged SHA3 is refused, not hashed, where there's no SHA3
+    let tagged = tag_proof(&Sha3, &bits);
+    match verify_tagged(&root.0, &key.0, &tagged, 256) {
+        #[cfg(feature = "keccak")]
+        Err(VerifyError::Invalid("Head Mismatch")) => {}
+        #[cfg(not(feature = "keccak"))]
+        Err(VerifyError::UnknownHasher(urkel_rs::verify::SHA3_ID)) => {}
+        other => panic!("Unexpected {:?}", other),
+    }
+
+    // Absent keys too
+    let absent = blake3(b"absent");
+    let bits = t.prove(absent).unwrap().encode().unwrap();
+    assert_eq!(verify_bytes(&root.0, &absent.0, &bits, 256).unwrap(), None);
+    let proof_type = verify_reader(&mut &bits[..], root, absent, 256, &mut vec![]).unwrap();
+    assert_ne!(proof_type, ProofType::Exists);
+    drop(t);
+    fs::remove_dir_all(&dir).unwrap();
+}
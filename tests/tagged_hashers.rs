@@ -0,0 +1,50 @@
+//! Installing a provider changes hashing for the whole process, so this
+//! runs apart from the unit tests
+#![cfg(feature = "keccak")]
+extern crate urkel_rs;
+
+use urkel_rs::verify::{hasher_for, sha3, SHA3_ID};
+use urkel_rs::{set_sha3_provider, Sha3Provider, Sha3State};
+
+// Not SHA3: the bytes folded into 32 with xor
+struct Folding;
+
+struct FoldingState([u8; 32], usize);
+
+impl Sha3State for FoldingState {
+    fn update(&mut self, data: &[u8]) {
+        for b in data {
+            self.0[self.1 % 32] ^= b;
+            self.1 += 1;
+        }
+    }
+
+    fn finalize(self: Box<Self>) -> [u8; 32] {
+        self.0
+    }
+}
+
+impl Sha3Provider for Folding {
+    fn start(&self) -> Box<dyn Sha3State> {
+        Box::new(FoldingState([0; 32], 0))
+    }
+}
+
+#[test]
+fn sha3_tag_means_sha3_whatever_the_provider() {
+    assert!(set_sha3_provider(&Folding));
+    let mut folded = [0; 32];
+    folded[..3].copy_from_slice(b"abc");
+    assert_eq!(sha3(b"abc").0, folded);
+
+    let hasher = hasher_for(SHA3_ID).unwrap();
+    assert_eq!(
+        format!("{:x}", hasher.hash(b"abc")),
+        "0x3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532"
+    );
+    let (left, right) = (hasher.hash(b"left"), hasher.hash(b"right"));
+    let mut joined = vec![1];
+    joined.extend_from_slice(&left.0);
+    joined.extend_from_slice(&right.0);
+    assert_eq!(hasher.hash_internal(left, right), hasher.hash(&joined));
+}